@@ -47,6 +47,8 @@ impl Agent for DebugAgent {
                     stdout: Some("Build successfully!".into()),
                     stderr: None,
                     exit_code: None,
+                    artifact: None,
+                    termination: None,
                 })
                 .await;
         });
@@ -70,6 +72,8 @@ impl Agent for DebugAgent {
                         stdout: Some(content),
                         stderr: None,
                         exit_code: Some(0),
+                        artifact: None,
+                        termination: None,
                     })
                     .await;
             }
@@ -80,6 +84,8 @@ impl Agent for DebugAgent {
                     stdout: None,
                     stderr: Some("unable to read debug.txt".into()),
                     exit_code: Some(1),
+                    artifact: None,
+                    termination: None,
                 })
                 .await;
         });
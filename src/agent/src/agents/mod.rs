@@ -1,5 +1,5 @@
 use crate::{
-    agent::{execute_response::Stage, ExecuteResponse},
+    agent::{execute_response::Stage, Artifact, ExecuteResponse, Termination},
     AgentError, AgentResult,
 };
 use async_trait::async_trait;
@@ -18,6 +18,8 @@ pub struct AgentOutput {
     pub stdout: Option<String>,
     pub stderr: Option<String>,
     pub exit_code: Option<i32>,
+    pub artifact: Option<Artifact>,
+    pub termination: Option<Termination>,
 }
 
 impl From<AgentOutput> for ExecuteResponse {
@@ -27,6 +29,8 @@ impl From<AgentOutput> for ExecuteResponse {
             stdout: value.stdout,
             stderr: value.stderr,
             exit_code: value.exit_code,
+            artifact: value.artifact,
+            termination: value.termination,
         }
     }
 }
@@ -79,10 +83,13 @@ impl TryFrom<&str> for Language {
 
 mod process_utils {
     use super::AgentOutput;
-    use crate::agent::execute_response::Stage;
+    use crate::agent::{execute_response::Stage, Termination};
+    use nix::sys::signal::Signal;
+    use std::os::unix::process::ExitStatusExt;
+    use std::path::{Path, PathBuf};
     use tokio::{
         io::{AsyncBufReadExt, BufReader},
-        process::{ChildStderr, ChildStdout},
+        process::{ChildStderr, ChildStdout, Command},
         sync::mpsc,
         task::JoinHandle,
     };
@@ -104,6 +111,8 @@ mod process_utils {
                         stdout: Some(line),
                         stderr: None,
                         exit_code: None,
+                        artifact: None,
+                        termination: None,
                     })
                     .await;
             }
@@ -127,29 +136,108 @@ mod process_utils {
                         stdout: Some(line),
                         stderr: None,
                         exit_code: None,
+                        artifact: None,
+                        termination: None,
                     })
                     .await;
             }
         })
     }
 
+    /// Where to look for the core file of a workload killed by a signal.
+    pub struct CoreDump {
+        /// Working directory of the workload, where the kernel writes the core file.
+        pub dir: PathBuf,
+        /// Binary of the workload, used to symbolize the backtrace.
+        pub binary: PathBuf,
+    }
+
+    fn find_core_file(dir: &Path, pid: Option<u32>) -> Option<PathBuf> {
+        // Depending on `kernel.core_uses_pid`, the core file is named `core` or `core.<pid>`.
+        pid.map(|pid| dir.join(format!("core.{}", pid)))
+            .into_iter()
+            .chain(std::iter::once(dir.join("core")))
+            .find(|path| path.is_file())
+    }
+
+    /// Get the backtrace of the crashed thread with `gdb`, if it is installed.
+    async fn get_backtrace(binary: &Path, core_file: &Path) -> Option<String> {
+        let output = Command::new("gdb")
+            .arg("--batch")
+            .args(["-ex", "bt"])
+            .arg(binary)
+            .arg(core_file)
+            .output()
+            .await
+            .ok()?;
+
+        output
+            .status
+            .success()
+            .then(|| String::from_utf8_lossy(&output.stdout).to_string())
+    }
+
+    async fn get_termination(
+        signal: i32,
+        core_dumped: bool,
+        pid: Option<u32>,
+        core_dump: Option<CoreDump>,
+    ) -> Termination {
+        let signal_name = Signal::try_from(signal)
+            .map(|signal| signal.as_str().to_string())
+            .unwrap_or_else(|_| format!("SIG{}", signal));
+
+        let mut termination = Termination {
+            signal,
+            signal_name,
+            core_dumped,
+            core_file: None,
+            backtrace: None,
+        };
+
+        if let (true, Some(core_dump)) = (core_dumped, core_dump) {
+            if let Some(core_file) = find_core_file(&core_dump.dir, pid) {
+                termination.backtrace = get_backtrace(&core_dump.binary, &core_file).await;
+                termination.core_file = Some(core_file.to_string_lossy().to_string());
+            }
+        }
+
+        termination
+    }
+
     /// Function to wait for the `child` to finish and send the result to the `tx` given as a parameter.
+    ///
+    /// If the child is killed by a signal, the signal is reported and, when `core_dump` is given,
+    /// the path of its core file and its backtrace too.
     pub async fn send_exit_status_to_tx(
         mut child: tokio::process::Child,
         tx: mpsc::Sender<AgentOutput>,
         send_done: bool,
+        core_dump: Option<CoreDump>,
     ) -> Result<(), ()> {
-        let exit_status = child.wait().await.map(|status| status.code());
+        let pid = child.id();
+        let exit_status = child.wait().await;
 
         match exit_status {
-            Ok(exit_code) => {
+            Ok(status) => {
+                let exit_code = status.code();
+
                 if exit_code != Some(0_i32) {
+                    let termination = match status.signal() {
+                        Some(signal) => Some(
+                            get_termination(signal, status.core_dumped(), pid, core_dump).await,
+                        ),
+                        None => None,
+                    };
+
                     let _ = tx
                         .send(AgentOutput {
                             stage: Stage::Failed,
                             stdout: None,
                             stderr: None,
                             exit_code,
+                            artifact: None,
+                            termination,
                         })
                         .await;
 
@@ -162,6 +250,8 @@ mod process_utils {
                                 stdout: None,
                                 stderr: None,
                                 exit_code,
+                                artifact: None,
+                                termination: None,
                             })
                             .await;
                     }
@@ -176,6 +266,8 @@ mod process_utils {
                         stdout: None,
                         stderr: Some(e.to_string()),
                         exit_code: None,
+                        artifact: None,
+                        termination: None,
                     })
                     .await;
 
@@ -3,28 +3,123 @@ use crate::agent::execute_response::Stage;
 use crate::agents::process_utils;
 use crate::{workload, AgentError, AgentResult};
 use async_trait::async_trait;
+use nix::sys::resource::{setrlimit, Resource, RLIM_INFINITY};
 use rand::distributions::{Alphanumeric, DistString};
-use serde::Deserialize;
-use std::collections::HashSet;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
 use std::fs::create_dir_all;
+use std::path::PathBuf;
 use std::process::Stdio;
 use std::sync::Arc;
-use tokio::process::{Child, Command};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::{Child, ChildStdout, Command};
 use tokio::sync::{
     broadcast,
     mpsc::{self, Receiver},
     Mutex,
 };
 
+/// Directory where the core files of crashed workloads are written.
+const CORE_DUMPS_DIR: &str = "/tmp/cores";
+
 #[derive(Deserialize)]
 #[serde(rename_all = "kebab-case")]
 struct RustAgentBuildConfig {
+    /// Build with the `release` profile. Ignored when `profile` is set.
+    #[serde(default)]
     release: bool,
+    /// Custom cargo profile (`--profile`).
+    profile: Option<String>,
+    /// Cargo features to enable. They are declared in the generated `Cargo.toml`.
+    #[serde(default)]
+    features: Vec<String>,
+    /// Disable the default features of the package.
+    #[serde(default)]
+    no_default_features: bool,
+    /// Target triple to build for (e.g. `x86_64-unknown-linux-musl`).
+    target: Option<String>,
+    /// Value of the `RUSTFLAGS` environment variable passed to cargo.
+    rustflags: Option<String>,
+    /// Rust edition of the generated package.
+    #[serde(default = "default_edition")]
+    edition: String,
+    /// Name of the binary, defaults to the workload name.
+    binary_name: Option<String>,
+}
+
+fn default_edition() -> String {
+    "2021".to_string()
+}
+
+/// Subset of a cargo JSON message (`--message-format=json`) we care about.
+#[derive(Deserialize)]
+struct CargoMessage {
+    reason: String,
+    target: Option<CargoTarget>,
+    executable: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+struct CargoTarget {
+    name: String,
+    kind: Vec<String>,
+}
+
+/// `Cargo.toml` of the package built for a workload. It's serialized rather than formatted, so
+/// the names coming from the request can't inject anything into the manifest.
+#[derive(Serialize)]
+struct CargoManifest<'a> {
+    package: CargoPackage<'a>,
+    bin: Vec<CargoBin<'a>>,
+    features: BTreeMap<&'a str, Vec<String>>,
+}
+
+#[derive(Serialize)]
+struct CargoPackage<'a> {
+    name: &'a str,
+    version: &'a str,
+    edition: &'a str,
+}
+
+#[derive(Serialize)]
+struct CargoBin<'a> {
+    name: &'a str,
+    path: &'a str,
+}
+
+impl<'a> CargoManifest<'a> {
+    fn new(name: &'a str, edition: &'a str, binary_name: &'a str, features: &'a [String]) -> Self {
+        CargoManifest {
+            package: CargoPackage {
+                name,
+                version: "0.1.0",
+                edition,
+            },
+            bin: vec![CargoBin {
+                name: binary_name,
+                path: "src/main.rs",
+            }],
+            features: features
+                .iter()
+                .map(|feature| (feature.as_str(), Vec::new()))
+                .collect(),
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "kebab-case")]
+struct RustAgentRunConfig {
+    /// Let the workload dump its core when it crashes, and report its backtrace.
+    #[serde(default)]
+    core_dump: bool,
 }
 
 #[derive(Deserialize)]
 struct RustAgentConfig {
     build: RustAgentBuildConfig,
+    #[serde(default)]
+    run: RustAgentRunConfig,
 }
 
 pub struct RustAgent {
@@ -47,24 +142,69 @@ impl From<workload::config::Config> for RustAgent {
 }
 
 impl RustAgent {
+    fn binary_name(&self) -> String {
+        self.rust_config
+            .build
+            .binary_name
+            .clone()
+            .unwrap_or_else(|| self.workload_config.workload_name.clone())
+    }
+
+    fn cargo_toml(&self) -> String {
+        let build = &self.rust_config.build;
+        let binary_name = self.binary_name();
+        let manifest = CargoManifest::new(
+            &self.workload_config.workload_name,
+            &build.edition,
+            &binary_name,
+            &build.features,
+        );
+
+        toml::to_string(&manifest).expect("Cargo manifests are valid TOML")
+    }
+
     async fn get_build_child_process(
         &self,
         function_dir: &str,
         child_processes: Arc<Mutex<HashSet<u32>>>,
     ) -> Child {
+        let build = &self.rust_config.build;
+
         let mut command = Command::new("cargo");
-        let command = if self.rust_config.build.release {
-            command
-                .stderr(Stdio::piped())
-                .arg("build")
-                .current_dir(function_dir)
-                .arg("--release")
-        } else {
-            command
-                .stderr(Stdio::piped())
-                .arg("build")
-                .current_dir(function_dir)
-        };
+        command
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .arg("build")
+            // Diagnostics are rendered on stderr, stdout only holds JSON messages.
+            .arg("--message-format=json-render-diagnostics")
+            .current_dir(function_dir);
+
+        match &build.profile {
+            Some(profile) => {
+                command.arg("--profile").arg(profile);
+            }
+            None if build.release => {
+                command.arg("--release");
+            }
+            None => {}
+        }
+
+        if let Some(target) = &build.target {
+            command.arg("--target").arg(target);
+        }
+
+        if !build.features.is_empty() {
+            command.arg("--features").arg(build.features.join(","));
+        }
+
+        if build.no_default_features {
+            command.arg("--no-default-features");
+        }
+
+        if let Some(rustflags) = &build.rustflags {
+            command.env("RUSTFLAGS", rustflags);
+        }
+
         let child = command.spawn().expect("Failed to start build");
 
         {
@@ -73,6 +213,31 @@ impl RustAgent {
 
         child
     }
+
+    /// Read cargo JSON messages from `stdout` and return the path of the executable
+    /// produced for the binary named `binary_name`, if any.
+    async fn find_executable(stdout: ChildStdout, binary_name: String) -> Option<PathBuf> {
+        let mut reader_lines = BufReader::new(stdout).lines();
+        let mut executable = None;
+
+        while let Ok(Some(line)) = reader_lines.next_line().await {
+            let Ok(message) = serde_json::from_str::<CargoMessage>(&line) else {
+                continue;
+            };
+
+            if message.reason != "compiler-artifact" {
+                continue;
+            }
+
+            if let (Some(target), Some(path)) = (message.target, message.executable) {
+                if target.name == binary_name && target.kind.iter().any(|kind| kind == "bin") {
+                    executable = Some(path);
+                }
+            }
+        }
+
+        executable
+    }
 }
 
 #[async_trait]
@@ -96,15 +261,7 @@ impl Agent for RustAgent {
         )
         .expect("Unable to write main.rs file");
 
-        let cargo_toml = format!(
-            r#"
-            [package]
-            name = "{}"
-            version = "0.1.0"
-            edition = "2021"
-        "#,
-            self.workload_config.workload_name
-        );
+        let cargo_toml = self.cargo_toml();
 
         std::fs::write(format!("{}/Cargo.toml", &function_dir), cargo_toml)
             .expect("Unable to write Cargo.toml file");
@@ -113,32 +270,49 @@ impl Agent for RustAgent {
             .get_build_child_process(&function_dir, child_processes)
             .await;
         let workload_name = self.workload_config.workload_name.clone();
-        let is_release = self.rust_config.build.release;
+        let binary_name = self.binary_name();
         let tx_build_notifier = self.build_notifier.clone();
 
         let (tx, rx) = mpsc::channel(10);
         tokio::spawn(async move {
+            let stdout = child.stdout.take().unwrap();
+            let executable = tokio::spawn(Self::find_executable(stdout, binary_name));
+
             let stderr = child.stderr.take().unwrap();
             let _ = process_utils::send_stderr_to_tx(stderr, tx.clone(), Some(Stage::Building))
                 .await
                 .await;
-            let build_result = process_utils::send_exit_status_to_tx(child, tx, false).await;
+            let build_result =
+                process_utils::send_exit_status_to_tx(child, tx.clone(), false, None).await;
+            let executable = executable.await.ok().flatten();
+
             // if error in build, short-circuit the execution
-            if build_result.is_err() {
-                let _ = tx_build_notifier.send(Err(()));
-            } else {
-                // Once finished: copy the binary to /tmp
-                // We could imagine a more complex scenario where we would put this in an artifact repository (like S3)
-                let binary_path = match is_release {
-                    true => format!("{}/target/release/{}", &function_dir, workload_name),
-                    false => format!("{}/target/debug/{}", &function_dir, workload_name),
-                };
-
-                std::fs::copy(binary_path, format!("/tmp/{}", workload_name))
-                    .expect("Unable to copy binary");
-
-                // notify when build is done
-                let _ = tx_build_notifier.send(build_result);
+            match (build_result, executable) {
+                (Ok(()), Some(binary_path)) => {
+                    // Once finished: copy the binary to /tmp
+                    // We could imagine a more complex scenario where we would put this in an artifact repository (like S3)
+                    std::fs::copy(binary_path, format!("/tmp/{}", workload_name))
+                        .expect("Unable to copy binary");
+
+                    // notify when build is done
+                    let _ = tx_build_notifier.send(Ok(()));
+                }
+                (Ok(()), None) => {
+                    let _ = tx
+                        .send(AgentOutput {
+                            stage: Stage::Failed,
+                            stdout: None,
+                            stderr: Some("Unable to find the built binary in cargo output".into()),
+                            exit_code: None,
+                            artifact: None,
+                            termination: None,
+                        })
+                        .await;
+                    let _ = tx_build_notifier.send(Err(()));
+                }
+                (Err(()), _) => {
+                    let _ = tx_build_notifier.send(Err(()));
+                }
             }
 
             std::fs::remove_dir_all(&function_dir).expect("Unable to remove directory");
@@ -160,11 +334,36 @@ impl Agent for RustAgent {
             .map_err(|_| AgentError::BuildFailed)?;
 
         println!("Starting run()");
-        let mut child = Command::new(format!("/tmp/{}", self.workload_config.workload_name))
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn()
-            .expect("Failed to run function");
+        let binary = PathBuf::from(format!("/tmp/{}", self.workload_config.workload_name));
+        let mut command = Command::new(&binary);
+        command.stdout(Stdio::piped()).stderr(Stdio::piped());
+
+        let core_dump = if self.rust_config.run.core_dump {
+            // The kernel writes the core file in the working directory of the process. Runs of
+            // the same workload, possibly concurrent, each get their own directory, so that
+            // the core file of another run isn't mistaken for the one of this run.
+            let run_id = Alphanumeric.sample_string(&mut rand::thread_rng(), 8);
+            let dir = PathBuf::from(format!(
+                "{}/{}-{}",
+                CORE_DUMPS_DIR, self.workload_config.workload_name, run_id
+            ));
+            create_dir_all(&dir).map_err(AgentError::CoreDumpsDir)?;
+            command.current_dir(&dir);
+
+            // SAFETY: only async-signal-safe calls are made between fork and exec.
+            unsafe {
+                command.pre_exec(|| {
+                    setrlimit(Resource::RLIMIT_CORE, RLIM_INFINITY, RLIM_INFINITY)?;
+                    Ok(())
+                });
+            }
+
+            Some(process_utils::CoreDump { dir, binary })
+        } else {
+            None
+        };
+
+        let mut child = command.spawn().expect("Failed to run function");
 
         {
             child_processes.lock().await.insert(child.id().unwrap());
@@ -180,7 +379,7 @@ impl Agent for RustAgent {
             let _ = process_utils::send_stdout_to_tx(child_stdout, tx_stdout.clone(), None)
                 .await
                 .await;
-            let _ = process_utils::send_exit_status_to_tx(child, tx_stdout, true).await;
+            let _ = process_utils::send_exit_status_to_tx(child, tx_stdout, true, core_dump).await;
         });
 
         tokio::spawn(async move {
@@ -192,3 +391,22 @@ impl Agent for RustAgent {
         Ok(rx)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manifest_features_cant_inject_tables() {
+        let features = vec!["a = []\n[dependencies]\nx = \"*\"".to_string()];
+        let manifest = toml::to_string(&CargoManifest::new("app", "2021", "app", &features))
+            .unwrap()
+            .parse::<toml::Table>()
+            .unwrap();
+
+        assert!(!manifest.contains_key("dependencies"));
+        assert_eq!(manifest["features"].as_table().unwrap().len(), 1);
+        assert_eq!(manifest["package"]["edition"].as_str(), Some("2021"));
+        assert_eq!(manifest["bin"][0]["path"].as_str(), Some("src/main.rs"));
+    }
+}
@@ -1,6 +1,7 @@
 use std::fmt;
 
 mod agents;
+pub mod vsock;
 pub mod workload;
 
 #[derive(Debug)]
@@ -10,6 +11,9 @@ pub enum AgentError {
     InvalidLanguage(String),
     BuildNotifier,
     BuildFailed,
+    InvalidOutputPattern(glob::PatternError),
+    ArtifactArchive(std::io::Error),
+    CoreDumpsDir(std::io::Error),
 }
 
 impl fmt::Display for AgentError {
@@ -22,10 +26,17 @@ impl fmt::Display for AgentError {
                 write!(f, "Could not get notification from build notifier")
             }
             AgentError::BuildFailed => write!(f, "Build has failed"),
+            AgentError::InvalidOutputPattern(e) => write!(f, "Invalid output pattern: {}", e),
+            AgentError::ArtifactArchive(e) => write!(f, "Failed to archive outputs: {}", e),
+            AgentError::CoreDumpsDir(e) => {
+                write!(f, "Failed to create the core dumps directory: {}", e)
+            }
         }
     }
 }
 
+impl std::error::Error for AgentError {}
+
 pub type AgentResult<T> = Result<T, AgentError>;
 
 pub mod agent {
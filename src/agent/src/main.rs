@@ -1,35 +1,164 @@
 use agent::{
-    agent::workload_runner_server::WorkloadRunnerServer, workload::service::WorkloadRunnerService,
+    agent::{execute_response::Stage, workload_runner_server::WorkloadRunnerServer},
+    vsock::VsockListener,
+    workload::{config::Config, runner::Runner, service::WorkloadRunnerService},
 };
 use clap::Parser;
+use std::collections::HashSet;
+use std::fs::OpenOptions;
+use std::io::{self, Seek, SeekFrom, Write};
 use std::net::ToSocketAddrs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::Mutex;
 use tonic::transport::Server;
 
+/// Port of the boot timer of the VMM, and the marker telling it the agent is ready.
+const BOOT_TIMER_PORT: u64 = 0x3f0;
+const AGENT_READY_MARKER: u8 = 2;
+
 #[derive(Debug, Parser)]
+#[command(version, about)]
 struct Args {
+    #[command(subcommand)]
+    command: Commands,
+}
+
+#[derive(Debug, Parser)]
+enum Commands {
+    #[command(about = "Run a GRPC server listening for workloads.")]
+    Serve(ServeArgs),
+    #[command(about = "Run a single workload on the host and print its outputs.")]
+    Run(RunArgs),
+}
+
+#[derive(Debug, Parser)]
+struct ServeArgs {
     #[clap(long, env, default_value = "0.0.0.0")]
     grpc_server_address: String,
     #[clap(long, env, default_value = "50051")]
     grpc_server_port: u16,
+    /// Maximum number of workloads running at the same time, the others are queued.
+    /// Defaults to the number of available CPUs.
+    #[clap(long, env)]
+    max_concurrent_workloads: Option<usize>,
+    /// Also listen on this vsock port, for hosts reaching the agent without network.
+    #[clap(long, env)]
+    vsock_port: Option<u32>,
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args = Args::parse();
+#[derive(Debug, Parser)]
+struct RunArgs {
+    /// Path to the TOML configuration of the workload.
+    #[clap(short, long)]
+    config: PathBuf,
+}
 
+async fn serve(args: ServeArgs) -> Result<(), Box<dyn std::error::Error>> {
     let bind_address = format!("{}:{}", args.grpc_server_address, args.grpc_server_port)
         .to_socket_addrs()
         .unwrap()
         .next()
         .unwrap();
 
-    let server = WorkloadRunnerService;
+    let max_concurrent_workloads = args.max_concurrent_workloads.unwrap_or_else(|| {
+        std::thread::available_parallelism()
+            .map(|count| count.get())
+            .unwrap_or(1)
+    });
 
-    Server::builder()
-        .add_service(WorkloadRunnerServer::new(server))
-        .serve(bind_address)
-        .await
-        .unwrap();
+    // Both listeners share the same workload queue.
+    let service = WorkloadRunnerServer::from_arc(Arc::new(WorkloadRunnerService::new(
+        max_concurrent_workloads,
+    )));
+
+    let tcp_server = Server::builder()
+        .add_service(service.clone())
+        .serve(bind_address);
+
+    let vsock_listener = args.vsock_port.and_then(|port| {
+        VsockListener::bind(port)
+            .map_err(|e| eprintln!("Failed to listen on vsock port {}: {}", port, e))
+            .ok()
+    });
+
+    match vsock_listener {
+        Some(listener) => {
+            // Listening on vsock means running in a VM, the host can reach the agent.
+            if let Err(e) = report_ready() {
+                eprintln!("Failed to report the agent ready to the VMM: {}", e);
+            }
+
+            let vsock_server = Server::builder()
+                .add_service(service)
+                .serve_with_incoming(listener.incoming());
+            tokio::try_join!(tcp_server, vsock_server)?;
+        }
+        None => tcp_server.await?,
+    }
 
     Ok(())
 }
+
+/// Tell the boot timer of the VMM that the agent accepts requests.
+fn report_ready() -> io::Result<()> {
+    let mut port = OpenOptions::new().write(true).open("/dev/port")?;
+    port.seek(SeekFrom::Start(BOOT_TIMER_PORT))?;
+    port.write_all(&[AGENT_READY_MARKER])
+}
+
+/// Run the workload described by the configuration file and return its exit code.
+async fn run(args: RunArgs) -> Result<i32, Box<dyn std::error::Error>> {
+    let config = Config::from_file(&args.config)?;
+    let runner = Runner::new(config, Arc::new(Mutex::new(HashSet::new())));
+
+    let mut rx = runner.run().await?;
+    let mut exit_code = 0;
+
+    while let Some(output) = rx.recv().await {
+        if let Some(stdout) = &output.stdout {
+            println!("[{:?}] {}", output.stage, stdout);
+        }
+        if let Some(stderr) = &output.stderr {
+            eprintln!("[{:?}] {}", output.stage, stderr);
+        }
+
+        if let Some(artifact) = &output.artifact {
+            println!("Outputs archived in {}", artifact.path);
+        }
+
+        match output.stage {
+            Stage::Done => exit_code = output.exit_code.unwrap_or(0),
+            Stage::Failed => {
+                exit_code = match (&output.termination, output.exit_code) {
+                    (Some(termination), _) => {
+                        eprintln!("Workload killed by {}", termination.signal_name);
+                        if let Some(backtrace) = &termination.backtrace {
+                            eprintln!("{}", backtrace);
+                        }
+                        // Same convention as the shells.
+                        128 + termination.signal
+                    }
+                    (None, Some(exit_code)) => exit_code,
+                    (None, None) => 1,
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(exit_code)
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+
+    match args.command {
+        Commands::Serve(args) => serve(args).await,
+        Commands::Run(args) => {
+            let exit_code = run(args).await?;
+            std::process::exit(exit_code);
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use nix::libc::VMADDR_CID_ANY;
+use nix::sys::socket::{
+    accept4, bind, listen, socket, AddressFamily, Backlog, SockFlag, SockType, VsockAddr,
+};
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::time::Duration;
+use tokio::io::unix::AsyncFd;
+use tokio::net::UnixStream;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+
+/// Listening vsock socket, to serve the host without any network.
+pub struct VsockListener {
+    fd: AsyncFd<OwnedFd>,
+}
+
+impl VsockListener {
+    /// Listen on `port` for connections from any CID.
+    pub fn bind(port: u32) -> io::Result<Self> {
+        let fd = socket(
+            AddressFamily::Vsock,
+            SockType::Stream,
+            SockFlag::SOCK_NONBLOCK | SockFlag::SOCK_CLOEXEC,
+            None,
+        )?;
+        bind(fd.as_raw_fd(), &VsockAddr::new(VMADDR_CID_ANY, port))?;
+        listen(&fd, Backlog::new(128)?)?;
+
+        Ok(Self {
+            fd: AsyncFd::new(fd)?,
+        })
+    }
+
+    /// Accept a connection.
+    ///
+    /// tokio has no vsock stream, but connected stream sockets are all driven the same way,
+    /// so the connection is returned as a [`UnixStream`].
+    pub async fn accept(&self) -> io::Result<UnixStream> {
+        loop {
+            let mut guard = self.fd.readable().await?;
+
+            let fd = match guard.try_io(|fd| {
+                accept4(
+                    fd.as_raw_fd(),
+                    SockFlag::SOCK_NONBLOCK | SockFlag::SOCK_CLOEXEC,
+                )
+                .map_err(io::Error::from)
+            }) {
+                Ok(result) => result?,
+                Err(_would_block) => continue,
+            };
+
+            // SAFETY: `accept4` returned a new file descriptor we now own.
+            let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+            return UnixStream::from_std(std::os::unix::net::UnixStream::from(fd));
+        }
+    }
+
+    /// Stream of the accepted connections, for [`tonic::transport::Server::serve_with_incoming`].
+    pub fn incoming(self) -> ReceiverStream<io::Result<UnixStream>> {
+        let (tx, rx) = mpsc::channel(16);
+
+        tokio::spawn(async move {
+            loop {
+                match self.accept().await {
+                    Ok(stream) => {
+                        if tx.send(Ok(stream)).await.is_err() {
+                            break;
+                        }
+                    }
+                    // Failing to accept a connection must not stop the server.
+                    Err(e) => {
+                        eprintln!("Failed to accept vsock connection: {}", e);
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                    }
+                }
+            }
+        });
+
+        ReceiverStream::new(rx)
+    }
+}
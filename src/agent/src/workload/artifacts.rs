@@ -0,0 +1,253 @@
+use super::files::hex_digest;
+use crate::{
+    agent::{execute_response::Stage, Artifact},
+    agents::AgentOutput,
+    AgentError, AgentResult,
+};
+use flate2::{write::GzEncoder, Compression};
+use rand::distributions::{Alphanumeric, DistString};
+use sha2::{Digest, Sha256};
+use std::fs::{create_dir_all, File};
+use std::io::{self, Write};
+use std::path::Path;
+use tokio::sync::mpsc::{self, Receiver};
+
+/// Directory where the output archives are written.
+const ARTIFACTS_DIR: &str = "/tmp/artifacts";
+
+/// Forward the outputs of a workload and, once it has finished, attach the archive of the
+/// files matching `patterns` to its final `Done` or `Failed` output.
+pub fn attach_artifact(
+    mut rx: Receiver<AgentOutput>,
+    workload_name: String,
+    patterns: Vec<String>,
+) -> Receiver<AgentOutput> {
+    let (tx, forwarded_rx) = mpsc::channel(10);
+
+    tokio::spawn(async move {
+        let mut final_output: Option<AgentOutput> = None;
+
+        while let Some(output) = rx.recv().await {
+            if matches!(output.stage, Stage::Done | Stage::Failed) {
+                if let Some(previous) = final_output.replace(output) {
+                    let _ = tx.send(previous).await;
+                }
+            } else {
+                let _ = tx.send(output).await;
+            }
+        }
+
+        // The channel is closed once every process of the workload has exited.
+        let Some(mut output) = final_output else {
+            return;
+        };
+
+        match tokio::task::spawn_blocking(move || {
+            bundle_outputs(Path::new(ARTIFACTS_DIR), &workload_name, &patterns)
+        })
+        .await
+        {
+            Ok(Ok(artifact)) => output.artifact = artifact,
+            Ok(Err(e)) => append_stderr(&mut output, e.to_string()),
+            Err(e) => append_stderr(&mut output, e.to_string()),
+        }
+
+        let _ = tx.send(output).await;
+    });
+
+    forwarded_rx
+}
+
+fn append_stderr(output: &mut AgentOutput, message: String) {
+    match &mut output.stderr {
+        Some(stderr) => {
+            stderr.push('\n');
+            stderr.push_str(&message);
+        }
+        None => output.stderr = Some(message),
+    }
+}
+
+/// Writer hashing and counting the bytes written through it.
+struct HashingWriter<W> {
+    inner: W,
+    hasher: Sha256,
+    size: u64,
+}
+
+impl<W: Write> HashingWriter<W> {
+    fn new(inner: W) -> Self {
+        HashingWriter {
+            inner,
+            hasher: Sha256::new(),
+            size: 0,
+        }
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let count = self.inner.write(buf)?;
+        self.hasher.update(&buf[..count]);
+        self.size += count as u64;
+
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Bundle the files matching `patterns` into a gzipped tarball in `artifacts_dir`.
+/// Returns `None` if no file matches.
+fn bundle_outputs(
+    artifacts_dir: &Path,
+    workload_name: &str,
+    patterns: &[String],
+) -> AgentResult<Option<Artifact>> {
+    let mut files = Vec::new();
+    for pattern in patterns {
+        let paths = glob::glob(pattern).map_err(AgentError::InvalidOutputPattern)?;
+        // Unreadable paths are skipped.
+        files.extend(paths.flatten().filter(|path| path.is_file()));
+    }
+    files.sort();
+    files.dedup();
+
+    if files.is_empty() {
+        return Ok(None);
+    }
+
+    create_dir_all(artifacts_dir).map_err(AgentError::ArtifactArchive)?;
+    // Runs of the same workload, possibly concurrent, each get their own archive.
+    let run_id = Alphanumeric.sample_string(&mut rand::thread_rng(), 8);
+    let archive_path = artifacts_dir.join(format!("{}-{}.tar.gz", workload_name, run_id));
+    let archive = File::create(&archive_path).map_err(AgentError::ArtifactArchive)?;
+
+    // The archive is hashed as it is written.
+    let mut builder = tar::Builder::new(GzEncoder::new(
+        HashingWriter::new(archive),
+        Compression::default(),
+    ));
+    for file in &files {
+        let name = file.strip_prefix("/").unwrap_or(file);
+        builder
+            .append_path_with_name(file, name)
+            .map_err(AgentError::ArtifactArchive)?;
+    }
+    let mut archive = builder
+        .into_inner()
+        .and_then(|encoder| encoder.finish())
+        .map_err(AgentError::ArtifactArchive)?;
+    archive.flush().map_err(AgentError::ArtifactArchive)?;
+
+    println!(
+        "Collected {} output file(s) in {}",
+        files.len(),
+        archive_path.display()
+    );
+
+    Ok(Some(Artifact {
+        path: archive_path.to_string_lossy().to_string(),
+        size: archive.size,
+        sha256: hex_digest(archive.hasher),
+        files: files
+            .iter()
+            .map(|file| file.to_string_lossy().to_string())
+            .collect(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::path::PathBuf;
+
+    /// Directory holding `out/a.json`, `out/b.json`, `out/c.txt` and the directory
+    /// `out/d.json`.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("agent-artifacts-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        create_dir_all(dir.join("out/d.json")).unwrap();
+        for file in ["a.json", "b.json", "c.txt"] {
+            std::fs::write(dir.join("out").join(file), file).unwrap();
+        }
+
+        dir
+    }
+
+    fn pattern(dir: &Path, pattern: &str) -> String {
+        dir.join(pattern).to_string_lossy().to_string()
+    }
+
+    /// Paths of the entries of the archive at `path`.
+    fn archive_entries(path: &str) -> Vec<String> {
+        let mut archive = tar::Archive::new(GzDecoder::new(File::open(path).unwrap()));
+        archive
+            .entries()
+            .unwrap()
+            .map(|entry| entry.unwrap().path().unwrap().to_string_lossy().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn bundle_matching_files() {
+        let dir = test_dir("bundle");
+        let patterns = [
+            pattern(&dir, "out/*.json"),
+            pattern(&dir, "out/a.*"),
+            pattern(&dir, "missing/*"),
+        ];
+
+        let artifact = bundle_outputs(&dir.join("artifacts"), "app", &patterns)
+            .unwrap()
+            .unwrap();
+
+        // Files are sorted and deduplicated, directories are skipped.
+        let files = [pattern(&dir, "out/a.json"), pattern(&dir, "out/b.json")];
+        assert_eq!(artifact.files, files);
+        assert!(artifact.path.starts_with(&pattern(&dir, "artifacts/app-")));
+
+        // The paths in the archive are relative to the root.
+        let entries: Vec<String> = files
+            .iter()
+            .map(|file| file.trim_start_matches('/').to_string())
+            .collect();
+        assert_eq!(archive_entries(&artifact.path), entries);
+
+        let content = std::fs::read(&artifact.path).unwrap();
+        assert_eq!(artifact.size, content.len() as u64);
+        assert_eq!(
+            artifact.sha256,
+            hex_digest(Sha256::new_with_prefix(&content))
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn bundle_nothing() {
+        let dir = test_dir("nothing");
+        let patterns = [pattern(&dir, "out/*.csv"), pattern(&dir, "out/d.*")];
+
+        let artifact = bundle_outputs(&dir.join("artifacts"), "app", &patterns).unwrap();
+        assert!(artifact.is_none());
+        assert!(!dir.join("artifacts").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn invalid_pattern() {
+        let dir = test_dir("invalid");
+        let patterns = [pattern(&dir, "out/[.json")];
+
+        let result = bundle_outputs(&dir.join("artifacts"), "app", &patterns);
+        assert!(matches!(result, Err(AgentError::InvalidOutputPattern(_))));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -17,9 +17,18 @@ pub struct Config {
     /// Action to perform.
     pub action: Action,
     /// Code
+    #[serde(default)]
     pub code: String,
+    /// Path of a file holding the code, relative to the configuration file.
+    /// Only used by [`Config::from_file`], where it takes precedence over `code`.
+    #[serde(default)]
+    pub code_path: Option<PathBuf>,
     /// Rest of the configuration as a string.
+    #[serde(default)]
     pub config_string: String,
+    /// Glob patterns of the files to collect once the workload has run.
+    #[serde(default)]
+    pub outputs: Vec<String>,
 }
 
 impl Config {
@@ -32,6 +41,15 @@ impl Config {
 
         config.config_string = config_string;
 
+        if let Some(code_path) = config.code_path.take() {
+            let code_path = match file_path.parent() {
+                Some(dir) => dir.join(code_path),
+                None => code_path,
+            };
+            config.code =
+                std::fs::read_to_string(&code_path).map_err(AgentError::OpenConfigFileError)?;
+        }
+
         Ok(config)
     }
 
@@ -42,6 +60,8 @@ impl Config {
             action: execute_request.action().into(),
             config_string: execute_request.config_str,
             code: execute_request.code,
+            code_path: None,
+            outputs: execute_request.outputs,
         })
     }
 }
@@ -0,0 +1,245 @@
+use crate::agent::{
+    exec_request::{self, Start},
+    exec_response, ExecRequest, ExecResponse,
+};
+use nix::pty::{openpty, Winsize};
+use std::collections::HashSet;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::os::unix::process::ExitStatusExt;
+use std::process::Stdio;
+use std::sync::Arc;
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::process::{Child, Command};
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::JoinHandle;
+use tonic::{Status, Streaming};
+
+/// End-of-transmission character, closes the input of a terminal.
+const EOT: u8 = 0x04;
+
+nix::ioctl_write_int_bad!(tiocsctty, nix::libc::TIOCSCTTY);
+nix::ioctl_write_ptr_bad!(tiocswinsz, nix::libc::TIOCSWINSZ, Winsize);
+
+type Input = Box<dyn AsyncWrite + Send + Unpin>;
+type Output = Box<dyn AsyncRead + Send + Unpin>;
+
+/// A command spawned by [`exec`], with the host side of its standard streams.
+struct Session {
+    child: Child,
+    stdin: Option<Input>,
+    stdout: Output,
+    stderr: Option<Output>,
+    /// Master side of the pseudo-terminal, if one was allocated.
+    pty_master: Option<OwnedFd>,
+}
+
+fn window_size(rows: u32, cols: u32) -> Winsize {
+    Winsize {
+        ws_row: rows as u16,
+        ws_col: cols as u16,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    }
+}
+
+fn spawn_piped(mut command: Command) -> std::io::Result<Session> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    Ok(Session {
+        stdin: child.stdin.take().map(|stdin| Box::new(stdin) as Input),
+        stdout: Box::new(child.stdout.take().unwrap()),
+        stderr: child.stderr.take().map(|stderr| Box::new(stderr) as Output),
+        child,
+        pty_master: None,
+    })
+}
+
+fn spawn_tty(mut command: Command, start: &Start) -> std::io::Result<Session> {
+    let winsize = window_size(start.rows, start.cols);
+    let pty = openpty(&winsize, None)?;
+
+    command
+        .stdin(Stdio::from(pty.slave.try_clone()?))
+        .stdout(Stdio::from(pty.slave.try_clone()?))
+        .stderr(Stdio::from(pty.slave));
+
+    // SAFETY: only async-signal-safe calls are made between fork and exec.
+    unsafe {
+        command.pre_exec(|| {
+            // Start a new session and make the terminal (our stdin) the controlling one.
+            nix::unistd::setsid()?;
+            tiocsctty(0, 0)?;
+            Ok(())
+        });
+    }
+
+    let child = command.spawn()?;
+    // Drop the slave side held by the command, so that reading the master fails
+    // once the child has exited.
+    drop(command);
+
+    let reader = std::fs::File::from(pty.master.try_clone()?);
+    let writer = std::fs::File::from(pty.master.try_clone()?);
+
+    Ok(Session {
+        child,
+        stdin: Some(Box::new(File::from_std(writer))),
+        stdout: Box::new(File::from_std(reader)),
+        stderr: None,
+        pty_master: Some(pty.master),
+    })
+}
+
+/// Spawn a tokio task sending everything read from `output` to `tx`.
+fn forward_output(
+    mut output: Output,
+    tx: mpsc::Sender<Result<ExecResponse, Status>>,
+    to_response: fn(Vec<u8>) -> exec_response::Response,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut buffer = [0u8; 4096];
+
+        // A PTY master returns EIO once the child has exited, treat it as EOF.
+        while let Ok(count @ 1..) = output.read(&mut buffer).await {
+            let response = ExecResponse {
+                response: Some(to_response(buffer[..count].to_vec())),
+            };
+
+            if tx.send(Ok(response)).await.is_err() {
+                break;
+            }
+        }
+    })
+}
+
+/// Spawn a tokio task applying the requests following `start` (stdin, resizes, ...)
+/// to the session.
+fn handle_requests(
+    mut stream: Streaming<ExecRequest>,
+    mut stdin: Option<Input>,
+    pty_master: Option<OwnedFd>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        while let Ok(Some(request)) = stream.message().await {
+            match request.request {
+                Some(exec_request::Request::Stdin(data)) => {
+                    if let Some(input) = stdin.as_mut() {
+                        if input.write_all(&data).await.is_err() || input.flush().await.is_err() {
+                            stdin = None;
+                        }
+                    }
+                }
+                Some(exec_request::Request::Resize(resize)) => {
+                    if let Some(master) = &pty_master {
+                        let winsize = window_size(resize.rows, resize.cols);
+                        // SAFETY: the fd is a valid PTY master and `winsize` outlives the call.
+                        let _ = unsafe { tiocswinsz(master.as_raw_fd(), &winsize) };
+                    }
+                }
+                Some(exec_request::Request::CloseStdin(true)) => {
+                    close_stdin(&mut stdin, pty_master.is_some()).await;
+                }
+                Some(exec_request::Request::CloseStdin(false))
+                | Some(exec_request::Request::Start(_))
+                | None => {}
+            }
+        }
+
+        // The client has nothing more to send.
+        close_stdin(&mut stdin, pty_master.is_some()).await;
+    })
+}
+
+async fn close_stdin(stdin: &mut Option<Input>, tty: bool) {
+    if let Some(mut input) = stdin.take() {
+        if tty {
+            let _ = input.write_all(&[EOT]).await;
+        }
+        let _ = input.shutdown().await;
+    }
+}
+
+/// Run the command described by the first request of `stream` and stream back its output
+/// and its exit code.
+pub async fn exec(
+    mut stream: Streaming<ExecRequest>,
+    child_processes: Arc<Mutex<HashSet<u32>>>,
+) -> Result<mpsc::Receiver<Result<ExecResponse, Status>>, Status> {
+    let start = match stream.message().await? {
+        Some(ExecRequest {
+            request: Some(exec_request::Request::Start(start)),
+        }) => start,
+        _ => {
+            return Err(Status::invalid_argument(
+                "The first request must start a command",
+            ))
+        }
+    };
+
+    println!("Executing {} {:?}", start.command, start.args);
+
+    let mut command = Command::new(&start.command);
+    command.args(&start.args).envs(&start.env);
+    if !start.working_dir.is_empty() {
+        command.current_dir(&start.working_dir);
+    }
+
+    let session = if start.tty {
+        spawn_tty(command, &start)
+    } else {
+        spawn_piped(command)
+    }
+    .map_err(|e| Status::failed_precondition(format!("Failed to run command: {}", e)))?;
+
+    let Session {
+        mut child,
+        stdin,
+        stdout,
+        stderr,
+        pty_master,
+    } = session;
+
+    let pid = child.id();
+    if let Some(pid) = pid {
+        child_processes.lock().await.insert(pid);
+    }
+
+    let (tx, rx) = mpsc::channel(10);
+
+    let stdout = forward_output(stdout, tx.clone(), exec_response::Response::Stdout);
+    let stderr =
+        stderr.map(|stderr| forward_output(stderr, tx.clone(), exec_response::Response::Stderr));
+    let requests = handle_requests(stream, stdin, pty_master);
+
+    tokio::spawn(async move {
+        let _ = stdout.await;
+        if let Some(stderr) = stderr {
+            let _ = stderr.await;
+        }
+
+        let response = match child.wait().await {
+            Ok(status) => Ok(ExecResponse {
+                response: Some(exec_response::Response::ExitCode(
+                    status
+                        .code()
+                        .or_else(|| status.signal().map(|signal| 128 + signal))
+                        .unwrap_or(-1),
+                )),
+            }),
+            Err(e) => Err(Status::internal(e.to_string())),
+        };
+        let _ = tx.send(response).await;
+
+        requests.abort();
+        if let Some(pid) = pid {
+            child_processes.lock().await.remove(&pid);
+        }
+    });
+
+    Ok(rx)
+}
@@ -0,0 +1,310 @@
+use crate::agent::FileChunk;
+use sha2::{Digest, Sha256};
+use std::fs::Permissions;
+use std::io::ErrorKind;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use tokio::fs::{self, File};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio_stream::{Stream, StreamExt};
+use tonic::Status;
+
+/// Size of the chunks sent back to the host.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+fn io_status(e: std::io::Error) -> Status {
+    match e.kind() {
+        ErrorKind::NotFound => Status::not_found(e.to_string()),
+        ErrorKind::PermissionDenied => Status::permission_denied(e.to_string()),
+        _ => Status::internal(e.to_string()),
+    }
+}
+
+pub(crate) fn hex_digest(hasher: Sha256) -> String {
+    hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Write a file received as a stream of [`FileChunk`].
+///
+/// The file is removed if the stream fails or if the checksum sent by the host does not match
+/// its content, so no truncated file is left behind.
+/// Returns the size and the SHA-256 checksum of the written file.
+pub async fn write_file<S>(mut stream: S) -> Result<(u64, String), Status>
+where
+    S: Stream<Item = Result<FileChunk, Status>> + Unpin,
+{
+    let first = stream
+        .next()
+        .await
+        .transpose()?
+        .ok_or_else(|| Status::invalid_argument("Empty file stream"))?;
+
+    if first.path.is_empty() {
+        return Err(Status::invalid_argument("Missing file path"));
+    }
+
+    let path = PathBuf::from(&first.path);
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).await.map_err(io_status)?;
+    }
+
+    let file = File::create(&path).await.map_err(io_status)?;
+    let result = write_chunks(file, &path, first, stream).await;
+    if result.is_err() {
+        let _ = fs::remove_file(&path).await;
+    }
+
+    result
+}
+
+/// Write the content of the chunks to `file`, created at `path`, and check its checksum.
+async fn write_chunks<S>(
+    mut file: File,
+    path: &Path,
+    first: FileChunk,
+    mut stream: S,
+) -> Result<(u64, String), Status>
+where
+    S: Stream<Item = Result<FileChunk, Status>> + Unpin,
+{
+    let mode = first.mode;
+    let mut hasher = Sha256::new();
+    let mut size = 0;
+    let mut expected_sha256 = None;
+
+    let mut next_chunk = Some(first);
+    while let Some(chunk) = next_chunk {
+        file.write_all(&chunk.data).await.map_err(io_status)?;
+        hasher.update(&chunk.data);
+        size += chunk.data.len() as u64;
+
+        if chunk.sha256.is_some() {
+            expected_sha256 = chunk.sha256;
+        }
+
+        next_chunk = stream.next().await.transpose()?;
+    }
+
+    file.flush().await.map_err(io_status)?;
+
+    if let Some(mode) = mode {
+        fs::set_permissions(path, Permissions::from_mode(mode))
+            .await
+            .map_err(io_status)?;
+    }
+
+    let sha256 = hex_digest(hasher);
+    if let Some(expected_sha256) = expected_sha256 {
+        if !expected_sha256.eq_ignore_ascii_case(&sha256) {
+            return Err(Status::data_loss(format!(
+                "Checksum mismatch for {}: expected {}, got {}",
+                path.display(),
+                expected_sha256,
+                sha256
+            )));
+        }
+    }
+
+    println!("Received file {} ({} bytes)", path.display(), size);
+
+    Ok((size, sha256))
+}
+
+/// Open the file at `path` and spawn a task sending its content as a stream of [`FileChunk`].
+///
+/// The first chunk holds the path and mode of the file, the last one (possibly empty) holds
+/// its SHA-256 checksum.
+pub async fn read_file(path: PathBuf) -> Result<mpsc::Receiver<Result<FileChunk, Status>>, Status> {
+    let mut file = File::open(&path).await.map_err(io_status)?;
+    let metadata = file.metadata().await.map_err(io_status)?;
+
+    if !metadata.is_file() {
+        return Err(Status::invalid_argument(format!(
+            "{} is not a regular file",
+            path.display()
+        )));
+    }
+
+    let (tx, rx) = mpsc::channel(10);
+    tokio::spawn(async move {
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0u8; CHUNK_SIZE];
+        let mut header = Some((
+            path.to_string_lossy().to_string(),
+            metadata.permissions().mode() & 0o7777,
+        ));
+
+        loop {
+            let count = match file.read(&mut buffer).await {
+                Ok(count) => count,
+                Err(e) => {
+                    let _ = tx.send(Err(io_status(e))).await;
+                    return;
+                }
+            };
+            hasher.update(&buffer[..count]);
+
+            let (path, mode) = header.take().unzip();
+            let mut chunk = FileChunk {
+                path: path.unwrap_or_default(),
+                mode,
+                data: buffer[..count].to_vec(),
+                sha256: None,
+            };
+
+            if count == 0 {
+                chunk.sha256 = Some(hex_digest(hasher));
+                let _ = tx.send(Ok(chunk)).await;
+                return;
+            }
+
+            if tx.send(Ok(chunk)).await.is_err() {
+                return;
+            }
+        }
+    });
+
+    Ok(rx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Empty directory for the files of a test, removed with [`remove_dir`].
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("agent-files-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        dir
+    }
+
+    fn remove_dir(dir: &Path) {
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn chunk(data: &[u8]) -> FileChunk {
+        FileChunk {
+            data: data.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    fn sha256(data: &[u8]) -> String {
+        hex_digest(Sha256::new_with_prefix(data))
+    }
+
+    /// Chunks of a file uploaded at `path`, with the checksum `sha256`.
+    fn upload(path: &Path, sha256: &str) -> Vec<Result<FileChunk, Status>> {
+        let mut first = chunk(b"hello, ");
+        first.path = path.to_string_lossy().to_string();
+        first.mode = Some(0o750);
+        let mut last = chunk(b"world");
+        last.sha256 = Some(sha256.to_string());
+
+        vec![Ok(first), Ok(last)]
+    }
+
+    #[tokio::test]
+    async fn write_chunks_to_file() {
+        let dir = test_dir("write");
+        let path = dir.join("sub/file");
+
+        let stream = tokio_stream::iter(upload(&path, &sha256(b"hello, world")));
+        let (size, sha) = write_file(stream).await.unwrap();
+
+        assert_eq!(size, 12);
+        assert_eq!(sha, sha256(b"hello, world"));
+        assert_eq!(std::fs::read(&path).unwrap(), b"hello, world");
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o7777, 0o750);
+
+        remove_dir(&dir);
+    }
+
+    #[tokio::test]
+    async fn checksum_mismatch_removes_file() {
+        let dir = test_dir("mismatch");
+        let path = dir.join("file");
+
+        let stream = tokio_stream::iter(upload(&path, &sha256(b"hello")));
+        let status = write_file(stream).await.unwrap_err();
+
+        assert_eq!(status.code(), tonic::Code::DataLoss);
+        assert!(!path.exists());
+
+        remove_dir(&dir);
+    }
+
+    #[tokio::test]
+    async fn failed_stream_removes_file() {
+        let dir = test_dir("failed");
+        let path = dir.join("file");
+
+        let mut chunks = upload(&path, &sha256(b"hello, world"));
+        chunks.insert(1, Err(Status::cancelled("upload cancelled")));
+        let status = write_file(tokio_stream::iter(chunks)).await.unwrap_err();
+
+        assert_eq!(status.code(), tonic::Code::Cancelled);
+        assert!(!path.exists());
+
+        remove_dir(&dir);
+    }
+
+    #[tokio::test]
+    async fn missing_path() {
+        let status = write_file(tokio_stream::iter(vec![Ok(chunk(b"data"))]))
+            .await
+            .unwrap_err();
+
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn read_file_chunks() {
+        let dir = test_dir("read");
+        let path = dir.join("file");
+        let content: Vec<u8> = (0..CHUNK_SIZE + 10).map(|i| i as u8).collect();
+        std::fs::write(&path, &content).unwrap();
+        std::fs::set_permissions(&path, Permissions::from_mode(0o640)).unwrap();
+
+        let mut rx = read_file(path.clone()).await.unwrap();
+        let mut chunks = Vec::new();
+        while let Some(chunk) = rx.recv().await {
+            chunks.push(chunk.unwrap());
+        }
+
+        let (first, rest) = chunks.split_first().unwrap();
+        assert_eq!(first.path, path.to_string_lossy());
+        assert_eq!(first.mode, Some(0o640));
+        assert!(rest
+            .iter()
+            .all(|chunk| chunk.path.is_empty() && chunk.mode.is_none()));
+
+        let last = chunks.last().unwrap();
+        assert!(last.data.is_empty());
+        assert_eq!(last.sha256, Some(sha256(&content)));
+
+        let data: Vec<u8> = chunks.iter().flat_map(|chunk| chunk.data.clone()).collect();
+        assert_eq!(data, content);
+
+        remove_dir(&dir);
+    }
+
+    #[tokio::test]
+    async fn read_directory() {
+        let dir = test_dir("read-dir");
+
+        let status = read_file(dir.clone()).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+        remove_dir(&dir);
+    }
+}
@@ -1,3 +1,7 @@
+pub mod artifacts;
 pub mod config;
+pub mod exec;
+pub mod files;
+pub mod queue;
 pub mod runner;
 pub mod service;
@@ -0,0 +1,179 @@
+use crate::agent::{execute_response::Stage, ExecuteResponse};
+use crate::agents::AgentOutput;
+use std::collections::BTreeSet;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+};
+use tokio::sync::{mpsc, watch, OwnedSemaphorePermit, Semaphore};
+
+/// FIFO queue limiting the number of workloads running at the same time.
+pub struct WorkloadQueue {
+    semaphore: Arc<Semaphore>,
+    /// Ticket given to the next queued workload.
+    next_ticket: AtomicU64,
+    /// Tickets of the workloads waiting for a slot. As the semaphore is fair, workloads get a
+    /// slot in ticket order.
+    waiting: Mutex<BTreeSet<u64>>,
+    /// Notified each time a workload leaves the queue, with a slot or cancelled.
+    moved: watch::Sender<()>,
+}
+
+/// A workload in the queue, which leaves it when dropped.
+struct Waiting<'a> {
+    queue: &'a WorkloadQueue,
+    ticket: u64,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.queue.waiting.lock().unwrap().remove(&self.ticket);
+        self.queue.moved.send_replace(());
+    }
+}
+
+impl WorkloadQueue {
+    pub fn new(max_concurrent_workloads: usize) -> Self {
+        Self {
+            semaphore: Arc::new(Semaphore::new(max_concurrent_workloads)),
+            next_ticket: AtomicU64::new(0),
+            waiting: Mutex::new(BTreeSet::new()),
+            moved: watch::channel(()).0,
+        }
+    }
+
+    /// Wait for a slot to run a workload.
+    ///
+    /// While waiting, a `Pending` output with the position in the queue is sent to `tx`
+    /// each time the queue moves. The workload can run until the permit is dropped, and
+    /// leaves the queue if the returned future is dropped.
+    pub async fn acquire(
+        &self,
+        tx: &mpsc::Sender<Result<ExecuteResponse, tonic::Status>>,
+    ) -> OwnedSemaphorePermit {
+        if let Ok(permit) = self.semaphore.clone().try_acquire_owned() {
+            return permit;
+        }
+
+        let mut moved = self.moved.subscribe();
+        let ticket = self.next_ticket.fetch_add(1, Ordering::SeqCst);
+        self.waiting.lock().unwrap().insert(ticket);
+        let waiting = Waiting {
+            queue: self,
+            ticket,
+        };
+        let acquire = self.semaphore.clone().acquire_owned();
+        tokio::pin!(acquire);
+
+        loop {
+            moved.borrow_and_update();
+            let position = self.waiting.lock().unwrap().range(..ticket).count() + 1;
+            let _ = tx
+                .send(Ok(AgentOutput {
+                    stage: Stage::Pending,
+                    stdout: Some(format!("Queued at position {}", position)),
+                    stderr: None,
+                    exit_code: None,
+                    artifact: None,
+                    termination: None,
+                }
+                .into()))
+                .await;
+
+            tokio::select! {
+                permit = &mut acquire => {
+                    drop(waiting);
+                    // The semaphore is never closed.
+                    return permit.expect("Workload queue semaphore closed");
+                }
+                _ = moved.changed() => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::task::JoinHandle;
+
+    type Outputs = mpsc::Receiver<Result<ExecuteResponse, tonic::Status>>;
+
+    /// Queue a workload, returns its permit once it gets a slot and its `Pending` outputs.
+    fn queue_workload(queue: &Arc<WorkloadQueue>) -> (JoinHandle<OwnedSemaphorePermit>, Outputs) {
+        let queue = queue.clone();
+        let (tx, rx) = mpsc::channel(10);
+
+        (tokio::spawn(async move { queue.acquire(&tx).await }), rx)
+    }
+
+    /// Position in the last `Pending` output.
+    async fn position(outputs: &mut Outputs) -> String {
+        let output = outputs.recv().await.unwrap().unwrap();
+        assert_eq!(output.stage, Stage::Pending as i32);
+
+        output.stdout.unwrap()
+    }
+
+    async fn is_waiting(workload: &mut JoinHandle<OwnedSemaphorePermit>) -> bool {
+        tokio::time::timeout(Duration::from_millis(50), workload)
+            .await
+            .is_err()
+    }
+
+    #[tokio::test]
+    async fn limits_concurrent_workloads() {
+        let queue = Arc::new(WorkloadQueue::new(2));
+        let (tx, mut outputs) = mpsc::channel(10);
+
+        let first = queue.acquire(&tx).await;
+        let _second = queue.acquire(&tx).await;
+        assert!(outputs.try_recv().is_err());
+
+        let (mut third, mut outputs) = queue_workload(&queue);
+        assert_eq!(position(&mut outputs).await, "Queued at position 1");
+        assert!(is_waiting(&mut third).await);
+
+        drop(first);
+        assert!(third.await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn workloads_run_in_order() {
+        let queue = Arc::new(WorkloadQueue::new(1));
+        let (tx, _outputs) = mpsc::channel(10);
+        let running = queue.acquire(&tx).await;
+
+        let (mut first, mut first_outputs) = queue_workload(&queue);
+        assert_eq!(position(&mut first_outputs).await, "Queued at position 1");
+        let (mut second, mut second_outputs) = queue_workload(&queue);
+        assert_eq!(position(&mut second_outputs).await, "Queued at position 2");
+
+        drop(running);
+        let running = (&mut first).await.unwrap();
+        assert!(is_waiting(&mut second).await);
+        assert_eq!(position(&mut second_outputs).await, "Queued at position 1");
+
+        drop(running);
+        assert!(second.await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn cancelled_workload_leaves_queue() {
+        let queue = Arc::new(WorkloadQueue::new(1));
+        let (tx, _outputs) = mpsc::channel(10);
+        let running = queue.acquire(&tx).await;
+
+        let (first, mut first_outputs) = queue_workload(&queue);
+        assert_eq!(position(&mut first_outputs).await, "Queued at position 1");
+        let (second, mut second_outputs) = queue_workload(&queue);
+        assert_eq!(position(&mut second_outputs).await, "Queued at position 2");
+
+        first.abort();
+        assert_eq!(position(&mut second_outputs).await, "Queued at position 1");
+
+        drop(running);
+        assert!(second.await.is_ok());
+    }
+}
@@ -1,6 +1,6 @@
-use super::config::Config;
+use super::{artifacts, config::Config};
 use crate::{
-    agent::ExecuteRequest,
+    agent::{execute_response::Stage, ExecuteRequest},
     agents::{rust, Agent, AgentOutput, Language},
     workload::config::Action,
     AgentError, AgentResult,
@@ -44,6 +44,10 @@ impl Runner {
     }
 
     pub async fn run(self) -> AgentResult<Receiver<AgentOutput>> {
+        let workload_name = self.config.workload_name.clone();
+        let outputs = self.config.outputs.clone();
+        let collect_outputs = !outputs.is_empty() && !matches!(self.config.action, Action::Prepare);
+
         let rx = match self.config.action {
             Action::Prepare => {
                 self.agent
@@ -68,9 +72,25 @@ impl Runner {
 
                 tokio::spawn(async move {
                     let rx_run = self.agent.run(Arc::clone(&self.child_processes)).await;
-                    if let Ok(mut rx_run) = rx_run {
-                        while let Some(output) = rx_run.recv().await {
-                            let _ = tx2.clone().send(output).await;
+                    match rx_run {
+                        Ok(mut rx_run) => {
+                            while let Some(output) = rx_run.recv().await {
+                                let _ = tx2.clone().send(output).await;
+                            }
+                        }
+                        // A failed build is already reported by `prepare`.
+                        Err(AgentError::BuildFailed | AgentError::BuildNotifier) => {}
+                        Err(e) => {
+                            let _ = tx2
+                                .send(AgentOutput {
+                                    stage: Stage::Failed,
+                                    stdout: None,
+                                    stderr: Some(e.to_string()),
+                                    exit_code: None,
+                                    artifact: None,
+                                    termination: None,
+                                })
+                                .await;
                         }
                     }
                 });
@@ -79,6 +99,10 @@ impl Runner {
             }
         };
 
+        if collect_outputs {
+            return Ok(artifacts::attach_artifact(rx, workload_name, outputs));
+        }
+
         Ok(rx)
     }
 }
@@ -1,19 +1,35 @@
-use super::runner::Runner;
-use crate::agent::{self, ExecuteRequest, ExecuteResponse, SignalRequest};
+use super::{exec, files, queue::WorkloadQueue, runner::Runner};
+use crate::agent::{
+    self, ExecRequest, ExecResponse, ExecuteRequest, ExecuteResponse, FileChunk, GetFileRequest,
+    PutFileResponse, SignalRequest,
+};
 use agent::workload_runner_server::WorkloadRunner;
 use once_cell::sync::Lazy;
 use std::collections::HashSet;
+use std::path::PathBuf;
 use std::{process, sync::Arc};
 use tokio::sync::{mpsc, Mutex};
 use tokio_stream::wrappers::ReceiverStream;
-use tonic::{Request, Response};
+use tonic::{Request, Response, Streaming};
 
 type Result<T> = std::result::Result<Response<T>, tonic::Status>;
 
 static CHILD_PROCESSES: Lazy<Arc<Mutex<HashSet<u32>>>> =
     Lazy::new(|| Arc::new(Mutex::new(HashSet::new())));
 
-pub struct WorkloadRunnerService;
+pub struct WorkloadRunnerService {
+    queue: Arc<WorkloadQueue>,
+}
+
+impl WorkloadRunnerService {
+    /// Create a service running at most `max_concurrent_workloads` workloads at the same time,
+    /// the others being queued.
+    pub fn new(max_concurrent_workloads: usize) -> Self {
+        Self {
+            queue: Arc::new(WorkloadQueue::new(max_concurrent_workloads)),
+        }
+    }
+}
 
 #[tonic::async_trait]
 impl WorkloadRunner for WorkloadRunnerService {
@@ -23,13 +39,25 @@ impl WorkloadRunner for WorkloadRunnerService {
         let runner = Runner::new_from_execute_request(req.into_inner(), CHILD_PROCESSES.clone())
             .map_err(|e| tonic::Status::internal(e.to_string()))?;
 
-        let mut runner_rx = runner
-            .run()
-            .await
-            .map_err(|e| tonic::Status::internal(e.to_string()))?;
-
+        let queue = self.queue.clone();
         let (tx, rx) = mpsc::channel(10);
         tokio::spawn(async move {
+            // Hold the permit until the workload has finished.
+            let _permit = queue.acquire(&tx).await;
+
+            if tx.is_closed() {
+                println!("gRPC client is gone, skipping the workload");
+                return;
+            }
+
+            let mut runner_rx = match runner.run().await {
+                Ok(runner_rx) => runner_rx,
+                Err(e) => {
+                    let _ = tx.send(Err(tonic::Status::internal(e.to_string()))).await;
+                    return;
+                }
+            };
+
             while let Some(agent_output) = runner_rx.recv().await {
                 println!("Sending to the gRPC client: {:?}", agent_output);
                 let _ = tx.send(Ok(agent_output.into())).await;
@@ -39,6 +67,31 @@ impl WorkloadRunner for WorkloadRunnerService {
         Ok(Response::new(ReceiverStream::new(rx)))
     }
 
+    type GetFileStream = ReceiverStream<std::result::Result<FileChunk, tonic::Status>>;
+
+    async fn put_file(&self, req: Request<Streaming<FileChunk>>) -> Result<PutFileResponse> {
+        let (size, sha256) = files::write_file(req.into_inner()).await?;
+
+        Ok(Response::new(PutFileResponse { size, sha256 }))
+    }
+
+    async fn get_file(&self, req: Request<GetFileRequest>) -> Result<Self::GetFileStream> {
+        let path = PathBuf::from(req.into_inner().path);
+        println!("Sending file {} to the gRPC client", path.display());
+
+        let rx = files::read_file(path).await?;
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    type ExecStream = ReceiverStream<std::result::Result<ExecResponse, tonic::Status>>;
+
+    async fn exec(&self, req: Request<Streaming<ExecRequest>>) -> Result<Self::ExecStream> {
+        let rx = exec::exec(req.into_inner(), CHILD_PROCESSES.clone()).await?;
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
     async fn signal(&self, _: Request<SignalRequest>) -> Result<()> {
         let child_processes = CHILD_PROCESSES.lock().await;
 
@@ -1,5 +1,6 @@
 use std::time::Duration;
 
+use tokio_stream::Stream;
 use tonic::{transport::Channel, Streaming};
 use vmmorchestrator::vmm_service_client::VmmServiceClient;
 
@@ -30,6 +31,34 @@ impl VmmClient {
         Ok(response_stream)
     }
 
+    pub async fn put_file(
+        &mut self,
+        chunks: impl Stream<Item = vmmorchestrator::FileChunk> + Send + 'static,
+    ) -> Result<vmmorchestrator::PutFileResponse, tonic::Status> {
+        let response = self.client.put_file(chunks).await?.into_inner();
+
+        Ok(response)
+    }
+
+    pub async fn get_file(
+        &mut self,
+        request: vmmorchestrator::GetFileRequest,
+    ) -> Result<Streaming<vmmorchestrator::FileChunk>, tonic::Status> {
+        let request = tonic::Request::new(request);
+        let response_stream = self.client.get_file(request).await?.into_inner();
+
+        Ok(response_stream)
+    }
+
+    pub async fn exec(
+        &mut self,
+        requests: impl Stream<Item = vmmorchestrator::ExecRequest> + Send + 'static,
+    ) -> Result<Streaming<vmmorchestrator::ExecResponse>, tonic::Status> {
+        let response_stream = self.client.exec(requests).await?.into_inner();
+
+        Ok(response_stream)
+    }
+
     pub async fn shutdown_vm(
         &mut self,
         request: vmmorchestrator::ShutdownVmRequest,
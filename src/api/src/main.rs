@@ -1,13 +1,20 @@
 use actix_web::{App, HttpServer};
-use api::service::{run, shutdown};
+use api::service::{exec, get_file, put_file, run, shutdown};
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     let port = 3000;
 
     println!("Starting server on port:  {}", port);
-    HttpServer::new(|| App::new().service(run).service(shutdown))
-        .bind(("127.0.0.1", port))?
-        .run()
-        .await
+    HttpServer::new(|| {
+        App::new()
+            .service(run)
+            .service(shutdown)
+            .service(put_file)
+            .service(get_file)
+            .service(exec)
+    })
+    .bind(("127.0.0.1", port))?
+    .run()
+    .await
 }
@@ -1,17 +1,25 @@
 use crate::client::{
     vmmorchestrator::{
-        execute_response::Stage, ExecuteResponse, RunVmmRequest, ShutdownVmRequest,
-        ShutdownVmResponse,
+        exec_request, exec_response, execute_response::Stage, Artifact, ExecRequest, ExecResponse,
+        ExecuteResponse, FileChunk, GetFileRequest, PutFileResponse, RunVmmRequest,
+        ShutdownVmRequest, ShutdownVmResponse, Termination,
     },
     VmmClient,
 };
-use actix_web::{post, web, HttpRequest, HttpResponse, Responder};
+use actix_web::{get, post, put, web, Either, HttpResponse, Responder};
 use actix_web_lab::sse;
 use async_stream::stream;
-use serde::Serialize;
-use shared_models::{CloudletDtoRequest, Language};
-use tokio_stream::StreamExt;
-use tonic::Streaming;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use shared_models::{
+    CloudletDtoRequest, CloudletExecInput, CloudletExecResponse, CloudletShutdownRequest, Language,
+};
+use std::mem::take;
+use tokio_stream::{wrappers::ReceiverStream, Stream, StreamExt};
+use tonic::{Code, Streaming};
+
+/// Size of the chunks sent to the VMM when uploading a file.
+const FILE_CHUNK_SIZE: usize = 64 * 1024;
 
 #[post("/run")]
 pub async fn run(req_body: web::Json<CloudletDtoRequest>) -> impl Responder {
@@ -30,6 +38,8 @@ pub async fn run(req_body: web::Json<CloudletDtoRequest>) -> impl Responder {
             Language::NODE => 2,
         },
         log_level: req.log_level as i32,
+        outputs: req.outputs,
+        kernel_cmdline: req.kernel_cmdline,
     };
 
     println!("Request: {:?}", vmm_request);
@@ -56,6 +66,53 @@ pub struct ExecuteJsonResponse {
     pub stdout: Option<String>,
     pub stderr: Option<String>,
     pub exit_code: Option<i32>,
+    pub artifact: Option<ArtifactJson>,
+    pub termination: Option<TerminationJson>,
+    /// VM running the workload, to target it with the other endpoints.
+    pub vm_id: String,
+}
+
+/// Archive of the output files of a workload, downloadable from
+/// `/files?vm_id={vm_id}&path={path}`.
+#[derive(Debug, Serialize)]
+pub struct ArtifactJson {
+    pub path: String,
+    pub size: u64,
+    pub sha256: String,
+    pub files: Vec<String>,
+}
+
+impl From<Artifact> for ArtifactJson {
+    fn from(value: Artifact) -> Self {
+        Self {
+            path: value.path,
+            size: value.size,
+            sha256: value.sha256,
+            files: value.files,
+        }
+    }
+}
+
+/// Signal which killed a workload, with its core dump if one was written.
+#[derive(Debug, Serialize)]
+pub struct TerminationJson {
+    pub signal: i32,
+    pub signal_name: String,
+    pub core_dumped: bool,
+    pub core_file: Option<String>,
+    pub backtrace: Option<String>,
+}
+
+impl From<Termination> for TerminationJson {
+    fn from(value: Termination) -> Self {
+        Self {
+            signal: value.signal,
+            signal_name: value.signal_name,
+            core_dumped: value.core_dumped,
+            core_file: value.core_file,
+            backtrace: value.backtrace,
+        }
+    }
 }
 
 #[derive(Debug, Serialize)]
@@ -88,19 +145,22 @@ impl From<ExecuteResponse> for ExecuteJsonResponse {
             stdout: value.stdout,
             stderr: value.stderr,
             exit_code: value.exit_code,
+            artifact: value.artifact.map(Into::into),
+            termination: value.termination.map(Into::into),
+            vm_id: value.vm_id,
         }
     }
 }
 
 #[post("/shutdown")]
-pub async fn shutdown(request: HttpRequest) -> impl Responder {
-    let req = request;
+pub async fn shutdown(req_body: web::Json<CloudletShutdownRequest>) -> impl Responder {
+    let req = req_body.into_inner();
 
     let mut client = VmmClient::new().await.unwrap();
 
     println!("Request: {:?}", req);
 
-    let shutdown_request = ShutdownVmRequest {};
+    let shutdown_request = ShutdownVmRequest { vm_id: req.vm_id };
     let response_result = client.shutdown_vm(shutdown_request).await;
 
     match response_result {
@@ -127,3 +187,287 @@ impl From<ShutdownVmResponse> for ShutdownJsonResponse {
         }
     }
 }
+
+#[derive(Debug, Deserialize)]
+pub struct FileQuery {
+    pub vm_id: String,
+    pub path: String,
+    /// Octal file mode, e.g. `755`.
+    pub mode: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PutFileJsonResponse {
+    pub size: u64,
+    pub sha256: String,
+}
+
+impl From<PutFileResponse> for PutFileJsonResponse {
+    fn from(value: PutFileResponse) -> Self {
+        Self {
+            size: value.size,
+            sha256: value.sha256,
+        }
+    }
+}
+
+fn status_to_http_response(status: tonic::Status) -> HttpResponse {
+    match status.code() {
+        Code::NotFound => HttpResponse::NotFound().body(status.message().to_string()),
+        Code::InvalidArgument => HttpResponse::BadRequest().body(status.message().to_string()),
+        Code::PermissionDenied => HttpResponse::Forbidden().body(status.message().to_string()),
+        _ => HttpResponse::InternalServerError().body(status.message().to_string()),
+    }
+}
+
+/// Upload the request body into the guest at `path`. The body is forwarded to the VMM as it
+/// arrives.
+#[put("/files")]
+pub async fn put_file(query: web::Query<FileQuery>, payload: web::Payload) -> impl Responder {
+    let query = query.into_inner();
+
+    let mode = match query.mode.map(|mode| u32::from_str_radix(&mode, 8)) {
+        Some(Ok(mode)) => Some(mode),
+        Some(Err(_)) => return HttpResponse::BadRequest().body("Invalid file mode"),
+        None => None,
+    };
+
+    let first = FileChunk {
+        path: query.path,
+        mode,
+        vm_id: query.vm_id,
+        ..Default::default()
+    };
+
+    let mut client = VmmClient::new().await.unwrap();
+
+    let (tx, rx) = tokio::sync::mpsc::channel(4);
+    let upload = client.put_file(ReceiverStream::new(rx));
+    tokio::pin!(upload);
+
+    let result = tokio::select! {
+        result = &mut upload => result,
+        sent = send_payload(payload, first, tx) => match sent {
+            Ok(()) => upload.await,
+            // Dropping the upload cancels it, the partial file is removed by the agent.
+            Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+        },
+    };
+
+    match result {
+        Ok(response) => {
+            let json_response: PutFileJsonResponse = response.into();
+            HttpResponse::Ok().json(json_response)
+        }
+        Err(status) => status_to_http_response(status),
+    }
+}
+
+/// Send the request body to `tx` as [`FileChunk`]s of [`FILE_CHUNK_SIZE`] bytes. The `first`
+/// chunk holds the destination of the file, the last one its SHA-256 checksum.
+async fn send_payload(
+    mut payload: web::Payload,
+    first: FileChunk,
+    tx: tokio::sync::mpsc::Sender<FileChunk>,
+) -> Result<(), actix_web::error::PayloadError> {
+    let mut hasher = Sha256::new();
+    let mut chunk = first;
+
+    while let Some(bytes) = payload.next().await {
+        let bytes = bytes?;
+        hasher.update(&bytes);
+
+        let mut bytes = &bytes[..];
+        while !bytes.is_empty() {
+            let count = (FILE_CHUNK_SIZE - chunk.data.len()).min(bytes.len());
+            chunk.data.extend_from_slice(&bytes[..count]);
+            bytes = &bytes[count..];
+
+            // The upload ended early when the channel is closed, its status tells why.
+            if chunk.data.len() == FILE_CHUNK_SIZE && tx.send(take(&mut chunk)).await.is_err() {
+                return Ok(());
+            }
+        }
+    }
+
+    chunk.sha256 = Some(
+        hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect(),
+    );
+    let _ = tx.send(chunk).await;
+
+    Ok(())
+}
+
+/// Download the file at `path` from the guest.
+#[get("/files")]
+pub async fn get_file(query: web::Query<FileQuery>) -> impl Responder {
+    let mut client = VmmClient::new().await.unwrap();
+
+    let query = query.into_inner();
+    let request = GetFileRequest {
+        path: query.path,
+        vm_id: query.vm_id,
+    };
+
+    let mut response_stream = match client.get_file(request).await {
+        Ok(response_stream) => response_stream,
+        Err(status) => return status_to_http_response(status),
+    };
+
+    // The first message tells whether the file could be opened in the guest.
+    let first_chunk = match response_stream.message().await {
+        Ok(first_chunk) => first_chunk,
+        Err(status) => return status_to_http_response(status),
+    };
+
+    let stream = stream! {
+        if let Some(chunk) = first_chunk {
+            yield Ok::<_, tonic::Status>(web::Bytes::from(chunk.data));
+        }
+        while let Some(chunk) = response_stream.next().await {
+            yield chunk.map(|chunk| web::Bytes::from(chunk.data));
+        }
+    };
+
+    HttpResponse::Ok()
+        .content_type("application/octet-stream")
+        .streaming(stream)
+}
+
+impl From<ExecResponse> for CloudletExecResponse {
+    fn from(value: ExecResponse) -> Self {
+        let mut response = CloudletExecResponse {
+            stdout: None,
+            stderr: None,
+            exit_code: None,
+        };
+
+        match value.response {
+            Some(exec_response::Response::Stdout(data)) => response.stdout = Some(data),
+            Some(exec_response::Response::Stderr(data)) => response.stderr = Some(data),
+            Some(exec_response::Response::ExitCode(code)) => response.exit_code = Some(code),
+            None => {}
+        }
+
+        response
+    }
+}
+
+/// Run a command in a VM and stream its output.
+///
+/// The request body is a stream of [`CloudletExecInput`], one JSON document per line, starting
+/// with the command to run. The next ones are forwarded to the command as they arrive, while
+/// its output is streamed back as server-sent events. A failure of the command is sent as an
+/// `error` event.
+#[post("/exec")]
+pub async fn exec(payload: web::Payload) -> impl Responder {
+    let mut inputs = Box::pin(exec_inputs(payload));
+
+    let start = match inputs.next().await {
+        Some(Ok(CloudletExecInput::Start(start))) => start,
+        Some(Err(e)) => return Either::Left(HttpResponse::BadRequest().body(e)),
+        _ => {
+            return Either::Left(
+                HttpResponse::BadRequest().body("An exec session starts with the command to run"),
+            )
+        }
+    };
+
+    let mut client = VmmClient::new().await.unwrap();
+
+    let (tx, rx) = tokio::sync::mpsc::channel(16);
+    let _ = tx
+        .send(ExecRequest {
+            request: Some(exec_request::Request::Start(exec_request::Start {
+                command: start.command,
+                args: start.args,
+                tty: start.tty,
+                rows: start.rows,
+                cols: start.cols,
+                vm_id: start.vm_id,
+                ..Default::default()
+            })),
+        })
+        .await;
+
+    // The payload can't leave the worker thread of the request.
+    actix_web::rt::spawn(async move {
+        while let Some(Ok(input)) = inputs.next().await {
+            let request = match input {
+                CloudletExecInput::Stdin(data) => exec_request::Request::Stdin(data),
+                CloudletExecInput::Resize { rows, cols } => {
+                    exec_request::Request::Resize(exec_request::Resize { rows, cols })
+                }
+                CloudletExecInput::CloseStdin => exec_request::Request::CloseStdin(true),
+                CloudletExecInput::Start(_) => break,
+            };
+
+            let request = ExecRequest {
+                request: Some(request),
+            };
+            if tx.send(request).await.is_err() {
+                break;
+            }
+        }
+        // Dropping the sender ends the requests, the agent then closes the input of the command.
+    });
+
+    let mut response_stream = match client.exec(ReceiverStream::new(rx)).await {
+        Ok(response_stream) => response_stream,
+        Err(status) => return Either::Left(status_to_http_response(status)),
+    };
+
+    let stream = stream! {
+        while let Some(exec_response) = response_stream.next().await {
+            match exec_response {
+                Ok(exec_response) => {
+                    let json: CloudletExecResponse = exec_response.into();
+                    yield sse::Event::Data(sse::Data::new_json(json).unwrap());
+                }
+                Err(status) => {
+                    yield sse::Event::Data(
+                        sse::Data::new(status.message().to_string()).event("error"),
+                    );
+                    break;
+                }
+            }
+        }
+    };
+
+    Either::Right(sse::Sse::from_infallible_stream(stream))
+}
+
+/// Messages of an exec session, read line by line from the request body.
+fn exec_inputs(mut payload: web::Payload) -> impl Stream<Item = Result<CloudletExecInput, String>> {
+    stream! {
+        let mut buffer = Vec::new();
+        let mut done = false;
+
+        while !done {
+            match payload.next().await {
+                Some(Ok(bytes)) => buffer.extend_from_slice(&bytes),
+                Some(Err(e)) => {
+                    yield Err(e.to_string());
+                    return;
+                }
+                // The last line may not end with a newline.
+                None => {
+                    buffer.push(b'\n');
+                    done = true;
+                }
+            }
+
+            while let Some(end) = buffer.iter().position(|&byte| byte == b'\n') {
+                let line: Vec<u8> = buffer.drain(..=end).collect();
+                if line.iter().all(u8::is_ascii_whitespace) {
+                    continue;
+                }
+                yield serde_json::from_slice(&line).map_err(|e| e.to_string());
+            }
+        }
+    }
+}
@@ -14,5 +14,18 @@ pub enum Commands {
         #[arg(short, long)]
         config_path: PathBuf,
     },
-    Shutdown {},
+    /// Shut down a running VM.
+    Shutdown { vm_id: String },
+    /// Run a command inside a running VM, e.g. `cli exec <vm> -- ls /tmp`.
+    Exec {
+        vm_id: String,
+        /// Forward the standard input to the command.
+        #[arg(short, long)]
+        interactive: bool,
+        /// Run the command in a pseudo-terminal, the local one is put in raw mode.
+        #[arg(short, long)]
+        tty: bool,
+        #[arg(last = true, required = true)]
+        command: Vec<String>,
+    },
 }
@@ -7,6 +7,7 @@ use std::{fs, io, process::exit};
 
 mod args;
 mod services;
+mod terminal;
 mod utils;
 
 #[tokio::main]
@@ -30,8 +31,8 @@ async fn main() -> io::Result<()> {
                 Err(e) => eprintln!("Error while making the request: {}", e),
             }
         }
-        Commands::Shutdown {} => {
-            let response = CloudletClient::shutdown().await;
+        Commands::Shutdown { vm_id } => {
+            let response = CloudletClient::shutdown(vm_id).await;
             match response {
                 Ok(bool) => {
                     if bool {
@@ -43,6 +44,22 @@ async fn main() -> io::Result<()> {
                 Err(()) => println!("Cannot send shutdown Request"),
             }
         }
+        Commands::Exec {
+            vm_id,
+            interactive,
+            tty,
+            command,
+        } => {
+            let body = CloudletClient::new_exec_request(vm_id, command, tty);
+
+            match CloudletClient::exec(body, interactive).await {
+                Ok(exit_code) => exit(exit_code),
+                Err(e) => {
+                    eprintln!("Error while making the request: {}", e);
+                    exit(1);
+                }
+            }
+        }
     }
 
     Ok(())
@@ -1,10 +1,20 @@
+use crate::terminal::{self, RawMode};
 use crate::utils::ConfigFileHandler;
-use reqwest::Client;
+use reqwest::{Body, Client};
 use serde::Deserialize;
 use shared_models::{
-    BuildConfig, CloudletDtoRequest, CloudletShutdownResponse, Language, ServerConfig,
+    BuildConfig, CloudletDtoRequest, CloudletExecInput, CloudletExecRequest, CloudletExecResponse,
+    CloudletShutdownRequest, CloudletShutdownResponse, Language, ServerConfig,
 };
 use std::error::Error;
+use std::io::Write;
+use tokio::io::AsyncReadExt;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc;
+use tokio_stream::{wrappers::ReceiverStream, StreamExt};
+
+/// Size of the pseudo-terminal when the CLI doesn't run in one.
+const DEFAULT_WINDOW_SIZE: (u32, u32) = (24, 80);
 
 #[derive(Deserialize, Debug)]
 struct TomlConfig {
@@ -14,6 +24,10 @@ struct TomlConfig {
     action: String,
     server: ServerConfig,
     build: BuildConfig,
+    #[serde(default)]
+    outputs: Vec<String>,
+    #[serde(rename = "kernel-cmdline", default)]
+    kernel_cmdline: Option<String>,
 }
 
 pub struct CloudletClient {}
@@ -36,6 +50,8 @@ impl CloudletClient {
             server: config.server,
             build: config.build,
             action: config.action,
+            outputs: config.outputs,
+            kernel_cmdline: config.kernel_cmdline,
         }
     }
 
@@ -53,9 +69,124 @@ impl CloudletClient {
         Ok(())
     }
 
-    pub async fn shutdown() -> Result<bool, ()> {
+    pub fn new_exec_request(
+        vm_id: String,
+        mut command: Vec<String>,
+        tty: bool,
+    ) -> CloudletExecRequest {
+        let args = command.split_off(1);
+        let (rows, cols) = match tty {
+            true => terminal::window_size().unwrap_or(DEFAULT_WINDOW_SIZE),
+            false => (0, 0),
+        };
+
+        CloudletExecRequest {
+            vm_id,
+            command: command.remove(0),
+            args,
+            tty,
+            rows,
+            cols,
+        }
+    }
+
+    /// Run a command in a VM, print its output as it arrives and return its exit code.
+    ///
+    /// The standard input is forwarded to the command when `interactive` is set, and so are
+    /// the resizes of the terminal when it runs in one.
+    pub async fn exec(
+        request: CloudletExecRequest,
+        interactive: bool,
+    ) -> Result<i32, Box<dyn Error>> {
+        let tty = request.tty;
+        let (inputs, rx) = mpsc::channel(16);
+        inputs.send(CloudletExecInput::Start(request)).await?;
+
+        if interactive {
+            tokio::spawn(forward_stdin(inputs.clone()));
+        } else {
+            inputs.send(CloudletExecInput::CloseStdin).await?;
+        }
+        if tty {
+            tokio::spawn(forward_resizes(inputs.clone()));
+        }
+        drop(inputs);
+
+        // One JSON message per line, sent as the session goes.
+        let body = ReceiverStream::new(rx).map(|input| {
+            serde_json::to_vec(&input).map(|mut line| {
+                line.push(b'\n');
+                line
+            })
+        });
+
         let client = Client::new();
-        let response = client.post("http://127.0.0.1:3000/shutdown").send().await;
+        let mut res = client
+            .post("http://127.0.0.1:3000/exec")
+            .header(reqwest::header::CONTENT_TYPE, "application/x-ndjson")
+            .body(Body::wrap_stream(body))
+            .send()
+            .await?
+            .error_for_status()?;
+
+        // Restored when returning, before the exit code is used.
+        let _raw_mode = if tty { Some(RawMode::enable()?) } else { None };
+
+        let mut exit_code = None;
+        let mut buffer = Vec::new();
+        let mut event = String::new();
+
+        // The response is a stream of server-sent events, one JSON output per event. A chunk
+        // may end in the middle of a line, which is kept until its end arrives.
+        while let Some(chunk) = res.chunk().await? {
+            buffer.extend_from_slice(&chunk);
+
+            while let Some(end) = buffer.iter().position(|&byte| byte == b'\n') {
+                let line: Vec<u8> = buffer.drain(..=end).collect();
+                let line = std::str::from_utf8(&line)?.trim_end();
+                if line.is_empty() {
+                    event.clear();
+                    continue;
+                }
+                if let Some(name) = line.strip_prefix("event: ") {
+                    event = name.to_string();
+                    continue;
+                }
+                let Some(data) = line.strip_prefix("data: ") else {
+                    continue;
+                };
+
+                if event == "error" {
+                    return Err(format!("The command failed: {}", data).into());
+                }
+
+                let output: CloudletExecResponse = serde_json::from_str(data)?;
+                if let Some(stdout) = output.stdout {
+                    let mut out = std::io::stdout();
+                    out.write_all(&stdout)?;
+                    out.flush()?;
+                }
+                if let Some(stderr) = output.stderr {
+                    std::io::stderr().write_all(&stderr)?;
+                }
+                if output.exit_code.is_some() {
+                    exit_code = output.exit_code;
+                }
+            }
+        }
+
+        exit_code.ok_or_else(|| "The command did not report an exit code".into())
+    }
+
+    pub async fn shutdown(vm_id: String) -> Result<bool, ()> {
+        let client = Client::new();
+        let json = serde_json::to_string(&CloudletShutdownRequest { vm_id }).map_err(|_| ())?;
+        let response = client
+            .post("http://127.0.0.1:3000/shutdown")
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(json)
+            .send()
+            .await;
 
         let shutdown_response: CloudletShutdownResponse = response
             .unwrap()
@@ -66,3 +197,40 @@ impl CloudletClient {
         Ok(shutdown_response.success)
     }
 }
+
+/// Send the standard input to the command, then close its input.
+async fn forward_stdin(inputs: mpsc::Sender<CloudletExecInput>) {
+    let mut stdin = tokio::io::stdin();
+    let mut buffer = vec![0u8; 4096];
+
+    while let Ok(count) = stdin.read(&mut buffer).await {
+        if count == 0 {
+            break;
+        }
+        let input = CloudletExecInput::Stdin(buffer[..count].to_vec());
+        if inputs.send(input).await.is_err() {
+            return;
+        }
+    }
+
+    let _ = inputs.send(CloudletExecInput::CloseStdin).await;
+}
+
+/// Send the new size of the terminal each time it's resized.
+async fn forward_resizes(inputs: mpsc::Sender<CloudletExecInput>) {
+    let Ok(mut resizes) = signal(SignalKind::window_change()) else {
+        return;
+    };
+
+    while resizes.recv().await.is_some() {
+        if let Some((rows, cols)) = terminal::window_size() {
+            if inputs
+                .send(CloudletExecInput::Resize { rows, cols })
+                .await
+                .is_err()
+            {
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,47 @@
+//! Terminal of the CLI, handed to commands run with `exec --tty`.
+use nix::libc::{winsize, STDIN_FILENO, STDOUT_FILENO, TIOCGWINSZ};
+use nix::sys::termios::{cfmakeraw, tcgetattr, tcsetattr, SetArg, Termios};
+use std::os::fd::BorrowedFd;
+
+nix::ioctl_read_bad!(tiocgwinsz, TIOCGWINSZ, winsize);
+
+/// Rows and columns of the terminal, if the standard output is one.
+pub fn window_size() -> Option<(u32, u32)> {
+    let mut size = winsize {
+        ws_row: 0,
+        ws_col: 0,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    // SAFETY: `size` outlives the call, which only writes to it.
+    unsafe { tiocgwinsz(STDOUT_FILENO, &mut size) }.ok()?;
+
+    Some((size.ws_row.into(), size.ws_col.into()))
+}
+
+/// Puts the terminal in raw mode, so that every key goes to the remote command, until dropped.
+pub struct RawMode {
+    original: Termios,
+}
+
+impl RawMode {
+    pub fn enable() -> nix::Result<Self> {
+        let original = tcgetattr(stdin())?;
+        let mut raw = original.clone();
+        cfmakeraw(&mut raw);
+        tcsetattr(stdin(), SetArg::TCSANOW, &raw)?;
+
+        Ok(RawMode { original })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = tcsetattr(stdin(), SetArg::TCSANOW, &self.original);
+    }
+}
+
+fn stdin() -> BorrowedFd<'static> {
+    // SAFETY: the standard input stays open for the lifetime of the process.
+    unsafe { BorrowedFd::borrow_raw(STDIN_FILENO) }
+}
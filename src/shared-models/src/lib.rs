@@ -37,6 +37,15 @@ pub struct CloudletDtoRequest {
     pub action: String,
     pub server: ServerConfig,
     pub build: BuildConfig,
+    #[serde(default)]
+    pub outputs: Vec<String>,
+    #[serde(default)]
+    pub kernel_cmdline: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CloudletShutdownRequest {
+    pub vm_id: String,
 }
 
 #[derive(Debug, Deserialize)]
@@ -60,3 +69,37 @@ pub struct BuildConfig {
 #[derive(Serialize, Deserialize, Debug)]
 
 pub struct AgentExecuteDtoRequest {}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CloudletExecRequest {
+    pub vm_id: String,
+    pub command: String,
+    pub args: Vec<String>,
+    /// Run the command in a pseudo-terminal of `rows` x `cols`.
+    #[serde(default)]
+    pub tty: bool,
+    #[serde(default)]
+    pub rows: u32,
+    #[serde(default)]
+    pub cols: u32,
+}
+
+/// Message of an exec session, sent by the client as one line of JSON in the request body.
+/// The session starts with [`CloudletExecInput::Start`], the other messages are applied to the
+/// command while it runs.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum CloudletExecInput {
+    Start(CloudletExecRequest),
+    Stdin(Vec<u8>),
+    Resize { rows: u32, cols: u32 },
+    CloseStdin,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CloudletExecResponse {
+    /// Output of the command as it was written, which may not be UTF-8 nor end on a character.
+    pub stdout: Option<Vec<u8>>,
+    pub stderr: Option<Vec<u8>>,
+    pub exit_code: Option<i32>,
+}
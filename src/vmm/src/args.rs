@@ -4,6 +4,9 @@ use std::{net::Ipv4Addr, path::PathBuf};
 use clap::Parser;
 use clap_verbosity_flag::{InfoLevel, Verbosity};
 use tracing::level_filters;
+use vmm::core::vmm::{ConsoleConfig, ConsoleOutput, DiskMode, MemoryRestore, SharedDirConfig};
+use vmm::grpc::server::vmmorchestrator::Language;
+use vmm::grpc::vm_pool::PoolSize;
 
 #[derive(Parser, Debug)]
 #[command(version, about)]
@@ -17,40 +20,93 @@ pub enum Commands {
     #[command(about = "Run a VMM instance.")]
     Cli(CliArguments),
     #[command(about = "Run a GRPC server listening for incoming requests.")]
-    Grpc,
+    Grpc(GrpcArguments),
+    #[command(about = "Run a VMM instance from a snapshot.")]
+    Restore(RestoreArguments),
+    #[command(about = "Run a workload in a VM and exit with its exit code.")]
+    RunWorkload(RunWorkloadArguments),
 }
 
 /// Run a VMM instance.
 #[derive(Parser, Debug)]
 #[command(author, version, about)]
 pub struct CliArguments {
-    /// Path to the image of the Linux kernel to boot.
-    #[arg(short, long, env, required = true)]
-    pub kernel: PathBuf,
+    /// Path to a TOML file describing the VM, in place of the flags describing it.
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "kernel", "initramfs", "rootfs", "rootfs_mode", "cpus", "memory", "iface_host_addr",
+            "netmask", "iface_guest_addr", "shared_dirs", "vsock_cid", "vsock_uds", "cmdline",
+            "console", "headless",
+        ],
+    )]
+    pub config: Option<PathBuf>,
+
+    /// Path to the Linux kernel to boot, an ELF vmlinux or a bzImage.
+    #[arg(short, long, env, required_unless_present = "config")]
+    pub kernel: Option<PathBuf>,
 
     /// Path to the cpio archive to use as the initramfs.
-    #[arg(short, long, env, required = true)]
+    #[arg(short, long, env, required_unless_present_any = ["rootfs", "config"])]
     pub initramfs: Option<PathBuf>,
 
-    /// Number of virtual CPUs assigned to the guest.
-    #[clap(short, long, env, default_value = "1")]
-    pub cpus: u8,
+    /// Path to a raw disk image to mount as the root filesystem.
+    #[arg(long, env)]
+    pub rootfs: Option<PathBuf>,
+
+    /// Access mode of the root filesystem image: rw, ro or cow. rw if not set.
+    #[arg(long, env)]
+    pub rootfs_mode: Option<DiskMode>,
 
-    /// Memory amount (in MBytes) assigned to the guest.
-    #[clap(short, long, env, default_value = "512")]
-    pub memory: u32,
+    /// Number of virtual CPUs assigned to the guest, 1 if not set.
+    #[clap(short, long, env)]
+    pub cpus: Option<u8>,
+
+    /// Memory amount (in MBytes) assigned to the guest, 512 if not set.
+    #[clap(short, long, env)]
+    pub memory: Option<u32>,
 
     /// IPv4 address of the host tap interface.
-    #[clap(long, env, required = true)]
-    pub iface_host_addr: Ipv4Addr,
+    #[clap(long, env, required_unless_present = "config")]
+    pub iface_host_addr: Option<Ipv4Addr>,
 
     /// Subnet mask for network.
-    #[clap(long, env, required = true)]
-    pub netmask: Ipv4Addr,
+    #[clap(long, env, required_unless_present = "config")]
+    pub netmask: Option<Ipv4Addr>,
 
     /// IPv4 address of the guest eth0 interface.
-    #[clap(long, env, required = true)]
-    pub iface_guest_addr: Ipv4Addr,
+    #[clap(long, env, required_unless_present = "config")]
+    pub iface_guest_addr: Option<Ipv4Addr>,
+
+    /// Host directory shared with the guest, as `<tag>=<path>`. Can be repeated.
+    #[clap(long = "shared-dir")]
+    pub shared_dirs: Vec<SharedDirConfig>,
+
+    /// Context ID of the guest, adds a vsock device when set.
+    #[clap(long, env, requires = "vsock_uds")]
+    pub vsock_cid: Option<u32>,
+
+    /// Path of the Unix socket backing the vsock device.
+    #[clap(long, env, requires = "vsock_cid")]
+    pub vsock_uds: Option<PathBuf>,
+
+    /// Base of the kernel command line, the devices add their own parameters and replace the
+    /// ones with the same key. The default one if not set.
+    #[clap(long, env)]
+    pub cmdline: Option<String>,
+
+    /// Directory where a snapshot of the VM is saved when the VMM receives SIGUSR1.
+    #[clap(long, env)]
+    pub snapshot_dir: Option<PathBuf>,
+
+    /// Where the output of the serial console goes: stdout, file:<path> or unix:<path>.
+    /// stdout if not set.
+    #[clap(long, env)]
+    pub console: Option<ConsoleOutput>,
+
+    /// Don't forward the standard input to the guest, nor touch the terminal.
+    #[clap(long, env)]
+    pub headless: bool,
 
     /// Verbosity level.
     #[command(flatten)]
@@ -60,13 +116,114 @@ pub struct CliArguments {
 impl CliArguments {
     /// Get the log level filter.
     pub fn convert_log_to_tracing(&self) -> level_filters::LevelFilter {
-        match self.verbose.log_level_filter() {
-            log::LevelFilter::Off => level_filters::LevelFilter::OFF,
-            log::LevelFilter::Error => level_filters::LevelFilter::ERROR,
-            log::LevelFilter::Warn => level_filters::LevelFilter::WARN,
-            log::LevelFilter::Info => level_filters::LevelFilter::INFO,
-            log::LevelFilter::Debug => level_filters::LevelFilter::DEBUG,
-            log::LevelFilter::Trace => level_filters::LevelFilter::TRACE,
-        }
+        convert_log_to_tracing(&self.verbose)
+    }
+}
+
+/// Run a GRPC server listening for incoming requests.
+#[derive(Parser, Debug)]
+#[command(author, version, about)]
+pub struct GrpcArguments {
+    /// Number of VMs kept booted for a language, as `<language>=<size>`. Can be repeated.
+    #[clap(long = "pool-size")]
+    pub pool_sizes: Vec<PoolSize>,
+}
+
+/// Run a VMM instance from a snapshot.
+#[derive(Parser, Debug)]
+#[command(author, version, about)]
+pub struct RestoreArguments {
+    /// Directory of the snapshot to restore.
+    #[clap(long, env, required = true)]
+    pub snapshot_dir: PathBuf,
+
+    /// How the guest memory is restored: eager reads it all upfront, lazy reads each page
+    /// when the guest first touches it.
+    #[clap(long, env, default_value = "lazy")]
+    pub memory_restore: MemoryRestore,
+
+    /// Where the output of the serial console goes: stdout, file:<path> or unix:<path>.
+    #[clap(long, env, default_value = "stdout")]
+    pub console: ConsoleOutput,
+
+    /// Don't forward the standard input to the guest, nor touch the terminal.
+    #[clap(long, env)]
+    pub headless: bool,
+
+    /// Verbosity level.
+    #[command(flatten)]
+    pub verbose: Verbosity<InfoLevel>,
+}
+
+impl RestoreArguments {
+    /// Get the log level filter.
+    pub fn convert_log_to_tracing(&self) -> level_filters::LevelFilter {
+        convert_log_to_tracing(&self.verbose)
+    }
+
+    pub fn console(&self) -> ConsoleConfig {
+        console_config(&self.console, self.headless)
+    }
+}
+
+/// Run a workload in a VM and exit with its exit code.
+#[derive(Parser, Debug)]
+#[command(author, version, about)]
+pub struct RunWorkloadArguments {
+    /// Language of the workload: rust, python or node.
+    #[clap(long, env, value_parser = parse_language)]
+    pub language: Language,
+
+    /// Path to the source code of the workload.
+    #[clap(long, env)]
+    pub code: PathBuf,
+
+    /// Name of the workload.
+    #[clap(long, env, default_value = "workload")]
+    pub workload_name: String,
+
+    /// Glob pattern of the files to collect once the workload has run. Can be repeated.
+    #[clap(long = "output")]
+    pub outputs: Vec<String>,
+
+    /// Base of the kernel command line, the default one if not set.
+    #[clap(long, env)]
+    pub cmdline: Option<String>,
+
+    /// Print the serial console of the VM on the standard error.
+    #[clap(long)]
+    pub console: bool,
+
+    /// Verbosity level.
+    #[command(flatten)]
+    pub verbose: Verbosity<InfoLevel>,
+}
+
+impl RunWorkloadArguments {
+    /// Get the log level filter.
+    pub fn convert_log_to_tracing(&self) -> level_filters::LevelFilter {
+        convert_log_to_tracing(&self.verbose)
+    }
+}
+
+fn parse_language(s: &str) -> Result<Language, String> {
+    Language::from_str_name(&s.to_uppercase()).ok_or_else(|| format!("unknown language {}", s))
+}
+
+fn console_config(output: &ConsoleOutput, headless: bool) -> ConsoleConfig {
+    ConsoleConfig {
+        output: output.clone(),
+        stdin: !headless,
+    }
+}
+
+fn convert_log_to_tracing(verbose: &Verbosity<InfoLevel>) -> level_filters::LevelFilter {
+    match verbose.log_level_filter() {
+        log::LevelFilter::Off => level_filters::LevelFilter::OFF,
+        log::LevelFilter::Error => level_filters::LevelFilter::ERROR,
+        log::LevelFilter::Warn => level_filters::LevelFilter::WARN,
+        log::LevelFilter::Info => level_filters::LevelFilter::INFO,
+        log::LevelFilter::Debug => level_filters::LevelFilter::DEBUG,
+        log::LevelFilter::Trace => level_filters::LevelFilter::TRACE,
     }
 }
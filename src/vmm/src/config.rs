@@ -0,0 +1,683 @@
+//! Declarative description of a VM, read from a TOML file by `vmm cli --config`. Relative
+//! paths are relative to the directory of the file.
+//!
+//! ```toml
+//! [machine]
+//! cpus = 2
+//! memory_mb = 512
+//!
+//! [kernel]
+//! path = "vmlinux.bin"
+//! initramfs = "initramfs.img"
+//! cmdline = "console=ttyS0 reboot=k panic=1 pci=off quiet"
+//!
+//! [rootfs]
+//! path = "rootfs.img"
+//! mode = "cow"
+//!
+//! [[network]]
+//! host_addr = "172.29.0.1"
+//! netmask = "255.255.0.0"
+//! guest_addr = "172.29.0.2"
+//!
+//! [[disk]]
+//! path = "data.img"
+//! mode = "ro"
+//!
+//! [[shared_dir]]
+//! tag = "cache"
+//! path = "/tmp/cache"
+//!
+//! [vsock]
+//! cid = 3
+//! uds = "/tmp/vm.sock"
+//!
+//! [console]
+//! output = "file:/tmp/vm.log"
+//! stdin = false
+//!
+//! [limits]
+//! timeout_secs = 600
+//! ```
+
+use std::collections::HashSet;
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::{de, Deserialize, Deserializer};
+use vmm::core::vmm::{
+    BlockConfig, ConsoleConfig, ConsoleOutput, DiskMode, SharedDirConfig, VsockConfig,
+};
+
+use crate::args::CliArguments;
+
+/// Number of vCPUs when `vmm cli` isn't given one.
+const DEFAULT_CPUS: u8 = 1;
+/// Memory size when `vmm cli` isn't given one.
+const DEFAULT_MEMORY_MB: u32 = 512;
+/// Highest number of vCPUs, the limit of the MP table.
+const MAX_VCPUS: u8 = 254;
+/// Highest memory size, the guest memory must end below the MMIO gap.
+const MAX_MEMORY_MB: u32 = (16 << 10) - 768;
+/// Context IDs below are reserved for the hypervisor and the host.
+const MIN_GUEST_CID: u32 = 3;
+
+#[derive(Debug)]
+pub enum Error {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    /// Problems found in the definition, all reported at once.
+    Invalid(Vec<String>),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Read(path, e) => write!(f, "failed to read {}: {}", path.display(), e),
+            Error::Parse(path, e) => write!(f, "invalid VM definition {}: {}", path.display(), e),
+            Error::Invalid(problems) => {
+                write!(f, "invalid VM definition:")?;
+                for problem in problems {
+                    write!(f, "\n  - {}", problem)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Parse a value from its string representation.
+fn from_str<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    String::deserialize(deserializer)?
+        .parse()
+        .map_err(de::Error::custom)
+}
+
+fn default_disk_mode() -> DiskMode {
+    DiskMode::ReadWrite
+}
+
+fn default_stdin() -> bool {
+    true
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VmDefinition {
+    pub machine: Machine,
+    pub kernel: Kernel,
+    pub rootfs: Option<Disk>,
+    #[serde(default)]
+    pub network: Vec<Network>,
+    #[serde(default, rename = "disk")]
+    pub disks: Vec<Disk>,
+    #[serde(default, rename = "shared_dir")]
+    pub shared_dirs: Vec<SharedDir>,
+    pub vsock: Option<Vsock>,
+    #[serde(default)]
+    pub console: Console,
+    #[serde(default)]
+    pub limits: Limits,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Machine {
+    pub cpus: u8,
+    pub memory_mb: u32,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Kernel {
+    /// ELF vmlinux or bzImage.
+    pub path: PathBuf,
+    pub initramfs: Option<PathBuf>,
+    /// Base of the command line, the default one if not set.
+    pub cmdline: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Disk {
+    pub path: PathBuf,
+    /// rw, ro or cow.
+    #[serde(default = "default_disk_mode", deserialize_with = "from_str")]
+    pub mode: DiskMode,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Network {
+    pub host_addr: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    pub guest_addr: Ipv4Addr,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SharedDir {
+    pub tag: String,
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Vsock {
+    pub cid: u32,
+    /// Unix socket backing the device.
+    pub uds: PathBuf,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Console {
+    /// stdout, file:<path> or unix:<path>.
+    #[serde(default, deserialize_with = "from_str")]
+    pub output: ConsoleOutput,
+    #[serde(default = "default_stdin")]
+    pub stdin: bool,
+}
+
+impl Default for Console {
+    fn default() -> Self {
+        Console {
+            output: ConsoleOutput::Stdout,
+            stdin: true,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Limits {
+    /// The VM is stopped once it has run for that long.
+    pub timeout_secs: Option<u64>,
+}
+
+impl VmDefinition {
+    /// Read the definition from a TOML file.
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let content =
+            std::fs::read_to_string(path).map_err(|e| Error::Read(path.to_path_buf(), e))?;
+        let mut definition: VmDefinition =
+            toml::from_str(&content).map_err(|e| Error::Parse(path.to_path_buf(), e))?;
+
+        if let Some(dir) = path.parent() {
+            definition.resolve_paths(dir);
+        }
+        Ok(definition)
+    }
+
+    /// Make the relative paths relative to `dir` rather than to the working directory.
+    fn resolve_paths(&mut self, dir: &Path) {
+        let resolve = |path: &mut PathBuf| *path = dir.join(&*path);
+
+        resolve(&mut self.kernel.path);
+        if let Some(initramfs) = &mut self.kernel.initramfs {
+            resolve(initramfs);
+        }
+        for disk in self.rootfs.iter_mut().chain(self.disks.iter_mut()) {
+            resolve(&mut disk.path);
+        }
+        for shared_dir in self.shared_dirs.iter_mut() {
+            resolve(&mut shared_dir.path);
+        }
+        if let Some(vsock) = &mut self.vsock {
+            resolve(&mut vsock.uds);
+        }
+        if let ConsoleOutput::File(path) | ConsoleOutput::UnixSocket(path) =
+            &mut self.console.output
+        {
+            resolve(path);
+        }
+    }
+
+    /// Definition of the VM described by the flags of `vmm cli`.
+    pub fn from_args(args: &CliArguments) -> Self {
+        VmDefinition {
+            machine: Machine {
+                cpus: args.cpus.unwrap_or(DEFAULT_CPUS),
+                memory_mb: args.memory.unwrap_or(DEFAULT_MEMORY_MB),
+            },
+            kernel: Kernel {
+                // Required by clap without a definition file.
+                path: args.kernel.clone().unwrap_or_default(),
+                initramfs: args.initramfs.clone(),
+                cmdline: args.cmdline.clone(),
+            },
+            rootfs: args.rootfs.clone().map(|path| Disk {
+                path,
+                mode: args.rootfs_mode.unwrap_or_else(default_disk_mode),
+            }),
+            network: args
+                .iface_host_addr
+                .zip(args.netmask)
+                .zip(args.iface_guest_addr)
+                .map(|((host_addr, netmask), guest_addr)| Network {
+                    host_addr,
+                    netmask,
+                    guest_addr,
+                })
+                .into_iter()
+                .collect(),
+            disks: Vec::new(),
+            shared_dirs: args
+                .shared_dirs
+                .iter()
+                .map(|shared_dir| SharedDir {
+                    tag: shared_dir.tag.clone(),
+                    path: shared_dir.path.clone(),
+                })
+                .collect(),
+            vsock: args
+                .vsock_cid
+                .zip(args.vsock_uds.clone())
+                .map(|(cid, uds)| Vsock { cid, uds }),
+            console: Console {
+                output: args.console.clone().unwrap_or_default(),
+                stdin: !args.headless,
+            },
+            limits: Limits::default(),
+        }
+    }
+
+    /// Check the definition can be booted.
+    pub fn validate(&self) -> Result<(), Error> {
+        let mut problems = Vec::new();
+        let mut check = |ok: bool, problem: String| {
+            if !ok {
+                problems.push(problem);
+            }
+        };
+
+        check(
+            (1..=MAX_VCPUS).contains(&self.machine.cpus),
+            format!("machine.cpus must be between 1 and {}", MAX_VCPUS),
+        );
+        check(
+            (1..=MAX_MEMORY_MB).contains(&self.machine.memory_mb),
+            format!("machine.memory_mb must be between 1 and {}", MAX_MEMORY_MB),
+        );
+
+        check(
+            self.kernel.path.is_file(),
+            format!("kernel {} not found", self.kernel.path.display()),
+        );
+        if let Some(initramfs) = &self.kernel.initramfs {
+            check(
+                initramfs.is_file(),
+                format!("initramfs {} not found", initramfs.display()),
+            );
+        }
+        check(
+            self.kernel.initramfs.is_some() || self.rootfs.is_some(),
+            "an initramfs or a rootfs is required".to_string(),
+        );
+
+        for disk in self.rootfs.iter().chain(self.disks.iter()) {
+            check(
+                disk.path.is_file(),
+                format!("disk image {} not found", disk.path.display()),
+            );
+        }
+
+        match self.network.as_slice() {
+            [network] => {
+                let subnet = |addr: Ipv4Addr| u32::from(addr) & u32::from(network.netmask);
+                check(
+                    subnet(network.host_addr) == subnet(network.guest_addr),
+                    format!(
+                        "network: {} and {} are not in the same subnet",
+                        network.host_addr, network.guest_addr
+                    ),
+                );
+                check(
+                    network.host_addr != network.guest_addr,
+                    "network: the host and the guest must have different addresses".to_string(),
+                );
+            }
+            _ => check(
+                false,
+                "exactly one network interface is supported".to_string(),
+            ),
+        }
+
+        let mut tags = HashSet::new();
+        for shared_dir in self.shared_dirs.iter() {
+            check(
+                shared_dir.path.is_dir(),
+                format!("shared directory {} not found", shared_dir.path.display()),
+            );
+            check(
+                !shared_dir.tag.is_empty() && tags.insert(&shared_dir.tag),
+                format!(
+                    "shared directory tag {:?} is empty or used twice",
+                    shared_dir.tag
+                ),
+            );
+        }
+
+        if let Some(vsock) = &self.vsock {
+            check(
+                vsock.cid >= MIN_GUEST_CID,
+                format!("vsock.cid must be at least {}", MIN_GUEST_CID),
+            );
+        }
+
+        check(
+            self.limits.timeout_secs != Some(0),
+            "limits.timeout_secs must not be 0".to_string(),
+        );
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::Invalid(problems))
+        }
+    }
+
+    /// The network interface, once validated.
+    pub fn network(&self) -> &Network {
+        &self.network[0]
+    }
+
+    pub fn block_configs(&self) -> Vec<BlockConfig> {
+        let rootfs = self.rootfs.iter().map(|disk| BlockConfig {
+            path: disk.path.clone(),
+            mode: disk.mode,
+            root: true,
+        });
+        let disks = self.disks.iter().map(|disk| BlockConfig {
+            path: disk.path.clone(),
+            mode: disk.mode,
+            root: false,
+        });
+
+        rootfs.chain(disks).collect()
+    }
+
+    pub fn shared_dir_configs(&self) -> Vec<SharedDirConfig> {
+        self.shared_dirs
+            .iter()
+            .map(|shared_dir| SharedDirConfig {
+                tag: shared_dir.tag.clone(),
+                path: shared_dir.path.clone(),
+            })
+            .collect()
+    }
+
+    pub fn vsock_config(&self) -> Option<VsockConfig> {
+        self.vsock.as_ref().map(|vsock| VsockConfig {
+            guest_cid: vsock.cid,
+            uds_path: vsock.uds.clone(),
+        })
+    }
+
+    pub fn console_config(&self) -> ConsoleConfig {
+        ConsoleConfig {
+            output: self.console.output.clone(),
+            stdin: self.console.stdin,
+        }
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.limits.timeout_secs.map(Duration::from_secs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    /// Directory holding a kernel, an initramfs and a directory to share, removed once
+    /// dropped.
+    struct Fixture(PathBuf);
+
+    impl std::ops::Deref for Fixture {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn fixture(name: &str) -> Fixture {
+        let dir = std::env::temp_dir().join(format!("vmm-config-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(dir.join("shared")).unwrap();
+        std::fs::write(dir.join("vmlinux"), b"").unwrap();
+        std::fs::write(dir.join("initramfs.img"), b"").unwrap();
+
+        Fixture(dir)
+    }
+
+    /// Definition of a valid VM in `dir`, followed by `extra`.
+    fn definition(dir: &Path, extra: &str) -> VmDefinition {
+        let content = format!(
+            r#"
+            [machine]
+            cpus = 2
+            memory_mb = 512
+
+            [kernel]
+            path = "{dir}/vmlinux"
+            initramfs = "{dir}/initramfs.img"
+
+            [[network]]
+            host_addr = "172.29.0.1"
+            netmask = "255.255.0.0"
+            guest_addr = "172.29.0.2"
+
+            [[shared_dir]]
+            tag = "cache"
+            path = "{dir}/shared"
+
+            {extra}
+            "#,
+            dir = dir.display(),
+            extra = extra,
+        );
+
+        toml::from_str(&content).unwrap()
+    }
+
+    fn problems(definition: &VmDefinition) -> Vec<String> {
+        match definition.validate() {
+            Ok(()) => Vec::new(),
+            Err(Error::Invalid(problems)) => problems,
+            Err(e) => panic!("unexpected error {}", e),
+        }
+    }
+
+    #[test]
+    fn valid_definition() {
+        let dir = fixture("valid");
+
+        let definition = definition(&dir, "[vsock]\ncid = 3\nuds = \"/tmp/vm.sock\"");
+        assert!(definition.validate().is_ok());
+    }
+
+    #[test]
+    fn paths_relative_to_the_file() {
+        let dir = fixture("relative");
+        let path = dir.join("vm.toml");
+        std::fs::write(
+            &path,
+            r#"
+            [machine]
+            cpus = 1
+            memory_mb = 512
+
+            [kernel]
+            path = "vmlinux"
+            initramfs = "initramfs.img"
+
+            [rootfs]
+            path = "images/rootfs.img"
+
+            [[shared_dir]]
+            tag = "cache"
+            path = "shared"
+
+            [[shared_dir]]
+            tag = "data"
+            path = "/var/data"
+
+            [console]
+            output = "file:vm.log"
+            "#,
+        )
+        .unwrap();
+
+        let definition = VmDefinition::load(&path).unwrap();
+        assert_eq!(definition.kernel.path, dir.join("vmlinux"));
+        assert_eq!(definition.kernel.initramfs, Some(dir.join("initramfs.img")));
+        assert_eq!(
+            definition.rootfs.unwrap().path,
+            dir.join("images/rootfs.img")
+        );
+        assert_eq!(definition.shared_dirs[0].path, dir.join("shared"));
+        assert_eq!(definition.shared_dirs[1].path, Path::new("/var/data"));
+        assert!(matches!(
+            definition.console.output,
+            ConsoleOutput::File(log) if log == dir.join("vm.log")
+        ));
+    }
+
+    #[test]
+    fn problems_reported_at_once() {
+        let dir = fixture("problems");
+
+        let mut definition = definition(&dir, "[limits]\ntimeout_secs = 0");
+        definition.machine.cpus = 0;
+        definition.machine.memory_mb = MAX_MEMORY_MB + 1;
+        definition.kernel.path = dir.join("bzImage");
+        definition.kernel.initramfs = None;
+
+        assert_eq!(
+            problems(&definition),
+            [
+                "machine.cpus must be between 1 and 254".to_string(),
+                format!("machine.memory_mb must be between 1 and {}", MAX_MEMORY_MB),
+                format!("kernel {}/bzImage not found", dir.display()),
+                "an initramfs or a rootfs is required".to_string(),
+                "limits.timeout_secs must not be 0".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn missing_files() {
+        let dir = fixture("missing");
+
+        let definition = definition(
+            &dir,
+            "[rootfs]\npath = \"/nonexistent/rootfs.img\"\n\
+             [[shared_dir]]\ntag = \"data\"\npath = \"/nonexistent\"",
+        );
+        assert_eq!(
+            problems(&definition),
+            [
+                "disk image /nonexistent/rootfs.img not found",
+                "shared directory /nonexistent not found",
+            ]
+        );
+    }
+
+    #[test]
+    fn network() {
+        let dir = fixture("network");
+
+        let mut definition = definition(&dir, "");
+        definition.network[0].guest_addr = Ipv4Addr::new(172, 30, 0, 2);
+        assert_eq!(
+            problems(&definition),
+            ["network: 172.29.0.1 and 172.30.0.2 are not in the same subnet"]
+        );
+
+        definition.network[0].guest_addr = definition.network[0].host_addr;
+        assert_eq!(
+            problems(&definition),
+            ["network: the host and the guest must have different addresses"]
+        );
+
+        definition.network.clear();
+        assert_eq!(
+            problems(&definition),
+            ["exactly one network interface is supported"]
+        );
+    }
+
+    #[test]
+    fn shared_dir_tags() {
+        let dir = fixture("tags");
+
+        let shared_dir = format!(
+            "[[shared_dir]]\ntag = \"cache\"\npath = \"{}\"",
+            dir.display()
+        );
+        let definition = definition(&dir, &shared_dir);
+        assert_eq!(
+            problems(&definition),
+            ["shared directory tag \"cache\" is empty or used twice"]
+        );
+    }
+
+    #[test]
+    fn reserved_vsock_cid() {
+        let dir = fixture("vsock");
+
+        let definition = definition(&dir, "[vsock]\ncid = 2\nuds = \"/tmp/vm.sock\"");
+        assert_eq!(problems(&definition), ["vsock.cid must be at least 3"]);
+    }
+
+    #[test]
+    fn defaults_of_the_flags() {
+        let args = CliArguments::try_parse_from([
+            "cli",
+            "--kernel=vmlinux",
+            "--rootfs=rootfs.img",
+            "--iface-host-addr=172.29.0.1",
+            "--netmask=255.255.0.0",
+            "--iface-guest-addr=172.29.0.2",
+        ])
+        .unwrap();
+        let definition = VmDefinition::from_args(&args);
+
+        assert_eq!(definition.machine.cpus, DEFAULT_CPUS);
+        assert_eq!(definition.machine.memory_mb, DEFAULT_MEMORY_MB);
+        assert_eq!(definition.kernel.cmdline, None);
+        assert!(matches!(
+            definition.rootfs.unwrap().mode,
+            DiskMode::ReadWrite
+        ));
+        assert!(matches!(definition.console.output, ConsoleOutput::Stdout));
+    }
+
+    #[test]
+    fn flags_conflict_with_config() {
+        for flag in ["--cpus=2", "--memory=1024", "--cmdline=quiet", "--headless"] {
+            let args = ["cli", "--config", "vm.toml", flag];
+            assert!(CliArguments::try_parse_from(args).is_err(), "{}", flag);
+        }
+
+        assert!(CliArguments::try_parse_from(["cli", "--config", "vm.toml"]).is_ok());
+    }
+}
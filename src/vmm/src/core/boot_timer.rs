@@ -0,0 +1,88 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Time taken by each phase of the boot of a VM, the ones of the VMM and the ones of the guest
+//! reported through [`BOOT_TIMER_PORT`].
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tracing::info;
+
+/// Port the guest writes the markers of its boot phases to. The guest has no floppy controller
+/// to claim it, while port 0x80 is written by Linux to delay I/O.
+pub const BOOT_TIMER_PORT: u16 = 0x3f0;
+
+/// A phase of the boot, in the order they are reached.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BootPhase {
+    VmmCreated,
+    MemorySetUp,
+    KernelLoaded,
+    FirstVcpuRun,
+    /// Written by `/init` once it starts.
+    InitStarted,
+    /// Written by the agent once it accepts requests.
+    AgentReady,
+}
+
+impl BootPhase {
+    /// Phase of a marker written by the guest to [`BOOT_TIMER_PORT`].
+    pub fn from_marker(marker: u8) -> Option<Self> {
+        match marker {
+            1 => Some(BootPhase::InitStarted),
+            2 => Some(BootPhase::AgentReady),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for BootPhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let phase = match self {
+            BootPhase::VmmCreated => "vmm-created",
+            BootPhase::MemorySetUp => "memory-set-up",
+            BootPhase::KernelLoaded => "kernel-loaded",
+            BootPhase::FirstVcpuRun => "first-vcpu-run",
+            BootPhase::InitStarted => "init-started",
+            BootPhase::AgentReady => "agent-ready",
+        };
+        f.write_str(phase)
+    }
+}
+
+/// Time elapsed between the start of the creation of the VMM and each phase, shared by the VMM
+/// and its vCPUs.
+#[derive(Clone, Debug)]
+pub struct BootTimer {
+    start: Instant,
+    phases: Arc<Mutex<Vec<(BootPhase, Duration)>>>,
+}
+
+impl Default for BootTimer {
+    fn default() -> Self {
+        BootTimer {
+            start: Instant::now(),
+            phases: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+}
+
+impl BootTimer {
+    /// Record that `phase` is reached. Only the first time counts, a rebooted guest or each of
+    /// the vCPUs reach it again.
+    pub fn record(&self, phase: BootPhase) {
+        let elapsed = self.start.elapsed();
+        let mut phases = self.phases.lock().unwrap();
+
+        if phases.iter().all(|(recorded, _)| *recorded != phase) {
+            info!(%phase, ?elapsed, "Boot phase reached");
+            phases.push((phase, elapsed));
+        }
+    }
+
+    /// Phases reached so far, with the time it took to reach them.
+    pub fn phases(&self) -> Vec<(BootPhase, Duration)> {
+        self.phases.lock().unwrap().clone()
+    }
+}
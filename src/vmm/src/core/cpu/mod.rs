@@ -1,22 +1,27 @@
 // Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
 
+use crate::core::boot_timer::{BootPhase, BootTimer, BOOT_TIMER_PORT};
+use crate::core::devices::console::ConsoleWriter;
 use crate::core::devices::serial::{
     LumperSerial, SERIAL2_PORT_BASE, SERIAL2_PORT_LAST_REGISTER, SERIAL_PORT_BASE,
     SERIAL_PORT_LAST_REGISTER,
 };
-use kvm_bindings::{kvm_fpu, kvm_regs, CpuId};
+use crate::core::kernel::{BootProtocol, KernelEntry, PVH_INFO_START, ZEROPG_START};
+use kvm_bindings::{
+    kvm_cpuid_entry2, kvm_debugregs, kvm_fpu, kvm_lapic_state, kvm_mp_state, kvm_msr_entry,
+    kvm_regs, kvm_sregs, kvm_vcpu_events, kvm_xcrs, kvm_xsave, CpuId, Msrs, KVM_MAX_CPUID_ENTRIES,
+};
 use kvm_ioctls::{VcpuExit, VcpuFd, VmFd};
 use std::convert::TryInto;
-use std::io::Stdout;
+use std::sync::mpsc::{Receiver, Sender};
 use std::sync::{Arc, Mutex};
-use std::{io, process};
 use std::{result, u64};
 use tracing::{error, info, warn};
 use vm_device::bus::MmioAddress;
 use vm_device::device_manager::{IoManager, MmioManager};
 use vm_memory::{Address, Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
-use vmm_sys_util::terminal::Terminal;
+use vmm_sys_util::signal::{register_signal_handler, SIGRTMIN};
 
 pub(crate) mod cpuid;
 mod gdt;
@@ -45,6 +50,10 @@ const X86_CR4_PAE: u64 = 0x20;
 const KBD_CMD_IO_ADDR: u16 = 0x64;
 const KBD_RESET_CMD: u8 = 0xFE;
 
+/// Port the guest writes its exit status to before shutting down, the one of QEMU's
+/// `isa-debug-exit` device.
+pub const EXIT_PORT: u16 = 0x501;
+
 /// Errors encountered during vCPU operation.
 #[derive(Debug)]
 pub enum Error {
@@ -60,11 +69,80 @@ pub enum Error {
     SetModelSpecificRegistersCount,
     /// Failed to configure MSRs.
     CreateMsr(msrs::Error),
+    /// Failed to create the CPUID of a restored vCPU.
+    CreateCpuid,
+    /// Failed to register the signal handler used to kick vCPUs.
+    RegisterSignalHandler(vmm_sys_util::errno::Error),
 }
 
 /// Dedicated Result type.
 pub type Result<T> = result::Result<T, Error>;
 
+/// Signal sent to a vCPU thread to make it leave `KVM_RUN`.
+pub(crate) fn kick_signal() -> i32 {
+    SIGRTMIN()
+}
+
+/// Register the handler of [`kick_signal`], which does nothing: receiving the signal is enough
+/// to interrupt `KVM_RUN`.
+pub(crate) fn register_kick_signal_handler() -> Result<()> {
+    extern "C" fn handle_kick(_: libc::c_int, _: *mut libc::siginfo_t, _: *mut libc::c_void) {}
+
+    register_signal_handler(kick_signal(), handle_kick).map_err(Error::RegisterSignalHandler)
+}
+
+/// Why a vCPU stopped running the guest for good.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VmExit {
+    /// The guest shut down, with the status it wrote to [`EXIT_PORT`] if any.
+    Shutdown(Option<u8>),
+    /// The guest reset the machine through the keyboard controller.
+    Reset,
+    /// The VMM stopped the vCPU.
+    Stopped,
+}
+
+impl VmExit {
+    /// Exit code of the VMM process for this exit: the status reported by the guest, 1 when
+    /// it didn't report one, as after a panic, or when the VMM stopped it.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            VmExit::Shutdown(Some(status)) => i32::from(*status),
+            VmExit::Shutdown(None) | VmExit::Reset | VmExit::Stopped => 1,
+        }
+    }
+}
+
+/// Requests handled by a vCPU thread between two VM exits.
+pub(crate) enum VcpuRequest {
+    /// Stop running the guest until [`VcpuRequest::Resume`].
+    Pause,
+    /// Save the state of a paused vCPU.
+    SaveState,
+    Resume,
+    /// Leave the run loop.
+    Stop,
+}
+
+pub(crate) enum VcpuResponse {
+    Paused,
+    State(Box<Result<VcpuState>>),
+}
+
+/// State of a vCPU, saved in snapshots.
+pub(crate) struct VcpuState {
+    pub cpuid: Vec<kvm_cpuid_entry2>,
+    pub msrs: Vec<kvm_msr_entry>,
+    pub mp_state: kvm_mp_state,
+    pub regs: kvm_regs,
+    pub sregs: kvm_sregs,
+    pub xsave: kvm_xsave,
+    pub xcrs: kvm_xcrs,
+    pub debug_regs: kvm_debugregs,
+    pub lapic: kvm_lapic_state,
+    pub vcpu_events: kvm_vcpu_events,
+}
+
 /// Struct for interacting with vCPUs.
 ///
 /// This struct is a temporary (and quite terrible) placeholder until the
@@ -76,8 +154,11 @@ pub(crate) struct Vcpu {
     pub vcpu_fd: VcpuFd,
 
     device_mgr: Arc<Mutex<IoManager>>,
-    serial: Arc<Mutex<LumperSerial<Stdout>>>,
+    serial: Arc<Mutex<LumperSerial<ConsoleWriter>>>,
     slip_pty: Arc<Mutex<SlipPty>>,
+    boot_timer: BootTimer,
+    /// MSRs saved in snapshots.
+    msr_indices: Vec<u32>,
 }
 
 impl Vcpu {
@@ -86,8 +167,10 @@ impl Vcpu {
         vm_fd: &VmFd,
         index: u64,
         device_mgr: Arc<Mutex<IoManager>>,
-        serial: Arc<Mutex<LumperSerial<Stdout>>>,
+        serial: Arc<Mutex<LumperSerial<ConsoleWriter>>>,
         slip_pty: Arc<Mutex<SlipPty>>,
+        boot_timer: BootTimer,
+        msr_indices: Vec<u32>,
     ) -> Result<Self> {
         Ok(Vcpu {
             index,
@@ -95,6 +178,8 @@ impl Vcpu {
             device_mgr,
             serial,
             slip_pty,
+            boot_timer,
+            msr_indices,
         })
     }
 
@@ -119,10 +204,21 @@ impl Vcpu {
     }
 
     /// Configure regs.
-    pub fn configure_regs(&self, kernel_load: GuestAddress) -> Result<()> {
+    pub fn configure_regs(&self, kernel_entry: KernelEntry) -> Result<()> {
+        if kernel_entry.protocol == BootProtocol::Pvh {
+            let regs = kvm_regs {
+                rflags: 0x0000_0000_0000_0002u64,
+                rip: kernel_entry.addr.raw_value(),
+                // Must point to the start info per PVH ABI.
+                rbx: PVH_INFO_START,
+                ..Default::default()
+            };
+            return self.vcpu_fd.set_regs(&regs).map_err(Error::KvmIoctl);
+        }
+
         let regs = kvm_regs {
             rflags: 0x0000_0000_0000_0002u64,
-            rip: kernel_load.raw_value(),
+            rip: kernel_entry.addr.raw_value(),
             // Frame pointer. It gets a snapshot of the stack pointer (rsp) so that when adjustments are
             // made to rsp (i.e. reserving space for local variables or pushing values on to the stack),
             // local variables and function parameters are still accessible from a constant offset from rbp.
@@ -130,23 +226,35 @@ impl Vcpu {
             // Starting stack pointer.
             rbp: BOOT_STACK_POINTER,
             // Must point to zero page address per Linux ABI. This is x86_64 specific.
-            rsi: crate::core::kernel::ZEROPG_START,
+            rsi: ZEROPG_START,
             ..Default::default()
         };
         self.vcpu_fd.set_regs(&regs).map_err(Error::KvmIoctl)
     }
 
-    /// Configure sregs.
-    pub fn configure_sregs(&self, guest_memory: &GuestMemoryMmap) -> Result<()> {
+    /// Configure sregs, for the mode `protocol` starts the kernel in.
+    pub fn configure_sregs(
+        &self,
+        guest_memory: &GuestMemoryMmap,
+        protocol: BootProtocol,
+    ) -> Result<()> {
         let mut sregs = self.vcpu_fd.get_sregs().map_err(Error::KvmIoctl)?;
 
         // Global descriptor tables.
-        let gdt_table: [u64; BOOT_GDT_MAX] = [
-            gdt_entry(0, 0, 0),            // NULL
-            gdt_entry(0xa09b, 0, 0xfffff), // CODE
-            gdt_entry(0xc093, 0, 0xfffff), // DATA
-            gdt_entry(0x808b, 0, 0xfffff), // TSS
-        ];
+        let gdt_table: [u64; BOOT_GDT_MAX] = match protocol {
+            BootProtocol::Linux => [
+                gdt_entry(0, 0, 0),            // NULL
+                gdt_entry(0xa09b, 0, 0xfffff), // CODE
+                gdt_entry(0xc093, 0, 0xfffff), // DATA
+                gdt_entry(0x808b, 0, 0xfffff), // TSS
+            ],
+            BootProtocol::Pvh => [
+                gdt_entry(0, 0, 0),            // NULL
+                gdt_entry(0xc09b, 0, 0xfffff), // CODE, 32-bit
+                gdt_entry(0xc093, 0, 0xfffff), // DATA
+                gdt_entry(0x008b, 0, 0x67),    // TSS
+            ],
+        };
 
         let code_seg = kvm_segment_from_gdt(gdt_table[1], 1);
         let data_seg = kvm_segment_from_gdt(gdt_table[2], 2);
@@ -169,6 +277,13 @@ impl Vcpu {
         sregs.ss = data_seg;
         sregs.tr = tss_seg;
 
+        if protocol == BootProtocol::Pvh {
+            // 32-bit protected mode, without paging.
+            sregs.cr0 = X86_CR0_PE;
+            sregs.cr4 = 0;
+            return self.vcpu_fd.set_sregs(&sregs).map_err(Error::KvmIoctl);
+        }
+
         // 64-bit protected mode.
         sregs.cr0 |= X86_CR0_PE;
         sregs.efer |= (msr_index::EFER_LME | msr_index::EFER_LMA) as u64;
@@ -234,8 +349,145 @@ impl Vcpu {
         self.vcpu_fd.set_lapic(&klapic).map_err(Error::KvmIoctl)
     }
 
-    /// vCPU emulation loop.
-    pub fn run(&mut self) {
+    /// Read the MSRs listed in `msr_indices`, skipping the ones KVM refuses to read.
+    fn save_msrs(&self) -> Result<Vec<kvm_msr_entry>> {
+        let mut entries: Vec<kvm_msr_entry> = self
+            .msr_indices
+            .iter()
+            .map(|&index| kvm_msr_entry {
+                index,
+                ..Default::default()
+            })
+            .collect();
+
+        // KVM stops at the first MSR it fails to read: drop it and read the next ones.
+        let mut saved = Vec::new();
+        while !entries.is_empty() {
+            let mut msrs = Msrs::from_entries(&entries)
+                .map_err(|_| Error::CreateMsr(msrs::Error::CreateMsrs))?;
+            let read = self.vcpu_fd.get_msrs(&mut msrs).map_err(Error::KvmIoctl)?;
+
+            saved.extend_from_slice(&msrs.as_slice()[..read]);
+            entries.drain(..(read + 1).min(entries.len()));
+        }
+
+        Ok(saved)
+    }
+
+    /// Write back MSRs saved by [`Vcpu::save_msrs`], skipping the ones KVM refuses to write.
+    fn restore_msrs(&self, entries: &[kvm_msr_entry]) -> Result<()> {
+        let mut entries = entries;
+        while !entries.is_empty() {
+            let msrs = Msrs::from_entries(entries)
+                .map_err(|_| Error::CreateMsr(msrs::Error::CreateMsrs))?;
+            let written = self.vcpu_fd.set_msrs(&msrs).map_err(Error::KvmIoctl)?;
+
+            if written < entries.len() {
+                warn!(
+                    vcpu_index = self.index,
+                    "Failed to restore MSR {:#x}", entries[written].index
+                );
+            }
+            entries = &entries[(written + 1).min(entries.len())..];
+        }
+
+        Ok(())
+    }
+
+    /// Save the state of the vCPU, which must not be running.
+    pub fn save_state(&self) -> Result<VcpuState> {
+        let fd = &self.vcpu_fd;
+
+        Ok(VcpuState {
+            cpuid: fd
+                .get_cpuid2(KVM_MAX_CPUID_ENTRIES)
+                .map_err(Error::KvmIoctl)?
+                .as_slice()
+                .to_vec(),
+            msrs: self.save_msrs()?,
+            mp_state: fd.get_mp_state().map_err(Error::KvmIoctl)?,
+            regs: fd.get_regs().map_err(Error::KvmIoctl)?,
+            sregs: fd.get_sregs().map_err(Error::KvmIoctl)?,
+            xsave: fd.get_xsave().map_err(Error::KvmIoctl)?,
+            xcrs: fd.get_xcrs().map_err(Error::KvmIoctl)?,
+            debug_regs: fd.get_debug_regs().map_err(Error::KvmIoctl)?,
+            lapic: fd.get_lapic().map_err(Error::KvmIoctl)?,
+            vcpu_events: fd.get_vcpu_events().map_err(Error::KvmIoctl)?,
+        })
+    }
+
+    /// Restore the state saved by [`Vcpu::save_state`], in place of the boot configuration.
+    pub fn restore_state(&self, state: &VcpuState) -> Result<()> {
+        let fd = &self.vcpu_fd;
+
+        let cpuid = CpuId::from_entries(&state.cpuid).map_err(|_| Error::CreateCpuid)?;
+        self.configure_cpuid(&cpuid)?;
+        fd.set_mp_state(state.mp_state).map_err(Error::KvmIoctl)?;
+        fd.set_regs(&state.regs).map_err(Error::KvmIoctl)?;
+        fd.set_sregs(&state.sregs).map_err(Error::KvmIoctl)?;
+        fd.set_xsave(&state.xsave).map_err(Error::KvmIoctl)?;
+        fd.set_xcrs(&state.xcrs).map_err(Error::KvmIoctl)?;
+        fd.set_debug_regs(&state.debug_regs)
+            .map_err(Error::KvmIoctl)?;
+        fd.set_lapic(&state.lapic).map_err(Error::KvmIoctl)?;
+        self.restore_msrs(&state.msrs)?;
+        fd.set_vcpu_events(&state.vcpu_events)
+            .map_err(Error::KvmIoctl)
+    }
+
+    /// Run the vCPU until the guest stops, handling the `requests` between two VM exits.
+    pub fn run_loop(
+        &mut self,
+        requests: Receiver<VcpuRequest>,
+        responses: Sender<VcpuResponse>,
+    ) -> VmExit {
+        self.boot_timer.record(BootPhase::FirstVcpuRun);
+
+        loop {
+            if let Some(exit) = self.run() {
+                return exit;
+            }
+
+            while let Ok(request) = requests.try_recv() {
+                let exit = match request {
+                    VcpuRequest::Pause => self.pause(&requests, &responses),
+                    VcpuRequest::Stop => Some(VmExit::Stopped),
+                    VcpuRequest::SaveState | VcpuRequest::Resume => None,
+                };
+                if let Some(exit) = exit {
+                    return exit;
+                }
+            }
+        }
+    }
+
+    /// Stay out of the guest until a [`VcpuRequest::Resume`]. Returns the exit of the vCPU if
+    /// it's stopped instead.
+    fn pause(
+        &self,
+        requests: &Receiver<VcpuRequest>,
+        responses: &Sender<VcpuResponse>,
+    ) -> Option<VmExit> {
+        let _ = responses.send(VcpuResponse::Paused);
+
+        loop {
+            match requests.recv() {
+                Ok(VcpuRequest::Pause) => {
+                    let _ = responses.send(VcpuResponse::Paused);
+                }
+                Ok(VcpuRequest::SaveState) => {
+                    let state = self.save_state();
+                    let _ = responses.send(VcpuResponse::State(Box::new(state)));
+                }
+                Ok(VcpuRequest::Resume) => return None,
+                // Nobody can resume the vCPU once the VMM dropped its handle.
+                Ok(VcpuRequest::Stop) | Err(_) => return Some(VmExit::Stopped),
+            }
+        }
+    }
+
+    /// vCPU emulation loop. Returns the exit of the vCPU once the guest stops.
+    pub fn run(&mut self) -> Option<VmExit> {
         // Call into KVM to launch (VMLAUNCH) or resume (VMRESUME) the virtual CPU.
         // This is a blocking function, it only returns for either an error or a
         // VM-Exit. In the latter case, we can inspect the exit reason.
@@ -244,49 +496,50 @@ impl Vcpu {
                 // The VM stopped (Shutdown ot HLT).
                 VcpuExit::Shutdown | VcpuExit::Hlt => {
                     info!(?exit_reason, "Guest shutdown. Bye!");
-                    let stdin = io::stdin();
-                    let stdin_lock = stdin.lock();
-                    stdin_lock.set_canon_mode().unwrap();
-
-                    unsafe { libc::exit(0) };
+                    return Some(VmExit::Shutdown(None));
                 }
 
                 // This is a PIO write, i.e. the guest is trying to write
                 // something to an I/O port.
                 VcpuExit::IoOut(addr, data) => match addr {
                     SERIAL_PORT_BASE..=SERIAL_PORT_LAST_REGISTER => {
-                        self.serial
-                            .lock()
-                            .unwrap()
-                            .serial
-                            .write(
-                                (addr - SERIAL_PORT_BASE)
-                                    .try_into()
-                                    .expect("Invalid serial register offset"),
-                                data[0],
-                            )
-                            .unwrap();
+                        // The output which can't be written is lost, it must not stop the
+                        // vCPU.
+                        if let Err(err) = self.serial.lock().unwrap().serial.write(
+                            (addr - SERIAL_PORT_BASE)
+                                .try_into()
+                                .expect("Invalid serial register offset"),
+                            data[0],
+                        ) {
+                            warn!(?err, "Failed to write to the serial device");
+                        }
                     }
                     SERIAL2_PORT_BASE..=SERIAL2_PORT_LAST_REGISTER => {
-                        self.slip_pty
-                            .lock()
-                            .unwrap()
-                            .serial_mut()
-                            .serial
-                            .write(
-                                (addr - SERIAL2_PORT_BASE)
-                                    .try_into()
-                                    .expect("Invalid serial register offset"),
-                                data[0],
-                            )
-                            .unwrap();
+                        // The output which can't be written is lost, it must not stop the
+                        // vCPU.
+                        if let Err(err) = self.slip_pty.lock().unwrap().serial_mut().serial.write(
+                            (addr - SERIAL2_PORT_BASE)
+                                .try_into()
+                                .expect("Invalid serial register offset"),
+                            data[0],
+                        ) {
+                            warn!(?err, "Failed to write to the serial device");
+                        }
                     }
                     KBD_CMD_IO_ADDR => {
                         if data[0] == KBD_RESET_CMD {
                             info!(?exit_reason, "Guest reset via keyboard controller. Bye!");
-                            process::exit(0);
+                            return Some(VmExit::Reset);
                         }
                     }
+                    BOOT_TIMER_PORT => match BootPhase::from_marker(data[0]) {
+                        Some(phase) => self.boot_timer.record(phase),
+                        None => warn!(marker = data[0], "Unknown boot timer marker"),
+                    },
+                    EXIT_PORT => {
+                        info!(status = data[0], "Guest reported its exit status. Bye!");
+                        return Some(VmExit::Shutdown(Some(data[0])));
+                    }
                     _ => {
                         warn!(address = addr, "Unsupported device write at {:x?}", addr);
                     }
@@ -339,7 +592,11 @@ impl Vcpu {
                     error!(?exit_reason, "Unhandled VM-Exit");
                 }
             },
+            // Kicked out of the guest by `kick_signal`.
+            Err(e) if e.errno() == libc::EINTR => {}
             Err(e) => error!(?e, "Emulation error"),
         }
+
+        None
     }
 }
@@ -0,0 +1,332 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Sinks of the output of the serial console.
+
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Stdout, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::broadcast;
+use tracing::warn;
+
+/// Length after which the line being written to a [`ConsoleOutput::Broadcast`] is sent even
+/// without a newline.
+const MAX_LINE_LEN: usize = 4096;
+
+/// Where the output of the serial console goes.
+#[derive(Clone, Debug, Default)]
+pub enum ConsoleOutput {
+    /// The standard output of the VMM.
+    #[default]
+    Stdout,
+    /// A file, appended to.
+    File(PathBuf),
+    /// The last bytes written, kept in memory.
+    Buffer(ConsoleBuffer),
+    /// The clients connected to a Unix socket bound at this path.
+    UnixSocket(PathBuf),
+    /// The subscribers of a channel, which receive the console line by line. Lines longer
+    /// than 4096 bytes are split.
+    Broadcast(broadcast::Sender<Vec<u8>>),
+    /// All of the outputs, in order.
+    Tee(Vec<ConsoleOutput>),
+}
+
+impl ConsoleOutput {
+    /// Open the sink, to be written to by the serial device.
+    pub(crate) fn open(&self) -> io::Result<ConsoleWriter> {
+        let writer = match self {
+            ConsoleOutput::Stdout => ConsoleWriter::Stdout(io::stdout()),
+            ConsoleOutput::File(path) => {
+                ConsoleWriter::File(OpenOptions::new().create(true).append(true).open(path)?)
+            }
+            ConsoleOutput::Buffer(buffer) => ConsoleWriter::Buffer(buffer.clone()),
+            ConsoleOutput::UnixSocket(path) => {
+                // Remove the socket left by a previous run.
+                let _ = std::fs::remove_file(path);
+                let listener = UnixListener::bind(path)?;
+                listener.set_nonblocking(true)?;
+
+                ConsoleWriter::UnixSocket {
+                    listener,
+                    clients: Vec::new(),
+                }
+            }
+            ConsoleOutput::Broadcast(sender) => ConsoleWriter::Broadcast {
+                sender: sender.clone(),
+                line: Vec::new(),
+            },
+            ConsoleOutput::Tee(outputs) => ConsoleWriter::Tee(
+                outputs
+                    .iter()
+                    .map(ConsoleOutput::open)
+                    .collect::<io::Result<_>>()?,
+            ),
+        };
+
+        Ok(writer)
+    }
+}
+
+/// Parse `stdout`, `file:<path>` or `unix:<path>`. The other outputs are only available to
+/// the users of the library.
+impl FromStr for ConsoleOutput {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "stdout" {
+            return Ok(ConsoleOutput::Stdout);
+        }
+
+        match s.split_once(':') {
+            Some(("file", path)) => Ok(ConsoleOutput::File(PathBuf::from(path))),
+            Some(("unix", path)) => Ok(ConsoleOutput::UnixSocket(PathBuf::from(path))),
+            _ => Err(format!(
+                "invalid console output {}, expected stdout, file:<path> or unix:<path>",
+                s
+            )),
+        }
+    }
+}
+
+/// Ring buffer holding the last `capacity` bytes of the console.
+#[derive(Clone, Debug)]
+pub struct ConsoleBuffer {
+    capacity: usize,
+    bytes: Arc<Mutex<VecDeque<u8>>>,
+}
+
+impl ConsoleBuffer {
+    pub fn new(capacity: usize) -> Self {
+        ConsoleBuffer {
+            capacity,
+            bytes: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+        }
+    }
+
+    /// Bytes currently in the buffer, oldest first.
+    pub fn contents(&self) -> Vec<u8> {
+        self.bytes.lock().unwrap().iter().copied().collect()
+    }
+
+    fn push(&self, buf: &[u8]) {
+        let mut bytes = self.bytes.lock().unwrap();
+        let buf = &buf[buf.len().saturating_sub(self.capacity)..];
+        let overflow = (bytes.len() + buf.len()).saturating_sub(self.capacity);
+
+        bytes.drain(..overflow);
+        bytes.extend(buf);
+    }
+}
+
+/// Console attached to a VM.
+#[derive(Clone, Debug)]
+pub struct ConsoleConfig {
+    pub output: ConsoleOutput,
+    /// Forward the standard input of the VMM to the guest. The terminal is put in raw mode
+    /// while the VM runs.
+    pub stdin: bool,
+}
+
+impl Default for ConsoleConfig {
+    fn default() -> Self {
+        ConsoleConfig {
+            output: ConsoleOutput::Stdout,
+            stdin: true,
+        }
+    }
+}
+
+impl ConsoleConfig {
+    /// Console writing to `output`, without input.
+    pub fn headless(output: ConsoleOutput) -> Self {
+        ConsoleConfig {
+            output,
+            stdin: false,
+        }
+    }
+}
+
+/// An opened [`ConsoleOutput`]. Writing to it never fails: the serial device would panic the
+/// vCPU on an error, so the output which can't be written is logged and dropped.
+pub(crate) enum ConsoleWriter {
+    Stdout(Stdout),
+    File(File),
+    Buffer(ConsoleBuffer),
+    UnixSocket {
+        listener: UnixListener,
+        clients: Vec<UnixStream>,
+    },
+    Broadcast {
+        sender: broadcast::Sender<Vec<u8>>,
+        /// The line being written.
+        line: Vec<u8>,
+    },
+    Tee(Vec<ConsoleWriter>),
+}
+
+impl Write for ConsoleWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            ConsoleWriter::Stdout(stdout) => {
+                if let Err(e) = stdout.write_all(buf) {
+                    warn!("Failed to write the console to stdout: {}", e);
+                }
+            }
+            ConsoleWriter::File(file) => {
+                if let Err(e) = file.write_all(buf) {
+                    warn!("Failed to write the console to its file: {}", e);
+                }
+            }
+            ConsoleWriter::Buffer(buffer) => buffer.push(buf),
+            ConsoleWriter::UnixSocket { listener, clients } => {
+                while let Ok((client, _)) = listener.accept() {
+                    // A client which doesn't read must not block the vCPU.
+                    if client.set_nonblocking(true).is_ok() {
+                        clients.push(client);
+                    }
+                }
+
+                // The output is lost for the clients which are too slow. The ones which only
+                // took part of it would get truncated lines, they are dropped along with the
+                // ones which went away.
+                clients.retain_mut(|client| match client.write(buf) {
+                    Ok(count) => count == buf.len(),
+                    Err(e) => e.kind() == io::ErrorKind::WouldBlock,
+                });
+            }
+            ConsoleWriter::Broadcast { sender, line } => {
+                for &byte in buf {
+                    line.push(byte);
+                    if byte == b'\n' || line.len() >= MAX_LINE_LEN {
+                        // Sending only fails when nobody is subscribed.
+                        let _ = sender.send(std::mem::take(line));
+                    }
+                }
+            }
+            ConsoleWriter::Tee(writers) => {
+                // The writers never fail, see above.
+                for writer in writers {
+                    let _ = writer.write_all(buf);
+                }
+            }
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let result = match self {
+            ConsoleWriter::Stdout(stdout) => stdout.flush(),
+            ConsoleWriter::File(file) => file.flush(),
+            ConsoleWriter::Tee(writers) => writers.iter_mut().try_for_each(Write::flush),
+            _ => Ok(()),
+        };
+        if let Err(e) = result {
+            warn!("Failed to flush the console: {}", e);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::path::Path;
+
+    #[test]
+    fn buffer_keeps_last_bytes() {
+        let buffer = ConsoleBuffer::new(8);
+
+        buffer.push(b"abc");
+        assert_eq!(buffer.contents(), b"abc");
+        buffer.push(b"defgh");
+        assert_eq!(buffer.contents(), b"abcdefgh");
+
+        // The oldest bytes make room for the new ones.
+        buffer.push(b"ij");
+        assert_eq!(buffer.contents(), b"cdefghij");
+
+        // Only the end of a write longer than the buffer is kept.
+        buffer.push(b"0123456789");
+        assert_eq!(buffer.contents(), b"23456789");
+        buffer.push(b"");
+        assert_eq!(buffer.contents(), b"23456789");
+    }
+
+    #[test]
+    fn broadcast_lines() {
+        let (sender, mut lines) = broadcast::channel(16);
+        let mut writer = ConsoleOutput::Broadcast(sender).open().unwrap();
+
+        writer.write_all(b"first\nsec").unwrap();
+        writer.write_all(b"ond\nthird").unwrap();
+        assert_eq!(lines.try_recv().unwrap(), b"first\n");
+        assert_eq!(lines.try_recv().unwrap(), b"second\n");
+        // The last line is sent once complete.
+        assert!(lines.try_recv().is_err());
+        writer.write_all(b"\n").unwrap();
+        assert_eq!(lines.try_recv().unwrap(), b"third\n");
+
+        // Long lines are split.
+        let mut long = vec![b'x'; MAX_LINE_LEN * 2 + 10];
+        long.push(b'\n');
+        writer.write_all(&long).unwrap();
+        assert_eq!(lines.try_recv().unwrap(), &long[..MAX_LINE_LEN]);
+        assert_eq!(
+            lines.try_recv().unwrap(),
+            &long[MAX_LINE_LEN..MAX_LINE_LEN * 2]
+        );
+        assert_eq!(lines.try_recv().unwrap(), &long[MAX_LINE_LEN * 2..]);
+        assert!(lines.try_recv().is_err());
+    }
+
+    #[test]
+    fn unix_socket_drops_truncated_clients() {
+        let path = std::env::temp_dir().join(format!("console-{}.sock", std::process::id()));
+        let mut writer = ConsoleOutput::UnixSocket(path.clone()).open().unwrap();
+        let mut client = UnixStream::connect(&path).unwrap();
+
+        writer.write_all(b"hello\n").unwrap();
+        let mut line = [0; 6];
+        client.read_exact(&mut line).unwrap();
+        assert_eq!(&line, b"hello\n");
+
+        // More than the socket holds: the client only gets the beginning, then is dropped.
+        let output = vec![b'x'; 4 << 20];
+        writer.write_all(&output).unwrap();
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).unwrap();
+        assert!(!received.is_empty());
+        assert!(received.len() < output.len());
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn parse_output() {
+        assert!(matches!(
+            "stdout".parse::<ConsoleOutput>(),
+            Ok(ConsoleOutput::Stdout)
+        ));
+        assert!(matches!(
+            "file:/var/log/vm.log".parse::<ConsoleOutput>(),
+            Ok(ConsoleOutput::File(path)) if path == Path::new("/var/log/vm.log")
+        ));
+        // Only the first colon separates the kind of output.
+        assert!(matches!(
+            "unix:/tmp/vm:0.sock".parse::<ConsoleOutput>(),
+            Ok(ConsoleOutput::UnixSocket(path)) if path == Path::new("/tmp/vm:0.sock")
+        ));
+
+        for invalid in ["", "stderr", "file", "tcp:localhost:1234"] {
+            assert!(invalid.parse::<ConsoleOutput>().is_err(), "{}", invalid);
+        }
+    }
+}
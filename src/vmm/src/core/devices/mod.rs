@@ -2,6 +2,7 @@
 
 use std::io;
 
+pub(crate) mod console;
 pub(crate) mod serial;
 pub(crate) mod virtio;
 
@@ -14,6 +15,8 @@ pub enum Error {
     EventFdClone(io::Error),
     // Serial raw bytes enqueueing error
     SerialEnqueue(vm_superio::serial::Error<io::Error>),
+    // Serial state restoration error
+    SerialRestore,
 }
 
 /// Dedicated [`Result`](https://doc.rust-lang.org/std/result/) type.
@@ -8,7 +8,7 @@ use std::sync::Arc;
 
 use super::{Error, Result};
 
-use vm_superio::serial::SerialEvents;
+use vm_superio::serial::{SerialEvents, SerialState};
 use vm_superio::{Serial, Trigger};
 use vmm_sys_util::eventfd::EventFd;
 
@@ -103,6 +103,23 @@ impl<W: Write> LumperSerial<W> {
     pub fn in_buffer_empty_eventfd(&self) -> &EventFd {
         &self.in_buffer_empty_eventfd
     }
+
+    pub fn state(&self) -> SerialState {
+        self.serial.state()
+    }
+
+    /// Replace the serial device by one in the saved `state`, writing to `out`.
+    pub fn restore(&mut self, state: &SerialState, out: W) -> Result<()> {
+        self.serial = Serial::from_state(
+            state,
+            self.eventfd.try_clone().map_err(Error::EventFdClone)?,
+            LumperSerialEvents::new(self.in_buffer_empty_eventfd.clone()),
+            out,
+        )
+        .map_err(|_| Error::SerialRestore)?;
+
+        Ok(())
+    }
 }
 
 pub(crate) struct LumperSerialEvents {
@@ -0,0 +1,166 @@
+use super::disk::Disk;
+use super::queue_handler::QueueHandler;
+use super::{BlockConfig, Error, Result, BLOCK_DEVICE_ID};
+use crate::core::devices::virtio::register::register_mmio_device;
+use crate::core::devices::virtio::{
+    self, Config, MmioConfig, SingleFdSignalQueue, Subscriber, VirtioState, QUEUE_MAX_SIZE,
+};
+use event_manager::RemoteEndpoint;
+use kvm_ioctls::VmFd;
+use std::{
+    borrow::{Borrow, BorrowMut},
+    sync::{Arc, Mutex},
+};
+use tracing::info;
+use virtio_bindings::virtio_blk::{VIRTIO_BLK_F_FLUSH, VIRTIO_BLK_F_RO};
+use virtio_bindings::virtio_config::VIRTIO_F_VERSION_1;
+use virtio_device::{VirtioConfig, VirtioDeviceActions, VirtioDeviceType, VirtioMmioDevice};
+use virtio_queue::Queue;
+use vm_device::device_manager::IoManager;
+use vm_device::{bus::MmioAddress, MutDeviceMmio};
+use vm_memory::GuestMemoryMmap;
+
+pub struct Block {
+    mem: Arc<GuestMemoryMmap>,
+    pub config: Config,
+    // Moved to the queue handler once the device is activated.
+    disk: Option<Disk>,
+    handler: Option<Arc<Mutex<QueueHandler<SingleFdSignalQueue>>>>,
+}
+
+impl Block {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        mem: Arc<GuestMemoryMmap>,
+        device_mgr: Arc<Mutex<IoManager>>,
+        mmio_cfg: MmioConfig,
+        block_cfg: &BlockConfig,
+        irq: u32,
+        endpoint: RemoteEndpoint<Subscriber>,
+        vm_fd: Arc<VmFd>,
+        cmdline_extra_parameters: &mut Vec<String>,
+    ) -> Result<Arc<Mutex<Self>>> {
+        let disk = Disk::open(&block_cfg.path, block_cfg.mode).map_err(Error::Disk)?;
+        info!(
+            "block device backed by {} ({} sectors, {:?})",
+            block_cfg.path.display(),
+            disk.sectors(),
+            block_cfg.mode
+        );
+
+        let mut device_features = 1 << VIRTIO_F_VERSION_1 | 1 << VIRTIO_BLK_F_FLUSH;
+        if disk.is_read_only() {
+            device_features |= 1 << VIRTIO_BLK_F_RO;
+        }
+
+        // The configuration space starts with the capacity of the disk, in sectors.
+        let config_space = disk.sectors().to_le_bytes().to_vec();
+
+        let queues =
+            vec![Queue::new(QUEUE_MAX_SIZE)
+                .map_err(|_| Error::Virtio(virtio::Error::QueuesNotValid))?];
+
+        let virtio_cfg = VirtioConfig::new(device_features, queues, config_space);
+
+        let cfg = Config::new(virtio_cfg, mmio_cfg, endpoint, vm_fd).map_err(Error::Virtio)?;
+
+        let block = Arc::new(Mutex::new(Block {
+            mem,
+            config: cfg,
+            disk: Some(disk),
+            handler: None,
+        }));
+
+        let vmmio_param = register_mmio_device(mmio_cfg, device_mgr, irq, None, block.clone())
+            .map_err(Error::Virtio)?;
+
+        cmdline_extra_parameters.push(vmmio_param);
+
+        Ok(block)
+    }
+
+    pub fn state(&self) -> VirtioState {
+        match &self.handler {
+            Some(handler) => {
+                let handler = handler.lock().unwrap();
+                self.config.state(&[&handler.queue])
+            }
+            None => self.config.state(&[]),
+        }
+    }
+
+    /// Restore the state of a device created with the same configuration.
+    pub fn restore(&mut self, state: &VirtioState) -> Result<()> {
+        self.config.restore(state).map_err(Error::Virtio)?;
+        if state.activated {
+            self.activate()?;
+        }
+        Ok(())
+    }
+}
+
+impl VirtioDeviceType for Block {
+    fn device_type(&self) -> u32 {
+        BLOCK_DEVICE_ID
+    }
+}
+
+impl Borrow<VirtioConfig<Queue>> for Block {
+    fn borrow(&self) -> &VirtioConfig<Queue> {
+        &self.config.virtio
+    }
+}
+
+impl BorrowMut<VirtioConfig<Queue>> for Block {
+    fn borrow_mut(&mut self) -> &mut VirtioConfig<Queue> {
+        &mut self.config.virtio
+    }
+}
+
+impl VirtioDeviceActions for Block {
+    type E = Error;
+
+    fn activate(&mut self) -> Result<()> {
+        let driver_notify = SingleFdSignalQueue {
+            irqfd: self.config.irqfd.clone(),
+            interrupt_status: self.config.virtio.interrupt_status.clone(),
+        };
+
+        let mut ioevents = self.config.prepare_activate().map_err(Error::Virtio)?;
+
+        let disk = self
+            .disk
+            .take()
+            .ok_or(Error::Virtio(virtio::Error::AlreadyActivated))?;
+
+        let handler = Arc::new(Mutex::new(QueueHandler {
+            driver_notify,
+            queue: self.config.virtio.queues.remove(0),
+            mem: self.mem.clone(),
+            disk,
+            ioevent: ioevents.remove(0),
+        }));
+        self.handler = Some(handler.clone());
+
+        self.config
+            .finalize_activate(handler)
+            .map_err(Error::Virtio)
+    }
+
+    fn reset(&mut self) -> std::result::Result<(), Error> {
+        // Not implemented for now.
+        Ok(())
+    }
+}
+
+impl VirtioMmioDevice for Block {}
+
+impl MutDeviceMmio for Block {
+    fn mmio_read(&mut self, _base: MmioAddress, offset: u64, data: &mut [u8]) {
+        self.read(offset, data);
+    }
+
+    fn mmio_write(&mut self, _base: MmioAddress, offset: u64, data: &[u8]) {
+        self.write(offset, data);
+    }
+}
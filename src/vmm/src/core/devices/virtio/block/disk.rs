@@ -0,0 +1,157 @@
+use super::{DiskMode, SECTOR_SHIFT, SECTOR_SIZE};
+use std::fs::{File, OpenOptions};
+use std::io::{self, ErrorKind};
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::path::Path;
+
+/// Sectors written by the guest to a copy-on-write disk, stored in a temporary file.
+struct Overlay {
+    file: File,
+    /// One bit per sector, set once the sector is in the overlay.
+    written: Vec<u64>,
+}
+
+impl Overlay {
+    fn new(size: u64) -> io::Result<Self> {
+        // Anonymous file, removed as soon as it's closed.
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .mode(0o600)
+            .custom_flags(libc::O_TMPFILE)
+            .open(std::env::temp_dir())?;
+        // Sparse, only the written sectors use space.
+        file.set_len(size)?;
+
+        let sectors = size >> SECTOR_SHIFT;
+        Ok(Overlay {
+            file,
+            written: vec![0; sectors.div_ceil(64) as usize],
+        })
+    }
+
+    fn is_written(&self, sector: u64) -> bool {
+        self.written[(sector / 64) as usize] & (1 << (sector % 64)) != 0
+    }
+
+    fn set_written(&mut self, sector: u64) {
+        self.written[(sector / 64) as usize] |= 1 << (sector % 64);
+    }
+}
+
+/// Disk image backing a block device.
+pub struct Disk {
+    image: File,
+    overlay: Option<Overlay>,
+    read_only: bool,
+    size: u64,
+}
+
+impl Disk {
+    pub fn open(path: &Path, mode: DiskMode) -> io::Result<Self> {
+        let image = OpenOptions::new()
+            .read(true)
+            .write(mode == DiskMode::ReadWrite)
+            .open(path)?;
+
+        // The guest only sees whole sectors.
+        let size = image.metadata()?.len() & !(SECTOR_SIZE - 1);
+
+        let overlay = match mode {
+            DiskMode::CopyOnWrite => Some(Overlay::new(size)?),
+            DiskMode::ReadWrite | DiskMode::ReadOnly => None,
+        };
+
+        Ok(Disk {
+            image,
+            overlay,
+            read_only: mode == DiskMode::ReadOnly,
+            size,
+        })
+    }
+
+    pub fn sectors(&self) -> u64 {
+        self.size >> SECTOR_SHIFT
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn check_range(&self, offset: u64, len: usize) -> io::Result<()> {
+        let in_range = offset
+            .checked_add(len as u64)
+            .is_some_and(|end| end <= self.size);
+        let aligned = offset % SECTOR_SIZE == 0 && len as u64 % SECTOR_SIZE == 0;
+
+        if in_range && aligned {
+            Ok(())
+        } else {
+            Err(io::Error::from(ErrorKind::InvalidInput))
+        }
+    }
+
+    /// Read whole sectors starting at `offset`.
+    pub fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        self.check_range(offset, buf.len())?;
+
+        let Some(overlay) = &self.overlay else {
+            return self.image.read_exact_at(buf, offset);
+        };
+
+        // Read the runs of sectors coming from the same file at once.
+        let mut done = 0;
+        while done < buf.len() {
+            let first_sector = (offset + done as u64) >> SECTOR_SHIFT;
+            let in_overlay = overlay.is_written(first_sector);
+
+            let mut end = done + SECTOR_SIZE as usize;
+            while end < buf.len()
+                && overlay.is_written((offset + end as u64) >> SECTOR_SHIFT) == in_overlay
+            {
+                end += SECTOR_SIZE as usize;
+            }
+
+            let file = if in_overlay {
+                &overlay.file
+            } else {
+                &self.image
+            };
+            file.read_exact_at(&mut buf[done..end], offset + done as u64)?;
+            done = end;
+        }
+
+        Ok(())
+    }
+
+    /// Write whole sectors starting at `offset`.
+    pub fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        if self.read_only {
+            return Err(io::Error::from(ErrorKind::PermissionDenied));
+        }
+        self.check_range(offset, buf.len())?;
+
+        match &mut self.overlay {
+            Some(overlay) => {
+                overlay.file.write_all_at(buf, offset)?;
+
+                let first_sector = offset >> SECTOR_SHIFT;
+                for sector in first_sector..first_sector + (buf.len() as u64 >> SECTOR_SHIFT) {
+                    overlay.set_written(sector);
+                }
+
+                Ok(())
+            }
+            None => self.image.write_all_at(buf, offset),
+        }
+    }
+
+    pub fn flush(&self) -> io::Result<()> {
+        match &self.overlay {
+            // The overlay doesn't outlive the VMM, no need to persist it.
+            Some(_) => Ok(()),
+            None if self.read_only => Ok(()),
+            None => self.image.sync_data(),
+        }
+    }
+}
@@ -0,0 +1,89 @@
+//! virtio-blk device, backed by a raw disk image.
+
+pub mod device;
+mod disk;
+mod queue_handler;
+
+use crate::core::devices::virtio;
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+const BLOCK_DEVICE_ID: u32 = 2;
+const SECTOR_SHIFT: u8 = 9;
+const SECTOR_SIZE: u64 = 1 << SECTOR_SHIFT;
+
+#[derive(Debug)]
+pub enum Error {
+    Virtio(virtio::Error),
+    /// Failed to open the disk image.
+    Disk(io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// How the guest accesses the disk image.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiskMode {
+    /// Writes go to the image.
+    ReadWrite,
+    /// Writes are refused.
+    ReadOnly,
+    /// Writes go to a temporary overlay, discarded when the VMM exits. The image is only read,
+    /// so it can be shared between VMs.
+    CopyOnWrite,
+}
+
+impl FromStr for DiskMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "rw" => Ok(DiskMode::ReadWrite),
+            "ro" => Ok(DiskMode::ReadOnly),
+            "cow" => Ok(DiskMode::CopyOnWrite),
+            _ => Err(format!("invalid disk mode {}, expected rw, ro or cow", s)),
+        }
+    }
+}
+
+/// Name given by the guest to the disk registered at `index`, counting from 0: `vda` to `vdz`,
+/// then `vdaa`, `vdab`... as `virtblk_name_format` in Linux.
+pub fn disk_name(index: usize) -> String {
+    let mut suffix = Vec::new();
+    let mut index = index + 1;
+
+    while index > 0 {
+        index -= 1;
+        suffix.push(b'a' + (index % 26) as u8);
+        index /= 26;
+    }
+    suffix.reverse();
+
+    format!("vd{}", String::from_utf8_lossy(&suffix))
+}
+
+/// Configuration of a block device.
+#[derive(Clone, Debug)]
+pub struct BlockConfig {
+    /// Path of the raw disk image, which may be a sparse file.
+    pub path: PathBuf,
+    pub mode: DiskMode,
+    /// Mount the disk as the root filesystem of the guest.
+    pub root: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disk_names() {
+        assert_eq!(disk_name(0), "vda");
+        assert_eq!(disk_name(25), "vdz");
+        assert_eq!(disk_name(26), "vdaa");
+        assert_eq!(disk_name(27), "vdab");
+        assert_eq!(disk_name(701), "vdzz");
+        assert_eq!(disk_name(702), "vdaaa");
+    }
+}
@@ -0,0 +1,213 @@
+use super::disk::Disk;
+use super::SECTOR_SHIFT;
+use crate::core::devices::virtio::SignalUsedQueue;
+use event_manager::{EventOps, Events, MutEventSubscriber};
+use log::error;
+use std::result;
+use std::sync::Arc;
+use virtio_bindings::virtio_blk::{
+    VIRTIO_BLK_ID_BYTES, VIRTIO_BLK_S_IOERR, VIRTIO_BLK_S_OK, VIRTIO_BLK_S_UNSUPP,
+    VIRTIO_BLK_T_FLUSH, VIRTIO_BLK_T_GET_ID, VIRTIO_BLK_T_IN, VIRTIO_BLK_T_OUT,
+};
+use virtio_queue::{Descriptor, DescriptorChain, Queue, QueueOwnedT, QueueT};
+use vm_memory::{Address, Bytes, GuestAddress, GuestAddressSpace, GuestMemoryMmap};
+use vmm_sys_util::epoll::EventSet;
+use vmm_sys_util::eventfd::EventFd;
+
+const IOEVENT_DATA: u32 = 0;
+/// Size of the header of a request (`struct virtio_blk_outhdr`).
+const REQUEST_HEADER_SIZE: usize = 16;
+/// Serial number of the disks, returned to `GET_ID` requests.
+const DISK_ID: &[u8] = b"cloudlet";
+/// Largest buffer allocated to copy data between the guest and the disk.
+const IO_CHUNK_SIZE: usize = 64 << 10;
+
+#[derive(Debug)]
+pub enum Error {
+    GuestMemory(vm_memory::GuestMemoryError),
+    Queue(virtio_queue::Error),
+}
+
+impl From<virtio_queue::Error> for Error {
+    fn from(e: virtio_queue::Error) -> Self {
+        Error::Queue(e)
+    }
+}
+
+/// Call `f` on consecutive chunks of at most `IO_CHUNK_SIZE` bytes of the buffer described by
+/// `desc`, with the guest address of each chunk. The length of a descriptor is chosen by the
+/// guest, so the data goes through a bounded buffer instead of one of that length.
+fn for_each_chunk<F>(desc: &Descriptor, mut f: F) -> result::Result<(), ()>
+where
+    F: FnMut(&mut [u8], GuestAddress) -> result::Result<(), ()>,
+{
+    let len = desc.len() as usize;
+    let mut buf = vec![0u8; len.min(IO_CHUNK_SIZE)];
+    let mut done = 0;
+
+    while done < len {
+        let count = (len - done).min(IO_CHUNK_SIZE);
+        let addr = desc.addr().checked_add(done as u64).ok_or(())?;
+        f(&mut buf[..count], addr)?;
+        done += count;
+    }
+
+    Ok(())
+}
+
+pub struct QueueHandler<S>
+where
+    S: SignalUsedQueue,
+{
+    pub driver_notify: S,
+    pub queue: Queue,
+    pub mem: Arc<GuestMemoryMmap>,
+    pub disk: Disk,
+    pub ioevent: EventFd,
+}
+
+impl<S> QueueHandler<S>
+where
+    S: SignalUsedQueue,
+{
+    /// Execute the request described by `header` on the `data` buffers. Returns the status of
+    /// the request and the number of bytes written to the guest.
+    fn execute(&mut self, header: &[u8; REQUEST_HEADER_SIZE], data: &[Descriptor]) -> (u32, u32) {
+        let request_type = u32::from_le_bytes(header[0..4].try_into().unwrap());
+        let sector = u64::from_le_bytes(header[8..16].try_into().unwrap());
+        let mut offset = sector << SECTOR_SHIFT;
+        let mut written = 0;
+
+        // The device writes the data of `IN` and `GET_ID` requests to the buffers and reads the
+        // data of `OUT` requests from them, the guest must flag the buffers accordingly.
+        let writable = |desc: &Descriptor| desc.is_write_only();
+
+        let result = match request_type {
+            VIRTIO_BLK_T_IN | VIRTIO_BLK_T_GET_ID if !data.iter().all(writable) => Err(()),
+            VIRTIO_BLK_T_OUT if data.iter().any(writable) => Err(()),
+            VIRTIO_BLK_T_IN => data.iter().try_for_each(|desc| {
+                for_each_chunk(desc, |buf, addr| {
+                    self.disk.read_at(buf, offset).map_err(|_| ())?;
+                    self.mem.write_slice(buf, addr).map_err(|_| ())?;
+                    offset += buf.len() as u64;
+                    written += buf.len() as u32;
+                    Ok(())
+                })
+            }),
+            VIRTIO_BLK_T_OUT => data.iter().try_for_each(|desc| {
+                for_each_chunk(desc, |buf, addr| {
+                    self.mem.read_slice(buf, addr).map_err(|_| ())?;
+                    self.disk.write_at(buf, offset).map_err(|_| ())?;
+                    offset += buf.len() as u64;
+                    Ok(())
+                })
+            }),
+            VIRTIO_BLK_T_FLUSH => self.disk.flush().map_err(|_| ()),
+            VIRTIO_BLK_T_GET_ID => match data.first() {
+                Some(desc) => {
+                    let len = DISK_ID
+                        .len()
+                        .min(desc.len() as usize)
+                        .min(VIRTIO_BLK_ID_BYTES as usize);
+                    written += len as u32;
+                    self.mem
+                        .write_slice(&DISK_ID[..len], desc.addr())
+                        .map_err(|_| ())
+                }
+                None => Err(()),
+            },
+            _ => return (VIRTIO_BLK_S_UNSUPP, 0),
+        };
+
+        match result {
+            Ok(()) => (VIRTIO_BLK_S_OK, written),
+            Err(()) => (VIRTIO_BLK_S_IOERR, written),
+        }
+    }
+
+    /// Handle a request, returns the number of bytes written to the guest.
+    fn process_request(
+        &mut self,
+        chain: DescriptorChain<Arc<GuestMemoryMmap>>,
+    ) -> result::Result<u32, Error> {
+        let descs: Vec<Descriptor> = chain.collect();
+
+        // A request has at least a header and a status descriptor.
+        let (Some(header_desc), Some(status_desc)) = (descs.first(), descs.last()) else {
+            return Ok(0);
+        };
+        if descs.len() < 2 || !status_desc.is_write_only() || status_desc.len() < 1 {
+            error!("Invalid block request");
+            return Ok(0);
+        }
+
+        let data = &descs[1..descs.len() - 1];
+        let mut header = [0u8; REQUEST_HEADER_SIZE];
+
+        let (status, written) =
+            if header_desc.is_write_only() || (header_desc.len() as usize) < REQUEST_HEADER_SIZE {
+                (VIRTIO_BLK_S_IOERR, 0)
+            } else {
+                self.mem
+                    .read_slice(&mut header, header_desc.addr())
+                    .map_err(Error::GuestMemory)?;
+                self.execute(&header, data)
+            };
+
+        self.mem
+            .write_slice(&[status as u8], status_desc.addr())
+            .map_err(Error::GuestMemory)?;
+
+        Ok(written + 1)
+    }
+
+    fn process_queue(&mut self) -> result::Result<(), Error> {
+        loop {
+            self.queue.disable_notification(self.mem.as_ref())?;
+
+            while let Some(chain) = self.queue.iter(self.mem.memory())?.next() {
+                let head_index = chain.head_index();
+                let written = self.process_request(chain)?;
+
+                self.queue
+                    .add_used(self.mem.as_ref(), head_index, written)?;
+
+                if self.queue.needs_notification(self.mem.as_ref())? {
+                    self.driver_notify.signal_used_queue(0);
+                }
+            }
+
+            if !self.queue.enable_notification(self.mem.as_ref())? {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl<S> MutEventSubscriber for QueueHandler<S>
+where
+    S: SignalUsedQueue,
+{
+    fn process(&mut self, events: Events, ops: &mut EventOps) {
+        if events.event_set() != EventSet::IN || events.data() != IOEVENT_DATA {
+            error!("Unexpected block device event");
+            return;
+        }
+
+        if self.ioevent.read().is_err() {
+            error!("Block ioevent read");
+            ops.remove(Events::empty(&self.ioevent))
+                .expect("Failed to remove block ioevent");
+            return;
+        }
+
+        if let Err(e) = self.process_queue() {
+            error!("Process block queue error {:?}", e);
+        }
+    }
+
+    fn init(&mut self, ops: &mut EventOps) {
+        ops.add(Events::with_data(&self.ioevent, IOEVENT_DATA, EventSet::IN))
+            .expect("Unable to add block ioevent");
+    }
+}
@@ -1,5 +1,8 @@
+pub mod block;
 pub mod net;
+pub mod p9;
 mod register;
+pub mod vsock;
 
 use event_manager::{
     Error as EvmgrError, MutEventSubscriber, RemoteEndpoint, Result as EvmgrResult, SubscriberId,
@@ -14,7 +17,7 @@ use std::{
     },
 };
 use virtio_device::VirtioConfig;
-use virtio_queue::{Queue, QueueT};
+use virtio_queue::{Queue, QueueState, QueueT};
 use vm_device::bus::{self, MmioRange};
 use vmm_sys_util::{errno, eventfd::EventFd};
 
@@ -54,7 +57,10 @@ pub enum Error {
     RegisterMmioDevice(bus::Error),
     Conversion,
     Mutex,
+    Block,
     Net,
+    P9,
+    Vsock,
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -161,6 +167,75 @@ impl Config {
     }
 }
 
+/// State of a virtio device, saved in snapshots.
+pub struct VirtioState {
+    pub device_features: u64,
+    pub driver_features: u64,
+    pub device_features_select: u32,
+    pub driver_features_select: u32,
+    pub device_status: u8,
+    pub queue_select: u16,
+    pub config_generation: u8,
+    pub config_space: Vec<u8>,
+    pub interrupt_status: u8,
+    pub activated: bool,
+    pub queues: Vec<QueueState>,
+}
+
+impl Config {
+    /// Save the state of the device. Once the device is activated, its queues are owned by its
+    /// queue handler, which gives them in `queues`.
+    pub fn state(&self, queues: &[&Queue]) -> VirtioState {
+        let virtio = &self.virtio;
+        let queues = if virtio.device_activated {
+            queues.iter().map(|queue| queue.state()).collect()
+        } else {
+            virtio.queues.iter().map(|queue| queue.state()).collect()
+        };
+
+        VirtioState {
+            device_features: virtio.device_features,
+            driver_features: virtio.driver_features,
+            device_features_select: virtio.device_features_select,
+            driver_features_select: virtio.driver_features_select,
+            device_status: virtio.device_status,
+            queue_select: virtio.queue_select,
+            config_generation: virtio.config_generation,
+            config_space: virtio.config_space.clone(),
+            interrupt_status: virtio.interrupt_status.load(Ordering::SeqCst),
+            activated: virtio.device_activated,
+            queues,
+        }
+    }
+
+    /// Restore the state of a device which has not been activated yet. The device must then be
+    /// activated again if `state.activated` is set.
+    pub fn restore(&mut self, state: &VirtioState) -> Result<()> {
+        if self.virtio.device_activated {
+            return Err(Error::AlreadyActivated);
+        }
+
+        let virtio = &mut self.virtio;
+        virtio.driver_features = state.driver_features;
+        virtio.device_features_select = state.device_features_select;
+        virtio.driver_features_select = state.driver_features_select;
+        virtio.device_status = state.device_status;
+        virtio.queue_select = state.queue_select;
+        virtio.config_generation = state.config_generation;
+        virtio.config_space = state.config_space.clone();
+        virtio
+            .interrupt_status
+            .store(state.interrupt_status, Ordering::SeqCst);
+        virtio.queues = state
+            .queues
+            .iter()
+            .map(|queue| Queue::try_from(*queue).map_err(|_| Error::QueuesNotValid))
+            .collect::<Result<_>>()?;
+
+        Ok(())
+    }
+}
+
 /// Simple trait to model the operation of signalling the driver about used events
 /// for the specified queue.
 // TODO: Does this need renaming to be relevant for packed queues as well?
@@ -10,7 +10,7 @@ use crate::core::devices::virtio::net::tuntap::open_tap::open_tap;
 use crate::core::devices::virtio::net::BRIDGE_NAME;
 use crate::core::devices::virtio::register::register_mmio_device;
 use crate::core::devices::virtio::{
-    self, Config, MmioConfig, SingleFdSignalQueue, Subscriber, QUEUE_MAX_SIZE,
+    self, Config, MmioConfig, SingleFdSignalQueue, Subscriber, VirtioState, QUEUE_MAX_SIZE,
 };
 use event_manager::RemoteEndpoint;
 use kvm_ioctls::VmFd;
@@ -39,6 +39,7 @@ pub struct Net {
     pub config: Config,
     tap: Arc<Mutex<Tap>>,
     _bridge: Bridge,
+    handler: Option<Arc<Mutex<QueueHandler<SingleFdSignalQueue>>>>,
 }
 
 impl Net {
@@ -115,6 +116,7 @@ impl Net {
             config: cfg,
             tap: Arc::new(Mutex::new(tap.clone())),
             _bridge: bridge,
+            handler: None,
         }));
 
         let vmmio_param = register_mmio_device(mmio_cfg, device_mgr, irq, None, net.clone())
@@ -129,6 +131,25 @@ impl Net {
 
         Ok(net)
     }
+
+    pub fn state(&self) -> VirtioState {
+        match &self.handler {
+            Some(handler) => {
+                let handler = handler.lock().unwrap();
+                self.config.state(&[&handler.inner.rxq, &handler.inner.txq])
+            }
+            None => self.config.state(&[]),
+        }
+    }
+
+    /// Restore the state of a device created with the same configuration.
+    pub fn restore(&mut self, state: &VirtioState) -> Result<()> {
+        self.config.restore(state).map_err(Error::Virtio)?;
+        if state.activated {
+            self.activate()?;
+        }
+        Ok(())
+    }
 }
 
 impl VirtioDeviceType for Net {
@@ -169,6 +190,7 @@ impl VirtioDeviceActions for Net {
             rx_ioevent: ioevents.remove(0),
             tx_ioevent: ioevents.remove(0),
         }));
+        self.handler = Some(handler.clone());
 
         self.config
             .finalize_activate(handler)
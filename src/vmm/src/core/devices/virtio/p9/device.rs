@@ -0,0 +1,170 @@
+use super::queue_handler::QueueHandler;
+use super::server::{Server, ServerState};
+use super::{Error, Result, SharedDirConfig, P9_DEVICE_ID, VIRTIO_9P_MOUNT_TAG};
+use crate::core::devices::virtio::register::register_mmio_device;
+use crate::core::devices::virtio::{
+    self, Config, MmioConfig, SingleFdSignalQueue, Subscriber, VirtioState, QUEUE_MAX_SIZE,
+};
+use event_manager::RemoteEndpoint;
+use kvm_ioctls::VmFd;
+use std::{
+    borrow::{Borrow, BorrowMut},
+    sync::{Arc, Mutex},
+};
+use tracing::info;
+use virtio_bindings::virtio_config::VIRTIO_F_VERSION_1;
+use virtio_device::{VirtioConfig, VirtioDeviceActions, VirtioDeviceType, VirtioMmioDevice};
+use virtio_queue::Queue;
+use vm_device::device_manager::IoManager;
+use vm_device::{bus::MmioAddress, MutDeviceMmio};
+use vm_memory::GuestMemoryMmap;
+
+pub struct P9 {
+    mem: Arc<GuestMemoryMmap>,
+    pub config: Config,
+    // Moved to the queue handler once the device is activated.
+    server: Option<Server>,
+    handler: Option<Arc<Mutex<QueueHandler<SingleFdSignalQueue>>>>,
+}
+
+impl P9 {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        mem: Arc<GuestMemoryMmap>,
+        device_mgr: Arc<Mutex<IoManager>>,
+        mmio_cfg: MmioConfig,
+        shared_dir_cfg: &SharedDirConfig,
+        irq: u32,
+        endpoint: RemoteEndpoint<Subscriber>,
+        vm_fd: Arc<VmFd>,
+        cmdline_extra_parameters: &mut Vec<String>,
+    ) -> Result<Arc<Mutex<Self>>> {
+        let server = Server::new(&shared_dir_cfg.path).map_err(Error::SharedDir)?;
+        info!(
+            "sharing {} with the guest as {}",
+            shared_dir_cfg.path.display(),
+            shared_dir_cfg.tag
+        );
+
+        let device_features = 1 << VIRTIO_F_VERSION_1 | 1 << VIRTIO_9P_MOUNT_TAG;
+
+        // The configuration space holds the length of the tag, then the tag itself.
+        let tag = shared_dir_cfg.tag.as_bytes();
+        let tag_len =
+            u16::try_from(tag.len()).map_err(|_| Error::Virtio(virtio::Error::Conversion))?;
+        let mut config_space = tag_len.to_le_bytes().to_vec();
+        config_space.extend_from_slice(tag);
+
+        let queues =
+            vec![Queue::new(QUEUE_MAX_SIZE)
+                .map_err(|_| Error::Virtio(virtio::Error::QueuesNotValid))?];
+
+        let virtio_cfg = VirtioConfig::new(device_features, queues, config_space);
+
+        let cfg = Config::new(virtio_cfg, mmio_cfg, endpoint, vm_fd).map_err(Error::Virtio)?;
+
+        let p9 = Arc::new(Mutex::new(P9 {
+            mem,
+            config: cfg,
+            server: Some(server),
+            handler: None,
+        }));
+
+        let vmmio_param = register_mmio_device(mmio_cfg, device_mgr, irq, None, p9.clone())
+            .map_err(Error::Virtio)?;
+
+        cmdline_extra_parameters.push(vmmio_param);
+
+        Ok(p9)
+    }
+
+    /// Save the state of the device, and of the files opened by the guest.
+    pub fn state(&self) -> (VirtioState, ServerState) {
+        match (&self.handler, &self.server) {
+            (Some(handler), _) => {
+                let handler = handler.lock().unwrap();
+                (self.config.state(&[&handler.queue]), handler.server.state())
+            }
+            (None, Some(server)) => (self.config.state(&[]), server.state()),
+            (None, None) => unreachable!("the server is only moved to the handler"),
+        }
+    }
+
+    /// Restore the state of a device created with the same configuration.
+    pub fn restore(&mut self, state: &VirtioState, server_state: &ServerState) -> Result<()> {
+        self.config.restore(state).map_err(Error::Virtio)?;
+        if let Some(server) = &mut self.server {
+            server.restore(server_state);
+        }
+        if state.activated {
+            self.activate()?;
+        }
+        Ok(())
+    }
+}
+
+impl VirtioDeviceType for P9 {
+    fn device_type(&self) -> u32 {
+        P9_DEVICE_ID
+    }
+}
+
+impl Borrow<VirtioConfig<Queue>> for P9 {
+    fn borrow(&self) -> &VirtioConfig<Queue> {
+        &self.config.virtio
+    }
+}
+
+impl BorrowMut<VirtioConfig<Queue>> for P9 {
+    fn borrow_mut(&mut self) -> &mut VirtioConfig<Queue> {
+        &mut self.config.virtio
+    }
+}
+
+impl VirtioDeviceActions for P9 {
+    type E = Error;
+
+    fn activate(&mut self) -> Result<()> {
+        let driver_notify = SingleFdSignalQueue {
+            irqfd: self.config.irqfd.clone(),
+            interrupt_status: self.config.virtio.interrupt_status.clone(),
+        };
+
+        let mut ioevents = self.config.prepare_activate().map_err(Error::Virtio)?;
+
+        let server = self
+            .server
+            .take()
+            .ok_or(Error::Virtio(virtio::Error::AlreadyActivated))?;
+
+        let handler = Arc::new(Mutex::new(QueueHandler {
+            driver_notify,
+            queue: self.config.virtio.queues.remove(0),
+            mem: self.mem.clone(),
+            server,
+            ioevent: ioevents.remove(0),
+        }));
+        self.handler = Some(handler.clone());
+
+        self.config
+            .finalize_activate(handler)
+            .map_err(Error::Virtio)
+    }
+
+    fn reset(&mut self) -> std::result::Result<(), Error> {
+        // Not implemented for now.
+        Ok(())
+    }
+}
+
+impl VirtioMmioDevice for P9 {}
+
+impl MutDeviceMmio for P9 {
+    fn mmio_read(&mut self, _base: MmioAddress, offset: u64, data: &mut [u8]) {
+        self.read(offset, data);
+    }
+
+    fn mmio_write(&mut self, _base: MmioAddress, offset: u64, data: &[u8]) {
+        self.write(offset, data);
+    }
+}
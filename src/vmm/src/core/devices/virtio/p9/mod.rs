@@ -0,0 +1,56 @@
+//! virtio-9p device, sharing a host directory with the guest.
+//!
+//! The guest mounts the directory with
+//! `mount -t 9p -o trans=virtio,version=9p2000.L <tag> <mountpoint>`.
+
+pub mod device;
+mod protocol;
+mod queue_handler;
+mod server;
+
+pub use server::{FidState, ServerState};
+
+use crate::core::devices::virtio;
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+const P9_DEVICE_ID: u32 = 9;
+/// The configuration space holds the mount tag of the device.
+const VIRTIO_9P_MOUNT_TAG: u64 = 0;
+
+#[derive(Debug)]
+pub enum Error {
+    Virtio(virtio::Error),
+    /// The shared directory can't be used.
+    SharedDir(io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Configuration of a shared directory.
+#[derive(Clone, Debug)]
+pub struct SharedDirConfig {
+    /// Tag used by the guest to mount the directory.
+    pub tag: String,
+    /// Path of the directory on the host.
+    pub path: PathBuf,
+}
+
+impl FromStr for SharedDirConfig {
+    type Err = String;
+
+    /// Parse a `<tag>=<path>` string.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((tag, path)) if !tag.is_empty() && !path.is_empty() => Ok(SharedDirConfig {
+                tag: tag.to_string(),
+                path: PathBuf::from(path),
+            }),
+            _ => Err(format!(
+                "invalid shared directory {}, expected <tag>=<path>",
+                s
+            )),
+        }
+    }
+}
@@ -0,0 +1,264 @@
+// Encoding of the 9P2000.L messages, see
+// https://github.com/chaos/diod/blob/master/protocol.md for the layout of each message.
+
+use std::fs::Metadata;
+use std::io::{self, ErrorKind};
+use std::os::unix::fs::MetadataExt;
+
+/// Size of the header of a message: size[4] type[1] tag[2].
+pub const HEADER_SIZE: usize = 7;
+
+pub const RLERROR: u8 = 7;
+pub const TSTATFS: u8 = 8;
+pub const TLOPEN: u8 = 12;
+pub const TLCREATE: u8 = 14;
+pub const TSYMLINK: u8 = 16;
+pub const TMKNOD: u8 = 18;
+pub const TRENAME: u8 = 20;
+pub const TREADLINK: u8 = 22;
+pub const TGETATTR: u8 = 24;
+pub const TSETATTR: u8 = 26;
+pub const TXATTRWALK: u8 = 30;
+pub const TXATTRCREATE: u8 = 32;
+pub const TREADDIR: u8 = 40;
+pub const TFSYNC: u8 = 50;
+pub const TLOCK: u8 = 52;
+pub const TGETLOCK: u8 = 54;
+pub const TLINK: u8 = 70;
+pub const TMKDIR: u8 = 72;
+pub const TRENAMEAT: u8 = 74;
+pub const TUNLINKAT: u8 = 76;
+pub const TVERSION: u8 = 100;
+pub const TAUTH: u8 = 102;
+pub const TATTACH: u8 = 104;
+pub const TFLUSH: u8 = 108;
+pub const TWALK: u8 = 110;
+pub const TREAD: u8 = 116;
+pub const TWRITE: u8 = 118;
+pub const TCLUNK: u8 = 120;
+pub const TREMOVE: u8 = 122;
+
+pub const QTDIR: u8 = 0x80;
+pub const QTSYMLINK: u8 = 0x02;
+pub const QTFILE: u8 = 0x00;
+
+/// Fields of `Tgetattr` filled by the server: mode, nlink, uid, gid, rdev, times, ino, size
+/// and blocks.
+pub const GETATTR_BASIC: u64 = 0x7ff;
+
+pub const SETATTR_MODE: u32 = 0x1;
+pub const SETATTR_UID: u32 = 0x2;
+pub const SETATTR_GID: u32 = 0x4;
+pub const SETATTR_SIZE: u32 = 0x8;
+pub const SETATTR_ATIME: u32 = 0x10;
+pub const SETATTR_MTIME: u32 = 0x20;
+pub const SETATTR_ATIME_SET: u32 = 0x80;
+pub const SETATTR_MTIME_SET: u32 = 0x100;
+
+/// Unique identifier of a file on the server.
+#[derive(Clone, Copy, Debug)]
+pub struct Qid {
+    pub type_: u8,
+    pub version: u32,
+    pub path: u64,
+}
+
+pub const QID_SIZE: usize = 13;
+
+impl From<&Metadata> for Qid {
+    fn from(metadata: &Metadata) -> Self {
+        let file_type = metadata.file_type();
+        let type_ = if file_type.is_dir() {
+            QTDIR
+        } else if file_type.is_symlink() {
+            QTSYMLINK
+        } else {
+            QTFILE
+        };
+
+        Qid {
+            type_,
+            version: 0,
+            path: metadata.ino(),
+        }
+    }
+}
+
+/// Reads the fields of a request.
+pub struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Reader { buf }
+    }
+
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.buf.len() < len {
+            return Err(io::Error::from(ErrorKind::InvalidInput));
+        }
+        let (head, tail) = self.buf.split_at(len);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    pub fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> io::Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn str(&mut self) -> io::Result<String> {
+        let len = self.u16()? as usize;
+        String::from_utf8(self.take(len)?.to_vec())
+            .map_err(|_| io::Error::from(ErrorKind::InvalidInput))
+    }
+
+    pub fn bytes(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        self.take(len)
+    }
+}
+
+/// Builds a reply, the header is filled by [`Writer::finish`].
+pub struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    pub fn new(type_: u8, tag: u16) -> Self {
+        let mut buf = vec![0; HEADER_SIZE];
+        buf[4] = type_;
+        buf[5..7].copy_from_slice(&tag.to_le_bytes());
+        Writer { buf }
+    }
+
+    /// Size of the reply so far, header included.
+    pub fn size(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn u8(&mut self, value: u8) -> &mut Self {
+        self.buf.push(value);
+        self
+    }
+
+    pub fn u16(&mut self, value: u16) -> &mut Self {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    pub fn u32(&mut self, value: u32) -> &mut Self {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    pub fn u64(&mut self, value: u64) -> &mut Self {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    pub fn str(&mut self, value: &str) -> &mut Self {
+        self.u16(value.len() as u16);
+        self.buf.extend_from_slice(value.as_bytes());
+        self
+    }
+
+    pub fn bytes(&mut self, value: &[u8]) -> &mut Self {
+        self.buf.extend_from_slice(value);
+        self
+    }
+
+    pub fn qid(&mut self, qid: Qid) -> &mut Self {
+        self.u8(qid.type_).u32(qid.version).u64(qid.path)
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        let size = self.buf.len() as u32;
+        self.buf[0..4].copy_from_slice(&size.to_le_bytes());
+        self.buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_then_read() {
+        let qid = Qid {
+            type_: QTDIR,
+            version: 7,
+            path: 0x1234_5678_9abc,
+        };
+        let mut writer = Writer::new(TWALK, 0xbeef);
+        writer
+            .u8(1)
+            .u16(2)
+            .u32(3)
+            .u64(4)
+            .str("name")
+            .qid(qid)
+            .bytes(b"data");
+        assert_eq!(
+            writer.size(),
+            HEADER_SIZE + 1 + 2 + 4 + 8 + 2 + 4 + QID_SIZE + 4
+        );
+        let message = writer.finish();
+
+        let mut reader = Reader::new(&message);
+        assert_eq!(reader.u32().unwrap() as usize, message.len());
+        assert_eq!(reader.u8().unwrap(), TWALK);
+        assert_eq!(reader.u16().unwrap(), 0xbeef);
+        assert_eq!(reader.u8().unwrap(), 1);
+        assert_eq!(reader.u16().unwrap(), 2);
+        assert_eq!(reader.u32().unwrap(), 3);
+        assert_eq!(reader.u64().unwrap(), 4);
+        assert_eq!(reader.str().unwrap(), "name");
+        assert_eq!(reader.u8().unwrap(), QTDIR);
+        assert_eq!(reader.u32().unwrap(), 7);
+        assert_eq!(reader.u64().unwrap(), 0x1234_5678_9abc);
+        assert_eq!(reader.bytes(4).unwrap(), b"data");
+        assert!(reader.u8().is_err());
+    }
+
+    #[test]
+    fn little_endian() {
+        let mut writer = Writer::new(RLERROR, 1);
+        writer.u32(0x0102_0304).str("ab");
+
+        assert_eq!(
+            writer.finish(),
+            [15, 0, 0, 0, RLERROR, 1, 0, 4, 3, 2, 1, 2, 0, b'a', b'b']
+        );
+    }
+
+    #[test]
+    fn read_past_end() {
+        let mut reader = Reader::new(&[1, 2, 3]);
+        assert!(reader.u32().is_err());
+        // A failed read doesn't consume anything.
+        assert_eq!(reader.u16().unwrap(), 0x0201);
+        assert!(reader.bytes(2).is_err());
+    }
+
+    #[test]
+    fn read_truncated_string() {
+        // The string announces 5 bytes, only 3 follow.
+        let mut reader = Reader::new(&[5, 0, b'a', b'b', b'c']);
+        assert!(reader.str().is_err());
+
+        let mut reader = Reader::new(&[2, 0, 0xff, 0xfe]);
+        assert!(reader.str().is_err());
+    }
+}
@@ -0,0 +1,143 @@
+use super::server::Server;
+use crate::core::devices::virtio::SignalUsedQueue;
+use event_manager::{EventOps, Events, MutEventSubscriber};
+use log::error;
+use std::result;
+use std::sync::Arc;
+use virtio_queue::{Descriptor, DescriptorChain, Queue, QueueOwnedT, QueueT};
+use vm_memory::{Bytes, GuestAddressSpace, GuestMemoryMmap};
+use vmm_sys_util::epoll::EventSet;
+use vmm_sys_util::eventfd::EventFd;
+
+const IOEVENT_DATA: u32 = 0;
+
+#[derive(Debug)]
+pub enum Error {
+    GuestMemory(vm_memory::GuestMemoryError),
+    Queue(virtio_queue::Error),
+}
+
+impl From<virtio_queue::Error> for Error {
+    fn from(e: virtio_queue::Error) -> Self {
+        Error::Queue(e)
+    }
+}
+
+pub struct QueueHandler<S>
+where
+    S: SignalUsedQueue,
+{
+    pub driver_notify: S,
+    pub queue: Queue,
+    pub mem: Arc<GuestMemoryMmap>,
+    pub server: Server,
+    pub ioevent: EventFd,
+}
+
+impl<S> QueueHandler<S>
+where
+    S: SignalUsedQueue,
+{
+    /// Handle a request: the readable descriptors hold the request, the reply is written to
+    /// the writable ones. Returns the size of the reply.
+    fn process_request(
+        &mut self,
+        chain: DescriptorChain<Arc<GuestMemoryMmap>>,
+    ) -> result::Result<u32, Error> {
+        let (writable, readable): (Vec<Descriptor>, Vec<Descriptor>) =
+            chain.partition(|desc| desc.is_write_only());
+
+        // The client can't send requests larger than the negotiated size, only read that much.
+        let msize = self.server.msize() as usize;
+        let mut request = Vec::new();
+        let mut oversized = false;
+        for desc in readable {
+            let start = request.len();
+            let len = (desc.len() as usize).min(msize - start);
+            oversized = len < desc.len() as usize;
+
+            request.resize(start + len, 0);
+            self.mem
+                .read_slice(&mut request[start..], desc.addr())
+                .map_err(Error::GuestMemory)?;
+
+            if oversized {
+                break;
+            }
+        }
+
+        let reply = if oversized {
+            Server::reject(&request, libc::EMSGSIZE)
+        } else {
+            self.server.handle(&request)
+        };
+
+        let mut written = 0;
+        for desc in writable {
+            if written == reply.len() {
+                break;
+            }
+            let len = (desc.len() as usize).min(reply.len() - written);
+            self.mem
+                .write_slice(&reply[written..written + len], desc.addr())
+                .map_err(Error::GuestMemory)?;
+            written += len;
+        }
+
+        if written < reply.len() {
+            error!("9p reply truncated to {} bytes", written);
+        }
+
+        Ok(written as u32)
+    }
+
+    fn process_queue(&mut self) -> result::Result<(), Error> {
+        loop {
+            self.queue.disable_notification(self.mem.as_ref())?;
+
+            while let Some(chain) = self.queue.iter(self.mem.memory())?.next() {
+                let head_index = chain.head_index();
+                let written = self.process_request(chain)?;
+
+                self.queue
+                    .add_used(self.mem.as_ref(), head_index, written)?;
+
+                if self.queue.needs_notification(self.mem.as_ref())? {
+                    self.driver_notify.signal_used_queue(0);
+                }
+            }
+
+            if !self.queue.enable_notification(self.mem.as_ref())? {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl<S> MutEventSubscriber for QueueHandler<S>
+where
+    S: SignalUsedQueue,
+{
+    fn process(&mut self, events: Events, ops: &mut EventOps) {
+        if events.event_set() != EventSet::IN || events.data() != IOEVENT_DATA {
+            error!("Unexpected 9p device event");
+            return;
+        }
+
+        if self.ioevent.read().is_err() {
+            error!("9p ioevent read");
+            ops.remove(Events::empty(&self.ioevent))
+                .expect("Failed to remove 9p ioevent");
+            return;
+        }
+
+        if let Err(e) = self.process_queue() {
+            error!("Process 9p queue error {:?}", e);
+        }
+    }
+
+    fn init(&mut self, ops: &mut EventOps) {
+        ops.add(Events::with_data(&self.ioevent, IOEVENT_DATA, EventSet::IN))
+            .expect("Unable to add 9p ioevent");
+    }
+}
@@ -0,0 +1,1027 @@
+use super::protocol::*;
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileExt, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+/// Largest message size accepted by the server.
+const MAX_MSIZE: u32 = 512 << 10;
+/// Smallest message size accepted by the server, large enough for the header of any reply.
+const MIN_MSIZE: u32 = 4096;
+/// Size of the header of `Rread` and `Rreaddir`: the message header and a count[4].
+const RREAD_HEADER_SIZE: u32 = HEADER_SIZE as u32 + 4;
+/// Magic number of 9p filesystems, returned by `Tstatfs`.
+const V9FS_MAGIC: u32 = 0x0102_1997;
+/// Flags of the descriptors kept for the fids, which only reference a file.
+const PATH_FLAGS: i32 = libc::O_PATH | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+/// Empty C string, to operate on the file of a descriptor with the `*at` calls.
+const EMPTY_PATH: &[u8] = b"\0";
+
+/// Entry of a directory listing, cached between the `Treaddir` requests.
+struct DirEntry {
+    name: String,
+    qid: Qid,
+    type_: u8,
+}
+
+/// A file referenced by the client.
+struct Fid {
+    /// Path of the file, relative to the exported directory.
+    path: PathBuf,
+    /// `O_PATH` descriptor of the file, every request on the fid goes through it.
+    handle: File,
+    /// Set once a regular file is opened.
+    file: Option<File>,
+    /// Flags the file was opened with, to open it again once restored.
+    open_flags: Option<i32>,
+    /// Set once a directory is read.
+    entries: Option<Vec<DirEntry>>,
+}
+
+impl Fid {
+    fn new(path: PathBuf, handle: File) -> Self {
+        Fid {
+            path,
+            handle,
+            file: None,
+            open_flags: None,
+            entries: None,
+        }
+    }
+
+    fn file(&self) -> io::Result<&File> {
+        self.file.as_ref().ok_or_else(|| errno(libc::EBADF))
+    }
+
+    fn fd(&self) -> RawFd {
+        self.handle.as_raw_fd()
+    }
+}
+
+fn errno(code: i32) -> io::Error {
+    io::Error::from_raw_os_error(code)
+}
+
+/// Convert the return value of a libc call to a result.
+fn check<T: Default + PartialOrd>(ret: T) -> io::Result<T> {
+    if ret < T::default() {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+fn dirent_type(metadata: &Metadata) -> u8 {
+    let file_type = metadata.file_type();
+    if file_type.is_dir() {
+        libc::DT_DIR
+    } else if file_type.is_symlink() {
+        libc::DT_LNK
+    } else if file_type.is_file() {
+        libc::DT_REG
+    } else {
+        libc::DT_UNKNOWN
+    }
+}
+
+fn cstring(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes()).map_err(|_| errno(libc::EINVAL))
+}
+
+/// Check that `name` is an entry of a directory, and not a path.
+fn entry_name(name: &str) -> io::Result<CString> {
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+        return Err(errno(libc::EINVAL));
+    }
+    cstring(Path::new(name))
+}
+
+/// Open `path` from the directory `dir` with `openat2`. The resolution fails if it leaves `dir`
+/// or goes through a symbolic link, a trailing link is opened with `O_PATH | O_NOFOLLOW`.
+fn open_beneath(dir: &File, path: &Path, flags: i32, mode: u32) -> io::Result<File> {
+    let path = cstring(path)?;
+
+    // SAFETY: `open_how` is a plain C struct, for which zero is a valid value.
+    let mut how: libc::open_how = unsafe { std::mem::zeroed() };
+    how.flags = flags as u64;
+    how.mode = mode as u64;
+    how.resolve = libc::RESOLVE_BENEATH | libc::RESOLVE_NO_SYMLINKS;
+
+    // SAFETY: `path` is a valid C string and `how` is valid for the size passed.
+    let fd = check(unsafe {
+        libc::syscall(
+            libc::SYS_openat2,
+            dir.as_raw_fd(),
+            path.as_ptr(),
+            &how as *const libc::open_how,
+            std::mem::size_of::<libc::open_how>(),
+        )
+    })?;
+
+    // SAFETY: the descriptor was just opened, and is owned by nobody else.
+    Ok(unsafe { File::from_raw_fd(fd as RawFd) })
+}
+
+/// Path of the file referenced by `handle` in `/proc`, for the calls that can't operate on an
+/// `O_PATH` descriptor. The path always leads to that file, whatever happens to its parents.
+fn proc_path(handle: &File) -> PathBuf {
+    PathBuf::from(format!("/proc/self/fd/{}", handle.as_raw_fd()))
+}
+
+/// Flags to open a file with the Linux `flags` of the client: only the access mode, `O_TRUNC`
+/// and `O_APPEND` are kept.
+fn open_flags(flags: i32) -> i32 {
+    flags & (libc::O_ACCMODE | libc::O_TRUNC | libc::O_APPEND) | libc::O_CLOEXEC
+}
+
+/// Open the file referenced by `handle` with the Linux `flags` of the client.
+fn reopen(handle: &File, flags: i32) -> io::Result<File> {
+    // Opening the link in `/proc` would follow a symbolic link.
+    if handle.metadata()?.is_symlink() {
+        return Err(errno(libc::ELOOP));
+    }
+
+    let path = cstring(&proc_path(handle))?;
+    // SAFETY: `path` is a valid C string.
+    let fd = check(unsafe { libc::open(path.as_ptr(), open_flags(flags)) })?;
+
+    // SAFETY: the descriptor was just opened, and is owned by nobody else.
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// A fid saved in a snapshot.
+pub struct FidState {
+    pub fid: u32,
+    /// Path of the file, relative to the exported directory.
+    pub path: PathBuf,
+    pub open_flags: Option<i32>,
+}
+
+/// State of the server, saved in snapshots so the guest keeps its fids once restored.
+pub struct ServerState {
+    pub msize: u32,
+    pub fids: Vec<FidState>,
+}
+
+/// 9P2000.L server exporting a host directory.
+///
+/// Each fid holds a descriptor of its file, and the files are only looked up from the
+/// descriptor of their directory with `RESOLVE_BENEATH | RESOLVE_NO_SYMLINKS`: the client can't
+/// leave the exported directory, neither with `..`, resolved by the server, nor with symbolic
+/// links, which are never followed on the host.
+pub struct Server {
+    root: File,
+    msize: u32,
+    fids: HashMap<u32, Fid>,
+}
+
+impl Server {
+    pub fn new(root: &Path) -> io::Result<Self> {
+        let root = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_PATH | libc::O_DIRECTORY | libc::O_CLOEXEC)
+            .open(root)?;
+
+        Ok(Server {
+            root,
+            msize: MAX_MSIZE,
+            fids: HashMap::new(),
+        })
+    }
+
+    /// Size negotiated with the client, the largest request or reply it can send or receive.
+    pub fn msize(&self) -> u32 {
+        self.msize
+    }
+
+    pub fn state(&self) -> ServerState {
+        ServerState {
+            msize: self.msize,
+            fids: self
+                .fids
+                .iter()
+                .map(|(&fid, state)| FidState {
+                    fid,
+                    path: state.path.clone(),
+                    open_flags: state.open_flags,
+                })
+                .collect(),
+        }
+    }
+
+    /// Restore the fids of a saved server. The files are opened again, without truncating
+    /// them: the fids of the files that can't be found anymore are dropped, and the files that
+    /// can't be opened anymore fail the next requests of the guest.
+    pub fn restore(&mut self, state: &ServerState) {
+        self.msize = state.msize.clamp(MIN_MSIZE, MAX_MSIZE);
+        self.fids = state
+            .fids
+            .iter()
+            .filter_map(|saved| {
+                let handle = self.open(&saved.path).ok()?;
+                let mut fid = Fid::new(saved.path.clone(), handle);
+                if let Some(flags) = saved.open_flags {
+                    fid.file = reopen(&fid.handle, flags & !libc::O_TRUNC).ok();
+                    fid.open_flags = Some(flags);
+                }
+                Some((saved.fid, fid))
+            })
+            .collect();
+    }
+
+    /// Handle a request and return the reply.
+    pub fn handle(&mut self, request: &[u8]) -> Vec<u8> {
+        let mut reader = Reader::new(request);
+        let (Ok(_size), Ok(type_), Ok(tag)) = (reader.u32(), reader.u8(), reader.u16()) else {
+            return Vec::new();
+        };
+
+        match self.dispatch(type_, tag, &mut reader) {
+            Ok(reply) => reply.finish(),
+            Err(e) => error_reply(tag, e),
+        }
+    }
+
+    /// Fail a request without handling it, with the error `code`. Only the header of the
+    /// request is read.
+    pub fn reject(request: &[u8], code: i32) -> Vec<u8> {
+        let mut reader = Reader::new(request);
+        let (Ok(_size), Ok(_type), Ok(tag)) = (reader.u32(), reader.u8(), reader.u16()) else {
+            return Vec::new();
+        };
+
+        error_reply(tag, errno(code))
+    }
+
+    fn fid(&self, fid: u32) -> io::Result<&Fid> {
+        self.fids.get(&fid).ok_or_else(|| errno(libc::EBADF))
+    }
+
+    fn fid_mut(&mut self, fid: u32) -> io::Result<&mut Fid> {
+        self.fids.get_mut(&fid).ok_or_else(|| errno(libc::EBADF))
+    }
+
+    /// Open an `O_PATH` descriptor of `path`, relative to the exported directory.
+    fn open(&self, path: &Path) -> io::Result<File> {
+        let path = if path.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            path
+        };
+        open_beneath(&self.root, path, PATH_FLAGS, 0)
+    }
+
+    /// Open the parent directory of `path`, the exported directory being its own parent.
+    fn open_parent(&self, path: &Path) -> io::Result<File> {
+        self.open(path.parent().unwrap_or(path))
+    }
+
+    /// Resolve `name` from the directory `dir` at `path`, without going above the root.
+    fn walk_one(&self, dir: &File, path: &Path, name: &str) -> io::Result<(PathBuf, File)> {
+        match name {
+            "." => Ok((path.to_path_buf(), dir.try_clone()?)),
+            ".." => Ok((
+                path.parent().unwrap_or(path).to_path_buf(),
+                self.open_parent(path)?,
+            )),
+            _ if name.is_empty() || name.contains('/') => Err(errno(libc::EINVAL)),
+            _ => Ok((
+                path.join(name),
+                open_beneath(dir, Path::new(name), PATH_FLAGS, 0)?,
+            )),
+        }
+    }
+
+    /// Update the fids under `from` once it has been renamed to `to`.
+    fn rebase_fids(&mut self, from: &Path, to: &Path) {
+        for fid in self.fids.values_mut() {
+            if let Ok(suffix) = fid.path.strip_prefix(from) {
+                fid.path = to.join(suffix);
+            }
+        }
+    }
+
+    /// Remove the entry `name` of the directory `dir`.
+    fn unlink(dir: &File, name: &CStr, flags: i32) -> io::Result<()> {
+        // SAFETY: `name` is a valid C string.
+        check(unsafe { libc::unlinkat(dir.as_raw_fd(), name.as_ptr(), flags) })?;
+        Ok(())
+    }
+
+    fn dispatch(&mut self, type_: u8, tag: u16, r: &mut Reader) -> io::Result<Writer> {
+        let mut reply = Writer::new(type_ + 1, tag);
+
+        match type_ {
+            TVERSION => {
+                let msize = r.u32()?;
+                let version = r.str()?;
+
+                if msize < MIN_MSIZE {
+                    return Err(errno(libc::EINVAL));
+                }
+
+                // A new session starts, forget the fids of the previous one.
+                self.fids.clear();
+                self.msize = msize.min(MAX_MSIZE);
+
+                let version = if version == "9P2000.L" {
+                    version.as_str()
+                } else {
+                    "unknown"
+                };
+                reply.u32(self.msize).str(version);
+            }
+            TAUTH => return Err(errno(libc::EOPNOTSUPP)),
+            TATTACH => {
+                let fid = r.u32()?;
+                let handle = self.root.try_clone()?;
+                let metadata = handle.metadata()?;
+
+                self.fids.insert(fid, Fid::new(PathBuf::new(), handle));
+                reply.qid(Qid::from(&metadata));
+            }
+            // Requests are handled synchronously, there is nothing left to cancel.
+            TFLUSH => {}
+            TWALK => {
+                let fid = r.u32()?;
+                let newfid = r.u32()?;
+                let nwname = r.u16()?;
+                let names = (0..nwname)
+                    .map(|_| r.str())
+                    .collect::<io::Result<Vec<_>>>()?;
+
+                if newfid != fid && self.fids.contains_key(&newfid) {
+                    return Err(errno(libc::EBADF));
+                }
+
+                let start = self.fid(fid)?;
+                let mut path = start.path.clone();
+                let mut handle = start.handle.try_clone()?;
+                let mut qids = Vec::new();
+                for name in names.iter() {
+                    let walked = handle.metadata().and_then(|metadata| {
+                        if !metadata.is_dir() {
+                            return Err(errno(libc::ENOTDIR));
+                        }
+                        let (next_path, next) = self.walk_one(&handle, &path, name)?;
+                        let metadata = next.metadata()?;
+                        Ok((next_path, next, metadata))
+                    });
+
+                    match walked {
+                        Ok((next_path, next, metadata)) => {
+                            path = next_path;
+                            handle = next;
+                            qids.push(Qid::from(&metadata));
+                        }
+                        // Only the failure of the first element is an error.
+                        Err(e) if qids.is_empty() => return Err(e),
+                        Err(_) => break,
+                    }
+                }
+
+                if qids.len() == names.len() {
+                    self.fids.insert(newfid, Fid::new(path, handle));
+                }
+
+                reply.u16(qids.len() as u16);
+                for qid in qids {
+                    reply.qid(qid);
+                }
+            }
+            TLOPEN => {
+                let fid = r.u32()?;
+                let flags = r.u32()? as i32;
+
+                let fid = self.fid_mut(fid)?;
+                let metadata = fid.handle.metadata()?;
+
+                if metadata.is_dir() {
+                    fid.entries = None;
+                } else {
+                    fid.file = Some(reopen(&fid.handle, flags)?);
+                    fid.open_flags = Some(flags);
+                }
+
+                reply.qid(Qid::from(&metadata)).u32(0);
+            }
+            TLCREATE => {
+                let fid = r.u32()?;
+                let name = r.str()?;
+                let flags = r.u32()? as i32;
+                let mode = r.u32()?;
+
+                let dir = self.fid(fid)?;
+                entry_name(&name)?;
+                let file = open_beneath(
+                    &dir.handle,
+                    Path::new(&name),
+                    open_flags(flags) | libc::O_CREAT | libc::O_EXCL,
+                    mode & 0o7777,
+                )?;
+                let handle = open_beneath(&dir.handle, Path::new(&name), PATH_FLAGS, 0)?;
+                let path = dir.path.join(&name);
+                let metadata = file.metadata()?;
+
+                // The fid now references the new file.
+                let fid = self.fid_mut(fid)?;
+                fid.path = path;
+                fid.handle = handle;
+                fid.file = Some(file);
+                fid.open_flags = Some(flags);
+
+                reply.qid(Qid::from(&metadata)).u32(0);
+            }
+            TSYMLINK => {
+                let fid = r.u32()?;
+                let name = r.str()?;
+                let target = r.str()?;
+
+                let dir = self.fid(fid)?;
+                let c_name = entry_name(&name)?;
+                let target = CString::new(target).map_err(|_| errno(libc::EINVAL))?;
+                // SAFETY: `target` and `c_name` are valid C strings.
+                check(unsafe { libc::symlinkat(target.as_ptr(), dir.fd(), c_name.as_ptr()) })?;
+
+                let link = open_beneath(&dir.handle, Path::new(&name), PATH_FLAGS, 0)?;
+                reply.qid(Qid::from(&link.metadata()?));
+            }
+            TMKNOD => return Err(errno(libc::EOPNOTSUPP)),
+            TRENAME => {
+                let fid = r.u32()?;
+                let dfid = r.u32()?;
+                let name = r.str()?;
+
+                let from = self.fid(fid)?.path.clone();
+                // The exported directory can't be renamed.
+                let from_name = from.file_name().ok_or_else(|| errno(libc::EBUSY))?;
+                let from_dir = self.open_parent(&from)?;
+                let to_dir = self.fid(dfid)?;
+                let to = to_dir.path.join(&name);
+
+                let from_name = cstring(Path::new(from_name))?;
+                let to_name = entry_name(&name)?;
+                // SAFETY: `from_name` and `to_name` are valid C strings.
+                check(unsafe {
+                    libc::renameat(
+                        from_dir.as_raw_fd(),
+                        from_name.as_ptr(),
+                        to_dir.fd(),
+                        to_name.as_ptr(),
+                    )
+                })?;
+
+                self.rebase_fids(&from, &to);
+            }
+            TREADLINK => {
+                let fid = r.u32()?;
+                let fid = self.fid(fid)?;
+
+                let mut target = vec![0u8; libc::PATH_MAX as usize];
+                // SAFETY: the path is an empty C string and `target` is valid for its length.
+                let len = check(unsafe {
+                    libc::readlinkat(
+                        fid.fd(),
+                        EMPTY_PATH.as_ptr().cast(),
+                        target.as_mut_ptr().cast(),
+                        target.len(),
+                    )
+                })?;
+
+                reply.str(&String::from_utf8_lossy(&target[..len as usize]));
+            }
+            TGETATTR => {
+                let fid = r.u32()?;
+                let metadata = self.fid(fid)?.handle.metadata()?;
+
+                reply
+                    .u64(GETATTR_BASIC)
+                    .qid(Qid::from(&metadata))
+                    .u32(metadata.mode())
+                    .u32(metadata.uid())
+                    .u32(metadata.gid())
+                    .u64(metadata.nlink())
+                    .u64(metadata.rdev())
+                    .u64(metadata.size())
+                    .u64(metadata.blksize())
+                    .u64(metadata.blocks())
+                    .u64(metadata.atime() as u64)
+                    .u64(metadata.atime_nsec() as u64)
+                    .u64(metadata.mtime() as u64)
+                    .u64(metadata.mtime_nsec() as u64)
+                    .u64(metadata.ctime() as u64)
+                    .u64(metadata.ctime_nsec() as u64)
+                    // Birth time, generation and data version are not reported.
+                    .u64(0)
+                    .u64(0)
+                    .u64(0)
+                    .u64(0);
+            }
+            TSETATTR => {
+                let fid = r.u32()?;
+                let valid = r.u32()?;
+                let mode = r.u32()?;
+                let uid = r.u32()?;
+                let gid = r.u32()?;
+                let size = r.u64()?;
+                let atime = (r.u64()?, r.u64()?);
+                let mtime = (r.u64()?, r.u64()?);
+
+                let handle = &self.fid(fid)?.handle;
+                setattr(handle, valid, mode, uid, gid, size, atime, mtime)?;
+            }
+            TXATTRWALK | TXATTRCREATE => return Err(errno(libc::EOPNOTSUPP)),
+            TREADDIR => {
+                let fid = r.u32()?;
+                let offset = r.u64()?;
+                let count = r.u32()?.min(self.msize - RREAD_HEADER_SIZE) as usize;
+
+                if offset == 0 || self.fid(fid)?.entries.is_none() {
+                    let dir = self.fid(fid)?;
+                    let entries = read_dir(&dir.handle, &self.open_parent(&dir.path)?)?;
+                    self.fid_mut(fid)?.entries = Some(entries);
+                }
+                let fid = self.fid(fid)?;
+
+                let mut data = Writer::new(0, 0);
+                for (index, entry) in fid
+                    .entries
+                    .iter()
+                    .flatten()
+                    .enumerate()
+                    .skip(offset as usize)
+                {
+                    let size = QID_SIZE + 8 + 1 + 2 + entry.name.len();
+                    if data.size() - HEADER_SIZE + size > count {
+                        break;
+                    }
+                    data.qid(entry.qid)
+                        .u64(index as u64 + 1)
+                        .u8(entry.type_)
+                        .str(&entry.name);
+                }
+
+                let data = data.finish();
+                reply
+                    .u32((data.len() - HEADER_SIZE) as u32)
+                    .bytes(&data[HEADER_SIZE..]);
+            }
+            TFSYNC => {
+                let fid = r.u32()?;
+                if let Some(file) = &self.fid(fid)?.file {
+                    file.sync_all()?;
+                }
+            }
+            // Locks are only taken by the guest, which is the single user of the directory.
+            TLOCK => {
+                reply.u8(0);
+            }
+            TGETLOCK => {
+                let _fid = r.u32()?;
+                let _type = r.u8()?;
+                let start = r.u64()?;
+                let length = r.u64()?;
+                let proc_id = r.u32()?;
+                let client_id = r.str()?;
+
+                reply
+                    .u8(libc::F_UNLCK as u8)
+                    .u64(start)
+                    .u64(length)
+                    .u32(proc_id)
+                    .str(&client_id);
+            }
+            TLINK => {
+                let dfid = r.u32()?;
+                let fid = r.u32()?;
+                let name = r.str()?;
+
+                let target = cstring(&proc_path(&self.fid(fid)?.handle))?;
+                let dir = self.fid(dfid)?;
+                let name = entry_name(&name)?;
+                // SAFETY: `target` and `name` are valid C strings.
+                check(unsafe {
+                    libc::linkat(
+                        libc::AT_FDCWD,
+                        target.as_ptr(),
+                        dir.fd(),
+                        name.as_ptr(),
+                        libc::AT_SYMLINK_FOLLOW,
+                    )
+                })?;
+            }
+            TMKDIR => {
+                let dfid = r.u32()?;
+                let name = r.str()?;
+                let mode = r.u32()?;
+
+                let dir = self.fid(dfid)?;
+                let c_name = entry_name(&name)?;
+                // SAFETY: `c_name` is a valid C string.
+                check(unsafe { libc::mkdirat(dir.fd(), c_name.as_ptr(), mode & 0o7777) })?;
+
+                let created = open_beneath(&dir.handle, Path::new(&name), PATH_FLAGS, 0)?;
+                reply.qid(Qid::from(&created.metadata()?));
+            }
+            TRENAMEAT => {
+                let old_dfid = r.u32()?;
+                let old_name = r.str()?;
+                let new_dfid = r.u32()?;
+                let new_name = r.str()?;
+
+                let old_dir = self.fid(old_dfid)?;
+                let new_dir = self.fid(new_dfid)?;
+                let from = old_dir.path.join(&old_name);
+                let to = new_dir.path.join(&new_name);
+
+                let old_name = entry_name(&old_name)?;
+                let new_name = entry_name(&new_name)?;
+                // SAFETY: `old_name` and `new_name` are valid C strings.
+                check(unsafe {
+                    libc::renameat(
+                        old_dir.fd(),
+                        old_name.as_ptr(),
+                        new_dir.fd(),
+                        new_name.as_ptr(),
+                    )
+                })?;
+
+                self.rebase_fids(&from, &to);
+            }
+            TUNLINKAT => {
+                let dfid = r.u32()?;
+                let name = r.str()?;
+                let flags = r.u32()? as i32;
+
+                let name = entry_name(&name)?;
+                Self::unlink(&self.fid(dfid)?.handle, &name, flags & libc::AT_REMOVEDIR)?;
+            }
+            TSTATFS => {
+                let fid = r.u32()?;
+                let fid = self.fid(fid)?;
+
+                // SAFETY: `stat` is a valid output buffer.
+                let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+                check(unsafe { libc::fstatvfs(fid.fd(), &mut stat) })?;
+
+                reply
+                    .u32(V9FS_MAGIC)
+                    .u32(stat.f_bsize as u32)
+                    .u64(stat.f_blocks)
+                    .u64(stat.f_bfree)
+                    .u64(stat.f_bavail)
+                    .u64(stat.f_files)
+                    .u64(stat.f_ffree)
+                    .u64(stat.f_fsid)
+                    .u32(stat.f_namemax as u32);
+            }
+            TREAD => {
+                let fid = r.u32()?;
+                let offset = r.u64()?;
+                let count = r.u32()?.min(self.msize - RREAD_HEADER_SIZE);
+
+                let mut buf = vec![0u8; count as usize];
+                let read = self.fid(fid)?.file()?.read_at(&mut buf, offset)?;
+
+                reply.u32(read as u32).bytes(&buf[..read]);
+            }
+            TWRITE => {
+                let fid = r.u32()?;
+                let offset = r.u64()?;
+                let count = r.u32()?;
+                let data = r.bytes(count as usize)?;
+
+                let written = self.fid(fid)?.file()?.write_at(data, offset)?;
+
+                reply.u32(written as u32);
+            }
+            TCLUNK => {
+                let fid = r.u32()?;
+                self.fids.remove(&fid).ok_or_else(|| errno(libc::EBADF))?;
+            }
+            TREMOVE => {
+                let fid = r.u32()?;
+                // The fid is clunked even if the removal fails.
+                let fid = self.fids.remove(&fid).ok_or_else(|| errno(libc::EBADF))?;
+
+                let name = fid.path.file_name().ok_or_else(|| errno(libc::EBUSY))?;
+                let flags = if fid.handle.metadata()?.is_dir() {
+                    libc::AT_REMOVEDIR
+                } else {
+                    0
+                };
+                Self::unlink(
+                    &self.open_parent(&fid.path)?,
+                    &cstring(Path::new(name))?,
+                    flags,
+                )?;
+            }
+            _ => return Err(errno(libc::EOPNOTSUPP)),
+        }
+
+        Ok(reply)
+    }
+}
+
+fn error_reply(tag: u16, e: io::Error) -> Vec<u8> {
+    let mut reply = Writer::new(RLERROR, tag);
+    reply.u32(e.raw_os_error().unwrap_or(libc::EIO) as u32);
+    reply.finish()
+}
+
+/// List the entries of the directory `dir`, including `.` and `..`.
+fn read_dir(dir: &File, parent: &File) -> io::Result<Vec<DirEntry>> {
+    let mut entries = Vec::new();
+    for (name, handle) in [(".", dir), ("..", parent)] {
+        entries.push(DirEntry {
+            name: name.to_string(),
+            qid: Qid::from(&handle.metadata()?),
+            type_: libc::DT_DIR,
+        });
+    }
+
+    for entry in fs::read_dir(proc_path(dir))? {
+        let entry = entry?;
+        // The entry may have been removed since the directory was listed.
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+
+        entries.push(DirEntry {
+            name: entry.file_name().to_string_lossy().into_owned(),
+            qid: Qid::from(&metadata),
+            type_: dirent_type(&metadata),
+        });
+    }
+
+    Ok(entries)
+}
+
+/// Timestamp of a `Tsetattr` request: `UTIME_OMIT` if not set, the current time if `set` is
+/// false, the value of the request otherwise.
+fn timespec(valid: bool, set: bool, (sec, nsec): (u64, u64)) -> libc::timespec {
+    let (tv_sec, tv_nsec) = match (valid, set) {
+        (false, _) => (0, libc::UTIME_OMIT),
+        (true, false) => (0, libc::UTIME_NOW),
+        (true, true) => (sec as libc::time_t, nsec as libc::c_long),
+    };
+    libc::timespec { tv_sec, tv_nsec }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn setattr(
+    handle: &File,
+    valid: u32,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    size: u64,
+    atime: (u64, u64),
+    mtime: (u64, u64),
+) -> io::Result<()> {
+    let metadata = handle.metadata()?;
+
+    if valid & SETATTR_MODE != 0 {
+        // Changing the mode of a link would change the mode of its target.
+        if metadata.is_symlink() {
+            return Err(errno(libc::EOPNOTSUPP));
+        }
+        fs::set_permissions(proc_path(handle), fs::Permissions::from_mode(mode & 0o7777))?;
+    }
+
+    if valid & (SETATTR_UID | SETATTR_GID) != 0 {
+        // -1 leaves the owner or the group unchanged.
+        let uid = if valid & SETATTR_UID != 0 {
+            uid
+        } else {
+            u32::MAX
+        };
+        let gid = if valid & SETATTR_GID != 0 {
+            gid
+        } else {
+            u32::MAX
+        };
+
+        // SAFETY: the path is an empty C string.
+        check(unsafe {
+            libc::fchownat(
+                handle.as_raw_fd(),
+                EMPTY_PATH.as_ptr().cast(),
+                uid,
+                gid,
+                libc::AT_EMPTY_PATH | libc::AT_SYMLINK_NOFOLLOW,
+            )
+        })?;
+    }
+
+    if valid & SETATTR_SIZE != 0 {
+        reopen(handle, libc::O_WRONLY)?.set_len(size)?;
+    }
+
+    if valid & (SETATTR_ATIME | SETATTR_MTIME) != 0 {
+        let times = [
+            timespec(
+                valid & SETATTR_ATIME != 0,
+                valid & SETATTR_ATIME_SET != 0,
+                atime,
+            ),
+            timespec(
+                valid & SETATTR_MTIME != 0,
+                valid & SETATTR_MTIME_SET != 0,
+                mtime,
+            ),
+        ];
+
+        // SAFETY: the path is an empty C string and `times` holds two timestamps.
+        check(unsafe {
+            libc::utimensat(
+                handle.as_raw_fd(),
+                EMPTY_PATH.as_ptr().cast(),
+                times.as_ptr(),
+                libc::AT_EMPTY_PATH | libc::AT_SYMLINK_NOFOLLOW,
+            )
+        })?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RWALK: u8 = TWALK + 1;
+
+    /// Exported directory `root`, holding a link `escape` to the directory `outside`, which
+    /// holds the file `secret`.
+    struct Fixture {
+        dir: PathBuf,
+        server: Server,
+    }
+
+    impl Fixture {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("p9-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(dir.join("root")).unwrap();
+            fs::create_dir_all(dir.join("outside")).unwrap();
+            fs::write(dir.join("outside/secret"), "secret").unwrap();
+            std::os::unix::fs::symlink(dir.join("outside"), dir.join("root/escape")).unwrap();
+
+            let mut server = Server::new(&dir.join("root")).unwrap();
+            let mut version = Writer::new(TVERSION, 0);
+            version.u32(8192).str("9P2000.L");
+            server.handle(&version.finish());
+            let mut attach = Writer::new(TATTACH, 0);
+            attach.u32(0).u32(u32::MAX).str("").str("").u32(0);
+            server.handle(&attach.finish());
+
+            Fixture { dir, server }
+        }
+
+        fn walk(&mut self, newfid: u32, names: &[&str]) -> Vec<u8> {
+            let mut walk = Writer::new(TWALK, 1);
+            walk.u32(0).u32(newfid).u16(names.len() as u16);
+            for name in names {
+                walk.str(name);
+            }
+            self.server.handle(&walk.finish())
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    /// Type of a reply, and its body.
+    fn parse(reply: &[u8]) -> (u8, Reader<'_>) {
+        let mut reader = Reader::new(reply);
+        reader.u32().unwrap();
+        let type_ = reader.u8().unwrap();
+        reader.u16().unwrap();
+        (type_, reader)
+    }
+
+    fn error_code(reply: &[u8]) -> Option<u32> {
+        match parse(reply) {
+            (RLERROR, mut reader) => Some(reader.u32().unwrap()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn walk_through_symlink() {
+        let mut fixture = Fixture::new("walk-through-symlink");
+
+        // The walk stops at the link, and the new fid isn't created.
+        let reply = fixture.walk(1, &["escape", "secret"]);
+        let (type_, mut reader) = parse(&reply);
+        assert_eq!(type_, RWALK);
+        assert_eq!(reader.u16().unwrap(), 1);
+        assert_eq!(reader.u8().unwrap(), QTSYMLINK);
+
+        let mut getattr = Writer::new(TGETATTR, 2);
+        getattr.u32(1).u64(GETATTR_BASIC);
+        let reply = fixture.server.handle(&getattr.finish());
+        assert_eq!(error_code(&reply), Some(libc::EBADF as u32));
+    }
+
+    #[test]
+    fn symlink_is_not_opened() {
+        let mut fixture = Fixture::new("symlink-is-not-opened");
+
+        let reply = fixture.walk(1, &["escape"]);
+        assert_eq!(error_code(&reply), None);
+
+        let mut lopen = Writer::new(TLOPEN, 2);
+        lopen.u32(1).u32(libc::O_RDONLY as u32);
+        let reply = fixture.server.handle(&lopen.finish());
+        assert_eq!(error_code(&reply), Some(libc::ELOOP as u32));
+
+        // The link itself can be read.
+        let mut readlink = Writer::new(TREADLINK, 3);
+        readlink.u32(1);
+        let reply = fixture.server.handle(&readlink.finish());
+        let (_, mut reader) = parse(&reply);
+        assert_eq!(
+            reader.str().unwrap(),
+            fixture.dir.join("outside").to_str().unwrap()
+        );
+    }
+
+    #[test]
+    fn swapped_directory_is_not_followed() {
+        let mut fixture = Fixture::new("swapped-directory-is-not-followed");
+        fs::create_dir(fixture.dir.join("root/dir")).unwrap();
+
+        let reply = fixture.walk(1, &["dir"]);
+        assert_eq!(error_code(&reply), None);
+
+        // The directory of the fid is replaced with a link leading out of the root.
+        let root = fixture.dir.join("root");
+        fs::rename(root.join("dir"), root.join("moved")).unwrap();
+        std::os::unix::fs::symlink(fixture.dir.join("outside"), root.join("dir")).unwrap();
+
+        let mut lcreate = Writer::new(TLCREATE, 2);
+        lcreate
+            .u32(1)
+            .str("created")
+            .u32(libc::O_WRONLY as u32)
+            .u32(0o644)
+            .u32(0);
+        let reply = fixture.server.handle(&lcreate.finish());
+        assert_eq!(error_code(&reply), None);
+
+        assert!(root.join("moved/created").exists());
+        assert!(!fixture.dir.join("outside/created").exists());
+
+        // Walking the path again goes through the link, and stops there.
+        let reply = fixture.walk(2, &["dir", "secret"]);
+        let (type_, mut reader) = parse(&reply);
+        assert_eq!(type_, RWALK);
+        assert_eq!(reader.u16().unwrap(), 1);
+    }
+
+    #[test]
+    fn small_msize_is_rejected() {
+        let mut fixture = Fixture::new("small-msize-is-rejected");
+
+        let mut version = Writer::new(TVERSION, 0);
+        version.u32(RREAD_HEADER_SIZE - 1).str("9P2000.L");
+        let reply = fixture.server.handle(&version.finish());
+        assert_eq!(error_code(&reply), Some(libc::EINVAL as u32));
+
+        // The session negotiated before is kept.
+        assert_eq!(fixture.server.msize(), 8192);
+        assert_eq!(error_code(&fixture.walk(1, &[])), None);
+    }
+
+    #[test]
+    fn walk_stays_in_root() {
+        let mut fixture = Fixture::new("walk-stays-in-root");
+        fs::create_dir(fixture.dir.join("root/dir")).unwrap();
+        fs::write(fixture.dir.join("root/file"), "file").unwrap();
+
+        let reply = fixture.walk(1, &["dir", "..", "..", "..", "file"]);
+        let (type_, mut reader) = parse(&reply);
+        assert_eq!(type_, RWALK);
+        assert_eq!(reader.u16().unwrap(), 5);
+
+        let mut lopen = Writer::new(TLOPEN, 2);
+        lopen.u32(1).u32(libc::O_RDONLY as u32);
+        assert_eq!(error_code(&fixture.server.handle(&lopen.finish())), None);
+
+        let mut read = Writer::new(TREAD, 3);
+        read.u32(1).u64(0).u32(4096);
+        let reply = fixture.server.handle(&read.finish());
+        let (_, mut reader) = parse(&reply);
+        let count = reader.u32().unwrap();
+        assert_eq!(reader.bytes(count as usize).unwrap(), b"file");
+    }
+}
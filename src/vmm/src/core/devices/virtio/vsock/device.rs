@@ -0,0 +1,184 @@
+use super::muxer::Muxer;
+use super::queue_handler::QueueHandler;
+use super::{Error, Result, VsockConfig, VSOCK_DEVICE_ID};
+use crate::core::devices::virtio::register::register_mmio_device;
+use crate::core::devices::virtio::{
+    self, Config, MmioConfig, SingleFdSignalQueue, Subscriber, VirtioState, QUEUE_MAX_SIZE,
+};
+use event_manager::RemoteEndpoint;
+use kvm_ioctls::VmFd;
+use std::os::unix::net::UnixListener;
+use std::path::PathBuf;
+use std::{
+    borrow::{Borrow, BorrowMut},
+    sync::{Arc, Mutex},
+};
+use tracing::info;
+use virtio_bindings::virtio_config::VIRTIO_F_VERSION_1;
+use virtio_device::{VirtioConfig, VirtioDeviceActions, VirtioDeviceType, VirtioMmioDevice};
+use virtio_queue::Queue;
+use vm_device::device_manager::IoManager;
+use vm_device::{bus::MmioAddress, MutDeviceMmio};
+use vm_memory::GuestMemoryMmap;
+
+pub struct Vsock {
+    mem: Arc<GuestMemoryMmap>,
+    pub config: Config,
+    guest_cid: u64,
+    uds_path: PathBuf,
+    // Moved to the queue handler once the device is activated.
+    listener: Option<UnixListener>,
+    handler: Option<Arc<Mutex<QueueHandler<SingleFdSignalQueue>>>>,
+}
+
+impl Vsock {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        mem: Arc<GuestMemoryMmap>,
+        device_mgr: Arc<Mutex<IoManager>>,
+        mmio_cfg: MmioConfig,
+        vsock_cfg: &VsockConfig,
+        irq: u32,
+        endpoint: RemoteEndpoint<Subscriber>,
+        vm_fd: Arc<VmFd>,
+        cmdline_extra_parameters: &mut Vec<String>,
+    ) -> Result<Arc<Mutex<Self>>> {
+        let device_features = 1 << VIRTIO_F_VERSION_1;
+
+        // The configuration space only holds the CID of the guest.
+        let guest_cid = u64::from(vsock_cfg.guest_cid);
+        let config_space = guest_cid.to_le_bytes().to_vec();
+
+        // Receive, transmit and event queues.
+        let queues = (0..3)
+            .map(|_| Queue::new(QUEUE_MAX_SIZE))
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|_| Error::Virtio(virtio::Error::QueuesNotValid))?;
+
+        let virtio_cfg = VirtioConfig::new(device_features, queues, config_space);
+
+        let cfg = Config::new(virtio_cfg, mmio_cfg, endpoint, vm_fd).map_err(Error::Virtio)?;
+
+        // Remove the socket left by a previous run.
+        let _ = std::fs::remove_file(&vsock_cfg.uds_path);
+        let listener = UnixListener::bind(&vsock_cfg.uds_path).map_err(Error::Bind)?;
+        listener.set_nonblocking(true).map_err(Error::Bind)?;
+        info!("vsock device listening on {}", vsock_cfg.uds_path.display());
+
+        let vsock = Arc::new(Mutex::new(Vsock {
+            mem,
+            config: cfg,
+            guest_cid,
+            uds_path: vsock_cfg.uds_path.clone(),
+            listener: Some(listener),
+            handler: None,
+        }));
+
+        let vmmio_param = register_mmio_device(mmio_cfg, device_mgr, irq, None, vsock.clone())
+            .map_err(Error::Virtio)?;
+
+        cmdline_extra_parameters.push(vmmio_param);
+
+        Ok(vsock)
+    }
+
+    /// Save the state of the device. The connections are not saved: the guest is told to drop
+    /// the ones opened at the time of the snapshot once it's restored.
+    pub fn state(&self) -> VirtioState {
+        match &self.handler {
+            Some(handler) => {
+                let handler = handler.lock().unwrap();
+                self.config
+                    .state(&[&handler.rxq, &handler.txq, &handler.evq])
+            }
+            None => self.config.state(&[]),
+        }
+    }
+
+    /// Restore the state of a device created with the same configuration.
+    pub fn restore(&mut self, state: &VirtioState) -> Result<()> {
+        self.config.restore(state).map_err(Error::Virtio)?;
+        if state.activated {
+            self.activate()?;
+            if let Some(handler) = &self.handler {
+                handler.lock().unwrap().reset_transport();
+            }
+        }
+        Ok(())
+    }
+}
+
+impl VirtioDeviceType for Vsock {
+    fn device_type(&self) -> u32 {
+        VSOCK_DEVICE_ID
+    }
+}
+
+impl Borrow<VirtioConfig<Queue>> for Vsock {
+    fn borrow(&self) -> &VirtioConfig<Queue> {
+        &self.config.virtio
+    }
+}
+
+impl BorrowMut<VirtioConfig<Queue>> for Vsock {
+    fn borrow_mut(&mut self) -> &mut VirtioConfig<Queue> {
+        &mut self.config.virtio
+    }
+}
+
+impl VirtioDeviceActions for Vsock {
+    type E = Error;
+
+    fn activate(&mut self) -> Result<()> {
+        let driver_notify = SingleFdSignalQueue {
+            irqfd: self.config.irqfd.clone(),
+            interrupt_status: self.config.virtio.interrupt_status.clone(),
+        };
+
+        let mut ioevents = self.config.prepare_activate().map_err(Error::Virtio)?;
+
+        let listener = self
+            .listener
+            .take()
+            .ok_or(Error::Virtio(virtio::Error::AlreadyActivated))?;
+
+        let rxq = self.config.virtio.queues.remove(0);
+        let txq = self.config.virtio.queues.remove(0);
+        let evq = self.config.virtio.queues.remove(0);
+
+        let handler = Arc::new(Mutex::new(QueueHandler {
+            driver_notify,
+            rxq,
+            txq,
+            evq,
+            reset_pending: false,
+            mem: self.mem.clone(),
+            muxer: Muxer::new(self.guest_cid, self.uds_path.clone(), listener),
+            rx_ioevent: ioevents.remove(0),
+            tx_ioevent: ioevents.remove(0),
+            ev_ioevent: ioevents.remove(0),
+        }));
+        self.handler = Some(handler.clone());
+
+        self.config
+            .finalize_activate(handler)
+            .map_err(Error::Virtio)
+    }
+
+    fn reset(&mut self) -> std::result::Result<(), Error> {
+        // Not implemented for now.
+        Ok(())
+    }
+}
+
+impl VirtioMmioDevice for Vsock {}
+
+impl MutDeviceMmio for Vsock {
+    fn mmio_read(&mut self, _base: MmioAddress, offset: u64, data: &mut [u8]) {
+        self.read(offset, data);
+    }
+
+    fn mmio_write(&mut self, _base: MmioAddress, offset: u64, data: &[u8]) {
+        self.write(offset, data);
+    }
+}
@@ -0,0 +1,40 @@
+//! virtio-vsock device, backed by a Unix socket on the host.
+//!
+//! Host-initiated connections follow the Firecracker convention: the host connects to the
+//! Unix socket and writes `CONNECT <port>\n`, the device then replies `OK <host port>\n`
+//! once the guest accepted the connection. Guest-initiated connections to the host port
+//! `<port>` are forwarded to the Unix socket `<uds_path>_<port>`.
+
+pub mod device;
+mod muxer;
+mod packet;
+mod queue_handler;
+
+use crate::core::devices::virtio;
+use std::io;
+use std::path::PathBuf;
+
+const VSOCK_DEVICE_ID: u32 = 19;
+const RXQ_INDEX: u16 = 0;
+const TXQ_INDEX: u16 = 1;
+const EVQ_INDEX: u16 = 2;
+/// Well-known CID of the host.
+const VSOCK_HOST_CID: u64 = 2;
+
+#[derive(Debug)]
+pub enum Error {
+    Virtio(virtio::Error),
+    /// Failed to bind the Unix socket of the device.
+    Bind(io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Configuration of a vsock device.
+#[derive(Clone, Debug)]
+pub struct VsockConfig {
+    /// Context ID of the guest, must be greater than 2.
+    pub guest_cid: u32,
+    /// Path of the Unix socket the host connects to.
+    pub uds_path: PathBuf,
+}
@@ -0,0 +1,598 @@
+use super::packet::{
+    Packet, PacketHeader, OP_CREDIT_REQUEST, OP_CREDIT_UPDATE, OP_REQUEST, OP_RESPONSE, OP_RST,
+    OP_RW, OP_SHUTDOWN, SHUTDOWN_RCV, SHUTDOWN_SEND, TYPE_STREAM,
+};
+use super::VSOCK_HOST_CID;
+use event_manager::{EventOps, Events};
+use log::{error, warn};
+use std::collections::{HashMap, VecDeque};
+use std::io::{ErrorKind, Read, Write};
+use std::net::Shutdown;
+use std::num::Wrapping;
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use vmm_sys_util::epoll::EventSet;
+
+/// Size of the buffer holding the data sent by the guest on a connection, advertised to the
+/// guest as our receive buffer.
+const CONN_TX_BUF_SIZE: u32 = 256 * 1024;
+/// A credit update is sent to the guest once that many bytes were forwarded to the host
+/// without the guest knowing.
+const CREDIT_UPDATE_THRESHOLD: u32 = CONN_TX_BUF_SIZE / 4;
+/// Maximum number of bytes read from a host socket at once.
+const MAX_READ_SIZE: usize = 64 * 1024;
+/// Ports of the host side of host-initiated connections are allocated from here.
+const FIRST_LOCAL_PORT: u32 = 1 << 30;
+/// Maximum length of a `CONNECT <port>` line.
+const MAX_CONNECT_LINE_SIZE: usize = 32;
+
+/// A connection is identified by its host and guest ports.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+struct ConnKey {
+    local_port: u32,
+    peer_port: u32,
+}
+
+#[derive(Debug, PartialEq)]
+enum ConnState {
+    /// Host-initiated connection, waiting for the guest to accept it.
+    Connecting,
+    Established,
+    /// The host closed its socket, waiting for the guest to reset the connection.
+    Closing,
+}
+
+/// What to do with a connection once a guest packet was handled.
+enum Action {
+    None,
+    Flush,
+    Reset,
+    Remove,
+}
+
+struct Connection {
+    stream: UnixStream,
+    state: ConnState,
+    /// Data sent by the guest and not written to the host socket yet.
+    tx_buf: Vec<u8>,
+    /// The guest won't send anything anymore, shut the host socket down for writing once
+    /// `tx_buf` is flushed.
+    shutdown_write: bool,
+    /// Bytes written to the host socket.
+    fwd_cnt: Wrapping<u32>,
+    /// Value of `fwd_cnt` the guest knows about.
+    last_fwd_cnt_sent: Wrapping<u32>,
+    /// Bytes sent to the guest.
+    rx_cnt: Wrapping<u32>,
+    peer_buf_alloc: u32,
+    peer_fwd_cnt: Wrapping<u32>,
+    credit_requested: bool,
+    /// The host socket hung up while the guest had no room for its data. It is removed from
+    /// the epoll set, which would report the hang up over and over, and the data left in it
+    /// is read as the guest gives credit.
+    draining: bool,
+    /// Events of the host socket currently registered.
+    interest: EventSet,
+}
+
+impl Connection {
+    fn new(stream: UnixStream, state: ConnState, peer: &PacketHeader) -> Self {
+        Connection {
+            stream,
+            state,
+            tx_buf: Vec::new(),
+            shutdown_write: false,
+            fwd_cnt: Wrapping(0),
+            last_fwd_cnt_sent: Wrapping(0),
+            rx_cnt: Wrapping(0),
+            peer_buf_alloc: peer.buf_alloc,
+            peer_fwd_cnt: Wrapping(peer.fwd_cnt),
+            credit_requested: false,
+            draining: false,
+            interest: EventSet::empty(),
+        }
+    }
+
+    /// Number of bytes the guest can still receive.
+    fn peer_credit(&self) -> u32 {
+        self.peer_buf_alloc
+            .saturating_sub((self.rx_cnt - self.peer_fwd_cnt).0)
+    }
+
+    fn wanted_interest(&self) -> EventSet {
+        let mut interest = EventSet::empty();
+
+        if self.state == ConnState::Established && self.peer_credit() > 0 {
+            interest |= EventSet::IN;
+        }
+        if !self.tx_buf.is_empty() {
+            interest |= EventSet::OUT;
+        }
+
+        interest
+    }
+
+    /// Register the events of the host socket we currently care about.
+    fn update_interest(&mut self, ops: &mut EventOps) {
+        if self.draining {
+            return;
+        }
+
+        let interest = self.wanted_interest();
+
+        if interest != self.interest {
+            let fd = self.stream.as_raw_fd();
+            match ops.modify(Events::with_data(&self.stream, fd as u32, interest)) {
+                Ok(()) => self.interest = interest,
+                Err(e) => error!("Failed to update vsock connection events: {:?}", e),
+            }
+        }
+    }
+
+    /// Build a packet for this connection, advertising our receive buffer.
+    fn packet(&mut self, header: PacketHeader, data: Vec<u8>) -> Packet {
+        self.last_fwd_cnt_sent = self.fwd_cnt;
+
+        Packet {
+            header: PacketHeader {
+                fwd_cnt: self.fwd_cnt.0,
+                ..header
+            },
+            data,
+        }
+    }
+}
+
+/// Host connection which didn't send its `CONNECT <port>` line yet.
+struct PendingConnection {
+    stream: UnixStream,
+    line: Vec<u8>,
+}
+
+/// Forwards the connections of the guest to Unix sockets on the host.
+pub struct Muxer {
+    guest_cid: u64,
+    uds_path: PathBuf,
+    listener: UnixListener,
+    connections: HashMap<ConnKey, Connection>,
+    /// Connection of each host socket.
+    keys: HashMap<RawFd, ConnKey>,
+    pending: HashMap<RawFd, PendingConnection>,
+    /// Packets waiting for a buffer of the guest.
+    rx_queue: VecDeque<Packet>,
+    next_local_port: u32,
+}
+
+impl Muxer {
+    pub fn new(guest_cid: u64, uds_path: PathBuf, listener: UnixListener) -> Self {
+        Muxer {
+            guest_cid,
+            uds_path,
+            listener,
+            connections: HashMap::new(),
+            keys: HashMap::new(),
+            pending: HashMap::new(),
+            rx_queue: VecDeque::new(),
+            next_local_port: FIRST_LOCAL_PORT,
+        }
+    }
+
+    pub fn listener(&self) -> &UnixListener {
+        &self.listener
+    }
+
+    pub fn has_pending_rx(&self) -> bool {
+        !self.rx_queue.is_empty()
+    }
+
+    /// Take the next packet to send to the guest, splitting its payload if it's larger than
+    /// `max_data` bytes.
+    pub fn pop_rx(&mut self, max_data: usize) -> Option<Packet> {
+        let packet = self.rx_queue.front_mut()?;
+
+        let mut packet = if packet.data.len() > max_data {
+            if max_data == 0 {
+                return None;
+            }
+            let rest = packet.data.split_off(max_data);
+            let data = std::mem::replace(&mut packet.data, rest);
+            Packet {
+                header: packet.header,
+                data,
+            }
+        } else {
+            self.rx_queue.pop_front()?
+        };
+
+        packet.header.len = packet.data.len() as u32;
+        Some(packet)
+    }
+
+    fn push_reset(&mut self, key: ConnKey) {
+        let header = host_header(self.guest_cid, key, OP_RST);
+        self.rx_queue.push_back(Packet {
+            header,
+            data: Vec::new(),
+        });
+    }
+
+    fn remove_connection(&mut self, key: ConnKey, ops: &mut EventOps) {
+        if let Some(connection) = self.connections.remove(&key) {
+            self.keys.remove(&connection.stream.as_raw_fd());
+            let _ = ops.remove(Events::empty(&connection.stream));
+        }
+    }
+
+    fn allocate_local_port(&mut self) -> u32 {
+        loop {
+            let port = self.next_local_port;
+            self.next_local_port = self
+                .next_local_port
+                .checked_add(1)
+                .unwrap_or(FIRST_LOCAL_PORT);
+
+            if !self.connections.keys().any(|key| key.local_port == port) {
+                return port;
+            }
+        }
+    }
+
+    /// Accept the connections waiting on the listener of the device.
+    pub fn accept(&mut self, ops: &mut EventOps) {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    if let Err(e) = stream.set_nonblocking(true) {
+                        error!("Failed to configure vsock host connection: {}", e);
+                        continue;
+                    }
+
+                    let fd = stream.as_raw_fd();
+                    if let Err(e) = ops.add(Events::with_data(&stream, fd as u32, EventSet::IN)) {
+                        error!("Failed to register vsock host connection: {:?}", e);
+                        continue;
+                    }
+
+                    self.pending.insert(
+                        fd,
+                        PendingConnection {
+                            stream,
+                            line: Vec::new(),
+                        },
+                    );
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    error!("Failed to accept vsock host connection: {}", e);
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Handle an event on the host socket `fd`.
+    pub fn handle_host_event(&mut self, fd: RawFd, event_set: EventSet, ops: &mut EventOps) {
+        if self.pending.contains_key(&fd) {
+            self.read_connect_line(fd, ops);
+            return;
+        }
+
+        let Some(&key) = self.keys.get(&fd) else {
+            warn!("Event on an unknown vsock host socket");
+            return;
+        };
+
+        if event_set.contains(EventSet::OUT) {
+            self.flush_tx(key, ops);
+        }
+
+        if event_set.intersects(EventSet::IN | EventSet::HANG_UP | EventSet::ERROR) {
+            self.read_host(key, event_set, ops);
+        }
+    }
+
+    /// Read the `CONNECT <port>` line of a host connection and forward it to the guest.
+    fn read_connect_line(&mut self, fd: RawFd, ops: &mut EventOps) {
+        let Some(pending) = self.pending.get_mut(&fd) else {
+            return;
+        };
+
+        // Read byte per byte not to consume the data following the line.
+        let mut byte = [0u8; 1];
+        let complete = loop {
+            match pending.stream.read(&mut byte) {
+                Ok(0) => break false,
+                Ok(_) if byte[0] == b'\n' => break true,
+                Ok(_) if pending.line.len() < MAX_CONNECT_LINE_SIZE => pending.line.push(byte[0]),
+                Ok(_) => break false,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(_) => break false,
+            }
+        };
+
+        let Some(pending) = self.pending.remove(&fd) else {
+            return;
+        };
+
+        let peer_port = String::from_utf8_lossy(&pending.line)
+            .trim()
+            .strip_prefix("CONNECT ")
+            .and_then(|port| port.trim().parse::<u32>().ok());
+
+        let (true, Some(peer_port)) = (complete, peer_port) else {
+            warn!("Invalid vsock connection request from the host");
+            let _ = ops.remove(Events::empty(&pending.stream));
+            return;
+        };
+
+        let key = ConnKey {
+            local_port: self.allocate_local_port(),
+            peer_port,
+        };
+
+        let mut connection = Connection::new(
+            pending.stream,
+            ConnState::Connecting,
+            &PacketHeader::default(),
+        );
+        // The socket was registered for reading, stop until the guest accepts the connection.
+        connection.interest = EventSet::IN;
+        connection.update_interest(ops);
+
+        let packet = connection.packet(host_header(self.guest_cid, key, OP_REQUEST), Vec::new());
+        self.rx_queue.push_back(packet);
+        self.keys.insert(fd, key);
+        self.connections.insert(key, connection);
+    }
+
+    /// Forward the data of a host socket to the guest, as far as the guest has room for it.
+    fn read_host(&mut self, key: ConnKey, event_set: EventSet, ops: &mut EventOps) {
+        let guest_cid = self.guest_cid;
+        let Some(connection) = self.connections.get_mut(&key) else {
+            return;
+        };
+        let hang_up = event_set.intersects(EventSet::HANG_UP | EventSet::ERROR);
+
+        let action = loop {
+            let credit = connection.peer_credit() as usize;
+
+            if connection.state != ConnState::Established {
+                // Nothing more can be read from the socket.
+                break if hang_up { Action::Reset } else { Action::None };
+            }
+
+            if credit == 0 {
+                if hang_up && !connection.draining {
+                    let _ = ops.remove(Events::empty(&connection.stream));
+                    connection.draining = true;
+                }
+                if !connection.credit_requested {
+                    let packet = connection
+                        .packet(host_header(guest_cid, key, OP_CREDIT_REQUEST), Vec::new());
+                    self.rx_queue.push_back(packet);
+                    connection.credit_requested = true;
+                }
+                break Action::None;
+            }
+
+            let mut buffer = vec![0u8; credit.min(MAX_READ_SIZE)];
+            match connection.stream.read(&mut buffer) {
+                Ok(0) => {
+                    let mut shutdown = host_header(guest_cid, key, OP_SHUTDOWN);
+                    shutdown.flags = SHUTDOWN_RCV | SHUTDOWN_SEND;
+                    let packet = connection.packet(shutdown, Vec::new());
+                    self.rx_queue.push_back(packet);
+                    connection.state = ConnState::Closing;
+                    break Action::None;
+                }
+                Ok(count) => {
+                    buffer.truncate(count);
+                    connection.rx_cnt += Wrapping(count as u32);
+                    let packet = connection.packet(host_header(guest_cid, key, OP_RW), buffer);
+                    self.rx_queue.push_back(packet);
+                    // No event tells when to read a draining socket again.
+                    if !connection.draining {
+                        break Action::None;
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break Action::None,
+                Err(_) => break Action::Reset,
+            }
+        };
+
+        self.apply(key, action, ops);
+    }
+
+    /// Write the data sent by the guest to the host socket.
+    fn flush_tx(&mut self, key: ConnKey, ops: &mut EventOps) {
+        let guest_cid = self.guest_cid;
+        let Some(connection) = self.connections.get_mut(&key) else {
+            return;
+        };
+
+        while !connection.tx_buf.is_empty() {
+            match connection.stream.write(&connection.tx_buf) {
+                Ok(count) if count > 0 => {
+                    connection.tx_buf.drain(..count);
+                    connection.fwd_cnt += Wrapping(count as u32);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Ok(_) | Err(_) => {
+                    self.apply(key, Action::Reset, ops);
+                    return;
+                }
+            }
+        }
+
+        if connection.tx_buf.is_empty() && connection.shutdown_write {
+            let _ = connection.stream.shutdown(Shutdown::Write);
+        }
+
+        if (connection.fwd_cnt - connection.last_fwd_cnt_sent).0 >= CREDIT_UPDATE_THRESHOLD {
+            let packet =
+                connection.packet(host_header(guest_cid, key, OP_CREDIT_UPDATE), Vec::new());
+            self.rx_queue.push_back(packet);
+        }
+
+        connection.update_interest(ops);
+    }
+
+    fn apply(&mut self, key: ConnKey, action: Action, ops: &mut EventOps) {
+        match action {
+            Action::None => {
+                if let Some(connection) = self.connections.get_mut(&key) {
+                    connection.update_interest(ops);
+                }
+            }
+            Action::Flush => self.flush_tx(key, ops),
+            Action::Reset => {
+                self.push_reset(key);
+                self.remove_connection(key, ops);
+            }
+            Action::Remove => self.remove_connection(key, ops),
+        }
+    }
+
+    /// Handle a packet sent by the guest.
+    pub fn handle_guest_packet(&mut self, packet: Packet, ops: &mut EventOps) {
+        let header = packet.header;
+        let key = ConnKey {
+            local_port: header.dst_port,
+            peer_port: header.src_port,
+        };
+
+        if header.dst_cid != VSOCK_HOST_CID
+            || header.src_cid != self.guest_cid
+            || header.type_ != TYPE_STREAM
+        {
+            warn!("Dropping invalid vsock packet: {:?}", header);
+            if header.op != OP_RST {
+                self.push_reset(key);
+            }
+            return;
+        }
+
+        if header.op == OP_REQUEST {
+            self.connect_to_host(key, &header, ops);
+            return;
+        }
+
+        let guest_cid = self.guest_cid;
+        let Some(connection) = self.connections.get_mut(&key) else {
+            if header.op != OP_RST {
+                self.push_reset(key);
+            }
+            return;
+        };
+
+        connection.peer_buf_alloc = header.buf_alloc;
+        connection.peer_fwd_cnt = Wrapping(header.fwd_cnt);
+
+        let action = match header.op {
+            OP_RESPONSE if connection.state == ConnState::Connecting => {
+                connection.state = ConnState::Established;
+
+                let reply = format!("OK {}\n", key.local_port);
+                match connection.stream.write_all(reply.as_bytes()) {
+                    Ok(()) => Action::None,
+                    Err(_) => Action::Reset,
+                }
+            }
+            OP_RW if connection.state == ConnState::Established => {
+                if connection.tx_buf.len() + packet.data.len() > CONN_TX_BUF_SIZE as usize {
+                    warn!("The guest overflowed the vsock connection buffer");
+                    Action::Reset
+                } else {
+                    connection.tx_buf.extend_from_slice(&packet.data);
+                    Action::Flush
+                }
+            }
+            OP_CREDIT_UPDATE => {
+                connection.credit_requested = false;
+                Action::None
+            }
+            OP_CREDIT_REQUEST => {
+                let packet =
+                    connection.packet(host_header(guest_cid, key, OP_CREDIT_UPDATE), Vec::new());
+                self.rx_queue.push_back(packet);
+                Action::None
+            }
+            OP_SHUTDOWN
+                if header.flags & SHUTDOWN_SEND != 0 && header.flags & SHUTDOWN_RCV != 0 =>
+            {
+                Action::Reset
+            }
+            OP_SHUTDOWN if header.flags & SHUTDOWN_SEND != 0 => {
+                connection.shutdown_write = true;
+                Action::Flush
+            }
+            OP_RST => Action::Remove,
+            _ => Action::None,
+        };
+
+        self.apply(key, action, ops);
+
+        // The guest may have given credit to read the rest of a host socket which hung up.
+        if self.connections.get(&key).is_some_and(|connection| {
+            connection.draining && connection.state == ConnState::Established
+        }) {
+            self.read_host(key, EventSet::HANG_UP, ops);
+        }
+    }
+
+    /// Forward a connection opened by the guest to the Unix socket `<uds_path>_<port>`.
+    fn connect_to_host(&mut self, key: ConnKey, header: &PacketHeader, ops: &mut EventOps) {
+        if self.connections.contains_key(&key) {
+            self.push_reset(key);
+            return;
+        }
+
+        let path = format!("{}_{}", self.uds_path.display(), key.local_port);
+        let stream = match UnixStream::connect(&path).and_then(|stream| {
+            stream.set_nonblocking(true)?;
+            Ok(stream)
+        }) {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("Failed to forward vsock connection to {}: {}", path, e);
+                self.push_reset(key);
+                return;
+            }
+        };
+
+        let fd = stream.as_raw_fd();
+        let mut connection = Connection::new(stream, ConnState::Established, header);
+        if let Err(e) = ops.add(Events::with_data(
+            &connection.stream,
+            fd as u32,
+            EventSet::empty(),
+        )) {
+            error!("Failed to register vsock host connection: {:?}", e);
+            self.push_reset(key);
+            return;
+        }
+        connection.update_interest(ops);
+
+        let packet = connection.packet(host_header(self.guest_cid, key, OP_RESPONSE), Vec::new());
+        self.rx_queue.push_back(packet);
+        self.keys.insert(fd, key);
+        self.connections.insert(key, connection);
+    }
+}
+
+/// Header of a packet sent by the host on the connection `key`.
+fn host_header(guest_cid: u64, key: ConnKey, op: u16) -> PacketHeader {
+    PacketHeader {
+        src_cid: VSOCK_HOST_CID,
+        dst_cid: guest_cid,
+        src_port: key.local_port,
+        dst_port: key.peer_port,
+        len: 0,
+        type_: TYPE_STREAM,
+        op,
+        flags: 0,
+        buf_alloc: CONN_TX_BUF_SIZE,
+        fwd_cnt: 0,
+    }
+}
@@ -0,0 +1,178 @@
+// Layout of the packets exchanged with the driver, see `struct virtio_vsock_hdr` in the
+// virtio specification.
+
+/// Size of the header of a packet.
+pub const HDR_SIZE: usize = 44;
+
+/// The only socket type supported by virtio-vsock.
+pub const TYPE_STREAM: u16 = 1;
+
+pub const OP_REQUEST: u16 = 1;
+pub const OP_RESPONSE: u16 = 2;
+pub const OP_RST: u16 = 3;
+pub const OP_SHUTDOWN: u16 = 4;
+pub const OP_RW: u16 = 5;
+pub const OP_CREDIT_UPDATE: u16 = 6;
+pub const OP_CREDIT_REQUEST: u16 = 7;
+
+/// The sender of a `SHUTDOWN` packet will not receive any more data.
+pub const SHUTDOWN_RCV: u32 = 1;
+/// The sender of a `SHUTDOWN` packet will not send any more data.
+pub const SHUTDOWN_SEND: u32 = 2;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PacketHeader {
+    pub src_cid: u64,
+    pub dst_cid: u64,
+    pub src_port: u32,
+    pub dst_port: u32,
+    pub len: u32,
+    pub type_: u16,
+    pub op: u16,
+    pub flags: u32,
+    pub buf_alloc: u32,
+    pub fwd_cnt: u32,
+}
+
+impl PacketHeader {
+    pub fn from_bytes(bytes: &[u8; HDR_SIZE]) -> Self {
+        // The slices below have a fixed size, so the conversions can't fail.
+        let u16_at =
+            |offset: usize| u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap());
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+        let u64_at =
+            |offset: usize| u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap());
+
+        PacketHeader {
+            src_cid: u64_at(0),
+            dst_cid: u64_at(8),
+            src_port: u32_at(16),
+            dst_port: u32_at(20),
+            len: u32_at(24),
+            type_: u16_at(28),
+            op: u16_at(30),
+            flags: u32_at(32),
+            buf_alloc: u32_at(36),
+            fwd_cnt: u32_at(40),
+        }
+    }
+
+    pub fn to_bytes(self) -> [u8; HDR_SIZE] {
+        let mut bytes = [0u8; HDR_SIZE];
+
+        bytes[0..8].copy_from_slice(&self.src_cid.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.dst_cid.to_le_bytes());
+        bytes[16..20].copy_from_slice(&self.src_port.to_le_bytes());
+        bytes[20..24].copy_from_slice(&self.dst_port.to_le_bytes());
+        bytes[24..28].copy_from_slice(&self.len.to_le_bytes());
+        bytes[28..30].copy_from_slice(&self.type_.to_le_bytes());
+        bytes[30..32].copy_from_slice(&self.op.to_le_bytes());
+        bytes[32..36].copy_from_slice(&self.flags.to_le_bytes());
+        bytes[36..40].copy_from_slice(&self.buf_alloc.to_le_bytes());
+        bytes[40..44].copy_from_slice(&self.fwd_cnt.to_le_bytes());
+
+        bytes
+    }
+}
+
+/// A packet with its payload, only `RW` packets have one.
+#[derive(Debug)]
+pub struct Packet {
+    pub header: PacketHeader,
+    pub data: Vec<u8>,
+}
+
+impl Packet {
+    /// Parse a packet read from the guest, `None` if `bytes` is shorter than the header or
+    /// than the payload length it announces.
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let header = PacketHeader::from_bytes(bytes.get(..HDR_SIZE)?.try_into().ok()?);
+        let data = bytes
+            .get(HDR_SIZE..HDR_SIZE.checked_add(header.len as usize)?)?
+            .to_vec();
+
+        Some(Packet { header, data })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header() -> PacketHeader {
+        PacketHeader {
+            src_cid: 3,
+            dst_cid: 2,
+            src_port: 1024,
+            dst_port: 50051,
+            len: 5,
+            type_: TYPE_STREAM,
+            op: OP_RW,
+            flags: 0,
+            buf_alloc: 0x4_0000,
+            fwd_cnt: 42,
+        }
+    }
+
+    #[test]
+    fn header_layout() {
+        let bytes = header().to_bytes();
+
+        assert_eq!(&bytes[0..8], &3u64.to_le_bytes());
+        assert_eq!(&bytes[8..16], &2u64.to_le_bytes());
+        assert_eq!(&bytes[16..20], &1024u32.to_le_bytes());
+        assert_eq!(&bytes[20..24], &50051u32.to_le_bytes());
+        assert_eq!(&bytes[24..28], &5u32.to_le_bytes());
+        assert_eq!(&bytes[28..30], &TYPE_STREAM.to_le_bytes());
+        assert_eq!(&bytes[30..32], &OP_RW.to_le_bytes());
+        assert_eq!(&bytes[36..40], &0x4_0000u32.to_le_bytes());
+        assert_eq!(&bytes[40..44], &42u32.to_le_bytes());
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let parsed = PacketHeader::from_bytes(&header().to_bytes());
+
+        assert_eq!(parsed.to_bytes(), header().to_bytes());
+        assert_eq!(parsed.dst_port, 50051);
+        assert_eq!(parsed.fwd_cnt, 42);
+    }
+
+    #[test]
+    fn parse_packet() {
+        let mut bytes = header().to_bytes().to_vec();
+        bytes.extend_from_slice(b"hello");
+
+        let packet = Packet::parse(&bytes).unwrap();
+        assert_eq!(packet.header.op, OP_RW);
+        assert_eq!(packet.data, b"hello");
+    }
+
+    #[test]
+    fn parse_ignores_trailing_bytes() {
+        let mut bytes = header().to_bytes().to_vec();
+        bytes.extend_from_slice(b"hello, world");
+
+        assert_eq!(Packet::parse(&bytes).unwrap().data, b"hello");
+    }
+
+    #[test]
+    fn parse_short_header() {
+        let bytes = header().to_bytes();
+
+        assert!(Packet::parse(&bytes[..HDR_SIZE - 1]).is_none());
+        assert!(Packet::parse(&[]).is_none());
+    }
+
+    #[test]
+    fn parse_truncated_payload() {
+        let mut bytes = header().to_bytes().to_vec();
+        bytes.extend_from_slice(b"hell");
+        assert!(Packet::parse(&bytes).is_none());
+
+        let mut header = header();
+        header.len = u32::MAX;
+        assert!(Packet::parse(&header.to_bytes()).is_none());
+    }
+}
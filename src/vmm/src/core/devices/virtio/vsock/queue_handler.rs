@@ -0,0 +1,272 @@
+use super::muxer::Muxer;
+use super::packet::{Packet, HDR_SIZE};
+use super::{EVQ_INDEX, RXQ_INDEX, TXQ_INDEX};
+use crate::core::devices::virtio::SignalUsedQueue;
+use event_manager::{EventOps, Events, MutEventSubscriber};
+use log::{error, warn};
+use std::os::fd::{AsRawFd, RawFd};
+use std::result;
+use std::sync::Arc;
+use virtio_queue::{DescriptorChain, Queue, QueueOwnedT, QueueT};
+use vm_memory::{Bytes, GuestAddress, GuestAddressSpace, GuestMemoryMmap};
+use vmm_sys_util::epoll::EventSet;
+use vmm_sys_util::eventfd::EventFd;
+
+/// Maximum payload of a packet sent by the guest (`VIRTIO_VSOCK_MAX_PKT_BUF_SIZE`).
+const MAX_PKT_DATA_SIZE: usize = 64 * 1024;
+/// Event telling the driver that all its connections are gone.
+const VIRTIO_VSOCK_EVENT_TRANSPORT_RESET: u32 = 0;
+
+#[derive(Debug)]
+pub enum Error {
+    GuestMemory(vm_memory::GuestMemoryError),
+    Queue(virtio_queue::Error),
+    InvalidPacket,
+}
+
+impl From<virtio_queue::Error> for Error {
+    fn from(e: virtio_queue::Error) -> Self {
+        Error::Queue(e)
+    }
+}
+
+pub struct QueueHandler<S>
+where
+    S: SignalUsedQueue,
+{
+    pub driver_notify: S,
+    pub rxq: Queue,
+    pub txq: Queue,
+    // The event queue is only used to notify the driver of transport resets, once the device
+    // is restored from a snapshot.
+    pub evq: Queue,
+    /// Set until the driver is notified of a transport reset.
+    pub reset_pending: bool,
+    pub mem: Arc<GuestMemoryMmap>,
+    pub muxer: Muxer,
+    pub rx_ioevent: EventFd,
+    pub tx_ioevent: EventFd,
+    pub ev_ioevent: EventFd,
+}
+
+impl<S> QueueHandler<S>
+where
+    S: SignalUsedQueue,
+{
+    fn read_packet(
+        &self,
+        mut chain: DescriptorChain<Arc<GuestMemoryMmap>>,
+    ) -> result::Result<Packet, Error> {
+        let mut bytes = Vec::new();
+
+        while let Some(desc) = chain.next() {
+            let start = bytes.len();
+            let len = desc.len() as usize;
+
+            if start + len > HDR_SIZE + MAX_PKT_DATA_SIZE {
+                return Err(Error::InvalidPacket);
+            }
+
+            bytes.resize(start + len, 0);
+            chain
+                .memory()
+                .read_slice(&mut bytes[start..], desc.addr())
+                .map_err(Error::GuestMemory)?;
+        }
+
+        Packet::parse(&bytes).ok_or(Error::InvalidPacket)
+    }
+
+    /// Write `packet` to the guest buffers `descs`, returns the number of bytes written.
+    fn write_packet(
+        &self,
+        descs: &[(GuestAddress, usize)],
+        packet: Packet,
+    ) -> result::Result<usize, Error> {
+        let mut bytes = packet.header.to_bytes().to_vec();
+        bytes.extend_from_slice(&packet.data);
+
+        let mut count = 0;
+        for &(addr, len) in descs {
+            if count == bytes.len() {
+                break;
+            }
+
+            let len = len.min(bytes.len() - count);
+            self.mem
+                .write_slice(&bytes[count..count + len], addr)
+                .map_err(Error::GuestMemory)?;
+            count += len;
+        }
+
+        Ok(count)
+    }
+
+    /// Send the packets queued by the muxer, as long as the guest has buffers for them.
+    fn process_rxq(&mut self) -> result::Result<(), Error> {
+        let mut used = false;
+
+        while self.muxer.has_pending_rx() {
+            let mut chain = match self.rxq.iter(self.mem.memory())?.next() {
+                Some(chain) => chain,
+                None => break,
+            };
+
+            let mut descs = Vec::new();
+            while let Some(desc) = chain.next() {
+                if desc.is_write_only() {
+                    descs.push((desc.addr(), desc.len() as usize));
+                }
+            }
+
+            let capacity: usize = descs.iter().map(|(_, len)| len).sum();
+            let count = match capacity
+                .checked_sub(HDR_SIZE)
+                .and_then(|max_data| self.muxer.pop_rx(max_data))
+            {
+                Some(packet) => self.write_packet(&descs, packet)?,
+                None => {
+                    warn!("vsock rx buffer too small");
+                    0
+                }
+            };
+
+            self.rxq
+                .add_used(self.mem.as_ref(), chain.head_index(), count as u32)?;
+            used = true;
+        }
+
+        if used && self.rxq.needs_notification(self.mem.as_ref())? {
+            self.driver_notify.signal_used_queue(RXQ_INDEX);
+        }
+
+        Ok(())
+    }
+
+    /// Notify the driver of a transport reset, once it gave a buffer for the event.
+    fn process_evq(&mut self) -> result::Result<(), Error> {
+        let event = VIRTIO_VSOCK_EVENT_TRANSPORT_RESET.to_le_bytes();
+        let mut used = false;
+
+        while self.reset_pending {
+            let mut chain = match self.evq.iter(self.mem.memory())?.next() {
+                Some(chain) => chain,
+                None => break,
+            };
+            let head_index = chain.head_index();
+
+            let count = match chain
+                .find(|desc| desc.is_write_only() && desc.len() as usize >= event.len())
+            {
+                Some(desc) => {
+                    self.mem
+                        .write_slice(&event, desc.addr())
+                        .map_err(Error::GuestMemory)?;
+                    self.reset_pending = false;
+                    event.len()
+                }
+                None => {
+                    warn!("vsock event buffer too small");
+                    0
+                }
+            };
+
+            self.evq
+                .add_used(self.mem.as_ref(), head_index, count as u32)?;
+            used = true;
+        }
+
+        if used && self.evq.needs_notification(self.mem.as_ref())? {
+            self.driver_notify.signal_used_queue(EVQ_INDEX);
+        }
+
+        Ok(())
+    }
+
+    /// Tell the driver that its connections are gone, as the host ones aren't saved in
+    /// snapshots.
+    pub fn reset_transport(&mut self) {
+        self.reset_pending = true;
+        if let Err(e) = self.process_evq() {
+            error!("vsock queue error: {:?}", e);
+        }
+    }
+
+    /// Handle the packets sent by the guest.
+    fn process_txq(&mut self, ops: &mut EventOps) -> result::Result<(), Error> {
+        loop {
+            self.txq.disable_notification(self.mem.as_ref())?;
+
+            while let Some(chain) = self.txq.iter(self.mem.memory())?.next() {
+                let head_index = chain.head_index();
+                let packet = self.read_packet(chain);
+
+                self.txq.add_used(self.mem.as_ref(), head_index, 0)?;
+
+                if self.txq.needs_notification(self.mem.as_ref())? {
+                    self.driver_notify.signal_used_queue(TXQ_INDEX);
+                }
+
+                match packet {
+                    Ok(packet) => self.muxer.handle_guest_packet(packet, ops),
+                    Err(e) => warn!("Dropping vsock packet: {:?}", e),
+                }
+            }
+
+            if !self.txq.enable_notification(self.mem.as_ref())? {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl<S> MutEventSubscriber for QueueHandler<S>
+where
+    S: SignalUsedQueue,
+{
+    fn process(&mut self, events: Events, ops: &mut EventOps) {
+        let fd = events.data() as RawFd;
+
+        let result = if fd == self.rx_ioevent.as_raw_fd() {
+            // The guest added buffers, the pending packets are sent below.
+            let _ = self.rx_ioevent.read();
+            Ok(())
+        } else if fd == self.tx_ioevent.as_raw_fd() {
+            let _ = self.tx_ioevent.read();
+            self.process_txq(ops)
+        } else if fd == self.ev_ioevent.as_raw_fd() {
+            // The guest added buffers for the events.
+            let _ = self.ev_ioevent.read();
+            self.process_evq()
+        } else if fd == self.muxer.listener().as_raw_fd() {
+            self.muxer.accept(ops);
+            Ok(())
+        } else {
+            self.muxer.handle_host_event(fd, events.event_set(), ops);
+            Ok(())
+        };
+
+        if let Err(e) = result.and_then(|_| self.process_rxq()) {
+            error!("vsock queue error: {:?}", e);
+        }
+    }
+
+    fn init(&mut self, ops: &mut EventOps) {
+        for ioevent in [&self.rx_ioevent, &self.tx_ioevent, &self.ev_ioevent] {
+            ops.add(Events::with_data(
+                ioevent,
+                ioevent.as_raw_fd() as u32,
+                EventSet::IN,
+            ))
+            .expect("Unable to add vsock ioevent");
+        }
+
+        let listener = self.muxer.listener();
+        ops.add(Events::with_data(
+            listener,
+            listener.as_raw_fd() as u32,
+            EventSet::IN,
+        ))
+        .expect("Unable to add vsock listener");
+    }
+}
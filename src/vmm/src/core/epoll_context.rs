@@ -36,6 +36,13 @@ impl EpollContext {
     }
 }
 
+impl Drop for EpollContext {
+    fn drop(&mut self) {
+        // SAFETY: the fd is owned by the context.
+        unsafe { libc::close(self.raw_fd) };
+    }
+}
+
 impl AsRawFd for EpollContext {
     fn as_raw_fd(&self) -> RawFd {
         self.raw_fd
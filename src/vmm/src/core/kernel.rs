@@ -5,11 +5,23 @@
 use crate::core::{Error, Result};
 use linux_loader::bootparam::boot_params;
 use linux_loader::cmdline::Cmdline;
-use linux_loader::configurator::{linux::LinuxBootConfigurator, BootConfigurator, BootParams};
-use linux_loader::loader::{elf::Elf, load_cmdline, KernelLoader, KernelLoaderResult};
+use linux_loader::configurator::{
+    linux::LinuxBootConfigurator, pvh::PvhBootConfigurator, BootConfigurator, BootParams,
+};
+use linux_loader::loader::elf::start_info::{
+    hvm_memmap_table_entry, hvm_modlist_entry, hvm_start_info,
+};
+use linux_loader::loader::{
+    bzimage::BzImage,
+    elf::{Elf, PvhBootCapability},
+    load_cmdline, KernelLoader, KernelLoaderResult,
+};
+use std::collections::HashSet;
 use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
 use std::path::PathBuf;
 use std::result;
+use tracing::info;
 use vm_memory::{Address, Bytes, GuestAddress, GuestMemory, GuestMemoryMmap};
 
 // x86_64 boot constants. See https://www.kernel.org/doc/Documentation/x86/boot.txt for the full
@@ -24,6 +36,16 @@ const KERNEL_HDR_MAGIC: u32 = 0x5372_6448;
 const KERNEL_LOADER_OTHER: u8 = 0xff;
 // Header field: `kernel_alignment`. Alignment unit required by a relocatable kernel.
 const KERNEL_MIN_ALIGNMENT_BYTES: u32 = 0x0100_0000;
+// Offset of the 64-bit entry point of a bzImage from where its protected-mode code is loaded.
+const BZIMAGE_64BIT_ENTRY_OFFSET: u64 = 0x200;
+// Header field: `xloadflags`. The kernel has the 64-bit entry point at +0x200.
+const XLF_KERNEL_64: u16 = 1 << 0;
+// Header field: `initrd_addr_max`, for the kernels too old to set it.
+const DEFAULT_INITRD_ADDR_MAX: u64 = 0x37ff_ffff;
+// First bytes of an ELF file.
+const ELF_MAGIC: [u8; 4] = *b"\x7fELF";
+// The initramfs is page aligned.
+const INITRAMFS_ALIGNMENT: u64 = 0x1000;
 
 // Start address for the EBDA (Extended Bios Data Area). Older computers (like the one this VMM
 // emulates) typically use 1 KiB for the EBDA, starting at 0x9fc00.
@@ -36,13 +58,59 @@ const E820_RAM: u32 = 1;
 
 /// Address of the zeropage, where Linux kernel boot parameters are written.
 pub(crate) const ZEROPG_START: u64 = 0x7000;
+/// Address of the `hvm_start_info` of the PVH boot protocol.
+pub(crate) const PVH_INFO_START: u64 = 0x6000;
+/// Address of the module list of the PVH boot protocol, right after the start info.
+const MODLIST_START: u64 = 0x6040;
+/// Address of the memory map of the PVH boot protocol, which has no zeropage.
+const MEMMAP_START: u64 = 0x7000;
+/// Magic number of the `hvm_start_info`.
+const XEN_HVM_START_MAGIC_VALUE: u32 = 0x336e_c578;
+/// Version of the `hvm_start_info` with a memory map.
+const XEN_HVM_START_INFO_VERSION: u32 = 1;
 
 const HIMEM_START: u64 = 0x0010_0000; // 1 MB
 
 /// Address where the kernel command line is written.
 const CMDLINE_START: u64 = 0x0002_0000;
-// Default command line
-const DEFAULT_CMDLINE: &str = "console=ttyS0 i8042.nokbd reboot=k panic=1 pci=off ip=172.29.0.2::172.29.0.1:255.255.0.0::eth0:off";
+/// Longest kernel command line, without its NUL: `COMMAND_LINE_SIZE` of x86 Linux.
+const CMDLINE_MAX_LEN: usize = 2048;
+/// Longest kernel command line of the bzImages older than the 2.06 boot protocol, which don't
+/// set `cmdline_size`.
+const CMDLINE_MAX_LEN_LEGACY: usize = 255;
+/// Default base of the kernel command line, the devices add their own parameters.
+pub const DEFAULT_CMDLINE: &str = "console=ttyS0 i8042.nokbd reboot=k panic=1 pci=off";
+
+/// Protocol the kernel is started with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BootProtocol {
+    /// 64-bit Linux boot protocol, with the boot parameters in the zeropage.
+    Linux,
+    /// PVH boot protocol, in 32-bit protected mode with the boot parameters in a
+    /// `hvm_start_info`.
+    Pvh,
+}
+
+/// Where and how the vCPUs start executing the kernel.
+#[derive(Clone, Copy, Debug)]
+pub struct KernelEntry {
+    pub addr: GuestAddress,
+    pub protocol: BootProtocol,
+}
+
+/// Usable RAM regions, as `(address, size)`: the low memory up to the EBDA, and the memory
+/// from `himem_start` to the end of the guest memory.
+fn ram_regions(
+    guest_memory: &GuestMemoryMmap,
+    himem_start: GuestAddress,
+) -> Result<[(u64, u64); 2]> {
+    let himem_size = guest_memory
+        .last_addr()
+        .checked_offset_from(himem_start)
+        .ok_or(Error::HimemStartPastMemEnd)?;
+
+    Ok([(0, EBDA_START), (himem_start.raw_value(), himem_size)])
+}
 
 fn add_e820_entry(
     params: &mut boot_params,
@@ -62,7 +130,7 @@ fn add_e820_entry(
     Ok(())
 }
 
-/// Build boot parameters for ELF kernels following the Linux boot protocol.
+/// Build boot parameters for kernels following the Linux boot protocol.
 ///
 /// # Arguments
 ///
@@ -81,87 +149,290 @@ pub fn build_bootparams(
     params.hdr.kernel_alignment = KERNEL_MIN_ALIGNMENT_BYTES;
     params.hdr.type_of_loader = KERNEL_LOADER_OTHER;
 
-    // Add an entry for EBDA itself.
-    add_e820_entry(&mut params, 0, EBDA_START, E820_RAM)?;
-
-    // Add entries for the usable RAM regions.
-    let last_addr = guest_memory.last_addr();
-    add_e820_entry(
-        &mut params,
-        himem_start.raw_value(),
-        last_addr
-            .checked_offset_from(himem_start)
-            .ok_or(Error::HimemStartPastMemEnd)?,
-        E820_RAM,
-    )?;
+    for (addr, size) in ram_regions(guest_memory, himem_start)? {
+        add_e820_entry(&mut params, addr, size, E820_RAM)?;
+    }
 
     Ok(params)
 }
 
+/// Write the boot parameters of the Linux boot protocol in the zeropage.
+fn write_bootparams(
+    guest_memory: &GuestMemoryMmap,
+    kernel_load: &KernelLoaderResult,
+    cmdline_size: usize,
+    initramfs: Option<(u32, u32)>,
+) -> Result<()> {
+    let mut bootparams = build_bootparams(guest_memory, GuestAddress(HIMEM_START))?;
+    if let Some(setup_header) = kernel_load.setup_header {
+        // Keep the setup header of the bzImage, only the loader type is ours.
+        bootparams.hdr = setup_header;
+        bootparams.hdr.type_of_loader = KERNEL_LOADER_OTHER;
+    }
+
+    // Add the kernel command line to the boot parameters.
+    bootparams.hdr.cmd_line_ptr = CMDLINE_START as u32;
+    bootparams.hdr.cmdline_size = cmdline_size as u32;
+
+    // Add the initramfs to the boot parameters.
+    if let Some((initramfs_addr, initramfs_size)) = initramfs {
+        bootparams.hdr.ramdisk_image = initramfs_addr;
+        bootparams.hdr.ramdisk_size = initramfs_size;
+    }
+
+    LinuxBootConfigurator::write_bootparams::<GuestMemoryMmap>(
+        &BootParams::new::<boot_params>(&bootparams, GuestAddress(ZEROPG_START)),
+        guest_memory,
+    )
+    .map_err(Error::BootConfigure)
+}
+
+/// Write the `hvm_start_info` of the PVH boot protocol, with the memory map and the initramfs
+/// as the only module.
+fn write_pvh_start_info(
+    guest_memory: &GuestMemoryMmap,
+    initramfs: Option<(u32, u32)>,
+) -> Result<()> {
+    let memmap: Vec<_> = ram_regions(guest_memory, GuestAddress(HIMEM_START))?
+        .iter()
+        .map(|&(addr, size)| hvm_memmap_table_entry {
+            addr,
+            size,
+            type_: E820_RAM,
+            reserved: 0,
+        })
+        .collect();
+    let modules: Vec<_> = initramfs
+        .iter()
+        .map(|&(addr, size)| hvm_modlist_entry {
+            paddr: addr.into(),
+            size: size.into(),
+            cmdline_paddr: 0,
+            reserved: 0,
+        })
+        .collect();
+
+    let start_info = hvm_start_info {
+        magic: XEN_HVM_START_MAGIC_VALUE,
+        version: XEN_HVM_START_INFO_VERSION,
+        nr_modules: modules.len() as u32,
+        modlist_paddr: MODLIST_START,
+        cmdline_paddr: CMDLINE_START,
+        memmap_paddr: MEMMAP_START,
+        memmap_entries: memmap.len() as u32,
+        ..Default::default()
+    };
+
+    let mut boot_params =
+        BootParams::new::<hvm_start_info>(&start_info, GuestAddress(PVH_INFO_START));
+    boot_params.set_sections::<hvm_memmap_table_entry>(&memmap, GuestAddress(MEMMAP_START));
+    if !modules.is_empty() {
+        boot_params.set_modules::<hvm_modlist_entry>(&modules, GuestAddress(MODLIST_START));
+    }
+
+    PvhBootConfigurator::write_bootparams::<GuestMemoryMmap>(&boot_params, guest_memory)
+        .map_err(Error::BootConfigure)
+}
+
+/// Longest command line the kernel loaded in `kernel_load` accepts, without its NUL.
+fn cmdline_max_len(kernel_load: &KernelLoaderResult) -> usize {
+    match kernel_load.setup_header {
+        Some(setup_header) if setup_header.cmdline_size != 0 => {
+            (setup_header.cmdline_size as usize).min(CMDLINE_MAX_LEN)
+        }
+        Some(_) => CMDLINE_MAX_LEN_LEGACY,
+        None => CMDLINE_MAX_LEN,
+    }
+}
+
+/// Build the kernel command line from the `base` parameters and the parameters of the devices.
+/// A device parameter replaces the base parameters with the same key, such as the `ip=` of the
+/// network device. The command line is rejected if it is longer than `max_len` bytes.
+fn build_cmdline(base: &str, device_parameters: &[String], max_len: usize) -> Result<Cmdline> {
+    let key = |param: &str| {
+        param
+            .split_once('=')
+            .map_or(param, |(key, _)| key)
+            .to_string()
+    };
+    let device_keys: HashSet<_> = device_parameters.iter().map(|param| key(param)).collect();
+
+    let params: Vec<&str> = base
+        .split_whitespace()
+        .filter(|param| !device_keys.contains(&key(param)))
+        .chain(device_parameters.iter().map(String::as_str))
+        .collect();
+
+    // Parameters are separated by a space, and the command line ends with a NUL.
+    let size = params
+        .iter()
+        .map(|param| param.len() + 1)
+        .sum::<usize>()
+        .max(1);
+    if size - 1 > max_len {
+        return Err(Error::Cmdline(linux_loader::cmdline::Error::TooLarge));
+    }
+    let mut cmdline = Cmdline::new(size).map_err(Error::Cmdline)?;
+    for param in params {
+        cmdline.insert_str(param).map_err(Error::Cmdline)?;
+    }
+
+    Ok(cmdline)
+}
+
+/// Load the kernel into guest memory, as an ELF vmlinux or as a bzImage.
+fn load_kernel(
+    guest_memory: &GuestMemoryMmap,
+    kernel_image: &mut File,
+) -> Result<KernelLoaderResult> {
+    let mut magic = [0u8; 4];
+    kernel_image.read_exact(&mut magic).map_err(Error::IO)?;
+    kernel_image.seek(SeekFrom::Start(0)).map_err(Error::IO)?;
+
+    if magic == ELF_MAGIC {
+        Elf::load(
+            guest_memory,
+            None,
+            kernel_image,
+            Some(GuestAddress(HIMEM_START)),
+        )
+    } else {
+        // The loader checks the magic numbers of the setup header.
+        BzImage::load(
+            guest_memory,
+            None,
+            kernel_image,
+            Some(GuestAddress(HIMEM_START)),
+        )
+    }
+    .map_err(Error::KernelLoad)
+}
+
+/// Entry point of the kernel loaded in `kernel_load`. The PVH entry point is preferred when the
+/// kernel has one, as it skips the decompression and the mode switches of the Linux entry.
+fn kernel_entry(kernel_load: &KernelLoaderResult) -> Result<KernelEntry> {
+    if let PvhBootCapability::PvhEntryPresent(addr) = kernel_load.pvh_boot_cap {
+        return Ok(KernelEntry {
+            addr,
+            protocol: BootProtocol::Pvh,
+        });
+    }
+
+    let addr = match kernel_load.setup_header {
+        // A bzImage is entered through the entry point of the 64-bit boot protocol, which
+        // only the kernels built for x86_64 have.
+        Some(setup_header) if setup_header.xloadflags & XLF_KERNEL_64 == 0 => {
+            return Err(Error::KernelNot64Bit)
+        }
+        Some(_) => kernel_load
+            .kernel_load
+            .unchecked_add(BZIMAGE_64BIT_ENTRY_OFFSET),
+        None => kernel_load.kernel_load,
+    };
+
+    Ok(KernelEntry {
+        addr,
+        protocol: BootProtocol::Linux,
+    })
+}
+
+/// End of the memory used by the kernel. A bzImage needs `init_size` bytes from where it was
+/// loaded to decompress itself.
+fn kernel_end(kernel_load: &KernelLoaderResult) -> u64 {
+    match kernel_load.setup_header {
+        Some(setup_header) => kernel_load
+            .kernel_end
+            .max(kernel_load.kernel_load.raw_value() + u64::from(setup_header.init_size)),
+        None => kernel_load.kernel_end,
+    }
+}
+
+/// Highest address the initramfs may end at.
+fn initramfs_max_addr(kernel_load: &KernelLoaderResult) -> u64 {
+    match kernel_load.setup_header {
+        Some(setup_header) if setup_header.initrd_addr_max != 0 => {
+            u64::from(setup_header.initrd_addr_max)
+        }
+        Some(_) => DEFAULT_INITRD_ADDR_MAX,
+        // The address is passed on 32 bits.
+        None => u64::from(u32::MAX),
+    }
+}
+
+/// Address of an initramfs of `size` bytes. Like QEMU and crosvm, the initramfs is put at the
+/// top of the memory the kernel can reach, out of the way of the kernel decompressing and
+/// relocating itself.
+fn initramfs_addr(
+    mem: &GuestMemoryMmap,
+    kernel_load: &KernelLoaderResult,
+    size: u64,
+) -> Result<u64> {
+    let max_addr = initramfs_max_addr(kernel_load).min(mem.last_addr().raw_value());
+    let addr = (max_addr + 1)
+        .checked_sub(size)
+        .ok_or(Error::InitramfsLoad)?
+        & !(INITRAMFS_ALIGNMENT - 1);
+
+    if addr < kernel_end(kernel_load) {
+        return Err(Error::InitramfsLoad);
+    }
+
+    Ok(addr)
+}
+
 /// Load the initramfs into guest memory. Returns a tuple containing the address
 /// where the initramfs was loaded, and its size.
 ///
 /// # Arguments
 ///
 /// * `guest_memory` - guest memory
-/// * `start_addr` - the address where to start looking for a place to store the initramfs
+/// * `kernel_load` - the kernel, see [`initramfs_addr`]
 /// * `data` - the initramfs data
-fn load_initramfs(mem: &GuestMemoryMmap, start_addr: u64, data: Vec<u8>) -> Result<(u32, u32)> {
-    let addr = GuestAddress(start_addr);
+fn load_initramfs(
+    mem: &GuestMemoryMmap,
+    kernel_load: &KernelLoaderResult,
+    data: Vec<u8>,
+) -> Result<(u32, u32)> {
+    let addr = GuestAddress(initramfs_addr(mem, kernel_load, data.len() as u64)?);
 
-    mem.checked_offset(addr, data.len())
-        .ok_or(Error::InitramfsLoad)?;
     mem.write_slice(data.as_slice(), addr)
         .map_err(|_| Error::InitramfsLoad)?;
 
     Ok((addr.raw_value() as u32, data.len() as u32))
 }
 
-/// Set guest kernel up.
+/// Set guest kernel up. Returns where the vCPUs start executing the kernel.
 ///
 /// # Arguments
 ///
-/// * `kernel_cfg` - [`KernelConfig`](struct.KernelConfig.html) struct containing kernel
-///                  configurations.
+/// * `guest_memory` - guest memory
+/// * `kernel_path` - path to an ELF vmlinux or a bzImage
+/// * `initramfs_path` - path to the initramfs, if any
+/// * `base_cmdline` - base of the command line, [`DEFAULT_CMDLINE`] if not set
+/// * `cmdline_extra_parameters` - parameters of the devices, see [`build_cmdline`]
 pub fn kernel_setup(
     guest_memory: &GuestMemoryMmap,
     kernel_path: PathBuf,
     initramfs_path: Option<PathBuf>,
-    cmdline_extra_parameters: &mut Vec<String>,
-) -> Result<KernelLoaderResult> {
+    base_cmdline: Option<&str>,
+    cmdline_extra_parameters: &[String],
+) -> Result<KernelEntry> {
     let mut kernel_image = File::open(kernel_path).map_err(Error::IO)?;
-    let zero_page_addr = GuestAddress(ZEROPG_START);
 
     // Load the kernel into guest memory.
-    let kernel_load = Elf::load(
-        guest_memory,
-        None,
-        &mut kernel_image,
-        Some(GuestAddress(HIMEM_START)),
-    )
-    .map_err(Error::KernelLoad)?;
-
-    // Generate boot parameters.
-    let mut bootparams = build_bootparams(guest_memory, GuestAddress(HIMEM_START))?;
+    let kernel_load = load_kernel(guest_memory, &mut kernel_image)?;
+    let entry = kernel_entry(&kernel_load)?;
 
-    let combined_cmdline: String = {
-        let mut combined = DEFAULT_CMDLINE.to_string();
-        for param in cmdline_extra_parameters {
-            combined.push(' ');
-            combined.push_str(param);
-        }
-        combined
-    };
-
-    // Add the kernel command line to the boot parameters.
-    bootparams.hdr.cmd_line_ptr = CMDLINE_START as u32;
-    bootparams.hdr.cmdline_size = combined_cmdline.len() as u32 + 1;
+    let cmdline = build_cmdline(
+        base_cmdline.unwrap_or(DEFAULT_CMDLINE),
+        cmdline_extra_parameters,
+        cmdline_max_len(&kernel_load),
+    )?;
+    let cmdline_str = cmdline.as_cstring().map_err(Error::Cmdline)?;
+    let cmdline_size = cmdline_str.as_bytes_with_nul().len();
+    info!(cmdline = ?cmdline_str, "Kernel command line");
 
     // Load the kernel command line into guest memory.
-    let mut cmdline = Cmdline::new(combined_cmdline.len() + 1).map_err(Error::Cmdline)?;
-    cmdline
-        .insert_str(combined_cmdline)
-        .map_err(Error::Cmdline)?;
     load_cmdline(
         guest_memory,
         GuestAddress(CMDLINE_START),
@@ -170,24 +441,167 @@ pub fn kernel_setup(
     )
     .map_err(Error::KernelLoad)?;
 
-    // Handle the initramfs.
-    if let Some(initramfs_path) = initramfs_path {
-        // Load the initramfs into guest memory.
-        let initramfs = fs::read(initramfs_path).map_err(Error::IO)?;
-        let (initramfs_addr, initramfs_size) =
-            load_initramfs(guest_memory, kernel_load.kernel_end, initramfs)?;
+    // Load the initramfs into guest memory.
+    let initramfs = match initramfs_path {
+        Some(initramfs_path) => {
+            let initramfs = fs::read(initramfs_path).map_err(Error::IO)?;
+            Some(load_initramfs(guest_memory, &kernel_load, initramfs)?)
+        }
+        None => None,
+    };
 
-        // Add the initramfs to the boot parameters.
-        bootparams.hdr.ramdisk_image = initramfs_addr;
-        bootparams.hdr.ramdisk_size = initramfs_size;
+    match entry.protocol {
+        BootProtocol::Linux => {
+            write_bootparams(guest_memory, &kernel_load, cmdline_size, initramfs)?
+        }
+        BootProtocol::Pvh => write_pvh_start_info(guest_memory, initramfs)?,
     }
 
-    // Write the boot parameters in the zeropage.
-    LinuxBootConfigurator::write_bootparams::<GuestMemoryMmap>(
-        &BootParams::new::<boot_params>(&bootparams, zero_page_addr),
-        guest_memory,
-    )
-    .map_err(Error::BootConfigure)?;
+    Ok(entry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use linux_loader::bootparam::setup_header;
+
+    const MEM_SIZE: u64 = 0x1000_0000;
+
+    fn guest_memory() -> GuestMemoryMmap {
+        GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE as usize)]).unwrap()
+    }
+
+    fn bzimage(header: setup_header) -> KernelLoaderResult {
+        KernelLoaderResult {
+            kernel_load: GuestAddress(HIMEM_START),
+            kernel_end: HIMEM_START + 0x80_0000,
+            setup_header: Some(header),
+            ..Default::default()
+        }
+    }
+
+    fn params(params: &[&str]) -> Vec<String> {
+        params.iter().map(|param| param.to_string()).collect()
+    }
+
+    fn cmdline_str(cmdline: Cmdline) -> String {
+        cmdline.as_cstring().unwrap().into_string().unwrap()
+    }
+
+    #[test]
+    fn cmdline_appends_device_parameters() {
+        let cmdline = build_cmdline(
+            "console=ttyS0 panic=1",
+            &params(&["virtio_mmio.device=4K@0xd0000000:5"]),
+            CMDLINE_MAX_LEN,
+        )
+        .unwrap();
+
+        assert_eq!(
+            cmdline_str(cmdline),
+            "console=ttyS0 panic=1 virtio_mmio.device=4K@0xd0000000:5"
+        );
+    }
 
-    Ok(kernel_load)
+    #[test]
+    fn cmdline_device_parameters_replace_base() {
+        let cmdline = build_cmdline(
+            "console=ttyS0 ip=dhcp  quiet panic=1",
+            &params(&["ip=172.29.0.2::172.29.0.1:255.255.0.0::eth0:off", "quiet"]),
+            CMDLINE_MAX_LEN,
+        )
+        .unwrap();
+
+        assert_eq!(
+            cmdline_str(cmdline),
+            "console=ttyS0 panic=1 ip=172.29.0.2::172.29.0.1:255.255.0.0::eth0:off quiet"
+        );
+    }
+
+    #[test]
+    fn cmdline_empty() {
+        let cmdline = build_cmdline("", &[], CMDLINE_MAX_LEN).unwrap();
+
+        assert_eq!(cmdline_str(cmdline), "");
+    }
+
+    #[test]
+    fn cmdline_too_long() {
+        let param = "a".repeat(CMDLINE_MAX_LEN);
+        assert!(build_cmdline(&param, &[], CMDLINE_MAX_LEN).is_ok());
+        assert!(matches!(
+            build_cmdline(&param, &params(&["b"]), CMDLINE_MAX_LEN),
+            Err(Error::Cmdline(linux_loader::cmdline::Error::TooLarge))
+        ));
+
+        assert!(build_cmdline("console=ttyS0 panic=1", &[], 20).is_err());
+    }
+
+    #[test]
+    fn cmdline_max_len_of_bzimage() {
+        let kernel_load = bzimage(setup_header {
+            cmdline_size: 0x7ff,
+            ..Default::default()
+        });
+        assert_eq!(cmdline_max_len(&kernel_load), 0x7ff);
+
+        let kernel_load = bzimage(setup_header::default());
+        assert_eq!(cmdline_max_len(&kernel_load), CMDLINE_MAX_LEN_LEGACY);
+
+        assert_eq!(
+            cmdline_max_len(&KernelLoaderResult::default()),
+            CMDLINE_MAX_LEN
+        );
+    }
+
+    #[test]
+    fn initramfs_at_top_of_memory() {
+        let kernel_load = bzimage(setup_header::default());
+
+        let addr = initramfs_addr(&guest_memory(), &kernel_load, 0x1800).unwrap();
+        assert_eq!(addr, MEM_SIZE - 0x2000);
+    }
+
+    #[test]
+    fn initramfs_below_initrd_addr_max() {
+        let kernel_load = bzimage(setup_header {
+            initrd_addr_max: 0x0800_0fff,
+            ..Default::default()
+        });
+
+        let addr = initramfs_addr(&guest_memory(), &kernel_load, 0x1000).unwrap();
+        assert_eq!(addr, 0x0800_0000);
+    }
+
+    #[test]
+    fn initramfs_above_decompressed_kernel() {
+        let kernel_load = bzimage(setup_header {
+            init_size: (MEM_SIZE - HIMEM_START - 0x1000) as u32,
+            ..Default::default()
+        });
+
+        assert!(initramfs_addr(&guest_memory(), &kernel_load, 0x1000).is_ok());
+        assert!(initramfs_addr(&guest_memory(), &kernel_load, 0x2000).is_err());
+        assert!(initramfs_addr(&guest_memory(), &kernel_load, MEM_SIZE + 1).is_err());
+    }
+
+    #[test]
+    fn bzimage_entry() {
+        let kernel_load = bzimage(setup_header {
+            xloadflags: XLF_KERNEL_64,
+            ..Default::default()
+        });
+        let entry = kernel_entry(&kernel_load).unwrap();
+        assert_eq!(
+            entry.addr,
+            GuestAddress(HIMEM_START + BZIMAGE_64BIT_ENTRY_OFFSET)
+        );
+        assert_eq!(entry.protocol, BootProtocol::Linux);
+
+        let kernel_load = bzimage(setup_header::default());
+        assert!(matches!(
+            kernel_entry(&kernel_load),
+            Err(Error::KernelNot64Bit)
+        ));
+    }
 }
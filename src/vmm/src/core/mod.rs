@@ -10,12 +10,14 @@ use std::io;
 
 use self::devices::virtio::{self, net::tuntap::open_tap};
 
+mod boot_timer;
 mod cpu;
 mod devices;
 mod epoll_context;
 mod irq_allocator;
 mod kernel;
 mod slip_pty;
+mod snapshot;
 pub mod vmm;
 
 #[derive(Debug)]
@@ -27,6 +29,8 @@ pub enum Error {
     Cmdline(linux_loader::cmdline::Error),
     /// Failed to load kernel.
     KernelLoad(loader::Error),
+    /// The bzImage has no 64-bit entry point.
+    KernelNot64Bit,
     /// Failed to load the initramfs.
     InitramfsLoad,
     /// Invalid E820 configuration.
@@ -73,6 +77,10 @@ pub enum Error {
     MmioRange,
     // Virtio net
     Virtio(virtio::Error),
+    /// Snapshot error.
+    Snapshot(snapshot::Error),
+    /// Failed to open the output of the console.
+    Console(io::Error),
 }
 
 /// Dedicated [`Result`](https://doc.rust-lang.org/std/result/) type.
@@ -7,6 +7,7 @@ use std::{
 
 use nix::sys::termios;
 use tracing::info;
+use vm_superio::serial::SerialState;
 
 use super::{devices::serial::LumperSerial, Error, Result};
 
@@ -46,6 +47,13 @@ impl SlipPty {
         &mut self.serial
     }
 
+    /// Restore the state of the serial device, which keeps writing to the PTY.
+    pub fn restore_serial(&mut self, state: &SerialState) -> Result<()> {
+        self.serial
+            .restore(state, self.master.clone())
+            .map_err(Error::SerialCreation)
+    }
+
     pub fn pty_master_fd(&self) -> RawFd {
         self.master.as_raw_fd()
     }
@@ -0,0 +1,710 @@
+//! Snapshots of a VM: the guest memory is dumped as is to [`MEMORY_FILE`], everything else is
+//! encoded to [`STATE_FILE`].
+
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use kvm_bindings::{
+    kvm_clock_data, kvm_cpuid_entry2, kvm_debugregs, kvm_irqchip, kvm_lapic_state, kvm_mp_state,
+    kvm_msr_entry, kvm_regs, kvm_sregs, kvm_vcpu_events, kvm_xcrs, kvm_xsave,
+};
+use virtio_queue::QueueState;
+use vm_memory::mmap::{MmapRegionBuilder, MmapRegionError};
+use vm_memory::{
+    Bytes, FileOffset, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion,
+    GuestRegionMmap,
+};
+use vm_superio::serial::SerialState;
+
+use super::cpu::VcpuState;
+use super::devices::virtio::p9::{FidState, ServerState};
+use super::devices::virtio::VirtioState;
+use super::vmm::{BlockConfig, DiskMode, MachineConfig, SharedDirConfig, VsockConfig};
+
+/// Raw content of the guest memory.
+pub const MEMORY_FILE: &str = "memory";
+/// State of the vCPUs and devices.
+pub const STATE_FILE: &str = "state";
+
+const MAGIC: &[u8; 8] = b"CLDSNAP\0";
+const VERSION: u32 = 1;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to read or write a snapshot file.
+    IO(io::Error),
+    /// The state file is truncated or corrupted.
+    InvalidState,
+    /// The snapshot was taken by an incompatible version of the VMM.
+    UnsupportedVersion(u32),
+    /// The VM has no running vCPU.
+    NotRunning,
+    /// A vCPU thread stopped answering.
+    VcpuGone(u64),
+    /// The VM uses a device whose state can't be saved.
+    UnsupportedDevice(&'static str),
+    /// Failed to map the memory file.
+    Mmap(MmapRegionError),
+    /// Failed to build the guest memory from the memory file.
+    Memory(vm_memory::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// How the guest memory is restored from [`MEMORY_FILE`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MemoryRestore {
+    /// The whole file is read before the VM starts.
+    Eager,
+    /// The file is mapped copy-on-write: a page is only read when the guest first touches it,
+    /// and the writes of the guest never reach the file.
+    #[default]
+    Lazy,
+}
+
+impl FromStr for MemoryRestore {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "eager" => Ok(MemoryRestore::Eager),
+            "lazy" => Ok(MemoryRestore::Lazy),
+            _ => Err(format!(
+                "invalid memory restore mode {}, expected eager or lazy",
+                s
+            )),
+        }
+    }
+}
+
+/// Write a snapshot file. The content goes to a temporary file first, renamed once complete:
+/// a VM lazily restored from the previous snapshot keeps mapping the previous file.
+fn write_file(path: &Path, write: impl FnOnce(&mut BufWriter<&File>) -> Result<()>) -> Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let file = File::create(&tmp_path).map_err(Error::IO)?;
+
+    let mut writer = BufWriter::new(&file);
+    write(&mut writer)?;
+    writer.flush().map_err(Error::IO)?;
+    drop(writer);
+
+    file.sync_all().map_err(Error::IO)?;
+    fs::rename(tmp_path, path).map_err(Error::IO)
+}
+
+/// State of the whole VM, apart from its memory.
+pub(crate) struct VmState {
+    pub machine: MachineConfig,
+    pub irqchips: Vec<kvm_irqchip>,
+    pub clock: kvm_clock_data,
+    pub vcpus: Vec<VcpuState>,
+    pub serial: SerialState,
+    pub slip_serial: SerialState,
+    pub net: Vec<VirtioState>,
+    pub block: Vec<VirtioState>,
+    pub p9: Vec<(VirtioState, ServerState)>,
+    pub vsock: Option<VirtioState>,
+}
+
+impl VmState {
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        let mut encoder = Encoder::default();
+        encoder.bytes(MAGIC);
+        VERSION.encode(&mut encoder);
+        self.encode(&mut encoder);
+
+        write_file(&dir.join(STATE_FILE), |writer| {
+            writer.write_all(&encoder.buf).map_err(Error::IO)
+        })
+    }
+
+    pub fn load(dir: &Path) -> Result<Self> {
+        let mut buf = Vec::new();
+        File::open(dir.join(STATE_FILE))
+            .and_then(|mut file| file.read_to_end(&mut buf))
+            .map_err(Error::IO)?;
+
+        let mut decoder = Decoder { buf: &buf };
+        if decoder.take(MAGIC.len())? != MAGIC {
+            return Err(Error::InvalidState);
+        }
+        let version = u32::decode(&mut decoder)?;
+        if version != VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+
+        VmState::decode(&mut decoder)
+    }
+}
+
+/// Write the content of the guest memory to `path`.
+pub(crate) fn save_memory(memory: &GuestMemoryMmap, path: &Path) -> Result<()> {
+    write_file(path, |writer| {
+        for region in memory.iter() {
+            memory
+                .write_all_to(region.start_addr(), writer, region.len() as usize)
+                .map_err(|_| Error::IO(io::Error::from(io::ErrorKind::UnexpectedEof)))?;
+        }
+        Ok(())
+    })
+}
+
+/// Fill the guest memory with the content of `path`.
+pub(crate) fn load_memory(memory: &GuestMemoryMmap, path: &Path) -> Result<()> {
+    let mut reader = BufReader::new(File::open(path).map_err(Error::IO)?);
+
+    for region in memory.iter() {
+        memory
+            .read_exact_from(region.start_addr(), &mut reader, region.len() as usize)
+            .map_err(|_| Error::IO(io::Error::from(io::ErrorKind::UnexpectedEof)))?;
+    }
+
+    Ok(())
+}
+
+/// Create the guest memory from `path`, mapped copy-on-write. The regions are laid out in the
+/// file one after the other, as written by [`save_memory`].
+pub(crate) fn map_memory(path: &Path, ranges: &[(GuestAddress, usize)]) -> Result<GuestMemoryMmap> {
+    let file = File::open(path).map_err(Error::IO)?;
+
+    // The pages mapped past the end of the file raise SIGBUS once the guest touches them.
+    let size: u64 = ranges.iter().map(|&(_, size)| size as u64).sum();
+    if file.metadata().map_err(Error::IO)?.len() != size {
+        return Err(Error::InvalidState);
+    }
+
+    let mut offset = 0;
+    let mut regions = Vec::with_capacity(ranges.len());
+    for &(start_addr, size) in ranges {
+        let file_offset = FileOffset::new(file.try_clone().map_err(Error::IO)?, offset);
+        let region = MmapRegionBuilder::new(size)
+            .with_mmap_prot(libc::PROT_READ | libc::PROT_WRITE)
+            .with_mmap_flags(libc::MAP_NORESERVE | libc::MAP_PRIVATE)
+            .with_file_offset(file_offset)
+            .build()
+            .map_err(Error::Mmap)?;
+
+        regions.push(GuestRegionMmap::new(region, start_addr).map_err(Error::Memory)?);
+        offset += size as u64;
+    }
+
+    GuestMemoryMmap::from_regions(regions).map_err(Error::Memory)
+}
+
+#[derive(Default)]
+pub(crate) struct Encoder {
+    buf: Vec<u8>,
+}
+
+impl Encoder {
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+}
+
+pub(crate) struct Decoder<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.buf.len() < len {
+            return Err(Error::InvalidState);
+        }
+        let (head, tail) = self.buf.split_at(len);
+        self.buf = tail;
+        Ok(head)
+    }
+}
+
+/// Binary encoding of the values saved in a snapshot.
+pub(crate) trait Persist: Sized {
+    fn encode(&self, encoder: &mut Encoder);
+    fn decode(decoder: &mut Decoder) -> Result<Self>;
+}
+
+macro_rules! persist_int {
+    ($($type:ty),*) => {
+        $(
+            impl Persist for $type {
+                fn encode(&self, encoder: &mut Encoder) {
+                    encoder.bytes(&self.to_le_bytes());
+                }
+
+                fn decode(decoder: &mut Decoder) -> Result<Self> {
+                    let bytes = decoder.take(std::mem::size_of::<Self>())?;
+                    Ok(Self::from_le_bytes(bytes.try_into().unwrap()))
+                }
+            }
+        )*
+    };
+}
+
+persist_int!(u8, u16, u32, u64, i32);
+
+// The KVM structures are plain data generated by bindgen, without pointers: they are saved as
+// their in-memory representation, the snapshot is only restored on the same host.
+macro_rules! persist_plain_data {
+    ($($type:ty),*) => {
+        $(
+            impl Persist for $type {
+                fn encode(&self, encoder: &mut Encoder) {
+                    // SAFETY: the structure is plain data, readable as bytes.
+                    let bytes = unsafe {
+                        std::slice::from_raw_parts(
+                            self as *const Self as *const u8,
+                            std::mem::size_of::<Self>(),
+                        )
+                    };
+                    encoder.bytes(bytes);
+                }
+
+                fn decode(decoder: &mut Decoder) -> Result<Self> {
+                    let bytes = decoder.take(std::mem::size_of::<Self>())?;
+                    // SAFETY: `bytes` holds `size_of::<Self>()` bytes, and any bit pattern is
+                    // a valid value of the structure.
+                    Ok(unsafe { std::ptr::read_unaligned(bytes.as_ptr() as *const Self) })
+                }
+            }
+        )*
+    };
+}
+
+persist_plain_data!(
+    kvm_clock_data,
+    kvm_cpuid_entry2,
+    kvm_debugregs,
+    kvm_irqchip,
+    kvm_lapic_state,
+    kvm_mp_state,
+    kvm_msr_entry,
+    kvm_regs,
+    kvm_sregs,
+    kvm_vcpu_events,
+    kvm_xcrs,
+    kvm_xsave
+);
+
+impl Persist for bool {
+    fn encode(&self, encoder: &mut Encoder) {
+        u8::from(*self).encode(encoder);
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<Self> {
+        Ok(u8::decode(decoder)? != 0)
+    }
+}
+
+impl<T: Persist> Persist for Vec<T> {
+    fn encode(&self, encoder: &mut Encoder) {
+        (self.len() as u64).encode(encoder);
+        for item in self {
+            item.encode(encoder);
+        }
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<Self> {
+        let len = u64::decode(decoder)?;
+        (0..len).map(|_| T::decode(decoder)).collect()
+    }
+}
+
+impl<T: Persist> Persist for Option<T> {
+    fn encode(&self, encoder: &mut Encoder) {
+        self.is_some().encode(encoder);
+        if let Some(value) = self {
+            value.encode(encoder);
+        }
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<Self> {
+        match bool::decode(decoder)? {
+            true => Ok(Some(T::decode(decoder)?)),
+            false => Ok(None),
+        }
+    }
+}
+
+impl<A: Persist, B: Persist> Persist for (A, B) {
+    fn encode(&self, encoder: &mut Encoder) {
+        self.0.encode(encoder);
+        self.1.encode(encoder);
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<Self> {
+        Ok((A::decode(decoder)?, B::decode(decoder)?))
+    }
+}
+
+impl Persist for String {
+    fn encode(&self, encoder: &mut Encoder) {
+        self.as_bytes().to_vec().encode(encoder);
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<Self> {
+        String::from_utf8(Vec::decode(decoder)?).map_err(|_| Error::InvalidState)
+    }
+}
+
+impl Persist for PathBuf {
+    fn encode(&self, encoder: &mut Encoder) {
+        use std::os::unix::ffi::OsStrExt;
+        self.as_os_str().as_bytes().to_vec().encode(encoder);
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<Self> {
+        use std::os::unix::ffi::OsStringExt;
+        Ok(PathBuf::from(std::ffi::OsString::from_vec(Vec::decode(
+            decoder,
+        )?)))
+    }
+}
+
+impl Persist for Ipv4Addr {
+    fn encode(&self, encoder: &mut Encoder) {
+        u32::from(*self).encode(encoder);
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<Self> {
+        Ok(Ipv4Addr::from(u32::decode(decoder)?))
+    }
+}
+
+/// Implement [`Persist`] for a structure by encoding its fields in order.
+macro_rules! persist_struct {
+    ($type:ty { $($field:ident),* $(,)? }) => {
+        impl Persist for $type {
+            fn encode(&self, encoder: &mut Encoder) {
+                $(self.$field.encode(encoder);)*
+            }
+
+            fn decode(decoder: &mut Decoder) -> Result<Self> {
+                Ok(Self {
+                    $($field: Persist::decode(decoder)?,)*
+                })
+            }
+        }
+    };
+}
+
+persist_struct!(VmState {
+    machine,
+    irqchips,
+    clock,
+    vcpus,
+    serial,
+    slip_serial,
+    net,
+    block,
+    p9,
+    vsock,
+});
+
+persist_struct!(MachineConfig {
+    num_vcpus,
+    mem_size_mb,
+    iface_host_addr,
+    netmask,
+    iface_guest_addr,
+    disks,
+    shared_dirs,
+    vsock,
+});
+
+persist_struct!(BlockConfig { path, mode, root });
+persist_struct!(SharedDirConfig { tag, path });
+persist_struct!(VsockConfig {
+    guest_cid,
+    uds_path
+});
+
+impl Persist for DiskMode {
+    fn encode(&self, encoder: &mut Encoder) {
+        let mode: u8 = match self {
+            DiskMode::ReadWrite => 0,
+            DiskMode::ReadOnly => 1,
+            DiskMode::CopyOnWrite => 2,
+        };
+        mode.encode(encoder);
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<Self> {
+        match u8::decode(decoder)? {
+            0 => Ok(DiskMode::ReadWrite),
+            1 => Ok(DiskMode::ReadOnly),
+            2 => Ok(DiskMode::CopyOnWrite),
+            _ => Err(Error::InvalidState),
+        }
+    }
+}
+
+persist_struct!(VcpuState {
+    cpuid,
+    msrs,
+    mp_state,
+    regs,
+    sregs,
+    xsave,
+    xcrs,
+    debug_regs,
+    lapic,
+    vcpu_events,
+});
+
+persist_struct!(VirtioState {
+    device_features,
+    driver_features,
+    device_features_select,
+    driver_features_select,
+    device_status,
+    queue_select,
+    config_generation,
+    config_space,
+    interrupt_status,
+    activated,
+    queues,
+});
+
+persist_struct!(QueueState {
+    max_size,
+    next_avail,
+    next_used,
+    event_idx_enabled,
+    size,
+    ready,
+    desc_table,
+    avail_ring,
+    used_ring,
+});
+
+persist_struct!(SerialState {
+    baud_divisor_low,
+    baud_divisor_high,
+    interrupt_enable,
+    interrupt_identification,
+    line_control,
+    line_status,
+    modem_control,
+    modem_status,
+    scratch,
+    in_buffer,
+});
+
+persist_struct!(ServerState { msize, fids });
+persist_struct!(FidState {
+    fid,
+    path,
+    open_flags
+});
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kvm_bindings::KVM_IRQCHIP_IOAPIC;
+
+    /// Directory removed once dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("snapshot-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn encoded<T: Persist>(value: &T) -> Vec<u8> {
+        let mut encoder = Encoder::default();
+        value.encode(&mut encoder);
+        encoder.buf
+    }
+
+    fn virtio_state() -> VirtioState {
+        VirtioState {
+            device_features: 1 << 32,
+            driver_features: 1 << 32,
+            device_features_select: 1,
+            driver_features_select: 1,
+            device_status: 0xf,
+            queue_select: 2,
+            config_generation: 0,
+            config_space: vec![3, 0, 0, 0, 0, 0, 0, 0],
+            interrupt_status: 1,
+            activated: true,
+            queues: vec![QueueState {
+                size: 256,
+                ready: true,
+                desc_table: 0x1000,
+                avail_ring: 0x2000,
+                used_ring: 0x3000,
+                next_avail: 7,
+                next_used: 5,
+                ..Default::default()
+            }],
+        }
+    }
+
+    fn vm_state() -> VmState {
+        VmState {
+            machine: MachineConfig {
+                num_vcpus: 2,
+                mem_size_mb: 128,
+                iface_host_addr: Ipv4Addr::new(172, 29, 0, 1),
+                netmask: Ipv4Addr::new(255, 255, 0, 0),
+                iface_guest_addr: Ipv4Addr::new(172, 29, 0, 2),
+                disks: vec![BlockConfig {
+                    path: PathBuf::from("/var/lib/disk.img"),
+                    mode: DiskMode::ReadOnly,
+                    root: true,
+                }],
+                shared_dirs: vec![SharedDirConfig {
+                    tag: "cache".to_string(),
+                    path: PathBuf::from("/var/cache"),
+                }],
+                vsock: Some(VsockConfig {
+                    guest_cid: 3,
+                    uds_path: PathBuf::from("/tmp/vm.sock"),
+                }),
+            },
+            irqchips: vec![kvm_irqchip {
+                chip_id: KVM_IRQCHIP_IOAPIC,
+                ..Default::default()
+            }],
+            clock: kvm_clock_data {
+                clock: 42,
+                ..Default::default()
+            },
+            vcpus: Vec::new(),
+            serial: SerialState::default(),
+            slip_serial: SerialState {
+                in_buffer: b"pending".to_vec(),
+                ..Default::default()
+            },
+            net: vec![virtio_state()],
+            block: Vec::new(),
+            p9: vec![(
+                virtio_state(),
+                ServerState {
+                    msize: 8192,
+                    fids: vec![FidState {
+                        fid: 1,
+                        path: PathBuf::from("dir/file"),
+                        open_flags: Some(libc::O_RDWR),
+                    }],
+                },
+            )],
+            vsock: None,
+        }
+    }
+
+    #[test]
+    fn encode_decode() {
+        let state = virtio_state();
+        let bytes = encoded(&state);
+
+        let mut decoder = Decoder { buf: &bytes };
+        let decoded = VirtioState::decode(&mut decoder).unwrap();
+        assert!(decoder.buf.is_empty());
+        assert_eq!(encoded(&decoded), bytes);
+        assert_eq!(decoded.queues[0].next_avail, 7);
+        assert_eq!(decoded.config_space, state.config_space);
+
+        // Every prefix is truncated.
+        for len in 0..bytes.len() {
+            let mut decoder = Decoder { buf: &bytes[..len] };
+            assert!(matches!(
+                VirtioState::decode(&mut decoder),
+                Err(Error::InvalidState)
+            ));
+        }
+    }
+
+    #[test]
+    fn save_load_state() {
+        let dir = TempDir::new("state");
+        let state = vm_state();
+
+        state.save(&dir.0).unwrap();
+        let loaded = VmState::load(&dir.0).unwrap();
+
+        assert_eq!(encoded(&loaded), encoded(&state));
+        assert_eq!(loaded.machine.disks[0].mode, DiskMode::ReadOnly);
+        assert_eq!(loaded.p9[0].1.fids[0].path, Path::new("dir/file"));
+        assert_eq!(loaded.slip_serial.in_buffer, b"pending");
+        // The temporary file is renamed once written.
+        assert!(!dir.0.join(STATE_FILE).with_extension("tmp").exists());
+    }
+
+    #[test]
+    fn load_invalid_state() {
+        let dir = TempDir::new("invalid-state");
+        let state_file = dir.0.join(STATE_FILE);
+        vm_state().save(&dir.0).unwrap();
+        let bytes = fs::read(&state_file).unwrap();
+
+        fs::write(&state_file, &bytes[..bytes.len() - 1]).unwrap();
+        assert!(matches!(VmState::load(&dir.0), Err(Error::InvalidState)));
+
+        let mut wrong_magic = bytes.clone();
+        wrong_magic[0] ^= 0xff;
+        fs::write(&state_file, wrong_magic).unwrap();
+        assert!(matches!(VmState::load(&dir.0), Err(Error::InvalidState)));
+
+        let mut wrong_version = bytes;
+        wrong_version[MAGIC.len()..MAGIC.len() + 4].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        fs::write(&state_file, wrong_version).unwrap();
+        assert!(matches!(
+            VmState::load(&dir.0),
+            Err(Error::UnsupportedVersion(version)) if version == VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn save_restore_memory() {
+        let dir = TempDir::new("memory");
+        let memory_file = dir.0.join(MEMORY_FILE);
+        let ranges = [(GuestAddress(0), 0x1000), (GuestAddress(0x4000), 0x2000)];
+
+        let memory = GuestMemoryMmap::from_ranges(&ranges).unwrap();
+        memory.write_slice(b"first", GuestAddress(0x10)).unwrap();
+        memory.write_slice(b"second", GuestAddress(0x5ff0)).unwrap();
+        save_memory(&memory, &memory_file).unwrap();
+
+        let read = |memory: &GuestMemoryMmap, addr: u64, len: usize| {
+            let mut buf = vec![0; len];
+            memory.read_slice(&mut buf, GuestAddress(addr)).unwrap();
+            buf
+        };
+
+        let loaded = GuestMemoryMmap::from_ranges(&ranges).unwrap();
+        load_memory(&loaded, &memory_file).unwrap();
+        assert_eq!(read(&loaded, 0x10, 5), b"first");
+        assert_eq!(read(&loaded, 0x5ff0, 6), b"second");
+
+        let mapped = map_memory(&memory_file, &ranges).unwrap();
+        assert_eq!(read(&mapped, 0x10, 5), b"first");
+        assert_eq!(read(&mapped, 0x5ff0, 6), b"second");
+
+        // The writes of the guest don't reach the file.
+        mapped.write_slice(b"guest", GuestAddress(0x10)).unwrap();
+        let map_again = map_memory(&memory_file, &ranges).unwrap();
+        assert_eq!(read(&map_again, 0x10, 5), b"first");
+
+        // The file doesn't match the layout of the memory.
+        assert!(matches!(
+            map_memory(&memory_file, &[(GuestAddress(0), 0x1000)]),
+            Err(Error::InvalidState)
+        ));
+    }
+}
@@ -1,33 +1,53 @@
 // SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
 
-use crate::core::cpu::{self, cpuid, mptable, Vcpu};
+use crate::core::cpu::{self, cpuid, mptable, Vcpu, VcpuRequest, VcpuResponse, VcpuState};
+use crate::core::devices::console::ConsoleWriter;
 use crate::core::devices::serial::LumperSerial;
 use crate::core::epoll_context::{EpollContext, EPOLL_EVENTS_LEN};
-use crate::core::kernel;
+use crate::core::kernel::{self, KernelEntry};
+use crate::core::snapshot::{self, VmState};
 use crate::core::{Error, Result};
 use event_manager::{EventManager, MutEventSubscriber};
-use kvm_bindings::{kvm_userspace_memory_region, KVM_MAX_CPUID_ENTRIES};
+use kvm_bindings::{
+    kvm_irqchip, kvm_userspace_memory_region, KVM_IRQCHIP_IOAPIC, KVM_IRQCHIP_PIC_MASTER,
+    KVM_IRQCHIP_PIC_SLAVE, KVM_MAX_CPUID_ENTRIES,
+};
 use kvm_ioctls::{Kvm, VmFd};
-use linux_loader::loader::KernelLoaderResult;
-use std::io::{self, stdout, Stdout};
+use std::io;
 use std::net::Ipv4Addr;
 use std::os::unix::io::AsRawFd;
 use std::os::unix::prelude::RawFd;
-use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
-use std::thread;
-use tracing::{error, info};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tracing::{error, info, warn};
 use vm_allocator::{AddressAllocator, AllocPolicy};
 use vm_device::bus::{MmioAddress, MmioRange};
 use vm_device::device_manager::IoManager;
 use vm_memory::{Address, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
+use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
+use vmm_sys_util::signal::Killable;
 use vmm_sys_util::terminal::Terminal;
 
+use super::devices::virtio::block::device::Block;
 use super::devices::virtio::net::device::Net;
+use super::devices::virtio::p9::device::P9;
+use super::devices::virtio::vsock::device::Vsock;
 use super::devices::virtio::{self, MmioConfig};
 use super::irq_allocator::IrqAllocator;
 use super::slip_pty::SlipPty;
 
+pub use super::boot_timer::{BootPhase, BootTimer, BOOT_TIMER_PORT};
+pub use super::cpu::VmExit;
+pub use super::devices::console::{ConsoleBuffer, ConsoleConfig, ConsoleOutput};
+pub use super::devices::virtio::block::{BlockConfig, DiskMode};
+pub use super::devices::virtio::p9::SharedDirConfig;
+pub use super::devices::virtio::vsock::VsockConfig;
+pub use super::kernel::DEFAULT_CMDLINE;
+pub use super::snapshot::MemoryRestore;
+
 #[cfg(target_arch = "x86_64")]
 pub(crate) const MMIO_GAP_END: u64 = 1 << 34;
 /// Size of the MMIO gap.
@@ -45,9 +65,111 @@ pub const DEFAULT_ALLOC_POLICY: AllocPolicy = AllocPolicy::FirstMatch;
 const SERIAL_IRQ: u32 = 4;
 /// Last usable IRQ ID for virtio device interrupts on x86_64.
 const IRQ_MAX: u8 = 23;
+/// Delay between two kicks of a vCPU which has not paused yet.
+const VCPU_KICK_INTERVAL: Duration = Duration::from_millis(10);
+/// How long the event manager waits for events before checking whether the VM stopped.
+const EVENT_LOOP_TIMEOUT_MS: i32 = 100;
 
 type EventMgr = Arc<Mutex<EventManager<Arc<Mutex<dyn MutEventSubscriber + Send>>>>>;
 
+/// Handles the events of the devices on its own thread. The loop only releases the event
+/// manager between two rounds and takes it again right away, so the others go through
+/// [`EventLoop::hold`] rather than competing for the lock.
+struct EventLoop {
+    event_mgr: EventMgr,
+    control: Mutex<EventLoopControl>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct EventLoopControl {
+    /// Number of callers holding the loop off.
+    holders: usize,
+    stopped: bool,
+}
+
+impl EventLoop {
+    fn new(event_mgr: EventMgr) -> Self {
+        EventLoop {
+            event_mgr,
+            control: Mutex::new(EventLoopControl::default()),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// Handle events until [`EventLoop::stop`].
+    fn run(&self) {
+        loop {
+            let control = self
+                .changed
+                .wait_while(self.control.lock().unwrap(), |control| {
+                    control.holders > 0 && !control.stopped
+                })
+                .unwrap();
+            if control.stopped {
+                return;
+            }
+            drop(control);
+
+            if let Err(e) = self
+                .event_mgr
+                .lock()
+                .unwrap()
+                .run_with_timeout(EVENT_LOOP_TIMEOUT_MS)
+            {
+                eprintln!("Failed to handle events: {:?}", e);
+            }
+        }
+    }
+
+    fn stop(&self) {
+        self.control.lock().unwrap().stopped = true;
+        self.changed.notify_all();
+    }
+
+    /// Run `f` while no event is handled.
+    fn hold<T>(&self, f: impl FnOnce() -> T) -> T {
+        self.control.lock().unwrap().holders += 1;
+        let event_mgr = self.event_mgr.lock().unwrap();
+        let result = f();
+        drop(event_mgr);
+
+        self.control.lock().unwrap().holders -= 1;
+        self.changed.notify_all();
+        result
+    }
+}
+
+/// Regions of the guest memory.
+fn memory_ranges(mem_size_mb: u32) -> Vec<(GuestAddress, usize)> {
+    // Convert memory size from MBytes to bytes.
+    let mem_size = ((mem_size_mb as u64) << 20) as usize;
+
+    // Create one single memory region, from zero to mem_size.
+    vec![(GuestAddress(0), mem_size)]
+}
+
+/// Description of the VM, enough to create it again from a snapshot.
+#[derive(Clone, Debug)]
+pub struct MachineConfig {
+    pub num_vcpus: u8,
+    pub mem_size_mb: u32,
+    pub iface_host_addr: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    pub iface_guest_addr: Ipv4Addr,
+    pub disks: Vec<BlockConfig>,
+    pub shared_dirs: Vec<SharedDirConfig>,
+    pub vsock: Option<VsockConfig>,
+}
+
+/// A running vCPU thread.
+struct VcpuHandle {
+    index: u64,
+    requests: Sender<VcpuRequest>,
+    responses: Receiver<VcpuResponse>,
+    thread: JoinHandle<()>,
+}
+
 pub struct VMM {
     vm_fd: Arc<VmFd>,
     kvm: Kvm,
@@ -56,15 +178,28 @@ pub struct VMM {
     irq_allocator: IrqAllocator,
     device_mgr: Arc<Mutex<IoManager>>,
     event_mgr: EventMgr,
+    event_loop: Arc<EventLoop>,
+    /// Runs the event loop, from the start of the VM until it stops.
+    event_thread: Option<JoinHandle<()>>,
     vcpus: Vec<Vcpu>,
 
     iface_host_addr: Ipv4Addr,
     netmask: Ipv4Addr,
     iface_guest_addr: Ipv4Addr,
     net_devices: Vec<Arc<Mutex<Net>>>,
-    serial: Arc<Mutex<LumperSerial<Stdout>>>,
+    block_devices: Vec<Arc<Mutex<Block>>>,
+    p9_devices: Vec<Arc<Mutex<P9>>>,
+    vsock_device: Option<Arc<Mutex<Vsock>>>,
+    serial: Arc<Mutex<LumperSerial<ConsoleWriter>>>,
     slip_pty: Arc<Mutex<SlipPty>>,
     epoll: EpollContext,
+    /// Whether the standard input is forwarded to the guest, see [`ConsoleConfig::stdin`].
+    attach_stdin: bool,
+    machine: Option<MachineConfig>,
+    vcpu_handles: Arc<Mutex<Vec<VcpuHandle>>>,
+    /// Written by a vCPU thread when it stops running the guest.
+    exit_evt: EventFd,
+    boot_timer: BootTimer,
 }
 
 impl VMM {
@@ -73,7 +208,10 @@ impl VMM {
         iface_host_addr: Ipv4Addr,
         netmask: Ipv4Addr,
         iface_guest_addr: Ipv4Addr,
+        console: ConsoleConfig,
     ) -> Result<Self> {
+        let boot_timer = BootTimer::default();
+
         // Open /dev/kvm and get a file descriptor to it.
         let kvm = Kvm::new().map_err(Error::KvmIoctl)?;
 
@@ -84,7 +222,9 @@ impl VMM {
         let slip_pty = SlipPty::new()?;
 
         let epoll = EpollContext::new().map_err(Error::EpollError)?;
-        epoll.add_stdin().map_err(Error::EpollError)?;
+        if console.stdin {
+            epoll.add_stdin().map_err(Error::EpollError)?;
+        }
         epoll
             .add_fd(
                 slip_pty.pty_master_fd(),
@@ -98,8 +238,14 @@ impl VMM {
             )
             .map_err(Error::EpollError)?;
 
+        let exit_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::IO)?;
+        epoll
+            .add_fd(exit_evt.as_raw_fd(), epoll::Events::EPOLLIN)
+            .map_err(Error::EpollError)?;
+
         let irq_allocator = IrqAllocator::new(SERIAL_IRQ, IRQ_MAX.into()).unwrap();
         let device_mgr = Arc::new(Mutex::new(IoManager::new()));
+        let event_mgr = Arc::new(Mutex::new(EventManager::new().unwrap()));
 
         let vmm = VMM {
             vm_fd,
@@ -108,32 +254,51 @@ impl VMM {
             address_allocator: None,
             device_mgr,
             irq_allocator,
-            event_mgr: Arc::new(Mutex::new(EventManager::new().unwrap())),
+            event_loop: Arc::new(EventLoop::new(event_mgr.clone())),
+            event_thread: None,
+            event_mgr,
             vcpus: vec![],
             serial: Arc::new(Mutex::new(
-                LumperSerial::new(stdout()).map_err(Error::SerialCreation)?,
+                LumperSerial::new(console.output.open().map_err(Error::Console)?)
+                    .map_err(Error::SerialCreation)?,
             )),
             slip_pty: Arc::new(Mutex::new(slip_pty)),
             epoll,
+            attach_stdin: console.stdin,
             iface_host_addr,
             netmask,
             iface_guest_addr,
             net_devices: Vec::new(),
+            block_devices: Vec::new(),
+            p9_devices: Vec::new(),
+            vsock_device: None,
+            machine: None,
+            vcpu_handles: Arc::new(Mutex::new(Vec::new())),
+            exit_evt,
+            boot_timer,
         };
+        vmm.boot_timer.record(BootPhase::VmmCreated);
 
         Ok(vmm)
     }
 
     fn configure_memory(&mut self, mem_size_mb: u32) -> Result<()> {
-        // Convert memory size from MBytes to bytes.
-        let mem_size = ((mem_size_mb as u64) << 20) as usize;
+        // Allocate the guest memory from the memory regions.
+        let guest_memory =
+            GuestMemoryMmap::from_ranges(&memory_ranges(mem_size_mb)).map_err(Error::Memory)?;
 
-        // Create one single memory region, from zero to mem_size.
-        let mem_regions = vec![(GuestAddress(0), mem_size)];
+        self.register_memory(guest_memory)
+    }
+
+    /// Use the memory file of a snapshot as the guest memory, see [`MemoryRestore::Lazy`].
+    fn configure_snapshot_memory(&mut self, mem_size_mb: u32, memory_file: &Path) -> Result<()> {
+        let guest_memory = snapshot::map_memory(memory_file, &memory_ranges(mem_size_mb))
+            .map_err(Error::Snapshot)?;
 
-        // Allocate the guest memory from the memory region.
-        let guest_memory = GuestMemoryMmap::from_ranges(&mem_regions).map_err(Error::Memory)?;
+        self.register_memory(guest_memory)
+    }
 
+    fn register_memory(&mut self, guest_memory: GuestMemoryMmap) -> Result<()> {
         // For each memory region in guest_memory:
         // 1. Create a KVM memory region mapping the memory region guest physical address to the host virtual address.
         // 2. Register the KVM memory region with KVM. EPTs are created then.
@@ -153,6 +318,7 @@ impl VMM {
         }
 
         self.guest_memory = guest_memory;
+        self.boot_timer.record(BootPhase::MemorySetUp);
 
         Ok(())
     }
@@ -211,10 +377,34 @@ impl VMM {
                 .map_err(Error::KvmIoctl)?;
         }
 
+        for block in self.block_devices.iter() {
+            let block_cfg = &block.lock().unwrap().config;
+
+            self.vm_fd
+                .register_irqfd(&block_cfg.irqfd, block_cfg.mmio.gsi)
+                .map_err(Error::KvmIoctl)?;
+        }
+
+        for p9 in self.p9_devices.iter() {
+            let p9_cfg = &p9.lock().unwrap().config;
+
+            self.vm_fd
+                .register_irqfd(&p9_cfg.irqfd, p9_cfg.mmio.gsi)
+                .map_err(Error::KvmIoctl)?;
+        }
+
+        if let Some(vsock) = &self.vsock_device {
+            let vsock_cfg = &vsock.lock().unwrap().config;
+
+            self.vm_fd
+                .register_irqfd(&vsock_cfg.irqfd, vsock_cfg.mmio.gsi)
+                .map_err(Error::KvmIoctl)?;
+        }
+
         Ok(())
     }
 
-    fn configure_vcpus(&mut self, num_vcpus: u8, kernel_load: KernelLoaderResult) -> Result<()> {
+    fn configure_vcpus(&mut self, num_vcpus: u8, kernel_entry: KernelEntry) -> Result<()> {
         mptable::setup_mptable(&self.guest_memory, num_vcpus)
             .map_err(|e| Error::Vcpu(cpu::Error::Mptable(e)))?;
 
@@ -222,16 +412,10 @@ impl VMM {
             .kvm
             .get_supported_cpuid(KVM_MAX_CPUID_ENTRIES)
             .map_err(Error::KvmIoctl)?;
+        let msr_indices = self.msr_indices()?;
 
         for index in 0..num_vcpus {
-            let vcpu = Vcpu::new(
-                &self.vm_fd,
-                index.into(),
-                self.device_mgr.clone(),
-                Arc::clone(&self.serial),
-                Arc::clone(&self.slip_pty),
-            )
-            .map_err(Error::Vcpu)?;
+            let vcpu = self.create_vcpu(index.into(), msr_indices.clone())?;
 
             // Set CPUID.
             let mut vcpu_cpuid = base_cpuid.clone();
@@ -247,9 +431,8 @@ impl VMM {
             vcpu.configure_msrs().map_err(Error::Vcpu)?;
 
             // Configure regs, sregs and fpu.
-            vcpu.configure_regs(kernel_load.kernel_load)
-                .map_err(Error::Vcpu)?;
-            vcpu.configure_sregs(&self.guest_memory)
+            vcpu.configure_regs(kernel_entry).map_err(Error::Vcpu)?;
+            vcpu.configure_sregs(&self.guest_memory, kernel_entry.protocol)
                 .map_err(Error::Vcpu)?;
             vcpu.configure_fpu().map_err(Error::Vcpu)?;
 
@@ -262,32 +445,113 @@ impl VMM {
         Ok(())
     }
 
-    /// Run all virtual CPUs.
-    pub fn run(&mut self) -> Result<()> {
+    /// MSRs supported by KVM, saved in snapshots.
+    fn msr_indices(&self) -> Result<Vec<u32>> {
+        Ok(self
+            .kvm
+            .get_msr_index_list()
+            .map_err(Error::KvmIoctl)?
+            .as_slice()
+            .to_vec())
+    }
+
+    fn create_vcpu(&self, index: u64, msr_indices: Vec<u32>) -> Result<Vcpu> {
+        Vcpu::new(
+            &self.vm_fd,
+            index,
+            self.device_mgr.clone(),
+            Arc::clone(&self.serial),
+            Arc::clone(&self.slip_pty),
+            self.boot_timer.clone(),
+            msr_indices,
+        )
+        .map_err(Error::Vcpu)
+    }
+
+    /// Run all virtual CPUs, until the guest shuts down or the VM is stopped with
+    /// [`VmHandle::stop`]. The vCPU and event threads are joined before returning, so dropping
+    /// the VMM afterwards releases the guest memory, the TAP devices and the other fds.
+    pub fn run(&mut self) -> Result<VmExit> {
+        cpu::register_kick_signal_handler().map_err(Error::Vcpu)?;
+
+        let (exit_sender, exits) = mpsc::channel();
         for mut vcpu in self.vcpus.drain(..) {
             info!(vcpu_index = vcpu.index, "Starting vCPU");
-            let _ = thread::Builder::new().spawn(move || loop {
-                vcpu.run();
+            let index = vcpu.index;
+            let (requests, vcpu_requests) = mpsc::channel();
+            let (vcpu_responses, responses) = mpsc::channel();
+            let exit_sender = exit_sender.clone();
+            let exit_evt = self.exit_evt.try_clone().map_err(Error::IO)?;
+
+            let thread = thread::Builder::new()
+                .name(format!("vcpu{}", index))
+                .spawn(move || {
+                    let exit = vcpu.run_loop(vcpu_requests, vcpu_responses);
+                    let _ = exit_sender.send((index, exit));
+                    let _ = exit_evt.write(1);
+                })
+                .map_err(Error::IO)?;
+
+            self.vcpu_handles.lock().unwrap().push(VcpuHandle {
+                index,
+                requests,
+                responses,
+                thread,
             });
         }
 
-        let stdin = io::stdin();
-        let stdin_lock = stdin.lock();
-        stdin_lock
-            .set_raw_mode()
-            .map_err(Error::TerminalConfigure)?;
+        let stdin_lock = if self.attach_stdin {
+            let stdin_lock = io::stdin().lock();
+            stdin_lock
+                .set_raw_mode()
+                .map_err(Error::TerminalConfigure)?;
+            Some(stdin_lock)
+        } else {
+            None
+        };
+
+        self.start_event_thread()?;
+
+        let result = self.run_event_loop(stdin_lock.as_ref(), &exits);
+
+        VmHandle::stop_vcpus(&mut self.vcpu_handles.lock().unwrap(), true);
+        self.stop_event_thread();
+        if let Some(Err(err)) = stdin_lock.map(|stdin_lock| stdin_lock.set_canon_mode()) {
+            warn!(?err, "Failed to restore the terminal");
+        }
+
+        result
+    }
+
+    /// Start handling the events of the devices, if not done yet. The handlers of the devices
+    /// are registered by the event thread, so it must run before a device is activated.
+    fn start_event_thread(&mut self) -> Result<()> {
+        if self.event_thread.is_none() {
+            let event_loop = self.event_loop.clone();
+            let thread = thread::Builder::new()
+                .spawn(move || event_loop.run())
+                .map_err(Error::IO)?;
+            self.event_thread = Some(thread);
+        }
+        Ok(())
+    }
+
+    fn stop_event_thread(&mut self) {
+        if let Some(thread) = self.event_thread.take() {
+            self.event_loop.stop();
+            let _ = thread.join();
+        }
+    }
+
+    /// Forward the input of the terminal and of the SLIP pty to the guest, until a vCPU exits.
+    fn run_event_loop(
+        &mut self,
+        stdin_lock: Option<&io::StdinLock>,
+        exits: &Receiver<(u64, VmExit)>,
+    ) -> Result<VmExit> {
         let mut events = [epoll::Event::new(epoll::Events::empty(), 0); EPOLL_EVENTS_LEN];
         let epoll_fd = self.epoll.as_raw_fd();
 
-        let event_mgr = self.event_mgr.clone();
-        let _ = thread::Builder::new().spawn(move || loop {
-            match event_mgr.lock().unwrap().run() {
-                Ok(_) => (),
-                Err(e) => eprintln!("Failed to handle events: {:?}", e),
-            }
-        });
-
-        // Let's start the STDIN polling thread.
         loop {
             let num_events =
                 epoll::wait(epoll_fd, -1, &mut events[..]).map_err(Error::EpollError)?;
@@ -296,7 +560,7 @@ impl VMM {
                 let event_evts = epoll::Events::from_bits_truncate(event.events);
                 let event_data = event.data as RawFd;
 
-                if let libc::STDIN_FILENO = event_data {
+                if let Some(stdin_lock) = stdin_lock.filter(|_| event_data == libc::STDIN_FILENO) {
                     let mut out = [0u8; 64];
 
                     let count = stdin_lock.read_raw(&mut out).map_err(Error::StdinRead)?;
@@ -307,6 +571,13 @@ impl VMM {
                         .serial
                         .enqueue_raw_bytes(&out[..count])
                         .map_err(Error::StdinWrite)?;
+                } else if event_data == self.exit_evt.as_raw_fd() {
+                    let _ = self.exit_evt.read();
+
+                    if let Ok((vcpu_index, exit)) = exits.try_recv() {
+                        info!(vcpu_index, ?exit, "vCPU exited, stopping the VM");
+                        return Ok(exit);
+                    }
                 } else if event_evts.intersects(epoll::Events::EPOLLIN)
                     && event_data == self.slip_pty.lock().unwrap().pty_master_fd()
                 {
@@ -336,39 +607,209 @@ impl VMM {
     /// * `mem_size_mb` Memory size (in MB)
     /// * `kernel_path` Path to a Linux kernel
     /// * `initramfs_path` Path to an initramfs
+    /// * `disks` Disk images exposed as virtio-blk devices
+    /// * `shared_dirs` Host directories shared with the guest
+    /// * `vsock_config` Configuration of the vsock device, if any
+    /// * `cmdline` Base of the kernel command line, [`DEFAULT_CMDLINE`] if not set
+    #[allow(clippy::too_many_arguments)]
     pub async fn configure(
         &mut self,
         num_vcpus: u8,
         mem_size_mb: u32,
         kernel_path: PathBuf,
         initramfs_path: &Option<PathBuf>,
+        disks: Vec<BlockConfig>,
+        shared_dirs: Vec<SharedDirConfig>,
+        vsock_config: Option<VsockConfig>,
+        cmdline: Option<String>,
     ) -> Result<()> {
         let cmdline_extra_parameters = &mut Vec::new();
+        let machine = MachineConfig {
+            num_vcpus,
+            mem_size_mb,
+            iface_host_addr: self.iface_host_addr,
+            netmask: self.netmask,
+            iface_guest_addr: self.iface_guest_addr,
+            disks,
+            shared_dirs,
+            vsock: vsock_config,
+        };
 
         self.configure_memory(mem_size_mb)?;
         self.configure_allocators(mem_size_mb)?;
-        self.configure_net_device(cmdline_extra_parameters).await?;
+        self.configure_devices(&machine, cmdline_extra_parameters)
+            .await?;
 
-        let kernel_load = kernel::kernel_setup(
+        let kernel_entry = kernel::kernel_setup(
             &self.guest_memory,
             kernel_path,
             initramfs_path.clone(),
+            cmdline.as_deref(),
             cmdline_extra_parameters,
         )?;
+        self.boot_timer.record(BootPhase::KernelLoaded);
         self.configure_io()?;
-        self.configure_vcpus(num_vcpus, kernel_load)?;
+        self.configure_vcpus(num_vcpus, kernel_entry)?;
+        self.machine = Some(machine);
 
         Ok(())
     }
 
-    pub async fn configure_net_device(
+    /// Create the devices of the VM. The devices are always created in the same order, so a
+    /// VM restored from a snapshot gets the same MMIO ranges and interrupts.
+    async fn configure_devices(
         &mut self,
+        machine: &MachineConfig,
         cmdline_extra_parameters: &mut Vec<String>,
     ) -> Result<()> {
-        let mem = Arc::new(self.guest_memory.clone());
-        let range = if let Some(allocator) = &self.address_allocator {
+        self.configure_net_device(cmdline_extra_parameters).await?;
+        for disk in machine.disks.iter() {
+            self.configure_block_device(disk, cmdline_extra_parameters)?;
+        }
+        for shared_dir in machine.shared_dirs.iter() {
+            self.configure_shared_dir(shared_dir, cmdline_extra_parameters)?;
+        }
+        if let Some(vsock_config) = &machine.vsock {
+            self.configure_vsock_device(vsock_config, cmdline_extra_parameters)?;
+        }
+
+        Ok(())
+    }
+
+    /// Create a VMM from a snapshot taken by [`VmHandle::snapshot`]. The VM resumes where it
+    /// was once [`VMM::run`] is called.
+    pub async fn restore(
+        snapshot_dir: &Path,
+        memory_restore: MemoryRestore,
+        console: ConsoleConfig,
+    ) -> Result<Self> {
+        let state = VmState::load(snapshot_dir).map_err(Error::Snapshot)?;
+        let machine = state.machine.clone();
+
+        let mut vmm = VMM::new(
+            machine.iface_host_addr,
+            machine.netmask,
+            machine.iface_guest_addr,
+            console.clone(),
+        )?;
+
+        let memory_file = snapshot_dir.join(snapshot::MEMORY_FILE);
+        match memory_restore {
+            MemoryRestore::Eager => {
+                vmm.configure_memory(machine.mem_size_mb)?;
+                snapshot::load_memory(&vmm.guest_memory, &memory_file).map_err(Error::Snapshot)?;
+            }
+            MemoryRestore::Lazy => {
+                vmm.configure_snapshot_memory(machine.mem_size_mb, &memory_file)?;
+            }
+        }
+        vmm.configure_allocators(machine.mem_size_mb)?;
+
+        // The guest already knows its devices, the kernel command line is not used anymore.
+        vmm.configure_devices(&machine, &mut Vec::new()).await?;
+        vmm.configure_io()?;
+
+        for irqchip in state.irqchips.iter() {
+            vmm.vm_fd.set_irqchip(irqchip).map_err(Error::KvmIoctl)?;
+        }
+
+        let msr_indices = vmm.msr_indices()?;
+        for (index, vcpu_state) in state.vcpus.iter().enumerate() {
+            let vcpu = vmm.create_vcpu(index as u64, msr_indices.clone())?;
+            vcpu.restore_state(vcpu_state).map_err(Error::Vcpu)?;
+            vmm.vcpus.push(vcpu);
+        }
+
+        // The flags only describe how the clock was read.
+        let mut clock = state.clock;
+        clock.flags = 0;
+        vmm.vm_fd.set_clock(&clock).map_err(Error::KvmIoctl)?;
+
+        vmm.serial
+            .lock()
+            .unwrap()
+            .restore(
+                &state.serial,
+                console.output.open().map_err(Error::Console)?,
+            )
+            .map_err(Error::SerialCreation)?;
+        vmm.slip_pty
+            .lock()
+            .unwrap()
+            .restore_serial(&state.slip_serial)?;
+
+        // The devices activated by the guest register their handlers with the event thread.
+        vmm.start_event_thread()?;
+        vmm.restore_devices(&state)?;
+        vmm.machine = Some(machine);
+
+        info!("Restored VM from {}", snapshot_dir.display());
+
+        Ok(vmm)
+    }
+
+    fn restore_devices(&self, state: &VmState) -> Result<()> {
+        for (net, net_state) in self.net_devices.iter().zip(state.net.iter()) {
+            net.lock().unwrap().restore(net_state).map_err(|err| {
+                error!("could not restore Net device: {:?}", err);
+                Error::Virtio(virtio::Error::Net)
+            })?;
+        }
+
+        for (block, block_state) in self.block_devices.iter().zip(state.block.iter()) {
+            block.lock().unwrap().restore(block_state).map_err(|err| {
+                error!("could not restore block device: {:?}", err);
+                Error::Virtio(virtio::Error::Block)
+            })?;
+        }
+
+        for (p9, (p9_state, server_state)) in self.p9_devices.iter().zip(state.p9.iter()) {
+            p9.lock()
+                .unwrap()
+                .restore(p9_state, server_state)
+                .map_err(|err| {
+                    error!("could not restore 9p device: {:?}", err);
+                    Error::Virtio(virtio::Error::P9)
+                })?;
+        }
+
+        if let (Some(vsock), Some(vsock_state)) = (&self.vsock_device, &state.vsock) {
+            vsock.lock().unwrap().restore(vsock_state).map_err(|err| {
+                error!("could not restore vsock device: {:?}", err);
+                Error::Virtio(virtio::Error::Vsock)
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Time taken by the phases of the boot of the VM, updated as the guest boots.
+    pub fn boot_timer(&self) -> BootTimer {
+        self.boot_timer.clone()
+    }
+
+    /// Get a handle to pause the VM and take snapshots, while [`VMM::run`] is running.
+    /// Returns `None` until the VMM is configured.
+    pub fn handle(&self) -> Option<VmHandle> {
+        Some(VmHandle {
+            machine: self.machine.clone()?,
+            vm_fd: self.vm_fd.clone(),
+            guest_memory: self.guest_memory.clone(),
+            serial: self.serial.clone(),
+            slip_pty: self.slip_pty.clone(),
+            net_devices: self.net_devices.clone(),
+            block_devices: self.block_devices.clone(),
+            p9_devices: self.p9_devices.clone(),
+            vsock_device: self.vsock_device.clone(),
+            event_loop: self.event_loop.clone(),
+            vcpus: self.vcpu_handles.clone(),
+        })
+    }
+
+    /// Allocate the MMIO range and the interrupt of a virtio device.
+    fn allocate_mmio_config(&mut self) -> Result<MmioConfig> {
+        let range = if let Some(allocator) = &mut self.address_allocator {
             allocator
-                .to_owned()
                 .allocate(0x1000, DEFAULT_ADDRESS_ALIGNEMNT, DEFAULT_ALLOC_POLICY)
                 .map_err(Error::Allocate)?
         } else {
@@ -378,10 +819,20 @@ impl VMM {
         let mmio_range = MmioRange::new(MmioAddress(range.start()), range.len())
             .map_err(|_| Error::MmioRange)?;
         let irq = self.irq_allocator.next_irq().map_err(Error::IrqAllocator)?;
-        let mmio_cfg = MmioConfig {
+
+        Ok(MmioConfig {
             range: mmio_range,
             gsi: irq,
-        };
+        })
+    }
+
+    pub async fn configure_net_device(
+        &mut self,
+        cmdline_extra_parameters: &mut Vec<String>,
+    ) -> Result<()> {
+        let mem = Arc::new(self.guest_memory.clone());
+        let mmio_cfg = self.allocate_mmio_config()?;
+        let irq = mmio_cfg.gsi;
 
         let remote_endpoint = { self.event_mgr.lock().unwrap().remote_endpoint() };
 
@@ -409,4 +860,320 @@ impl VMM {
 
         Ok(())
     }
+
+    pub fn configure_block_device(
+        &mut self,
+        block_config: &BlockConfig,
+        cmdline_extra_parameters: &mut Vec<String>,
+    ) -> Result<()> {
+        let mem = Arc::new(self.guest_memory.clone());
+        let mmio_cfg = self.allocate_mmio_config()?;
+        let irq = mmio_cfg.gsi;
+
+        let remote_endpoint = { self.event_mgr.lock().unwrap().remote_endpoint() };
+
+        let block = Block::new(
+            mem,
+            self.device_mgr.clone(),
+            mmio_cfg,
+            block_config,
+            irq,
+            remote_endpoint,
+            self.vm_fd.clone(),
+            cmdline_extra_parameters,
+        )
+        .map_err(|err| {
+            error!("could not configure block device: {:?}", err);
+            Error::Virtio(virtio::Error::Block)
+        })?;
+
+        if block_config.root {
+            // The guest names the disks in the order of their registration: vda, vdb...
+            let name = virtio::block::disk_name(self.block_devices.len());
+            cmdline_extra_parameters.push(format!("root=/dev/{}", name));
+            cmdline_extra_parameters.push(
+                match block_config.mode {
+                    DiskMode::ReadOnly => "ro",
+                    DiskMode::ReadWrite | DiskMode::CopyOnWrite => "rw",
+                }
+                .to_string(),
+            );
+        }
+
+        self.block_devices.push(block);
+
+        Ok(())
+    }
+
+    pub fn configure_shared_dir(
+        &mut self,
+        shared_dir_config: &SharedDirConfig,
+        cmdline_extra_parameters: &mut Vec<String>,
+    ) -> Result<()> {
+        let mem = Arc::new(self.guest_memory.clone());
+        let mmio_cfg = self.allocate_mmio_config()?;
+        let irq = mmio_cfg.gsi;
+
+        let remote_endpoint = { self.event_mgr.lock().unwrap().remote_endpoint() };
+
+        let p9 = P9::new(
+            mem,
+            self.device_mgr.clone(),
+            mmio_cfg,
+            shared_dir_config,
+            irq,
+            remote_endpoint,
+            self.vm_fd.clone(),
+            cmdline_extra_parameters,
+        )
+        .map_err(|err| {
+            error!("could not configure 9p device: {:?}", err);
+            Error::Virtio(virtio::Error::P9)
+        })?;
+
+        self.p9_devices.push(p9);
+
+        Ok(())
+    }
+
+    pub fn configure_vsock_device(
+        &mut self,
+        vsock_config: &VsockConfig,
+        cmdline_extra_parameters: &mut Vec<String>,
+    ) -> Result<()> {
+        let mem = Arc::new(self.guest_memory.clone());
+        let mmio_cfg = self.allocate_mmio_config()?;
+        let irq = mmio_cfg.gsi;
+
+        let remote_endpoint = { self.event_mgr.lock().unwrap().remote_endpoint() };
+
+        let vsock = Vsock::new(
+            mem,
+            self.device_mgr.clone(),
+            mmio_cfg,
+            vsock_config,
+            irq,
+            remote_endpoint,
+            self.vm_fd.clone(),
+            cmdline_extra_parameters,
+        )
+        .map_err(|err| {
+            error!("could not configure vsock device: {:?}", err);
+            Error::Virtio(virtio::Error::Vsock)
+        })?;
+
+        self.vsock_device = Some(vsock);
+
+        Ok(())
+    }
+}
+
+impl Drop for VMM {
+    // The event thread is left running when the VMM fails to restore a snapshot.
+    fn drop(&mut self) {
+        self.stop_event_thread();
+    }
+}
+
+/// Handle to a running VM, see [`VMM::handle`].
+#[derive(Clone)]
+pub struct VmHandle {
+    machine: MachineConfig,
+    vm_fd: Arc<VmFd>,
+    guest_memory: GuestMemoryMmap,
+    serial: Arc<Mutex<LumperSerial<ConsoleWriter>>>,
+    slip_pty: Arc<Mutex<SlipPty>>,
+    net_devices: Vec<Arc<Mutex<Net>>>,
+    block_devices: Vec<Arc<Mutex<Block>>>,
+    p9_devices: Vec<Arc<Mutex<P9>>>,
+    vsock_device: Option<Arc<Mutex<Vsock>>>,
+    /// Handles the events of the devices, unless held off.
+    event_loop: Arc<EventLoop>,
+    vcpus: Arc<Mutex<Vec<VcpuHandle>>>,
+}
+
+impl VmHandle {
+    /// Stop running the guest, until [`VmHandle::resume`].
+    pub fn pause(&self) -> Result<()> {
+        Self::pause_vcpus(&self.vcpus.lock().unwrap())
+    }
+
+    pub fn resume(&self) {
+        Self::resume_vcpus(&self.vcpus.lock().unwrap());
+    }
+
+    /// Stop running the guest for good, [`VMM::run`] then returns [`VmExit::Stopped`].
+    pub fn stop(&self) {
+        Self::stop_vcpus(&mut self.vcpus.lock().unwrap(), false);
+    }
+
+    /// Ask the vCPUs to leave their run loop and wait until they did. The threads are joined
+    /// when `join` is set.
+    fn stop_vcpus(vcpus: &mut Vec<VcpuHandle>, join: bool) {
+        for vcpu in vcpus.iter() {
+            let _ = vcpu.requests.send(VcpuRequest::Stop);
+        }
+
+        for vcpu in vcpus.iter() {
+            // Same as for a pause, the kick is lost if it arrives right before the vCPU enters
+            // the guest.
+            while !vcpu.thread.is_finished() {
+                let _ = vcpu.thread.kill(cpu::kick_signal());
+                thread::sleep(VCPU_KICK_INTERVAL);
+            }
+        }
+
+        if join {
+            for vcpu in vcpus.drain(..) {
+                if vcpu.thread.join().is_err() {
+                    warn!(vcpu_index = vcpu.index, "vCPU thread panicked");
+                }
+            }
+        }
+    }
+
+    /// Pause all the vCPUs, or none of them if one can't be paused.
+    fn pause_vcpus(vcpus: &[VcpuHandle]) -> Result<()> {
+        if vcpus.is_empty() {
+            return Err(Error::Snapshot(snapshot::Error::NotRunning));
+        }
+
+        for (index, vcpu) in vcpus.iter().enumerate() {
+            if let Err(err) = Self::pause_vcpu(vcpu) {
+                Self::resume_vcpus(&vcpus[..index]);
+                return Err(err);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn pause_vcpu(vcpu: &VcpuHandle) -> Result<()> {
+        let gone = || Error::Snapshot(snapshot::Error::VcpuGone(vcpu.index));
+
+        vcpu.requests.send(VcpuRequest::Pause).map_err(|_| gone())?;
+        loop {
+            // The vCPU only handles the request once out of the guest. The signal is lost if it
+            // arrives right before the vCPU enters the guest, so send it again until the vCPU
+            // answers.
+            let _ = vcpu.thread.kill(cpu::kick_signal());
+
+            match vcpu.responses.recv_timeout(VCPU_KICK_INTERVAL) {
+                Ok(VcpuResponse::Paused) => return Ok(()),
+                Ok(_) | Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => return Err(gone()),
+            }
+        }
+    }
+
+    fn resume_vcpus(vcpus: &[VcpuHandle]) {
+        for vcpu in vcpus {
+            if vcpu.requests.send(VcpuRequest::Resume).is_err() {
+                warn!(vcpu_index = vcpu.index, "Failed to resume vCPU");
+            }
+        }
+    }
+
+    /// Save the state of paused vCPUs.
+    fn save_vcpus(vcpus: &[VcpuHandle]) -> Result<Vec<VcpuState>> {
+        vcpus
+            .iter()
+            .map(|vcpu| {
+                let gone = || Error::Snapshot(snapshot::Error::VcpuGone(vcpu.index));
+
+                vcpu.requests
+                    .send(VcpuRequest::SaveState)
+                    .map_err(|_| gone())?;
+                match vcpu.responses.recv() {
+                    Ok(VcpuResponse::State(state)) => (*state).map_err(Error::Vcpu),
+                    _ => Err(gone()),
+                }
+            })
+            .collect()
+    }
+
+    /// Save a snapshot of the VM to `dir`, to be restored with [`VMM::restore`]. The VM is
+    /// paused while the snapshot is taken, and the events of its devices are left pending until
+    /// it's saved, so that the queues and the memory of the guest don't change in the meantime.
+    pub fn snapshot(&self, dir: &Path) -> Result<()> {
+        // The overlay of the disk only lives as long as the VMM.
+        if self
+            .machine
+            .disks
+            .iter()
+            .any(|disk| disk.mode == DiskMode::CopyOnWrite)
+        {
+            return Err(Error::Snapshot(snapshot::Error::UnsupportedDevice(
+                "copy-on-write disk",
+            )));
+        }
+
+        std::fs::create_dir_all(dir).map_err(|e| Error::Snapshot(snapshot::Error::IO(e)))?;
+
+        let vcpus = self.vcpus.lock().unwrap();
+        Self::pause_vcpus(&vcpus)?;
+        // Only held off once the vCPUs are paused: a vCPU activating a device waits for the
+        // event loop to register the handlers of the device.
+        let result = self.event_loop.hold(|| self.save(&vcpus, dir));
+        Self::resume_vcpus(&vcpus);
+
+        if result.is_ok() {
+            info!("Saved snapshot to {}", dir.display());
+        }
+        result
+    }
+
+    fn save(&self, vcpus: &[VcpuHandle], dir: &Path) -> Result<()> {
+        let vcpu_states = Self::save_vcpus(vcpus)?;
+
+        let irqchips = [
+            KVM_IRQCHIP_PIC_MASTER,
+            KVM_IRQCHIP_PIC_SLAVE,
+            KVM_IRQCHIP_IOAPIC,
+        ]
+        .into_iter()
+        .map(|chip_id| {
+            let mut irqchip = kvm_irqchip {
+                chip_id,
+                ..Default::default()
+            };
+            self.vm_fd
+                .get_irqchip(&mut irqchip)
+                .map(|_| irqchip)
+                .map_err(Error::KvmIoctl)
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+        let state = VmState {
+            machine: self.machine.clone(),
+            irqchips,
+            clock: self.vm_fd.get_clock().map_err(Error::KvmIoctl)?,
+            vcpus: vcpu_states,
+            serial: self.serial.lock().unwrap().state(),
+            slip_serial: self.slip_pty.lock().unwrap().serial().state(),
+            net: self
+                .net_devices
+                .iter()
+                .map(|net| net.lock().unwrap().state())
+                .collect(),
+            block: self
+                .block_devices
+                .iter()
+                .map(|block| block.lock().unwrap().state())
+                .collect(),
+            p9: self
+                .p9_devices
+                .iter()
+                .map(|p9| p9.lock().unwrap().state())
+                .collect(),
+            vsock: self
+                .vsock_device
+                .as_ref()
+                .map(|vsock| vsock.lock().unwrap().state()),
+        };
+
+        snapshot::save_memory(&self.guest_memory, &dir.join(snapshot::MEMORY_FILE))
+            .map_err(Error::Snapshot)?;
+        state.save(dir).map_err(Error::Snapshot)
+    }
 }
@@ -1,13 +1,26 @@
-use self::agent::{workload_runner_client::WorkloadRunnerClient, ExecuteRequest, SignalRequest};
+use self::agent::{
+    workload_runner_client::WorkloadRunnerClient, ExecRequest, ExecResponse, ExecuteRequest,
+    FileChunk, GetFileRequest, PutFileResponse, SignalRequest,
+};
 use super::server::vmmorchestrator::{ShutdownVmRequest, ShutdownVmResponse};
 use log::error;
-use std::{error::Error, net::Ipv4Addr, time::Duration};
-use tonic::{transport::Channel, Streaming};
+use std::{error::Error, io, net::Ipv4Addr, path::PathBuf, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::UnixStream,
+};
+use tokio_stream::Stream;
+use tonic::{
+    transport::{Channel, Endpoint, Uri},
+    Streaming,
+};
+use tower::service_fn;
 
 pub mod agent {
     tonic::include_proto!("cloudlet.agent");
 }
 
+#[derive(Clone)]
 pub struct WorkloadClient {
     client: WorkloadRunnerClient<Channel>,
 }
@@ -29,6 +42,34 @@ impl WorkloadClient {
         }
     }
 
+    /// Connect to the agent listening on the vsock `port` of the guest, through the Unix socket
+    /// of its vsock device.
+    pub async fn new_vsock(uds_path: PathBuf, port: u32) -> Result<Self, tonic::transport::Error> {
+        let delay = Duration::from_secs(2);
+        loop {
+            let uds_path = uds_path.clone();
+            // The URI is ignored, the connector opens the connection.
+            let channel = Endpoint::from_static("http://[::]:50051")
+                .connect_with_connector(service_fn(move |_: Uri| {
+                    vsock_connect(uds_path.clone(), port)
+                }))
+                .await;
+
+            match channel {
+                Ok(channel) => {
+                    return Ok(WorkloadClient {
+                        client: WorkloadRunnerClient::new(channel),
+                    });
+                }
+                Err(err) => {
+                    error!("Failed to connect to Agent service: {}", err);
+                    error!("Retrying in {:?}...", delay);
+                    tokio::time::sleep(delay).await;
+                }
+            }
+        }
+    }
+
     pub async fn execute(
         &mut self,
         request: ExecuteRequest,
@@ -39,6 +80,34 @@ impl WorkloadClient {
         Ok(response_stream)
     }
 
+    pub async fn put_file(
+        &mut self,
+        chunks: impl Stream<Item = FileChunk> + Send + 'static,
+    ) -> Result<PutFileResponse, tonic::Status> {
+        let response = self.client.put_file(chunks).await?.into_inner();
+
+        Ok(response)
+    }
+
+    pub async fn get_file(
+        &mut self,
+        request: GetFileRequest,
+    ) -> Result<Streaming<FileChunk>, tonic::Status> {
+        let request = tonic::Request::new(request);
+        let response_stream = self.client.get_file(request).await?.into_inner();
+
+        Ok(response_stream)
+    }
+
+    pub async fn exec(
+        &mut self,
+        requests: impl Stream<Item = ExecRequest> + Send + 'static,
+    ) -> Result<Streaming<ExecResponse>, tonic::Status> {
+        let response_stream = self.client.exec(requests).await?.into_inner();
+
+        Ok(response_stream)
+    }
+
     pub async fn shutdown(
         &mut self,
         _request: ShutdownVmRequest,
@@ -58,3 +127,32 @@ impl WorkloadClient {
         Ok(ShutdownVmResponse { success: false })
     }
 }
+
+/// Open a connection to the guest `port` with the `CONNECT` handshake of the vsock device.
+async fn vsock_connect(uds_path: PathBuf, port: u32) -> io::Result<UnixStream> {
+    let mut stream = UnixStream::connect(uds_path).await?;
+    stream
+        .write_all(format!("CONNECT {}\n", port).as_bytes())
+        .await?;
+
+    // Read the `OK <host port>` reply byte per byte not to consume the data following it.
+    let mut reply = Vec::new();
+    loop {
+        match stream.read_u8().await? {
+            b'\n' => break,
+            byte => reply.push(byte),
+        }
+    }
+
+    if !reply.starts_with(b"OK ") {
+        return Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            format!(
+                "vsock connection refused: {}",
+                String::from_utf8_lossy(&reply)
+            ),
+        ));
+    }
+
+    Ok(stream)
+}
@@ -1,24 +1,31 @@
 use self::vmmorchestrator::{
-    vmm_service_server::VmmService as VmmServiceTrait, Language, RunVmmRequest, ShutdownVmRequest,
-    ShutdownVmResponse,
+    exec_request, exec_response, execute_response, vm_info,
+    vmm_service_server::VmmService as VmmServiceTrait, CreateVmRequest, DeleteVmResponse,
+    ExecRequest, ExecResponse, ExecuteVmRequest, FileChunk, GetFileRequest, Language,
+    ListVmsRequest, ListVmsResponse, LogLevel, PutFileResponse, RunVmmRequest, ShutdownVmRequest,
+    ShutdownVmResponse, VmInfo, VmRequest,
 };
-use crate::grpc::client::agent::ExecuteRequest;
+use crate::core::vmm::{BootTimer, VmExit};
+use crate::grpc::client::agent::{self as agent_proto, ExecuteRequest};
+use crate::grpc::vm_manager::{Vm, VmConfig, VmManager, VmStatus};
+use crate::grpc::vm_pool::{PoolSize, VmPool};
 use crate::VmmErrors;
-use crate::{core::vmm::VMM, grpc::client::WorkloadClient};
 use std::ffi::OsStr;
-use std::time::Duration;
+use std::sync::Arc;
 use std::{
     convert::From,
     env::current_dir,
-    net::Ipv4Addr,
     path::{Path, PathBuf},
     process::{Command, Stdio},
 };
-use tokio_stream::wrappers::ReceiverStream;
-use tonic::{Request, Response, Status};
-use tracing::{error, info};
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio_stream::{wrappers::ReceiverStream, StreamExt};
+use tonic::metadata::{Ascii, MetadataKey, MetadataMap, MetadataValue};
+use tonic::{Request, Response, Status, Streaming};
+use tracing::{error, info, warn};
 
 type Result<T> = std::result::Result<Response<T>, tonic::Status>;
+type ExecuteSender = mpsc::Sender<std::result::Result<vmmorchestrator::ExecuteResponse, Status>>;
 
 pub mod vmmorchestrator {
     tonic::include_proto!("vmmorchestrator");
@@ -39,17 +46,172 @@ impl From<VmmErrors> for Status {
             VmmErrors::VmmNew(_) => Status::internal("Error creating VMM"),
             VmmErrors::VmmConfigure(_) => Status::internal("Error configuring VMM"),
             VmmErrors::VmmRun(_) => Status::internal("Error running VMM"),
+            VmmErrors::VmmRestore(_) => Status::internal("Error restoring VMM"),
             VmmErrors::VmmBuildEnvironment(_) => {
                 Status::internal("Error while compiling the necessary files for the VMM")
             }
+            VmmErrors::VmNotFound(id) => Status::not_found(format!("Unknown VM {}", id)),
+            VmmErrors::NoAddressAvailable => {
+                Status::resource_exhausted("No address left for a new VM")
+            }
+            VmmErrors::VmInvalidState(message) => Status::failed_precondition(message),
+            VmmErrors::VmmPause(_) => Status::internal("Error pausing VM"),
+            VmmErrors::AgentConnection(e) => {
+                Status::unavailable(format!("Failed to connect to the agent: {}", e))
+            }
+            VmmErrors::AgentTimeout => Status::deadline_exceeded("The agent didn't answer in time"),
+            VmmErrors::AgentShutdown(status) => status,
+            VmmErrors::VmBoot { error, .. } => (*error).into(),
+        }
+    }
+}
+
+impl From<FileChunk> for agent_proto::FileChunk {
+    fn from(value: FileChunk) -> Self {
+        Self {
+            path: value.path,
+            mode: value.mode,
+            data: value.data,
+            sha256: value.sha256,
+        }
+    }
+}
+
+impl From<agent_proto::FileChunk> for FileChunk {
+    fn from(value: agent_proto::FileChunk) -> Self {
+        Self {
+            path: value.path,
+            mode: value.mode,
+            data: value.data,
+            sha256: value.sha256,
+            vm_id: String::new(),
+        }
+    }
+}
+
+impl From<agent_proto::Artifact> for vmmorchestrator::Artifact {
+    fn from(value: agent_proto::Artifact) -> Self {
+        Self {
+            path: value.path,
+            size: value.size,
+            sha256: value.sha256,
+            files: value.files,
+        }
+    }
+}
+
+impl From<agent_proto::Termination> for vmmorchestrator::Termination {
+    fn from(value: agent_proto::Termination) -> Self {
+        Self {
+            signal: value.signal,
+            signal_name: value.signal_name,
+            core_dumped: value.core_dumped,
+            core_file: value.core_file,
+            backtrace: value.backtrace,
         }
     }
 }
 
-#[derive(Default)]
-pub struct VmmService;
+impl From<ExecRequest> for agent_proto::ExecRequest {
+    fn from(value: ExecRequest) -> Self {
+        use agent_proto::exec_request::{Request, Resize, Start};
+
+        let request = value.request.map(|request| match request {
+            exec_request::Request::Start(start) => Request::Start(Start {
+                command: start.command,
+                args: start.args,
+                env: start.env,
+                working_dir: start.working_dir,
+                tty: start.tty,
+                rows: start.rows,
+                cols: start.cols,
+            }),
+            exec_request::Request::Stdin(data) => Request::Stdin(data),
+            exec_request::Request::Resize(resize) => Request::Resize(Resize {
+                rows: resize.rows,
+                cols: resize.cols,
+            }),
+            exec_request::Request::CloseStdin(close) => Request::CloseStdin(close),
+        });
+
+        Self { request }
+    }
+}
+
+impl From<agent_proto::ExecResponse> for ExecResponse {
+    fn from(value: agent_proto::ExecResponse) -> Self {
+        use agent_proto::exec_response::Response;
+
+        let response = value.response.map(|response| match response {
+            Response::Stdout(data) => exec_response::Response::Stdout(data),
+            Response::Stderr(data) => exec_response::Response::Stderr(data),
+            Response::ExitCode(code) => exec_response::Response::ExitCode(code),
+        });
+
+        Self { response }
+    }
+}
+
+pub struct VmmService {
+    vms: Arc<VmManager>,
+    pool: Arc<VmPool>,
+}
+
+impl Default for VmmService {
+    fn default() -> Self {
+        Self::new(&[])
+    }
+}
 
 impl VmmService {
+    /// Create the service, keeping `pool_sizes` VMs booted per language once
+    /// [`VmmService::warm_up`] is called.
+    pub fn new(pool_sizes: &[PoolSize]) -> Self {
+        let vms = Arc::new(VmManager::default());
+        let pool = Arc::new(VmPool::new(vms.clone(), pool_sizes));
+
+        VmmService { vms, pool }
+    }
+
+    /// Start booting the VMs of the pools.
+    pub fn warm_up(&self) -> std::result::Result<(), VmmErrors> {
+        for language in self.pool.languages() {
+            let config = self.vm_config(language)?;
+            self.pool.fill(language, &config);
+        }
+
+        Ok(())
+    }
+
+    /// Resources of the VMs running `language` workloads, built if necessary.
+    fn vm_config(&self, language: &str) -> std::result::Result<VmConfig, VmmErrors> {
+        // get current directory
+        let curr_dir = current_dir()
+            .map_err(VmmErrors::VmmBuildEnvironment)?
+            .into_os_string();
+
+        // build kernel if necessary
+        let kernel_path: PathBuf = self.get_path(
+            &curr_dir,
+            "/tools/kernel/linux-cloud-hypervisor/arch/x86/boot/compressed/vmlinux.bin",
+            "sh",
+            vec!["./tools/kernel/mkkernel.sh"],
+        )?;
+
+        let initramfs_path = self.get_initramfs(language, curr_dir.as_os_str())?;
+
+        // Configure the VMM parameters might need to be calculated rather than hardcoded
+        Ok(VmConfig {
+            language: language.to_string(),
+            num_vcpus: 1,
+            mem_size_mb: 4000,
+            kernel: kernel_path,
+            initramfs: initramfs_path,
+            shared_dirs: Vec::new(),
+            cmdline: None,
+        })
+    }
+
     pub fn get_initramfs(
         &self,
         language: &str,
@@ -139,7 +301,7 @@ impl VmmService {
 
     pub fn get_agent_request(
         &self,
-        vmm_request: RunVmmRequest,
+        vmm_request: ExecuteVmRequest,
         language: String,
     ) -> ExecuteRequest {
         // Send the grpc request to start the agent
@@ -149,6 +311,165 @@ impl VmmService {
             action: 2, // Prepare and run
             code: vmm_request.code,
             config_str: "[build]\nrelease = true".to_string(),
+            outputs: vmm_request.outputs,
+        }
+    }
+
+    /// Run a workload in `vm` and stream its output. With `destroy`, for the VMs booted for a
+    /// single run, the VM is destroyed once the workload is done.
+    async fn execute_workload(
+        &self,
+        vm: Arc<Vm>,
+        request: ExecuteVmRequest,
+        destroy: bool,
+    ) -> Result<ReceiverStream<std::result::Result<vmmorchestrator::ExecuteResponse, Status>>> {
+        let status = vm.status();
+        if status != VmStatus::Running {
+            return Err(vm.invalid_status(status).into());
+        }
+
+        let (tx, rx) = tokio::sync::mpsc::channel(4);
+        // The console is followed until `console_done` is dropped, once the workload is done.
+        let (console_done, done) = oneshot::channel::<()>();
+        if request.log_level == LogLevel::Debug as i32 {
+            forward_console(&vm, tx.clone(), done);
+        }
+        let agent_request = self.get_agent_request(request, vm.config.language.clone());
+
+        let mut client = vm.agent().await?;
+        info!(vm_id = vm.id, "Successfully connected to Agent service");
+
+        // Start the execution
+        let mut response_stream = client.execute(agent_request).await?;
+
+        // Process each message as it arrives
+        let vm_id = vm.id.clone();
+        let vms = destroy.then(|| self.vms.clone());
+        tokio::spawn(async move {
+            while let Ok(Some(response)) = response_stream.message().await {
+                let vmm_response = vmmorchestrator::ExecuteResponse {
+                    stage: response.stage,
+                    stdout: response.stdout,
+                    stderr: response.stderr,
+                    exit_code: response.exit_code,
+                    artifact: response.artifact.map(Into::into),
+                    termination: response.termination.map(Into::into),
+                    vm_id: vm_id.clone(),
+                };
+                let _ = tx.send(Ok(vmm_response)).await;
+            }
+
+            // The client isn't kept waiting while the VM is torn down.
+            drop(console_done);
+            drop(tx);
+            if let Some(vms) = vms {
+                destroy_vm(&vms, &vm_id).await;
+            }
+        });
+
+        let mut response = Response::new(ReceiverStream::new(rx));
+        insert_boot_times(response.metadata_mut(), &vm.boot_timer);
+        Ok(response)
+    }
+}
+
+/// Destroy a VM booted for a single run.
+async fn destroy_vm(vms: &VmManager, id: &str) {
+    match vms.destroy(id).await {
+        Ok(()) => info!(vm_id = id, "VM destroyed"),
+        Err(err) => error!(vm_id = id, "Failed to destroy the VM: {:?}", err),
+    }
+}
+
+/// Add the time taken to reach each phase of the boot of a VM to `metadata`, in microseconds,
+/// as `boot-<phase>-us` entries.
+fn insert_boot_times(metadata: &mut MetadataMap, boot_timer: &BootTimer) {
+    for (phase, elapsed) in boot_timer.phases() {
+        let key = MetadataKey::<Ascii>::from_bytes(format!("boot-{}-us", phase).as_bytes())
+            .expect("Boot phases are valid metadata keys");
+        metadata.insert(key, MetadataValue::from(elapsed.as_micros() as u64));
+    }
+}
+
+/// Send the console lines of `vm` to `tx` as `DEBUG` responses: the ones printed so far, then
+/// the new ones until `done` is dropped.
+fn forward_console(vm: &Vm, tx: ExecuteSender, mut done: oneshot::Receiver<()>) {
+    let (history, mut lines) = vm.follow_console();
+    let vm_id = vm.id.clone();
+
+    tokio::spawn(async move {
+        let response = |line: Vec<u8>| {
+            Ok(vmmorchestrator::ExecuteResponse {
+                stage: execute_response::Stage::Debug as i32,
+                stdout: Some(String::from_utf8_lossy(&line).trim_end().to_string()),
+                vm_id: vm_id.clone(),
+                ..Default::default()
+            })
+        };
+
+        for line in history {
+            if tx.send(response(line)).await.is_err() {
+                return;
+            }
+        }
+
+        loop {
+            let line = tokio::select! {
+                _ = &mut done => return,
+                line = lines.recv() => line,
+            };
+
+            match line {
+                Ok(line) => {
+                    if tx.send(response(line)).await.is_err() {
+                        return;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(count)) => {
+                    warn!(vm_id, count, "Console lines lost");
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            }
+        }
+    });
+}
+
+impl From<&Vm> for VmInfo {
+    fn from(vm: &Vm) -> Self {
+        let state = match vm.status() {
+            VmStatus::Created => vm_info::State::Created,
+            VmStatus::Running => vm_info::State::Running,
+            VmStatus::Paused => vm_info::State::Paused,
+            VmStatus::Stopped => vm_info::State::Stopped,
+        };
+        let language =
+            Language::from_str_name(&vm.config.language.to_uppercase()).unwrap_or_default();
+        let exit = vm.exit().map(|exit| {
+            let (reason, guest_status, error) = match &exit {
+                Ok(VmExit::Shutdown(status)) => {
+                    (vm_info::exit::Reason::Shutdown, status.map(u32::from), None)
+                }
+                Ok(VmExit::Reset) => (vm_info::exit::Reason::Reset, None, None),
+                Ok(VmExit::Stopped) => (vm_info::exit::Reason::Stopped, None, None),
+                Err(err) => (vm_info::exit::Reason::Failed, None, Some(err.clone())),
+            };
+
+            vm_info::Exit {
+                reason: reason as i32,
+                guest_status,
+                exit_code: exit.as_ref().map_or(1, VmExit::exit_code),
+                error,
+            }
+        });
+
+        VmInfo {
+            vm_id: vm.id.clone(),
+            language: language as i32,
+            state: state as i32,
+            guest_ip: vm.guest_addr.to_string(),
+            cpus: vm.config.num_vcpus.into(),
+            memory_mb: vm.config.mem_size_mb,
+            exit,
         }
     }
 }
@@ -157,52 +478,154 @@ impl VmmService {
 impl VmmServiceTrait for VmmService {
     type RunStream =
         ReceiverStream<std::result::Result<vmmorchestrator::ExecuteResponse, tonic::Status>>;
+    type GetFileStream = ReceiverStream<std::result::Result<FileChunk, tonic::Status>>;
+    type ExecStream = ReceiverStream<std::result::Result<ExecResponse, tonic::Status>>;
+    type ExecuteStream =
+        ReceiverStream<std::result::Result<vmmorchestrator::ExecuteResponse, tonic::Status>>;
 
-    async fn shutdown(&self, request: Request<ShutdownVmRequest>) -> Result<ShutdownVmResponse> {
-        const GUEST_IP: Ipv4Addr = Ipv4Addr::new(172, 29, 0, 2);
+    async fn exec(&self, request: Request<Streaming<ExecRequest>>) -> Result<Self::ExecStream> {
+        let (tx, rx) = tokio::sync::mpsc::channel(4);
 
-        let grpc_client = tokio::spawn(async move {
-            // Wait 2 seconds
-            tokio::time::sleep(Duration::from_secs(2)).await;
-            println!("Connecting to Agent service");
+        // The first request starts the command, and tells in which VM.
+        let mut requests = request.into_inner();
+        let start = requests
+            .message()
+            .await?
+            .ok_or_else(|| Status::invalid_argument("Missing exec start request"))?;
+        let vm_id = match &start.request {
+            Some(exec_request::Request::Start(start)) => start.vm_id.clone(),
+            _ => return Err(Status::invalid_argument("Missing exec start request")),
+        };
 
-            WorkloadClient::new(GUEST_IP, 50051).await
-        })
-        .await
-        .unwrap();
+        let vm = self.vms.get(&vm_id)?;
+        let mut client = vm.agent().await?;
 
-        if let Ok(mut client) = grpc_client {
-            info!("Attempting to shutdown the VM...");
+        let requests = tokio_stream::once(start)
+            .chain(requests.map_while(|request| request.ok()))
+            .map(agent_proto::ExecRequest::from);
 
-            let response = client.shutdown(request.into_inner()).await.unwrap();
+        let mut response_stream = client.exec(requests).await?;
 
-            return Ok(Response::new(response));
-        } else if let Err(e) = grpc_client {
-            error!("ERROR {:?}", e);
-        }
-        return Err(Status::internal("Failed to shutdown the VM"));
+        tokio::spawn(async move {
+            loop {
+                match response_stream.message().await {
+                    Ok(Some(response)) => {
+                        if tx.send(Ok(response.into())).await.is_err() {
+                            break;
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(status) => {
+                        let _ = tx.send(Err(status)).await;
+                        break;
+                    }
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
     }
 
-    async fn run(&self, request: Request<RunVmmRequest>) -> Result<Self::RunStream> {
+    async fn put_file(&self, request: Request<Streaming<FileChunk>>) -> Result<PutFileResponse> {
+        // The first chunk tells to which VM the file is uploaded.
+        let mut chunks = request.into_inner();
+        let first_chunk = chunks
+            .message()
+            .await?
+            .ok_or_else(|| Status::invalid_argument("Missing file chunk"))?;
+
+        let vm = self.vms.get(&first_chunk.vm_id)?;
+        let mut client = vm.agent().await?;
+
+        let (chunk_tx, chunk_rx) = mpsc::channel(4);
+        let mut upload = Box::pin(
+            client.put_file(ReceiverStream::new(chunk_rx).map(agent_proto::FileChunk::from)),
+        );
+        let forward = async move {
+            let mut next_chunk = Some(first_chunk);
+            while let Some(chunk) = next_chunk {
+                if chunk_tx.send(chunk).await.is_err() {
+                    // The agent answered early, its response tells why.
+                    break;
+                }
+                next_chunk = match chunks.message().await {
+                    Ok(chunk) => chunk,
+                    // The sender is handed back, so the stream to the agent doesn't end as if the
+                    // file was complete before the call is dropped.
+                    Err(status) => return Err((status, chunk_tx)),
+                };
+            }
+            Ok(())
+        };
+
+        let response = tokio::select! {
+            response = &mut upload => response?,
+            forwarded = forward => match forwarded {
+                Ok(()) => upload.await?,
+                Err((status, chunk_tx)) => {
+                    // Dropping the call resets the stream, the agent then discards the file.
+                    drop(upload);
+                    drop(chunk_tx);
+                    return Err(status);
+                }
+            },
+        };
+
+        Ok(Response::new(PutFileResponse {
+            size: response.size,
+            sha256: response.sha256,
+        }))
+    }
+
+    async fn get_file(&self, request: Request<GetFileRequest>) -> Result<Self::GetFileStream> {
         let (tx, rx) = tokio::sync::mpsc::channel(4);
 
-        const HOST_IP: Ipv4Addr = Ipv4Addr::new(172, 29, 0, 1);
-        const HOST_NETMASK: Ipv4Addr = Ipv4Addr::new(255, 255, 0, 0);
-        const GUEST_IP: Ipv4Addr = Ipv4Addr::new(172, 29, 0, 2);
+        let request = request.into_inner();
+        let vm = self.vms.get(&request.vm_id)?;
+        let mut client = vm.agent().await?;
 
-        // get current directory
-        let curr_dir = current_dir()
-            .map_err(VmmErrors::VmmBuildEnvironment)?
-            .into_os_string();
+        let mut response_stream = client
+            .get_file(agent_proto::GetFileRequest { path: request.path })
+            .await?;
 
-        // build kernel if necessary
-        let kernel_path: PathBuf = self.get_path(
-            &curr_dir,
-            "/tools/kernel/linux-cloud-hypervisor/arch/x86/boot/compressed/vmlinux.bin",
-            "sh",
-            vec!["./tools/kernel/mkkernel.sh"],
-        )?;
+        tokio::spawn(async move {
+            loop {
+                match response_stream.message().await {
+                    Ok(Some(chunk)) => {
+                        if tx.send(Ok(chunk.into())).await.is_err() {
+                            break;
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(status) => {
+                        let _ = tx.send(Err(status)).await;
+                        break;
+                    }
+                }
+            }
+        });
 
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn shutdown(&self, request: Request<ShutdownVmRequest>) -> Result<ShutdownVmResponse> {
+        let request = request.into_inner();
+        let vm = self.vms.get(&request.vm_id)?;
+
+        info!(vm_id = vm.id, "Attempting to shutdown the VM...");
+        match self.vms.stop(&vm.id).await {
+            Ok(_) => {
+                self.vms.delete(&vm.id)?;
+                Ok(Response::new(ShutdownVmResponse { success: true }))
+            }
+            Err(e) => {
+                error!("ERROR {:?}", e);
+                Err(Status::internal("Failed to shutdown the VM"))
+            }
+        }
+    }
+
+    async fn run(&self, request: Request<RunVmmRequest>) -> Result<Self::RunStream> {
         // get request with the language
         let vmm_request = request.into_inner();
         let language: String = Language::from_i32(vmm_request.language)
@@ -210,61 +633,105 @@ impl VmmServiceTrait for VmmService {
             .as_str_name()
             .to_lowercase();
 
-        let initramfs_path = self.get_initramfs(&language, curr_dir.as_os_str())?;
+        let mut config = self.vm_config(&language)?;
+        config.cmdline = vmm_request.kernel_cmdline;
+        let vm = match self.pool.take(&language, config).await {
+            Ok(vm) => vm,
+            // The console of a VM which failed to boot can't be followed, the client gets its
+            // end instead.
+            Err(VmmErrors::VmBoot { error, console })
+                if vmm_request.log_level == LogLevel::Debug as i32 =>
+            {
+                let status = Status::from(*error);
+                return Err(Status::new(
+                    status.code(),
+                    format!("{}, end of the console:\n{}", status.message(), console),
+                ));
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let vm_id = vm.id.clone();
 
-        let mut vmm = VMM::new(HOST_IP, HOST_NETMASK, GUEST_IP).map_err(VmmErrors::VmmNew)?;
+        let request = ExecuteVmRequest {
+            vm_id: vm.id.clone(),
+            workload_name: vmm_request.workload_name,
+            code: vmm_request.code,
+            log_level: vmm_request.log_level,
+            outputs: vmm_request.outputs,
+        };
+        let response = self.execute_workload(vm, request, true).await;
+        if response.is_err() {
+            destroy_vm(&self.vms, &vm_id).await;
+        }
+        response
+    }
 
-        // Configure the VMM parameters might need to be calculated rather than hardcoded
-        vmm.configure(1, 4000, kernel_path, &Some(initramfs_path))
-            .await
-            .map_err(VmmErrors::VmmConfigure)?;
+    async fn create_vm(&self, request: Request<CreateVmRequest>) -> Result<VmInfo> {
+        let request = request.into_inner();
+        let language = Language::from_i32(request.language)
+            .ok_or_else(|| Status::invalid_argument("Unknown language"))?
+            .as_str_name()
+            .to_lowercase();
 
-        // Run the VMM in a separate task
-        tokio::spawn(async move {
-            info!("Running VMM");
-            if let Err(err) = vmm.run().map_err(VmmErrors::VmmRun) {
-                error!("Error running VMM: {:?}", err);
-            }
-        });
+        let mut config = self.vm_config(&language)?;
+        if let Some(cpus) = request.cpus {
+            config.num_vcpus = cpus
+                .try_into()
+                .map_err(|_| Status::invalid_argument("Too many vCPUs"))?;
+        }
+        if let Some(memory_mb) = request.memory_mb {
+            config.mem_size_mb = memory_mb;
+        }
+        config.cmdline = request.kernel_cmdline;
 
-        // run the grpc client
-        let grpc_client = tokio::spawn(async move {
-            // Wait 2 seconds
-            tokio::time::sleep(Duration::from_secs(2)).await;
-            info!("Connecting to Agent service");
+        let vm = self.vms.create(config).await?;
+        Ok(Response::new(vm.as_ref().into()))
+    }
 
-            WorkloadClient::new(GUEST_IP, 50051).await
-        })
-        .await
-        .unwrap();
-
-        let agent_request = self.get_agent_request(vmm_request, language);
-
-        match grpc_client {
-            Ok(mut client) => {
-                info!("Successfully connected to Agent service");
-
-                // Start the execution
-                let mut response_stream = client.execute(agent_request).await?;
-
-                // Process each message as it arrives
-                tokio::spawn(async move {
-                    while let Ok(Some(response)) = response_stream.message().await {
-                        let vmm_response = vmmorchestrator::ExecuteResponse {
-                            stage: response.stage,
-                            stdout: response.stdout,
-                            stderr: response.stderr,
-                            exit_code: response.exit_code,
-                        };
-                        let _ = tx.send(Ok(vmm_response)).await;
-                    }
-                });
-            }
-            Err(e) => {
-                error!("ERROR {:?}", e);
-            }
-        }
+    async fn start_vm(&self, request: Request<VmRequest>) -> Result<VmInfo> {
+        let vm = self.vms.start(&request.into_inner().vm_id)?;
+        Ok(Response::new(vm.as_ref().into()))
+    }
 
-        Ok(Response::new(ReceiverStream::new(rx)))
+    async fn pause_vm(&self, request: Request<VmRequest>) -> Result<VmInfo> {
+        let vm = self.vms.pause(&request.into_inner().vm_id).await?;
+        Ok(Response::new(vm.as_ref().into()))
+    }
+
+    async fn resume_vm(&self, request: Request<VmRequest>) -> Result<VmInfo> {
+        let vm = self.vms.resume(&request.into_inner().vm_id)?;
+        Ok(Response::new(vm.as_ref().into()))
+    }
+
+    async fn stop_vm(&self, request: Request<VmRequest>) -> Result<VmInfo> {
+        let vm = self.vms.stop(&request.into_inner().vm_id).await?;
+        Ok(Response::new(vm.as_ref().into()))
+    }
+
+    async fn delete_vm(&self, request: Request<VmRequest>) -> Result<DeleteVmResponse> {
+        self.vms.delete(&request.into_inner().vm_id)?;
+        Ok(Response::new(DeleteVmResponse {}))
+    }
+
+    async fn list_vms(&self, _request: Request<ListVmsRequest>) -> Result<ListVmsResponse> {
+        let vms = self
+            .vms
+            .list()
+            .iter()
+            .map(|vm| vm.as_ref().into())
+            .collect();
+        Ok(Response::new(ListVmsResponse { vms }))
+    }
+
+    async fn inspect_vm(&self, request: Request<VmRequest>) -> Result<VmInfo> {
+        let vm = self.vms.get(&request.into_inner().vm_id)?;
+        Ok(Response::new(vm.as_ref().into()))
+    }
+
+    async fn execute(&self, request: Request<ExecuteVmRequest>) -> Result<Self::ExecuteStream> {
+        let request = request.into_inner();
+        let vm = self.vms.get(&request.vm_id)?;
+
+        self.execute_workload(vm, request, false).await
     }
 }
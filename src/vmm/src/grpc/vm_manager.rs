@@ -0,0 +1,688 @@
+//! VMs run by the VMM service. Each VM gets an ID, its own TAP attached to the shared bridge,
+//! a guest address from the subnet of the bridge and its own connection to the agent.
+
+use super::client::WorkloadClient;
+use super::server::vmmorchestrator::ShutdownVmRequest;
+use crate::core::vmm::{
+    BootTimer, ConsoleBuffer, ConsoleConfig, ConsoleOutput, SharedDirConfig, VmExit, VmHandle,
+    VsockConfig, VMM,
+};
+use crate::VmmErrors;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{broadcast, watch, OnceCell};
+use tracing::{error, info, warn};
+
+/// Address of the host on the bridge shared by the VMs.
+pub const HOST_IP: Ipv4Addr = Ipv4Addr::new(172, 29, 0, 1);
+pub const HOST_NETMASK: Ipv4Addr = Ipv4Addr::new(255, 255, 0, 0);
+const AGENT_PORT: u32 = 50051;
+/// Time given to the agent of a VM to accept a connection, from the start of the VM.
+const AGENT_CONNECT_TIMEOUT: Duration = Duration::from_secs(60);
+/// Time given to a guest to shut down through its agent, before its vCPUs are stopped.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+const AGENT_VSOCK_CID: u32 = 3;
+/// Directory of the Unix sockets of the vsock devices, through which the agents are reached.
+const AGENT_VSOCK_UDS_DIR: &str = "/tmp";
+/// Directory of the console logs of the VMs.
+const CONSOLE_LOG_DIR: &str = "/tmp";
+/// Bytes of console kept in memory per VM, replayed to the clients following the console.
+const CONSOLE_HISTORY_SIZE: usize = 64 << 10;
+/// Console lines buffered for the clients following the console.
+const CONSOLE_LINES_CAPACITY: usize = 256;
+/// Directory of the build caches of the VMs, shared with the guests as [`CACHE_TAG`]. They are
+/// kept across VMs and runs of the service, so that the dependencies built by a workload are
+/// reused by the next ones of the same language.
+const CACHE_DIR: &str = "/tmp/cloudlet-cache";
+const CACHE_TAG: &str = "cloudlet-cache";
+
+/// Resources of a VM.
+#[derive(Clone)]
+pub struct VmConfig {
+    /// Language of the workloads run by the VM, which decides its initramfs.
+    pub language: String,
+    pub num_vcpus: u8,
+    pub mem_size_mb: u32,
+    pub kernel: PathBuf,
+    pub initramfs: PathBuf,
+    /// Directories shared with the guest, besides its build cache.
+    pub shared_dirs: Vec<SharedDirConfig>,
+    /// Base of the kernel command line, the default one if not set.
+    pub cmdline: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VmStatus {
+    /// Configured, the vCPUs have not started yet.
+    Created,
+    Running,
+    Paused,
+    /// The guest has been shut down.
+    Stopped,
+}
+
+impl fmt::Display for VmStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let status = match self {
+            VmStatus::Created => "created",
+            VmStatus::Running => "running",
+            VmStatus::Paused => "paused",
+            VmStatus::Stopped => "stopped",
+        };
+        f.write_str(status)
+    }
+}
+
+/// A VM managed by the service.
+pub struct Vm {
+    pub id: String,
+    pub guest_addr: Ipv4Addr,
+    pub config: VmConfig,
+    /// File the serial console of the VM is written to, removed once it's deleted.
+    pub console_log: PathBuf,
+    console_history: ConsoleBuffer,
+    console_lines: broadcast::Sender<Vec<u8>>,
+    /// Time taken by the phases of the boot of the VM.
+    pub boot_timer: BootTimer,
+    status: Mutex<VmStatus>,
+    /// The VMM, until it's started.
+    vmm: Mutex<Option<VMM>>,
+    /// Handle to the VMM, until it stops.
+    handle: Mutex<Option<VmHandle>>,
+    /// Set once the VMM has stopped and released the resources of the VM.
+    exited: watch::Sender<bool>,
+    /// Why the VMM stopped, or its error.
+    exit: Mutex<Option<Result<VmExit, String>>>,
+    vsock_uds: PathBuf,
+    /// Slot of the build cache used by the VM, given back once it's deleted.
+    cache_slot: usize,
+    agent: OnceCell<WorkloadClient>,
+}
+
+impl Vm {
+    pub fn status(&self) -> VmStatus {
+        *self.status.lock().unwrap()
+    }
+
+    /// Why the VM stopped, or the error of its VMM. Set once the VM is stopped, unless it was
+    /// never started.
+    pub fn exit(&self) -> Option<Result<VmExit, String>> {
+        self.exit.lock().unwrap().clone()
+    }
+
+    /// Handle to the VMM, or an error if it has stopped.
+    fn handle(&self) -> Result<VmHandle, VmmErrors> {
+        self.handle
+            .lock()
+            .unwrap()
+            .clone()
+            .ok_or_else(|| self.invalid_status(VmStatus::Stopped))
+    }
+
+    /// Wait until the VMM has stopped and released the resources of the VM.
+    async fn wait_exited(&self) {
+        let _ = self.exited.subscribe().wait_for(|&exited| exited).await;
+    }
+
+    /// Follow the serial console of the VM: the lines printed so far, and a receiver of the
+    /// next ones. A line printed right when following starts may be in both.
+    pub fn follow_console(&self) -> (Vec<Vec<u8>>, broadcast::Receiver<Vec<u8>>) {
+        let lines = self.console_lines.subscribe();
+
+        // The last line is sent to the receiver once complete.
+        let history = self
+            .console_history
+            .contents()
+            .split_inclusive(|&byte| byte == b'\n')
+            .filter(|line| line.ends_with(b"\n"))
+            .map(<[u8]>::to_vec)
+            .collect();
+
+        (history, lines)
+    }
+
+    /// The end of the serial console of the VM, at most `size` bytes starting at a line.
+    pub fn console_tail(&self, size: usize) -> String {
+        let contents = self.console_history.contents();
+        let mut tail = &contents[contents.len().saturating_sub(size)..];
+        if tail.len() < contents.len() {
+            if let Some(newline) = tail.iter().position(|&byte| byte == b'\n') {
+                tail = &tail[newline + 1..];
+            }
+        }
+
+        String::from_utf8_lossy(tail).into_owned()
+    }
+
+    /// Error for an operation not allowed while the VM is `status`.
+    pub fn invalid_status(&self, status: VmStatus) -> VmmErrors {
+        VmmErrors::VmInvalidState(format!("VM {} is {}", self.id, status))
+    }
+
+    /// Client of the agent of the VM, connected on first use. Fails if the agent can't be
+    /// reached within [`AGENT_CONNECT_TIMEOUT`].
+    pub async fn agent(&self) -> Result<WorkloadClient, VmmErrors> {
+        let connect = self
+            .agent
+            .get_or_try_init(|| WorkloadClient::new_vsock(self.vsock_uds.clone(), AGENT_PORT));
+
+        tokio::time::timeout(AGENT_CONNECT_TIMEOUT, connect)
+            .await
+            .map_err(|_| VmmErrors::AgentTimeout)?
+            .map_err(VmmErrors::AgentConnection)
+            .cloned()
+    }
+}
+
+/// Guest addresses of the subnet of the bridge, handed out to the VMs.
+struct AddressPool {
+    host_addr: u32,
+    network: u32,
+    broadcast: u32,
+    next: u32,
+    released: Vec<Ipv4Addr>,
+}
+
+impl AddressPool {
+    fn new(host_addr: Ipv4Addr, netmask: Ipv4Addr) -> Self {
+        let host_addr = u32::from(host_addr);
+        let netmask = u32::from(netmask);
+        let network = host_addr & netmask;
+
+        AddressPool {
+            host_addr,
+            network,
+            broadcast: network | !netmask,
+            next: network + 1,
+            released: Vec::new(),
+        }
+    }
+
+    fn allocate(&mut self) -> Option<Ipv4Addr> {
+        if let Some(addr) = self.released.pop() {
+            return Some(addr);
+        }
+
+        while self.next < self.broadcast {
+            let addr = self.next;
+            self.next += 1;
+            if addr != self.host_addr {
+                return Some(Ipv4Addr::from(addr));
+            }
+        }
+
+        None
+    }
+
+    fn release(&mut self, addr: Ipv4Addr) {
+        let addr_bits = u32::from(addr);
+        if addr_bits > self.network && addr_bits < self.broadcast {
+            self.released.push(addr);
+        }
+    }
+}
+
+/// Build caches of each language. A cache is used by a single VM at a time: the guests don't
+/// see each other's locks on the shared directories, so concurrent builds in the same target
+/// directory would clash.
+#[derive(Default)]
+struct CachePool {
+    /// Slots of each language not used by a VM.
+    free: HashMap<String, BTreeSet<usize>>,
+    /// Number of slots of each language.
+    count: HashMap<String, usize>,
+}
+
+impl CachePool {
+    /// Take a slot for a VM of `language`, the lowest free one so that the caches used the most
+    /// stay warm.
+    fn take(&mut self, language: &str) -> usize {
+        if let Some(slot) = self.free.get_mut(language).and_then(BTreeSet::pop_first) {
+            return slot;
+        }
+
+        let count = self.count.entry(language.to_string()).or_default();
+        *count += 1;
+        *count - 1
+    }
+
+    fn release(&mut self, language: &str, slot: usize) {
+        self.free
+            .entry(language.to_string())
+            .or_default()
+            .insert(slot);
+    }
+
+    fn dir(language: &str, slot: usize) -> PathBuf {
+        PathBuf::from(CACHE_DIR).join(format!("{}-{}", language, slot))
+    }
+}
+
+pub struct VmManager {
+    vms: Mutex<HashMap<String, Arc<Vm>>>,
+    addresses: Mutex<AddressPool>,
+    caches: Mutex<CachePool>,
+    next_id: AtomicU64,
+    /// The bridge, its address and the masquerading rule are set up by the network device of
+    /// the first VM: setting up several VMs at once would race on them.
+    setup: tokio::sync::Mutex<()>,
+}
+
+impl Default for VmManager {
+    fn default() -> Self {
+        VmManager {
+            vms: Mutex::new(HashMap::new()),
+            addresses: Mutex::new(AddressPool::new(HOST_IP, HOST_NETMASK)),
+            caches: Mutex::new(CachePool::default()),
+            next_id: AtomicU64::new(0),
+            setup: tokio::sync::Mutex::new(()),
+        }
+    }
+}
+
+impl VmManager {
+    /// Configure a new VM, which runs once started with [`VmManager::start`].
+    pub async fn create(&self, config: VmConfig) -> Result<Arc<Vm>, VmmErrors> {
+        let id = format!("vm-{}", self.next_id.fetch_add(1, Ordering::Relaxed));
+        let guest_addr = self
+            .addresses
+            .lock()
+            .unwrap()
+            .allocate()
+            .ok_or(VmmErrors::NoAddressAvailable)?;
+        let vsock_uds = PathBuf::from(AGENT_VSOCK_UDS_DIR).join(format!("cloudlet-{}.sock", id));
+        let cache_slot = self.caches.lock().unwrap().take(&config.language);
+        let cache_dir = CachePool::dir(&config.language, cache_slot);
+        let console_log = PathBuf::from(CONSOLE_LOG_DIR).join(format!("cloudlet-{}.log", id));
+        // IDs start over with the service, the log may be left by a VM of a previous run.
+        let _ = std::fs::remove_file(&console_log);
+        let console_history = ConsoleBuffer::new(CONSOLE_HISTORY_SIZE);
+        let (console_lines, _) = broadcast::channel(CONSOLE_LINES_CAPACITY);
+
+        // The service is shared by many VMs, none of them gets the terminal.
+        let console = ConsoleConfig::headless(ConsoleOutput::Tee(vec![
+            ConsoleOutput::File(console_log.clone()),
+            // Recorded before being sent, so that no line is missed by `Vm::follow_console`.
+            ConsoleOutput::Buffer(console_history.clone()),
+            ConsoleOutput::Broadcast(console_lines.clone()),
+        ]));
+
+        let vmm = match self
+            .configure(&config, guest_addr, &vsock_uds, &cache_dir, console)
+            .await
+        {
+            Ok(vmm) => vmm,
+            Err(err) => {
+                self.addresses.lock().unwrap().release(guest_addr);
+                self.caches
+                    .lock()
+                    .unwrap()
+                    .release(&config.language, cache_slot);
+                let _ = std::fs::remove_file(&console_log);
+                return Err(err);
+            }
+        };
+
+        info!(vm_id = id, %guest_addr, console_log = %console_log.display(), "VM created");
+
+        let vm = Arc::new(Vm {
+            id: id.clone(),
+            guest_addr,
+            config,
+            console_log,
+            console_history,
+            console_lines,
+            boot_timer: vmm.boot_timer(),
+            status: Mutex::new(VmStatus::Created),
+            // The VMM is configured, so it has a handle.
+            handle: Mutex::new(vmm.handle()),
+            vmm: Mutex::new(Some(vmm)),
+            exited: watch::channel(false).0,
+            exit: Mutex::new(None),
+            vsock_uds,
+            cache_slot,
+            agent: OnceCell::new(),
+        });
+        self.vms.lock().unwrap().insert(id, vm.clone());
+
+        Ok(vm)
+    }
+
+    async fn configure(
+        &self,
+        config: &VmConfig,
+        guest_addr: Ipv4Addr,
+        vsock_uds: &Path,
+        cache_dir: &Path,
+        console: ConsoleConfig,
+    ) -> Result<VMM, VmmErrors> {
+        std::fs::create_dir_all(cache_dir).map_err(VmmErrors::VmmBuildEnvironment)?;
+        let mut shared_dirs = config.shared_dirs.clone();
+        shared_dirs.push(SharedDirConfig {
+            tag: CACHE_TAG.to_string(),
+            path: cache_dir.to_path_buf(),
+        });
+
+        let _setup = self.setup.lock().await;
+
+        let mut vmm =
+            VMM::new(HOST_IP, HOST_NETMASK, guest_addr, console).map_err(VmmErrors::VmmNew)?;
+        let vsock_config = VsockConfig {
+            guest_cid: AGENT_VSOCK_CID,
+            uds_path: vsock_uds.to_path_buf(),
+        };
+        vmm.configure(
+            config.num_vcpus,
+            config.mem_size_mb,
+            config.kernel.clone(),
+            &Some(config.initramfs.clone()),
+            Vec::new(),
+            shared_dirs,
+            Some(vsock_config),
+            config.cmdline.clone(),
+        )
+        .await
+        .map_err(VmmErrors::VmmConfigure)?;
+
+        Ok(vmm)
+    }
+
+    /// Boot a created VM.
+    pub fn start(&self, id: &str) -> Result<Arc<Vm>, VmmErrors> {
+        let vm = self.get(id)?;
+
+        let mut status = vm.status.lock().unwrap();
+        let vmm = match *status {
+            VmStatus::Created => vm.vmm.lock().unwrap().take(),
+            _ => None,
+        };
+        let Some(mut vmm) = vmm else {
+            return Err(vm.invalid_status(*status));
+        };
+
+        // The VMM blocks on its event loop, it must not hold a worker of the runtime.
+        let running = vm.clone();
+        tokio::task::spawn_blocking(move || {
+            let exit = vmm.run().map_err(VmmErrors::VmmRun);
+            match &exit {
+                Ok(exit) => info!(vm_id = running.id, ?exit, "VM exited"),
+                Err(err) => error!(vm_id = running.id, "Error running VMM: {:?}", err),
+            }
+            *running.exit.lock().unwrap() = Some(exit.map_err(|err| format!("{:?}", err)));
+
+            // Release the memory, the TAP device and the other resources of the VM right away,
+            // only its address and ID are kept until it's deleted.
+            running.handle.lock().unwrap().take();
+            drop(vmm);
+            *running.status.lock().unwrap() = VmStatus::Stopped;
+            running.exited.send_replace(true);
+        });
+
+        *status = VmStatus::Running;
+        drop(status);
+        info!(vm_id = vm.id, "VM started");
+
+        Ok(vm)
+    }
+
+    pub async fn pause(&self, id: &str) -> Result<Arc<Vm>, VmmErrors> {
+        let vm = self.get(id)?;
+
+        let status = vm.status();
+        if status != VmStatus::Running {
+            return Err(vm.invalid_status(status));
+        }
+
+        // Each vCPU is waited for until it leaves the guest, which must not hold a worker of
+        // the runtime.
+        let handle = vm.handle()?;
+        tokio::task::spawn_blocking(move || handle.pause())
+            .await
+            .expect("Pausing the vCPUs panicked")
+            .map_err(VmmErrors::VmmPause)?;
+
+        let mut status = vm.status.lock().unwrap();
+        // The VM may have stopped in the meantime.
+        if *status != VmStatus::Running {
+            return Err(vm.invalid_status(*status));
+        }
+        *status = VmStatus::Paused;
+        drop(status);
+
+        Ok(vm)
+    }
+
+    pub fn resume(&self, id: &str) -> Result<Arc<Vm>, VmmErrors> {
+        let vm = self.get(id)?;
+
+        let mut status = vm.status.lock().unwrap();
+        if *status != VmStatus::Paused {
+            return Err(vm.invalid_status(*status));
+        }
+        vm.handle()?.resume();
+        *status = VmStatus::Running;
+        drop(status);
+
+        Ok(vm)
+    }
+
+    /// Shut down the guest through its agent, or stop its vCPUs if the agent can't. Returns
+    /// once the VMM has stopped.
+    pub async fn stop(&self, id: &str) -> Result<Arc<Vm>, VmmErrors> {
+        let vm = self.get(id)?;
+
+        match vm.status() {
+            VmStatus::Created => {
+                // Never started, the VMM only has to be released.
+                let released = {
+                    let mut status = vm.status.lock().unwrap();
+                    if *status == VmStatus::Created {
+                        vm.vmm.lock().unwrap().take();
+                        vm.handle.lock().unwrap().take();
+                        *status = VmStatus::Stopped;
+                        vm.exited.send_replace(true);
+                    }
+                    *status == VmStatus::Stopped
+                };
+                // Unless it was started in the meantime.
+                if !released {
+                    self.shutdown_guest(&vm).await;
+                }
+            }
+            VmStatus::Paused => {
+                // The agent must run to handle the shutdown.
+                self.resume(id)?;
+                self.shutdown_guest(&vm).await;
+            }
+            VmStatus::Running => self.shutdown_guest(&vm).await,
+            VmStatus::Stopped => return Ok(vm),
+        }
+
+        vm.wait_exited().await;
+        info!(vm_id = vm.id, "VM stopped");
+
+        Ok(vm)
+    }
+
+    /// Ask the agent to shut down the guest, and stop the vCPUs if the guest isn't down within
+    /// [`SHUTDOWN_TIMEOUT`].
+    async fn shutdown_guest(&self, vm: &Vm) {
+        let shutdown = async {
+            self.shutdown_agent(vm).await?;
+            vm.wait_exited().await;
+            Ok::<_, VmmErrors>(())
+        };
+
+        let err = match tokio::time::timeout(SHUTDOWN_TIMEOUT, shutdown).await {
+            Ok(Ok(())) => return,
+            Ok(Err(err)) => err,
+            Err(_) => VmmErrors::AgentTimeout,
+        };
+        warn!(
+            vm_id = vm.id,
+            "Failed to shut down through the agent: {:?}", err
+        );
+
+        if let Ok(handle) = vm.handle() {
+            let _ = tokio::task::spawn_blocking(move || handle.stop()).await;
+        }
+    }
+
+    async fn shutdown_agent(&self, vm: &Vm) -> Result<(), VmmErrors> {
+        let mut client = vm.agent().await?;
+        let response = client
+            .shutdown(ShutdownVmRequest {
+                vm_id: vm.id.clone(),
+            })
+            .await
+            .map_err(VmmErrors::AgentShutdown)?;
+
+        if !response.success {
+            return Err(VmmErrors::AgentShutdown(tonic::Status::internal(
+                "The agent failed to shut down the VM",
+            )));
+        }
+
+        Ok(())
+    }
+
+    pub fn get(&self, id: &str) -> Result<Arc<Vm>, VmmErrors> {
+        self.vms
+            .lock()
+            .unwrap()
+            .get(id)
+            .cloned()
+            .ok_or_else(|| VmmErrors::VmNotFound(id.to_string()))
+    }
+
+    pub fn list(&self) -> Vec<Arc<Vm>> {
+        let mut vms: Vec<_> = self.vms.lock().unwrap().values().cloned().collect();
+        vms.sort_by(|a, b| a.id.cmp(&b.id));
+        vms
+    }
+
+    /// Stop a VM and forget it.
+    pub async fn destroy(&self, id: &str) -> Result<(), VmmErrors> {
+        self.stop(id).await?;
+        self.delete(id)?;
+        Ok(())
+    }
+
+    /// Forget a VM which is stopped or was never started, its address can be handed out again.
+    pub fn delete(&self, id: &str) -> Result<Arc<Vm>, VmmErrors> {
+        let mut vms = self.vms.lock().unwrap();
+        let vm = vms
+            .get(id)
+            .ok_or_else(|| VmmErrors::VmNotFound(id.to_string()))?;
+
+        let status = vm.status();
+        if status != VmStatus::Created && status != VmStatus::Stopped {
+            return Err(vm.invalid_status(status));
+        }
+
+        // Checked above.
+        let vm = vms.remove(id).unwrap();
+        drop(vms);
+
+        vm.vmm.lock().unwrap().take();
+        self.addresses.lock().unwrap().release(vm.guest_addr);
+        let _ = std::fs::remove_file(&vm.vsock_uds);
+        let _ = std::fs::remove_file(&vm.console_log);
+        self.caches
+            .lock()
+            .unwrap()
+            .release(&vm.config.language, vm.cache_slot);
+        info!(vm_id = vm.id, "VM deleted");
+
+        Ok(vm)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allocate_skips_host() {
+        let mut pool = AddressPool::new(HOST_IP, HOST_NETMASK);
+
+        assert_eq!(pool.allocate(), Some(Ipv4Addr::new(172, 29, 0, 2)));
+        assert_eq!(pool.allocate(), Some(Ipv4Addr::new(172, 29, 0, 3)));
+
+        // The host isn't the first address of the subnet.
+        let mut pool =
+            AddressPool::new(Ipv4Addr::new(10, 0, 0, 2), Ipv4Addr::new(255, 255, 255, 0));
+        assert_eq!(pool.allocate(), Some(Ipv4Addr::new(10, 0, 0, 1)));
+        assert_eq!(pool.allocate(), Some(Ipv4Addr::new(10, 0, 0, 3)));
+    }
+
+    #[test]
+    fn release_reuses_address() {
+        let mut pool = AddressPool::new(HOST_IP, HOST_NETMASK);
+        let first = pool.allocate().unwrap();
+        pool.allocate().unwrap();
+
+        pool.release(first);
+        assert_eq!(pool.allocate(), Some(first));
+        assert_eq!(pool.allocate(), Some(Ipv4Addr::new(172, 29, 0, 4)));
+    }
+
+    #[test]
+    fn release_ignores_foreign_addresses() {
+        let mut pool = AddressPool::new(HOST_IP, HOST_NETMASK);
+
+        pool.release(Ipv4Addr::new(10, 0, 0, 2));
+        // Network and broadcast addresses.
+        pool.release(Ipv4Addr::new(172, 29, 0, 0));
+        pool.release(Ipv4Addr::new(172, 29, 255, 255));
+
+        assert_eq!(pool.allocate(), Some(Ipv4Addr::new(172, 29, 0, 2)));
+    }
+
+    #[test]
+    fn exhaustion() {
+        // 172.29.0.0/29: the host takes .1, .0 and .7 are the network and broadcast addresses.
+        let mut pool = AddressPool::new(HOST_IP, Ipv4Addr::new(255, 255, 255, 248));
+
+        let addresses: Vec<_> = std::iter::from_fn(|| pool.allocate()).collect();
+        assert_eq!(
+            addresses,
+            (2..=6)
+                .map(|last| Ipv4Addr::new(172, 29, 0, last))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(pool.allocate(), None);
+
+        pool.release(Ipv4Addr::new(172, 29, 0, 4));
+        assert_eq!(pool.allocate(), Some(Ipv4Addr::new(172, 29, 0, 4)));
+        assert_eq!(pool.allocate(), None);
+    }
+
+    #[test]
+    fn cache_slots() {
+        let mut caches = CachePool::default();
+
+        assert_eq!(caches.take("rust"), 0);
+        assert_eq!(caches.take("rust"), 1);
+        assert_eq!(caches.take("rust"), 2);
+        // Each language has its own slots.
+        assert_eq!(caches.take("python"), 0);
+
+        // The lowest free slot is reused first.
+        caches.release("rust", 2);
+        caches.release("rust", 0);
+        assert_eq!(caches.take("rust"), 0);
+        assert_eq!(caches.take("rust"), 2);
+        assert_eq!(caches.take("rust"), 3);
+
+        assert_eq!(
+            CachePool::dir("rust", 1),
+            Path::new("/tmp/cloudlet-cache/rust-1")
+        );
+    }
+}
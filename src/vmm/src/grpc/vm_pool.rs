@@ -0,0 +1,178 @@
+//! Pools of VMs booted ahead of the requests, with their agent ready. A VM taken from a pool
+//! is never given back: it is replaced right away, and destroyed once its workload is done.
+
+use super::server::vmmorchestrator::Language;
+use super::vm_manager::{Vm, VmConfig, VmManager, VmStatus};
+use crate::VmmErrors;
+use std::collections::{HashMap, VecDeque};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use tracing::{error, info, warn};
+
+/// Bytes of console kept in the error of a VM which failed to boot.
+const BOOT_CONSOLE_TAIL_SIZE: usize = 2048;
+
+/// Number of VMs kept booted for a language, parsed from `<language>=<size>`.
+#[derive(Clone, Debug)]
+pub struct PoolSize {
+    pub language: String,
+    pub size: usize,
+}
+
+impl FromStr for PoolSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (language, size) = s
+            .split_once('=')
+            .ok_or_else(|| format!("invalid pool size {}, expected <language>=<size>", s))?;
+
+        let language = language.to_lowercase();
+        if Language::from_str_name(&language.to_uppercase()).is_none() {
+            return Err(format!("unknown language {}", language));
+        }
+        let size = size
+            .parse()
+            .map_err(|_| format!("invalid pool size {}, expected a number", size))?;
+
+        Ok(PoolSize { language, size })
+    }
+}
+
+#[derive(Default)]
+struct Pool {
+    ready: VecDeque<Arc<Vm>>,
+    booting: usize,
+}
+
+pub struct VmPool {
+    vms: Arc<VmManager>,
+    sizes: HashMap<String, usize>,
+    pools: Mutex<HashMap<String, Pool>>,
+}
+
+impl VmPool {
+    pub fn new(vms: Arc<VmManager>, sizes: &[PoolSize]) -> Self {
+        VmPool {
+            vms,
+            sizes: sizes
+                .iter()
+                .map(|pool_size| (pool_size.language.clone(), pool_size.size))
+                .collect(),
+            pools: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Languages with a pool.
+    pub fn languages(&self) -> impl Iterator<Item = &str> {
+        self.sizes
+            .iter()
+            .filter(|(_, size)| **size > 0)
+            .map(|(language, _)| language.as_str())
+    }
+
+    /// Boot VMs in the background until the pool of `language` is full.
+    pub fn fill(self: &Arc<Self>, language: &str, config: &VmConfig) {
+        let size = self.sizes.get(language).copied().unwrap_or_default();
+
+        let missing = {
+            let mut pools = self.pools.lock().unwrap();
+            let pool = pools.entry(language.to_string()).or_default();
+            let missing = size.saturating_sub(pool.ready.len() + pool.booting);
+            pool.booting += missing;
+            missing
+        };
+
+        for _ in 0..missing {
+            let pool = self.clone();
+            let language = language.to_string();
+            let config = config.clone();
+
+            tokio::spawn(async move {
+                let vm = pool.boot(config).await;
+
+                let mut pools = pool.pools.lock().unwrap();
+                let entry = pools.entry(language.clone()).or_default();
+                entry.booting -= 1;
+                match vm {
+                    Ok(vm) => {
+                        info!(vm_id = vm.id, language, "VM ready in pool");
+                        entry.ready.push_back(vm);
+                    }
+                    Err(err) => error!(language, "Failed to boot a pooled VM: {:?}", err),
+                }
+            });
+        }
+    }
+
+    /// Boot a VM and wait for its agent to be ready. The VM is destroyed if it doesn't get
+    /// ready, the error then holds the end of its console.
+    async fn boot(&self, config: VmConfig) -> Result<Arc<Vm>, VmmErrors> {
+        let vm = self.vms.create(config).await?;
+
+        let ready = match self.vms.start(&vm.id) {
+            Ok(_) => vm.agent().await.map(|_| ()),
+            Err(err) => Err(err),
+        };
+        if let Err(err) = ready {
+            if let Err(destroy_err) = self.vms.destroy(&vm.id).await {
+                error!(vm_id = vm.id, "Failed to destroy the VM: {:?}", destroy_err);
+            }
+            // Taken once the VM stopped, so that it holds its last words.
+            let console = vm.console_tail(BOOT_CONSOLE_TAIL_SIZE);
+            return Err(VmmErrors::VmBoot {
+                error: Box::new(err),
+                console,
+            });
+        }
+
+        Ok(vm)
+    }
+
+    /// Take the first VM of the pool of `language` which is still running, the ones which
+    /// stopped in the meantime are destroyed.
+    fn pop_ready(&self, language: &str) -> Option<Arc<Vm>> {
+        let mut pools = self.pools.lock().unwrap();
+        let ready = &mut pools.get_mut(language)?.ready;
+
+        while let Some(vm) = ready.pop_front() {
+            let status = vm.status();
+            if status == VmStatus::Running {
+                return Some(vm);
+            }
+
+            warn!(vm_id = vm.id, %status, "Dropping a pooled VM which isn't running");
+            let vms = self.vms.clone();
+            tokio::spawn(async move {
+                if let Err(err) = vms.destroy(&vm.id).await {
+                    error!(vm_id = vm.id, "Failed to destroy the VM: {:?}", err);
+                }
+            });
+        }
+
+        None
+    }
+
+    /// Take a VM from the pool of `language` and boot its replacement. A VM is booted on the
+    /// spot when the pool is empty, or when `config` has its own kernel command line.
+    pub async fn take(
+        self: &Arc<Self>,
+        language: &str,
+        config: VmConfig,
+    ) -> Result<Arc<Vm>, VmmErrors> {
+        if config.cmdline.is_some() {
+            return self.boot(config).await;
+        }
+
+        let vm = self.pop_ready(language);
+        self.fill(language, &config);
+
+        match vm {
+            Some(vm) => {
+                info!(vm_id = vm.id, language, "VM taken from pool");
+                Ok(vm)
+            }
+            None => self.boot(config).await,
+        }
+    }
+}
@@ -2,6 +2,8 @@ pub mod core;
 pub mod grpc {
     pub mod client;
     pub mod server;
+    pub mod vm_manager;
+    pub mod vm_pool;
 }
 
 #[derive(Debug)]
@@ -9,5 +11,20 @@ pub enum VmmErrors {
     VmmNew(core::Error),
     VmmConfigure(core::Error),
     VmmRun(core::Error),
+    VmmRestore(core::Error),
     VmmBuildEnvironment(std::io::Error),
+    VmNotFound(String),
+    NoAddressAvailable,
+    /// The VM is not in a state allowing the operation.
+    VmInvalidState(String),
+    VmmPause(core::Error),
+    AgentConnection(tonic::transport::Error),
+    /// The agent didn't answer in time.
+    AgentTimeout,
+    AgentShutdown(tonic::Status),
+    /// The VM failed to boot, `console` holds the end of its console.
+    VmBoot {
+        error: Box<VmmErrors>,
+        console: String,
+    },
 }
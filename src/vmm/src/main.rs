@@ -1,13 +1,29 @@
-use crate::args::{CliArgs, Commands};
+use crate::args::{CliArgs, Commands, RunWorkloadArguments};
+use crate::config::VmDefinition;
 use clap::Parser;
-use tonic::transport::Server;
-use tracing::info;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_stream::StreamExt;
+use tonic::{transport::Server, Request};
+use tracing::{error, info, warn};
 use vmm::{
-    core::vmm::VMM,
-    grpc::server::{vmmorchestrator, VmmService},
+    core::vmm::{VmHandle, VMM},
+    grpc::server::{
+        vmmorchestrator::{
+            self, execute_response::Stage, vmm_service_server::VmmService as _, ListVmsRequest,
+            LogLevel, RunVmmRequest,
+        },
+        VmmService,
+    },
     VmmErrors,
 };
 mod args;
+mod config;
+
+/// Time given to the service to destroy the VM of a workload once it's done.
+const DESTROY_TIMEOUT: Duration = Duration::from_secs(30);
+const DESTROY_POLL_INTERVAL: Duration = Duration::from_millis(50);
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -21,12 +37,15 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     );
 
     let addr = "[::1]:50051".parse().unwrap();
-    let vmm_service = VmmService;
 
     // check if the args is grpc or command
     match args.command {
-        Commands::Grpc => {
+        Commands::Grpc(grpc_args) => {
             tracing_subscriber::fmt().init();
+
+            let vmm_service = VmmService::new(&grpc_args.pool_sizes);
+            vmm_service.warm_up().unwrap();
+
             Server::builder()
                 .add_service(vmmorchestrator::vmm_service_server::VmmServiceServer::new(
                     vmm_service,
@@ -39,29 +58,194 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .with_max_level(cli_args.convert_log_to_tracing())
                 .init();
 
+            let definition = match &cli_args.config {
+                Some(path) => VmDefinition::load(path)?,
+                None => VmDefinition::from_args(&cli_args),
+            };
+            definition.validate()?;
+
             // Create a new VMM
+            let network = definition.network();
             let mut vmm = VMM::new(
-                cli_args.iface_host_addr,
-                cli_args.netmask,
-                cli_args.iface_guest_addr,
+                network.host_addr,
+                network.netmask,
+                network.guest_addr,
+                definition.console_config(),
             )
             .map_err(VmmErrors::VmmNew)
             .unwrap();
 
             vmm.configure(
-                cli_args.cpus,
-                cli_args.memory,
-                cli_args.kernel,
-                &cli_args.initramfs,
+                definition.machine.cpus,
+                definition.machine.memory_mb,
+                definition.kernel.path.clone(),
+                &definition.kernel.initramfs,
+                definition.block_configs(),
+                definition.shared_dir_configs(),
+                definition.vsock_config(),
+                definition.kernel.cmdline.clone(),
             )
             .await
             .map_err(VmmErrors::VmmConfigure)
             .unwrap();
 
-            // Run the VMM
-            vmm.run().map_err(VmmErrors::VmmRun).unwrap();
+            if let (Some(snapshot_dir), Some(handle)) = (cli_args.snapshot_dir, vmm.handle()) {
+                snapshot_on_signal(handle, snapshot_dir)?;
+            }
+            if let (Some(timeout), Some(handle)) = (definition.timeout(), vmm.handle()) {
+                stop_after(handle, timeout);
+            }
+
+            // Run the VMM, then exit with the status reported by the guest
+            let exit = vmm.run().map_err(VmmErrors::VmmRun).unwrap();
+            drop(vmm);
+            std::process::exit(exit.exit_code());
+        }
+        Commands::Restore(restore_args) => {
+            tracing_subscriber::fmt()
+                .with_max_level(restore_args.convert_log_to_tracing())
+                .init();
+
+            let mut vmm = VMM::restore(
+                &restore_args.snapshot_dir,
+                restore_args.memory_restore,
+                restore_args.console(),
+            )
+            .await
+            .map_err(VmmErrors::VmmRestore)
+            .unwrap();
+
+            // Run the VMM, then exit with the status reported by the guest
+            let exit = vmm.run().map_err(VmmErrors::VmmRun).unwrap();
+            drop(vmm);
+            std::process::exit(exit.exit_code());
+        }
+        Commands::RunWorkload(run_args) => {
+            tracing_subscriber::fmt()
+                .with_max_level(run_args.convert_log_to_tracing())
+                .with_writer(std::io::stderr)
+                .init();
+
+            let exit_code = run_workload(run_args).await?;
+            std::process::exit(exit_code);
+        }
+    }
+
+    Ok(())
+}
+
+/// Run a workload through the gRPC service, in process, printing its output as it arrives.
+/// Return the exit code of the workload.
+async fn run_workload(args: RunWorkloadArguments) -> Result<i32, Box<dyn std::error::Error>> {
+    let code = std::fs::read_to_string(&args.code)?;
+    let log_level = if args.console {
+        LogLevel::Debug
+    } else {
+        LogLevel::Info
+    };
+
+    let vmm_service = VmmService::default();
+    let request = RunVmmRequest {
+        workload_name: args.workload_name,
+        language: args.language as i32,
+        code,
+        log_level: log_level as i32,
+        outputs: args.outputs,
+        kernel_cmdline: args.cmdline,
+    };
+    let mut responses = vmm_service.run(Request::new(request)).await?.into_inner();
+
+    let mut vm_id = None;
+    let mut exit_code = None;
+    while let Some(response) = responses.next().await {
+        let response = response?;
+        vm_id.get_or_insert(response.vm_id.clone());
+
+        let stage = Stage::from_i32(response.stage).unwrap_or_default();
+        if let Some(stdout) = response.stdout {
+            match stage {
+                // The console and the progress of the agent don't belong to the workload.
+                Stage::Debug | Stage::Pending | Stage::Building => eprintln!("{}", stdout),
+                _ => println!("{}", stdout),
+            }
+        }
+        if let Some(stderr) = response.stderr {
+            eprintln!("{}", stderr);
+        }
+        if let Some(artifact) = response.artifact {
+            info!(
+                path = artifact.path,
+                size = artifact.size,
+                "Artifact collected"
+            );
+        }
+        if let Some(termination) = response.termination {
+            warn!(
+                signal = %termination.signal_name,
+                core_dumped = termination.core_dumped,
+                "The workload was killed"
+            );
+            // Same convention as the shells.
+            exit_code = Some(128 + termination.signal);
+        }
+        if response.exit_code.is_some() {
+            exit_code = response.exit_code;
+        }
+    }
+
+    // The service destroys the VM once the stream is over, its resources must be released
+    // before exiting.
+    if let Some(vm_id) = vm_id {
+        if tokio::time::timeout(DESTROY_TIMEOUT, wait_destroyed(&vmm_service, &vm_id))
+            .await
+            .is_err()
+        {
+            warn!(vm_id, "The VM was not destroyed in time");
         }
     }
 
+    exit_code.ok_or_else(|| "The workload did not report an exit code".into())
+}
+
+/// Wait until the service no longer knows the VM `vm_id`.
+async fn wait_destroyed(vmm_service: &VmmService, vm_id: &str) {
+    loop {
+        let vms = match vmm_service.list_vms(Request::new(ListVmsRequest {})).await {
+            Ok(response) => response.into_inner().vms,
+            Err(_) => return,
+        };
+        if vms.iter().all(|vm| vm.vm_id != vm_id) {
+            return;
+        }
+        tokio::time::sleep(DESTROY_POLL_INTERVAL).await;
+    }
+}
+
+/// Save a snapshot of the VM to `snapshot_dir` each time SIGUSR1 is received.
+fn snapshot_on_signal(handle: VmHandle, snapshot_dir: PathBuf) -> std::io::Result<()> {
+    let mut signals = signal(SignalKind::user_defined1())?;
+
+    tokio::spawn(async move {
+        while signals.recv().await.is_some() {
+            let handle = handle.clone();
+            let snapshot_dir = snapshot_dir.clone();
+
+            match tokio::task::spawn_blocking(move || handle.snapshot(&snapshot_dir)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => error!("Failed to snapshot the VM: {:?}", e),
+                Err(e) => error!("Snapshot task failed: {:?}", e),
+            }
+        }
+    });
+
     Ok(())
 }
+
+/// Stop the VM once it has run for `timeout`.
+fn stop_after(handle: VmHandle, timeout: Duration) {
+    tokio::spawn(async move {
+        tokio::time::sleep(timeout).await;
+        warn!("The VM has run for {:?}, stopping it", timeout);
+        let _ = tokio::task::spawn_blocking(move || handle.stop()).await;
+    });
+}